@@ -7,18 +7,40 @@ interpreters that maintain state across executions.
 
 Protocol: [4-byte big-endian length][JSON payload]
 
+Inside a microVM, set SANDBOX_AGENT_VSOCK_PORT to accept the daemon's
+connection on AF_VSOCK instead; the protocol is unchanged. Likewise
+SANDBOX_AGENT_TCP_ADDR=[host:]port listens on TCP (loopback unless a host is
+given), and SANDBOX_AGENT_TOKEN makes a TCP daemon authenticate first.
+
+Cancel requests arrive out-of-band while an execution is in flight, so a
+reader thread consumes stdin and interrupts the running interpreter on
+cancel; every other message is queued for the main loop. Cancel never
+gets a response of its own — the interrupted execution's result is the
+reply.
+
 CRITICAL: Real stdin/stdout are saved at startup and used exclusively
 for protocol messages. sys.stdout/sys.stderr are replaced to prevent
 user code from corrupting the protocol stream.
 """
 
+import codecs
+import hmac
 import io
+import _thread
 import json
 import os
+import queue
+import re
 import secrets
+import shlex
+import shutil
+import signal
+import socket
 import struct
 import subprocess
 import sys
+import threading
+import zlib
 from contextlib import redirect_stderr, redirect_stdout
 
 # ─────────────────────────────────────────────────────────────────
@@ -35,14 +57,104 @@ sys.stderr = open("/workspace/.agent.log", "a")
 
 
 def send_message(msg: dict) -> None:
-    """Send a length-prefixed JSON message on real stdout."""
+    """Send a length-prefixed JSON message on real stdout.
+
+    Large payloads are gzip-compressed once the daemon has enabled it.
+    """
     payload = json.dumps(msg).encode()
-    REAL_STDOUT.write(struct.pack(">I", len(payload)))
+    header = len(payload)
+    if COMPRESS_RESPONSES and len(payload) >= COMPRESSION_THRESHOLD:
+        compressor = zlib.compressobj(1, wbits=31)  # gzip container, fast
+        payload = compressor.compress(payload) + compressor.flush()
+        header = len(payload) | GZIP_FLAG
+    REAL_STDOUT.write(struct.pack(">I", header))
     REAL_STDOUT.write(payload)
     REAL_STDOUT.flush()
 
 
+# Seconds a TCP client gets to send its token before it's disconnected
+AUTH_TIMEOUT = 10
+
+
+def listen_tcp(addr: str) -> None:
+    """Accept one daemon connection on TCP ([host:]port) and use it for protocol I/O.
+
+    Listens on loopback unless a host is given. With SANDBOX_AGENT_TOKEN set,
+    the first message must be {"type": "auth", "token": ...} with that token;
+    other connections are closed and the agent keeps listening.
+    The connection is plain TCP; put a TLS terminator in front for remote daemons.
+    """
+    global REAL_STDIN, REAL_STDOUT
+    host, _, port = addr.rpartition(":")
+    token = os.environ.get("SANDBOX_AGENT_TOKEN") or None
+    listener = socket.create_server((host or "127.0.0.1", int(port)))
+    while True:
+        conn, _ = listener.accept()
+        conn.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+        REAL_STDIN = conn.makefile("rb")
+        REAL_STDOUT = conn.makefile("wb")
+        if token is None or authenticated(conn, token):
+            break
+        REAL_STDIN.close()
+        REAL_STDOUT.close()
+        conn.close()
+    listener.close()
+
+
+def authenticated(conn: socket.socket, token: str) -> bool:
+    """Read the client's first message and check it carries `token`."""
+    conn.settimeout(AUTH_TIMEOUT)
+    try:
+        msg = recv_message()
+    except (OSError, EOFError, ValueError, zlib.error):
+        return False
+    finally:
+        conn.settimeout(None)
+    given = msg.get("token") if isinstance(msg, dict) and msg.get("type") == "auth" else None
+    return isinstance(given, str) and hmac.compare_digest(given.encode(), token.encode())
+
+
+def listen_vsock(port: int) -> None:
+    """Accept one daemon connection on AF_VSOCK and use it for protocol I/O."""
+    global REAL_STDIN, REAL_STDOUT
+    listener = socket.socket(socket.AF_VSOCK, socket.SOCK_STREAM)
+    listener.bind((socket.VMADDR_CID_ANY, port))
+    listener.listen(1)
+    conn, _ = listener.accept()
+    listener.close()
+    REAL_STDIN = conn.makefile("rb")
+    REAL_STDOUT = conn.makefile("wb")
+
+
 MAX_MESSAGE_SIZE = 64 * 1024 * 1024  # 64 MB, matches Rust transport limit
+PROTOCOL_VERSION = 2  # daemon accepts up to SUPPORTED_PROTOCOL (transport/protocol.rs)
+
+# Framing: the length header's high bit marks a gzip payload. Payloads at
+# least COMPRESSION_THRESHOLD bytes are compressed, matching the daemon.
+GZIP_FLAG = 1 << 31
+COMPRESSION_THRESHOLD = 16 * 1024
+
+# Set when the daemon sends enable_compression (only after we announce gzip)
+COMPRESS_RESPONSES = False
+
+# Codec subprocess interpreters' output is decoded with, from the daemon's
+# WHATWG name for the environment's output_encoding; UTF-8 if Python lacks it
+try:
+    OUTPUT_ENCODING = codecs.lookup(os.environ.get("SANDBOX_OUTPUT_ENCODING", "utf-8")).name
+except LookupError:
+    OUTPUT_ENCODING = "utf-8"
+
+# Version the daemon asked us to speak (use_protocol); ours unless told lower
+ACTIVE_PROTOCOL = PROTOCOL_VERSION
+
+
+def decompress(payload: bytes) -> bytes:
+    """Inflate a gzip payload, refusing output beyond MAX_MESSAGE_SIZE."""
+    decompressor = zlib.decompressobj(wbits=31)
+    data = decompressor.decompress(payload, MAX_MESSAGE_SIZE + 1)
+    if len(data) > MAX_MESSAGE_SIZE:
+        raise ValueError(f"decompressed message exceeds {MAX_MESSAGE_SIZE} bytes")
+    return data
 
 
 def recv_message() -> dict:
@@ -50,12 +162,15 @@ def recv_message() -> dict:
     raw_len = REAL_STDIN.read(4)
     if len(raw_len) < 4:
         raise EOFError("stdin closed")
-    (length,) = struct.unpack(">I", raw_len)
+    (header,) = struct.unpack(">I", raw_len)
+    length = header & ~GZIP_FLAG
     if length > MAX_MESSAGE_SIZE:
         raise ValueError(f"message too large: {length} bytes (max {MAX_MESSAGE_SIZE})")
     payload = REAL_STDIN.read(length)
     if len(payload) < length:
         raise EOFError("incomplete message")
+    if header & GZIP_FLAG:
+        payload = decompress(payload)
     return json.loads(payload)
 
 
@@ -63,6 +178,10 @@ def recv_message() -> dict:
 # Interpreters
 # ─────────────────────────────────────────────────────────────────
 
+# Exit code reported for cancelled executions (128 + SIGINT, like a shell)
+CANCELLED_EXIT_CODE = 130
+CANCELLED_MESSAGE = "Execution cancelled\n"
+
 
 class PythonInterpreter:
     """Persistent Python interpreter using exec() with a single shared namespace.
@@ -90,26 +209,45 @@ class PythonInterpreter:
                 buf_err.getvalue(),
                 e.code if isinstance(e.code, int) else 1,
             )
+        except KeyboardInterrupt:
+            return buf_out.getvalue(), buf_err.getvalue() + CANCELLED_MESSAGE, CANCELLED_EXIT_CODE
         except Exception:
             import traceback
 
             tb = traceback.format_exc()
             return buf_out.getvalue(), buf_err.getvalue() + tb, 1
 
+    def interrupt(self):
+        """Interrupt the running exec() by raising KeyboardInterrupt in the main thread."""
+        _thread.interrupt_main()
+
+    def set_env(self, key: str, value: str) -> None:
+        """Nothing to do: exec() shares this process's os.environ."""
+
 
 class BashInterpreter:
-    """Persistent bash process with per-execution nonce markers."""
+    """Persistent bash process with per-execution nonce markers.
+
+    Runs in its own process group so a cancel can SIGINT the foreground
+    command. The no-op INT trap keeps bash itself alive (a trapped signal
+    is reset to default in children, unlike an ignored one).
+    """
 
     def __init__(self):
+        self.cancelled = False
         self.proc = subprocess.Popen(
             ["bash", "--norc", "--noprofile"],
             stdin=subprocess.PIPE,
             stdout=subprocess.PIPE,
             stderr=subprocess.PIPE,
+            start_new_session=True,
         )
+        self.proc.stdin.write(b"trap ':' INT\n")
+        self.proc.stdin.flush()
 
     def execute(self, code: str) -> tuple[str, str, int]:
         """Execute code, returning (stdout, stderr, exit_code)."""
+        self.cancelled = False
         nonce = secrets.token_hex(16)
         stdout_marker = f"__STDOUT_DONE_{nonce}__"
         stderr_marker = f"__STDERR_DONE_{nonce}__"
@@ -129,7 +267,7 @@ class BashInterpreter:
         stdout_lines = []
         exit_code = 0
         for line in iter(self.proc.stdout.readline, b""):
-            decoded = line.decode(errors="replace")
+            decoded = line.decode(OUTPUT_ENCODING, errors="replace")
             if stdout_marker in decoded:
                 # Parse exit code from marker line
                 parts = decoded.strip().split()
@@ -148,13 +286,25 @@ class BashInterpreter:
         # Read stderr until marker
         stderr_lines = []
         for line in iter(self.proc.stderr.readline, b""):
-            decoded = line.decode(errors="replace")
+            decoded = line.decode(OUTPUT_ENCODING, errors="replace")
             if stderr_marker in decoded:
                 break
             stderr_lines.append(decoded)
 
+        if self.cancelled:
+            stderr_lines.append(CANCELLED_MESSAGE)
+            exit_code = CANCELLED_EXIT_CODE
         return "".join(stdout_lines), "".join(stderr_lines), exit_code
 
+    def interrupt(self):
+        """Send SIGINT to the bash process group."""
+        self.cancelled = True
+        os.killpg(self.proc.pid, signal.SIGINT)
+
+    def set_env(self, key: str, value: str) -> None:
+        """Export the variable in the running shell."""
+        self.execute(f"export {key}={shlex.quote(value)}")
+
     def close(self):
         if self.proc.poll() is None:
             self.proc.stdin.close()
@@ -171,6 +321,7 @@ class NodeInterpreter:
     - writer: () => ''     — suppress all result echo
     - output → stderr      — REPL chrome goes to stderr, not stdout
     - No try/catch wrap    — let/const persist in REPL top-level scope
+    - breakEvalOnSigint    — SIGINT aborts the running eval (cancel)
     """
 
     REPL_SETUP = (
@@ -179,6 +330,7 @@ class NodeInterpreter:
         "prompt:'',"
         "ignoreUndefined:true,"
         "writer:()=>'',"
+        "breakEvalOnSigint:true,"
         "output:new(require('stream').Writable)("
         "{write(c,e,cb){process.stderr.write(c,e,cb)}})"
         "});"
@@ -192,6 +344,7 @@ class NodeInterpreter:
         # (node -e doesn't fully initialize stdin as a readable stream)
         with open(self.REPL_SETUP_PATH, "w") as f:
             f.write(self.REPL_SETUP)
+        self.cancelled = False
         self.proc = subprocess.Popen(
             ["node", self.REPL_SETUP_PATH],
             stdin=subprocess.PIPE,
@@ -201,6 +354,7 @@ class NodeInterpreter:
 
     def execute(self, code: str) -> tuple[str, str, int]:
         """Execute code, returning (stdout, stderr, exit_code)."""
+        self.cancelled = False
         nonce = secrets.token_hex(16)
         stdout_marker = f"__STDOUT_DONE_{nonce}__"
         stderr_marker = f"__STDERR_DONE_{nonce}__"
@@ -219,7 +373,7 @@ class NodeInterpreter:
 
         stdout_lines = []
         for line in iter(self.proc.stdout.readline, b""):
-            decoded = line.decode(errors="replace")
+            decoded = line.decode(OUTPUT_ENCODING, errors="replace")
             if stdout_marker in decoded:
                 break
             stdout_lines.append(decoded)
@@ -229,7 +383,7 @@ class NodeInterpreter:
 
         stderr_lines = []
         for line in iter(self.proc.stderr.readline, b""):
-            decoded = line.decode(errors="replace")
+            decoded = line.decode(OUTPUT_ENCODING, errors="replace")
             if stderr_marker in decoded:
                 break
             if decoded.strip():  # Skip empty lines from REPL writer
@@ -238,8 +392,20 @@ class NodeInterpreter:
         stdout = "".join(stdout_lines)
         stderr = "".join(stderr_lines)
         exit_code = 1 if "Uncaught" in stderr else 0
+        if self.cancelled:
+            stderr += CANCELLED_MESSAGE
+            exit_code = CANCELLED_EXIT_CODE
         return stdout, stderr, exit_code
 
+    def interrupt(self):
+        """Send SIGINT to node; the REPL aborts the running eval."""
+        self.cancelled = True
+        self.proc.send_signal(signal.SIGINT)
+
+    def set_env(self, key: str, value: str) -> None:
+        """Set the variable in the running REPL's process.env."""
+        self.execute(f"process.env[{json.dumps(key)}] = {json.dumps(value)};")
+
     def close(self):
         if self.proc.poll() is None:
             self.proc.stdin.close()
@@ -258,7 +424,59 @@ INTERPRETER_CLASSES = {
     "node": NodeInterpreter,
 }
 
-def dispatch_execute(interpreters: dict, interpreter_name: str, code: str) -> dict:
+# External commands the subprocess-backed interpreters need
+INTERPRETER_COMMANDS = {
+    "bash": "bash",
+    "node": "node",
+}
+
+
+def available_interpreters() -> list:
+    """Interpreters whose command is on PATH (python always is: it's us)."""
+    return [
+        name for name in INTERPRETER_CLASSES
+        if name not in INTERPRETER_COMMANDS or shutil.which(INTERPRETER_COMMANDS[name])
+    ]
+
+
+def capabilities() -> dict:
+    """Describe this agent for the Ready handshake.
+
+    Only interpreters whose command is on PATH are advertised, so the daemon
+    can reject e.g. node code in a Python-only environment up front.
+    """
+    return {
+        "protocol_version": PROTOCOL_VERSION,
+        # v2 only adds execute_batch, so v1 daemons can still talk to us
+        "min_protocol_version": 1,
+        "interpreters": available_interpreters(),
+        "gzip": True,
+        "reset": True,
+        "session_env": True,
+        "list_interpreters": True,
+    }
+
+
+# The execution currently in flight: (request id, interpreter instance).
+# Guarded by IN_FLIGHT_LOCK since the reader thread handles cancel.
+IN_FLIGHT = None
+IN_FLIGHT_LOCK = threading.Lock()
+
+
+def cancel_execution(req_id: str) -> None:
+    """Interrupt the in-flight execution if its id matches.
+
+    A cancel that arrives after the execution finished is dropped — the
+    daemon already has (or is about to read) the result.
+    """
+    with IN_FLIGHT_LOCK:
+        if IN_FLIGHT is None or IN_FLIGHT[0] != req_id:
+            print(f"Ignoring cancel for '{req_id}': not in flight", file=sys.stderr)
+            return
+        IN_FLIGHT[1].interrupt()
+
+
+def dispatch_execute(interpreters: dict, interpreter_name: str, code: str, req_id: str = "") -> dict:
     """Dispatch code execution to the appropriate interpreter.
 
     Lazily creates interpreter instances on first use and caches them.
@@ -271,43 +489,155 @@ def dispatch_execute(interpreters: dict, interpreter_name: str, code: str) -> di
     if interpreter_name not in interpreters:
         interpreters[interpreter_name] = INTERPRETER_CLASSES[interpreter_name]()
 
-    stdout, stderr, exit_code = interpreters[interpreter_name].execute(code)
+    global IN_FLIGHT
+    interp = interpreters[interpreter_name]
+    with IN_FLIGHT_LOCK:
+        IN_FLIGHT = (req_id, interp)
+    try:
+        stdout, stderr, exit_code = interp.execute(code)
+    finally:
+        with IN_FLIGHT_LOCK:
+            IN_FLIGHT = None
     return {"stdout": stdout, "stderr": stderr, "exit_code": exit_code}
 
 
+def dispatch_batch(
+    interpreters: dict, interpreter_name: str, fragments: list, stop_on_error: bool, req_id: str = ""
+) -> list:
+    """Run code fragments in order in one interpreter, one result per fragment run.
+
+    Stops after a cancelled fragment, and after any failing fragment when
+    stop_on_error is set.
+    """
+    results = []
+    for code in fragments:
+        try:
+            result = dispatch_execute(interpreters, interpreter_name, code, req_id)
+        except KeyboardInterrupt:
+            # Cancel landed just outside exec() — still report it as cancelled
+            result = {"stdout": "", "stderr": CANCELLED_MESSAGE, "exit_code": CANCELLED_EXIT_CODE}
+        results.append(result)
+        if result["exit_code"] == CANCELLED_EXIT_CODE or (stop_on_error and result["exit_code"] != 0):
+            break
+    return results
+
+
 # ─────────────────────────────────────────────────────────────────
 # Main loop
 # ─────────────────────────────────────────────────────────────────
 
 
-def main():
-    # Send Ready message
-    send_message({"type": "ready"})
+def reset_interpreters(interpreters: dict) -> None:
+    """Drop every interpreter instance, clearing globals and imports.
 
-    interpreters = {}
+    The next execute lazily creates fresh ones. Files on disk (/workspace)
+    are untouched, and this process keeps running.
+    """
+    for interp in interpreters.values():
+        if hasattr(interp, "close"):
+            interp.close()
+    interpreters.clear()
+
+
+ENV_KEY = re.compile(r"[A-Za-z_][A-Za-z0-9_]*")
+
+
+def set_session_env(interpreters: dict, key: str, value: str) -> None:
+    """Set an env var for this agent and every interpreter it runs.
 
+    Interpreters started later inherit it from os.environ; running ones
+    are told directly, since their environment was copied at startup.
+    """
+    if not ENV_KEY.fullmatch(key):
+        raise ValueError(f"invalid variable name '{key}'")
+    os.environ[key] = value
+    for interp in interpreters.values():
+        interp.set_env(key, value)
+
+
+def reader_loop(inbox: queue.Queue) -> None:
+    """Read protocol messages; handle cancel immediately, queue the rest.
+
+    Runs on a daemon thread so cancel can interrupt the main thread while
+    it's busy executing. Puts None on EOF.
+    """
+    global COMPRESS_RESPONSES, ACTIVE_PROTOCOL
     while True:
         try:
             msg = recv_message()
         except EOFError:
-            break
-        except (json.JSONDecodeError, ValueError) as e:
-            send_message({"type": "error", "message": f"Bad message: {e}"})
+            inbox.put(None)
+            return
+        except (json.JSONDecodeError, ValueError, zlib.error) as e:
+            inbox.put({"type": "_bad_message", "error": str(e)})
             continue
 
+        if msg.get("type") == "cancel":
+            cancel_execution(msg.get("id", ""))
+        elif msg.get("type") == "enable_compression":
+            COMPRESS_RESPONSES = True
+        elif msg.get("type") == "use_protocol":
+            ACTIVE_PROTOCOL = int(msg.get("version", PROTOCOL_VERSION))
+        elif msg.get("type") == "auth":
+            pass  # A token this agent wasn't started with; needs no reply
+        else:
+            inbox.put(msg)
+
+
+def main():
+    vsock_port = os.environ.get("SANDBOX_AGENT_VSOCK_PORT")
+    tcp_addr = os.environ.get("SANDBOX_AGENT_TCP_ADDR")
+    if vsock_port:
+        listen_vsock(int(vsock_port))
+    elif tcp_addr:
+        listen_tcp(tcp_addr)
+
+    # Send Ready message
+    send_message({"type": "ready", "capabilities": capabilities()})
+
+    interpreters = {}
+    inbox = queue.Queue()
+    threading.Thread(target=reader_loop, args=(inbox,), daemon=True).start()
+
+    while True:
+        msg = inbox.get()
+        if msg is None:
+            break
+
         msg_type = msg.get("type")
 
+        if msg_type == "_bad_message":
+            send_message({"type": "error", "message": f"Bad message: {msg['error']}"})
+            continue
+
         if msg_type == "shutdown":
             break
         elif msg_type == "ping":
             send_message({"type": "pong"})
+        elif msg_type == "list_interpreters":
+            send_message({"type": "interpreters", "names": available_interpreters()})
+        elif msg_type in ("set_env", "get_env"):
+            key = msg.get("key", "")
+            try:
+                if msg_type == "set_env":
+                    set_session_env(interpreters, key, msg.get("value", ""))
+                send_message({"type": "env_value", "id": msg.get("id", ""), "key": key, "value": os.environ.get(key)})
+            except Exception as e:
+                send_message({"type": "error", "message": f"Setting {key} failed: {e}"})
+        elif msg_type == "reset":
+            try:
+                reset_interpreters(interpreters)
+                send_message({"type": "reset_done", "id": msg.get("id", "")})
+            except Exception as e:
+                interpreters.clear()
+                send_message({"type": "error", "message": f"Reset failed: {e}"})
         elif msg_type == "execute":
             req_id = msg.get("id", "")
             interpreter_name = msg.get("interpreter", "python")
             code = msg.get("code", "")
 
             try:
-                result = dispatch_execute(interpreters, interpreter_name, code)
+                result = dispatch_execute(interpreters, interpreter_name, code, req_id)
                 send_message(
                     {
                         "type": "result",
@@ -317,6 +647,17 @@ def main():
                         "exit_code": result["exit_code"],
                     }
                 )
+            except KeyboardInterrupt:
+                # Cancel landed just outside exec() — still report it as cancelled
+                send_message(
+                    {
+                        "type": "result",
+                        "id": req_id,
+                        "stdout": "",
+                        "stderr": CANCELLED_MESSAGE,
+                        "exit_code": CANCELLED_EXIT_CODE,
+                    }
+                )
             except Exception as e:
                 # Catch-all: send error response so daemon doesn't hang
                 import traceback
@@ -330,6 +671,25 @@ def main():
                         "message": f"Agent internal error: {e}",
                     }
                 )
+        elif msg_type == "execute_batch":
+            req_id = msg.get("id", "")
+            interpreter_name = msg.get("interpreter", "python")
+            fragments = msg.get("fragments", [])
+            stop_on_error = msg.get("stop_on_error", True)
+
+            try:
+                results = dispatch_batch(interpreters, interpreter_name, fragments, stop_on_error, req_id)
+                send_message({"type": "batch_result", "id": req_id, "results": results})
+            except Exception as e:
+                import traceback
+
+                print(traceback.format_exc(), file=sys.stderr)
+                send_message(
+                    {
+                        "type": "error",
+                        "message": f"Agent internal error: {e}",
+                    }
+                )
         else:
             send_message(
                 {
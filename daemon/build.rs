@@ -0,0 +1,35 @@
+//! Embeds build info for the `version` tool.
+//!
+//! The git commit comes from `NIX_SANDBOX_GIT_REV` (set by the flake, whose
+//! sources have no `.git`), else `git rev-parse`, else "unknown". Neither
+//! failing breaks the build.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=NIX_SANDBOX_GIT_REV");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+
+    let commit = std::env::var("NIX_SANDBOX_GIT_REV")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=NIX_SANDBOX_GIT_COMMIT={commit}");
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=NIX_SANDBOX_RUSTC_VERSION={rustc_version}");
+}
+
+/// First line of a command's stdout, if it ran successfully.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let line = text.lines().next()?.trim();
+    (!line.is_empty()).then(|| line.to_string())
+}
@@ -0,0 +1,166 @@
+//! Append-only audit log of run calls.
+//!
+//! With `[audit]` configured, every run call appends one JSON line to the
+//! audit file: when it finished, where it ran, how it ended, or why it was
+//! rejected before running. Code is recorded as a digest, never verbatim,
+//! so the log doesn't hold what was run. A failed write is logged and
+//! otherwise ignored; auditing never fails an execution.
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::warn;
+
+use crate::config::{expand_path, AuditConfig, HashAlgorithm};
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord<'a> {
+    /// Unix time the execution finished, in milliseconds.
+    pub timestamp_ms: u64,
+    /// ID of the run call, as in the daemon's logs.
+    pub request_id: &'a str,
+    pub env: &'a str,
+    pub session: Option<&'a str>,
+    /// `<algorithm>:<hex digest>` of the code.
+    pub code_hash: String,
+    /// `None` when the execution failed without an exit code.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub timed_out: bool,
+    /// Whether output was cut at the environment's `max_output_bytes`.
+    pub truncated: bool,
+    /// Why the call was rejected (invalid params, rate limit, server busy)
+    /// or failed without an exit code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Appends [`AuditRecord`]s to the audit file.
+#[derive(Debug)]
+pub struct AuditSink {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+    hash: HashAlgorithm,
+}
+
+impl AuditSink {
+    /// Open (or create) the audit file for appending, creating its
+    /// directory if needed.
+    pub fn open(config: &AuditConfig) -> Result<Self> {
+        let path = expand_path(&config.path.to_string_lossy()).context("Invalid audit path")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Cannot create audit log directory {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Cannot open audit log {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+            hash: config.hash,
+        })
+    }
+
+    /// Digest of `code` as recorded in `code_hash`.
+    pub fn code_hash(&self, code: &[u8]) -> String {
+        code_hash(self.hash, code)
+    }
+
+    /// Append `record` as one line, writing on a blocking thread. Failures
+    /// are logged, not returned.
+    pub async fn record(&self, record: &AuditRecord<'_>) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Failed to serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // One write per record, so concurrent appends don't interleave
+        let file = Arc::clone(&self.file);
+        let written = tokio::task::spawn_blocking(move || {
+            file.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_all(&line)
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!(path = %self.path.display(), error = %e, "Failed to write audit record");
+            }
+            Err(e) => warn!(error = %e, "Audit write task failed"),
+        }
+    }
+}
+
+/// `<algorithm>:<hex digest>` of `code`.
+pub fn code_hash(algorithm: HashAlgorithm, code: &[u8]) -> String {
+    let (name, digest) = match algorithm {
+        HashAlgorithm::Sha256 => ("sha256", Sha256::digest(code).to_vec()),
+        HashAlgorithm::Sha512 => ("sha512", Sha512::digest(code).to_vec()),
+    };
+    digest.iter().fold(format!("{name}:"), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_hash_uses_configured_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = AuditConfig {
+            path: dir.path().join("audit.jsonl"),
+            hash: HashAlgorithm::Sha256,
+        };
+        let sink = AuditSink::open(&config).unwrap();
+        assert_eq!(
+            sink.code_hash(b"abc"),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        config.hash = HashAlgorithm::Sha512;
+        let hash = AuditSink::open(&config).unwrap().code_hash(b"abc");
+        assert!(hash.starts_with("sha512:ddaf35a1"), "{hash}");
+        assert_eq!(hash.len(), "sha512:".len() + 128);
+    }
+
+    #[test]
+    fn open_creates_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/nested/audit.jsonl");
+        AuditSink::open(&AuditConfig {
+            path: path.clone(),
+            hash: HashAlgorithm::Sha256,
+        })
+        .unwrap();
+        assert!(path.is_file());
+    }
+}
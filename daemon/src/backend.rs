@@ -5,17 +5,44 @@
 
 mod jail;
 
-pub use jail::JailBackend;
+pub use jail::{JailBackend, SpawnRetry};
 
-use std::path::Path;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
 
-use crate::config::EnvironmentMeta;
+use crate::config::{EnvironmentMeta, Mounts, OutputEncoding, SecretEnv};
+
+/// Options the caller sets for one ephemeral run, beyond its code and input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecOptions {
+    /// Working directory inside the sandbox, from `Mounts::resolve_workdir`.
+    pub workdir: Option<String>,
+
+    /// Secret env vars for this run (`secret_env`).
+    pub secret_env: SecretEnv,
+
+    /// Send stderr into stdout, keeping their relative order.
+    pub combine_output: bool,
+
+    /// Send stdout and stderr to `/dev/null`; only the exit code is reported.
+    pub discard_output: bool,
+}
+
+impl ExecOptions {
+    /// Env vars telling the wrapper about these options (the working
+    /// directory as `SANDBOX_WORKDIR`). Secrets are passed separately.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        self.workdir
+            .iter()
+            .map(|workdir| ("SANDBOX_WORKDIR".to_string(), workdir.clone()))
+            .collect()
+    }
+}
 
 /// Result of executing code in a sandbox.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ExecutionResult {
     /// Exit code of the process (0 = success).
     pub exit_code: i32,
@@ -23,8 +50,171 @@ pub struct ExecutionResult {
     pub stdout: String,
     /// Captured stderr.
     pub stderr: String,
+    /// Wall-clock time from spawn (or request) to completion.
+    pub duration: Duration,
+    /// Whether the execution was killed for exceeding its timeout.
+    pub timed_out: bool,
+    /// Resources used by the execution, where the backend can measure them.
+    pub resource_usage: Option<ResourceUsage>,
+    /// Exact stdout bytes, when `stdout` doesn't hold them verbatim (invalid
+    /// UTF-8, or non-ASCII output of a Latin-1 environment).
+    pub raw_stdout: Option<Vec<u8>>,
+    /// Exact stderr bytes, when they aren't valid UTF-8.
+    pub raw_stderr: Option<Vec<u8>>,
+    /// Exit code of each fragment that ran, for a multi-fragment session
+    /// call. `exit_code` is then the first nonzero one.
+    pub fragment_exit_codes: Option<Vec<i32>>,
+}
+
+/// CPU and memory used by one execution.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// User + system CPU time in milliseconds.
+    pub cpu_ms: u64,
+    /// Peak resident set size in KiB, if known.
+    pub max_rss_kb: Option<u64>,
+}
+
+/// Why an execution failed without producing a result.
+///
+/// Backends and the session layer wrap errors whose cause they know in one
+/// of these, so a client can tell a broken environment from a transient
+/// fault and decide whether to retry. Find it in an error's chain with
+/// [`ExecError::find`]. Displays as the wrapped error's full chain.
+#[derive(Debug)]
+pub enum ExecError {
+    /// The sandbox (jail wrapper or session agent) couldn't be started,
+    /// usually a configuration problem.
+    SpawnFailed(anyhow::Error),
+    /// The sandbox stopped responding within its time limit.
+    Timeout(anyhow::Error),
+    /// Talking to the sandbox over its pipes or socket failed.
+    IoError(anyhow::Error),
+    /// The session agent sent a reply the daemon couldn't understand.
+    ProtocolError(anyhow::Error),
+}
+
+impl ExecError {
+    /// The `ExecError` in `err`'s chain, if any.
+    pub fn find(err: &anyhow::Error) -> Option<&Self> {
+        err.chain().find_map(|e| e.downcast_ref::<Self>())
+    }
+
+    /// Machine-readable name of the failure, e.g. `"spawn_failed"`.
+    pub const fn category(&self) -> &'static str {
+        match self {
+            Self::SpawnFailed(_) => "spawn_failed",
+            Self::Timeout(_) => "timeout",
+            Self::IoError(_) => "io_error",
+            Self::ProtocolError(_) => "protocol_error",
+        }
+    }
+
+    /// Whether the same call may succeed if retried. A sandbox that can't
+    /// start, or an agent speaking another protocol, will fail again.
+    pub const fn retryable(&self) -> bool {
+        matches!(self, Self::Timeout(_) | Self::IoError(_))
+    }
+
+    const fn inner(&self) -> &anyhow::Error {
+        match self {
+            Self::SpawnFailed(e) | Self::Timeout(e) | Self::IoError(e) | Self::ProtocolError(e) => {
+                e
+            }
+        }
+    }
 }
 
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The whole chain, since the wrapped error isn't exposed as a source
+        write!(f, "{:#}", self.inner())
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Exit code reported for executions killed on timeout (matches coreutils `timeout`).
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+impl ExecutionResult {
+    /// Result for an execution killed after exceeding `timeout`.
+    pub fn timed_out(timeout: Duration, duration: Duration) -> Self {
+        Self {
+            exit_code: TIMEOUT_EXIT_CODE,
+            stdout: String::new(),
+            stderr: format!("Execution timed out after {}s", timeout.as_secs()),
+            duration,
+            timed_out: true,
+            resource_usage: None,
+            raw_stdout: None,
+            raw_stderr: None,
+            fragment_exit_codes: None,
+        }
+    }
+
+    /// Attach output captured before a timeout. The timeout message is kept
+    /// after any partial stderr so it's the last thing a reader sees.
+    #[must_use]
+    pub fn with_partial_output(mut self, stdout: String, stderr: &str) -> Self {
+        self.stdout = stdout;
+        if !stderr.is_empty() {
+            let sep = if stderr.ends_with('\n') { "" } else { "\n" };
+            self.stderr = format!("{stderr}{sep}{}", self.stderr);
+        }
+        self
+    }
+
+    /// Stdout exactly as the program wrote it.
+    pub fn stdout_bytes(&self) -> &[u8] {
+        self.raw_stdout.as_deref().unwrap_or(self.stdout.as_bytes())
+    }
+
+    /// Stderr exactly as the program wrote it.
+    pub fn stderr_bytes(&self) -> &[u8] {
+        self.raw_stderr.as_deref().unwrap_or(self.stderr.as_bytes())
+    }
+}
+
+/// Decode captured output as text in `encoding`, keeping the raw bytes
+/// alongside whenever the text doesn't spell them out as UTF-8.
+pub(crate) fn decode_output(buf: Vec<u8>, encoding: OutputEncoding) -> (String, Option<Vec<u8>>) {
+    if encoding.is_utf8() {
+        return match String::from_utf8(buf) {
+            Ok(text) => (text, None),
+            Err(e) => {
+                let bytes = e.into_bytes();
+                (String::from_utf8_lossy(&bytes).into_owned(), Some(bytes))
+            }
+        };
+    }
+    let text = encoding.decode(&buf);
+    let raw = (text.as_bytes() != buf.as_slice()).then_some(buf);
+    (text, raw)
+}
+
+/// Which stream an output chunk was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of output forwarded while the process is still running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputChunk {
+    /// Stream the chunk was read from.
+    pub stream: OutputStream,
+    /// Chunk contents (lossily decoded; the final result has the exact text).
+    pub data: String,
+}
+
+/// Channel for incremental output.
+///
+/// Backends send chunks as they're read; the final `ExecutionResult` still
+/// carries the complete output.
+pub type OutputSender = tokio::sync::mpsc::UnboundedSender<OutputChunk>;
+
 /// Trait for isolation backends.
 ///
 /// Each backend knows how to execute code in a sandboxed environment.
@@ -35,18 +225,25 @@ pub trait IsolationBackend: Send + Sync {
     /// Execute code in the given environment.
     ///
     /// # Arguments
-    /// * `env` - Environment metadata (exec path, timeout, etc.)
+    /// * `env` - Environment metadata (exec path, limits, etc.)
     /// * `code` - The code to execute
-    /// * `project_dir` - Optional absolute path to mount as project directory
-    /// * `project_mount` - Mount point inside sandbox (e.g., "/project")
+    /// * `timeout` - Effective timeout for this call (see `EnvironmentMeta::effective_timeout`)
+    /// * `stdin` - Optional input data fed to the program after the code
+    /// * `mounts` - Host directories to bind (project read-only, scratch read-write)
+    /// * `options` - Per-call working directory, secrets, and output mode
+    /// * `output` - Optional channel to receive output chunks as they arrive
     ///
     /// # Returns
     /// Execution result with stdout, stderr, and exit code.
+    #[allow(clippy::too_many_arguments)]
     async fn execute(
         &self,
         env: &EnvironmentMeta,
         code: &str,
-        project_dir: Option<&Path>,
-        project_mount: &str,
+        timeout: Duration,
+        stdin: Option<&str>,
+        mounts: &Mounts,
+        options: &ExecOptions,
+        output: Option<&OutputSender>,
     ) -> Result<ExecutionResult>;
 }
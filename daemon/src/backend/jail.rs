@@ -2,114 +2,577 @@
 //!
 //! Executes code by forking and running the Nix-built jail wrapper.
 //! The wrapper handles all sandboxing via bubblewrap.
+//!
+//! CPU time is measured as the change in the daemon's reaped-children CPU
+//! time (`cutime`/`cstime` in `/proc/self/stat`) across the run, the same
+//! figure `getrusage(RUSAGE_CHILDREN)` reports. Executions finishing
+//! concurrently can inflate each other's numbers. Peak RSS isn't reported:
+//! the only source for a child tree is `getrusage`, which needs FFI.
+//!
+//! With a pool (`JailBackend::with_pool`), runs without input data are handed
+//! an already-started wrapper; see [`pool`].
 
-use std::path::Path;
+mod pool;
+
+use std::os::fd::OwnedFd;
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::Command;
-use tracing::{debug, instrument};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::process::{Child, ChildStdin};
+use tracing::{debug, instrument, warn};
+
+use super::{
+    decode_output, ExecError, ExecOptions, ExecutionResult, IsolationBackend, OutputChunk,
+    OutputSender, OutputStream, ResourceUsage,
+};
+use crate::config::{
+    EnvironmentMeta, InputMode, Mounts, OutputEncoding, SandboxDepth, SecretEnv,
+    DEFAULT_KILL_GRACE, DEFAULT_MAX_SANDBOX_DEPTH, SECRET_FILE_VAR,
+};
+use pool::{SlotKey, WarmPool};
 
-use super::{ExecutionResult, IsolationBackend};
-use crate::config::EnvironmentMeta;
+/// Retry policy for spawning the jail wrapper.
+///
+/// Only the spawn is retried, and only for errors that can be transient
+/// (e.g. `EAGAIN` from fork under load). A missing or non-executable
+/// wrapper fails at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawnRetry {
+    /// Retries after the first failed attempt.
+    pub retries: u32,
+    /// Delay before the first retry; doubled for each one after.
+    pub base_delay: Duration,
+}
+
+impl SpawnRetry {
+    const DEFAULT: Self = Self {
+        retries: 2,
+        base_delay: Duration::from_millis(50),
+    };
+}
+
+impl Default for SpawnRetry {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
 /// Backend that uses jail.nix (bubblewrap) for isolation.
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct JailBackend {
-    // Future: could hold pre-warmed sandbox pool
+    /// Pre-warmed wrapper processes (`None` = spawn per execution).
+    pool: Option<Arc<WarmPool>>,
+    spawn_retry: SpawnRetry,
+    depth: SandboxDepth,
+    /// Time between SIGTERM and SIGKILL for a run that timed out.
+    kill_grace: Duration,
 }
 
 impl JailBackend {
     /// Create a new jail backend.
     #[must_use]
     pub const fn new() -> Self {
-        Self {}
+        Self {
+            pool: None,
+            spawn_retry: SpawnRetry::DEFAULT,
+            depth: SandboxDepth {
+                current: 0,
+                max: DEFAULT_MAX_SANDBOX_DEPTH,
+            },
+            kill_grace: DEFAULT_KILL_GRACE,
+        }
+    }
+
+    /// Create a jail backend that keeps up to `size` warm wrapper processes
+    /// per environment. A size of 0 disables the pool.
+    #[must_use]
+    pub fn with_pool(size: usize) -> Self {
+        Self {
+            pool: (size > 0).then(|| Arc::new(WarmPool::new(size))),
+            ..Self::new()
+        }
+    }
+
+    /// Use `retry` when spawning the jail wrapper fails transiently.
+    #[must_use]
+    pub const fn with_spawn_retry(mut self, retry: SpawnRetry) -> Self {
+        self.spawn_retry = retry;
+        self
+    }
+
+    /// Run wrappers one level below `depth`, refusing past its limit.
+    #[must_use]
+    pub const fn with_depth(mut self, depth: SandboxDepth) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Give a timed-out run `grace` to exit after SIGTERM before SIGKILL.
+    #[must_use]
+    pub const fn with_kill_grace(mut self, grace: Duration) -> Self {
+        self.kill_grace = grace;
+        self
+    }
+
+    /// The wrapper to run for `env`, with its environment variables.
+    fn slot_key(&self, env: &EnvironmentMeta, mounts: &Mounts, options: &ExecOptions) -> SlotKey {
+        // Pass project/scratch dirs as env vars for runtime mounting (mkSandbox artifacts)
+        let mut key = SlotKey {
+            exec: env.exec.clone(),
+            args: Vec::new(),
+            env: mounts.env_vars(),
+        };
+        key.env.extend(options.env_vars());
+        key.env.extend(env.inherited_env());
+        // Last, so an inherited host value can't override the policy or
+        // reset the count
+        key.env.extend(env.interpreter_args_var());
+        key.env.push(env.network_var());
+        key.env.push(self.depth.child_var());
+        key
+    }
+
+    /// Spawn the wrapper for `key`, retrying transient failures.
+    async fn spawn(&self, key: &SlotKey, target: OutputTarget<'_>) -> Result<Child> {
+        let mut attempt = 0;
+        loop {
+            let mut cmd = key.command();
+            match target {
+                OutputTarget::Pipes => {}
+                OutputTarget::Combined(fd) => {
+                    cmd.stdout(fd.try_clone()?).stderr(fd.try_clone()?);
+                }
+                OutputTarget::Null => {
+                    cmd.stdout(Stdio::null()).stderr(Stdio::null());
+                }
+            }
+            match cmd.spawn() {
+                Ok(child) => return Ok(child),
+                Err(e) if attempt < self.spawn_retry.retries && is_transient(&e) => {
+                    let delay = self.spawn_retry.base_delay * 2u32.pow(attempt);
+                    attempt += 1;
+                    warn!(error = %e, attempt, ?delay, "Failed to spawn jail wrapper, retrying");
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to spawn jail wrapper: {}", key.exec))
+                }
+            }
+        }
     }
 }
 
+impl Default for JailBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a spawned wrapper's stdout and stderr go.
+#[derive(Debug, Clone, Copy)]
+enum OutputTarget<'a> {
+    /// A pipe each, as `key.command()` sets up.
+    Pipes,
+    /// Both into this one pipe, keeping their relative order.
+    Combined(&'a OwnedFd),
+    /// `/dev/null`, never read.
+    Null,
+}
+
+/// Whether a spawn error may go away on retry. A missing, inaccessible, or
+/// malformed wrapper won't.
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    !matches!(
+        e.kind(),
+        ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidInput
+    ) && e.raw_os_error() != Some(ENOEXEC)
+}
+
+/// `errno` for a wrapper that isn't a valid executable (Linux value).
+const ENOEXEC: i32 = 8;
+
 #[async_trait]
 impl IsolationBackend for JailBackend {
-    #[instrument(skip(self, code), fields(exec = %env.exec, timeout = env.timeout_seconds))]
+    #[instrument(skip(self, code, stdin, output), fields(exec = %env.exec))]
     async fn execute(
         &self,
         env: &EnvironmentMeta,
         code: &str,
-        project_dir: Option<&Path>,
-        project_mount: &str,
+        timeout: Duration,
+        stdin: Option<&str>,
+        mounts: &Mounts,
+        options: &ExecOptions,
+        output: Option<&OutputSender>,
     ) -> Result<ExecutionResult> {
-        debug!(code_len = code.len(), "Executing code in jail");
+        debug!(
+            code_len = code.len(),
+            stdin_len = stdin.map(str::len),
+            secret_env = ?options.secret_env,
+            "Executing code in jail"
+        );
+        self.depth.check().map_err(ExecError::SpawnFailed)?;
 
-        // Spawn the jail wrapper process
-        let mut cmd = Command::new(&env.exec);
-        cmd.stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-
-        // Pass project dir as env vars for runtime mounting (mkSandbox artifacts)
-        if let Some(dir) = project_dir {
-            cmd.env("PROJECT_DIR", dir);
-            cmd.env("PROJECT_MOUNT", project_mount);
+        if env.input_mode == InputMode::Argv && !options.secret_env.is_empty() {
+            warn!(
+                "input_mode \"argv\" puts code on a command line other host users can read; \
+                 keep secret values out of the code"
+            );
+        }
+        let mut key = self.slot_key(env, mounts, options);
+        let code = pass_code(env.input_mode, code, &mut key).map_err(ExecError::SpawnFailed)?;
+        // Removed when dropped, after the run
+        let secrets = SecretFile::create(&options.secret_env)
+            .await
+            .map_err(ExecError::SpawnFailed)?;
+        if let Some(secrets) = &secrets {
+            key.env.push(secrets.env_var());
         }
 
-        let mut child = cmd
-            .spawn()
-            .with_context(|| format!("Failed to spawn jail wrapper: {}", env.exec))?;
+        // When input data follows the code, tell the wrapper where the code ends
+        // so it can split it off and leave the rest of stdin for the program.
+        // That depends on the code, so such runs can't use a warm wrapper,
+        // and neither can code on argv.
+        // Nor can runs with secrets, which mustn't outlive the call in the pool.
+        // Combined or discarded output needs its stdout/stderr set at spawn.
+        let custom_output = options.combine_output || options.discard_output;
+        let warm = match (&self.pool, stdin) {
+            (Some(pool), None)
+                if options.secret_env.is_empty() && !custom_output && key.args.is_empty() =>
+            {
+                pool.take(&key)
+            }
+            _ => None,
+        };
+        if stdin.is_some() {
+            key.env
+                .push(("SANDBOX_CODE_BYTES".to_string(), code.len().to_string()));
+        }
 
-        // Write code to stdin
-        let mut stdin = child.stdin.take().context("Failed to open stdin")?;
-        stdin
-            .write_all(code.as_bytes())
-            .await
-            .context("Failed to write code to stdin")?;
-        drop(stdin); // Close stdin to signal EOF
+        let started = Instant::now();
+        let cpu_before = children_cpu_ms();
+        let (mut child, combined) = match warm {
+            Some(child) => (child, None),
+            // Discarded output is never read, so it can't be combined either
+            None if options.discard_output => {
+                let child = self.spawn(&key, OutputTarget::Null).await;
+                (child.map_err(ExecError::SpawnFailed)?, None)
+            }
+            None if options.combine_output => {
+                let (tx, rx) = pipe::pipe()
+                    .context("Failed to create output pipe")
+                    .map_err(ExecError::IoError)?;
+                let tx = tx
+                    .into_blocking_fd()
+                    .map_err(|e| ExecError::IoError(e.into()))?;
+                // Our write end closes after this arm, so EOF comes when the child's does
+                let child = self.spawn(&key, OutputTarget::Combined(&tx)).await;
+                (child.map_err(ExecError::SpawnFailed)?, Some(rx))
+            }
+            None => {
+                let child = self.spawn(&key, OutputTarget::Pipes).await;
+                (child.map_err(ExecError::SpawnFailed)?, None)
+            }
+        };
 
         // Take pipe handles out so `child` stays in scope for kill-on-timeout
-        let mut child_stdout = child.stdout.take().context("Failed to open stdout")?;
-        let mut child_stderr = child.stderr.take().context("Failed to open stderr")?;
-
-        // Read stdout+stderr concurrently, under the timeout.
-        // `child` is NOT moved into this future, so we can kill it on timeout.
-        let timeout_duration = std::time::Duration::from_secs(env.timeout_seconds);
-        let read_all = async {
-            let mut stdout_buf = Vec::new();
-            let mut stderr_buf = Vec::new();
-            let (r1, r2) = tokio::join!(
-                child_stdout.read_to_end(&mut stdout_buf),
-                child_stderr.read_to_end(&mut stderr_buf),
+        let child_stdin = child
+            .stdin
+            .take()
+            .context("Failed to open stdin")
+            .map_err(ExecError::IoError)?;
+        let (mut child_stdout, mut child_stderr) =
+            output_pipes(&mut child, combined, options.discard_output)
+                .map_err(ExecError::IoError)?;
+
+        // Write code (followed by any input data) to stdin while reading
+        // stdout+stderr, all under the timeout. Writing first would deadlock
+        // a program that echoes a large input: its stdout pipe fills while
+        // we're still blocked on its stdin.
+        // `child` is NOT moved into this future, so we can kill it on timeout,
+        // and the buffers live outside it so output read so far survives.
+        // So do the pipes: a program cleaning up after SIGTERM can still
+        // write output without dying of SIGPIPE.
+        let mut stdout_buf = Vec::new();
+        let mut stderr_buf = Vec::new();
+        let io = async {
+            let (w, r1, r2) = tokio::join!(
+                write_stdin(child_stdin, code, stdin),
+                read_stream(
+                    &mut child_stdout,
+                    &mut stdout_buf,
+                    OutputStream::Stdout,
+                    env.output_encoding,
+                    output
+                ),
+                read_stream(
+                    &mut child_stderr,
+                    &mut stderr_buf,
+                    OutputStream::Stderr,
+                    env.output_encoding,
+                    output
+                ),
             );
+            w.context("Failed to write to stdin")?;
             r1.context("Failed to read stdout")?;
-            r2.context("Failed to read stderr")?;
-            Ok::<_, anyhow::Error>((stdout_buf, stderr_buf))
+            r2.context("Failed to read stderr")
         };
 
-        let (stdout_buf, stderr_buf) =
-            if let Ok(result) = tokio::time::timeout(timeout_duration, read_all).await {
-                result?
-            } else {
-                let _ = child.kill().await;
-                anyhow::bail!("Command timed out after {}s", env.timeout_seconds);
-            };
+        if let Ok(result) = tokio::time::timeout(timeout, io).await {
+            result.map_err(ExecError::IoError)?;
+        } else {
+            terminate(&mut child, self.kill_grace).await;
+            debug!(timeout_secs = timeout.as_secs(), "Execution timed out");
+            let (stdout, raw_stdout) = decode_output(stdout_buf, env.output_encoding);
+            return Ok(ExecutionResult {
+                resource_usage: resource_usage_since(cpu_before),
+                raw_stdout,
+                ..ExecutionResult::timed_out(timeout, started.elapsed())
+            }
+            .with_partial_output(stdout, &env.output_encoding.decode(&stderr_buf)));
+        }
 
-        let status = child.wait().await.context("Failed to wait for process")?;
+        let status = child
+            .wait()
+            .await
+            .context("Failed to wait for process")
+            .map_err(ExecError::IoError)?;
 
+        let (stdout, raw_stdout) = decode_output(stdout_buf, env.output_encoding);
+        let (stderr, raw_stderr) = decode_output(stderr_buf, env.output_encoding);
         let result = ExecutionResult {
             exit_code: status.code().unwrap_or(-1),
-            stdout: String::from_utf8_lossy(&stdout_buf).into_owned(),
-            stderr: String::from_utf8_lossy(&stderr_buf).into_owned(),
+            stdout,
+            stderr,
+            duration: started.elapsed(),
+            timed_out: false,
+            resource_usage: resource_usage_since(cpu_before),
+            raw_stdout,
+            raw_stderr,
+            fragment_exit_codes: None,
         };
 
-        debug!(exit_code = result.exit_code, "Execution completed");
+        debug!(
+            exit_code = result.exit_code,
+            duration_ms = result.duration.as_millis(),
+            cpu_ms = result.resource_usage.map(|u| u.cpu_ms),
+            "Execution completed"
+        );
 
         Ok(result)
     }
 }
 
+/// Clock ticks per second for `/proc` times (`USER_HZ`, fixed at 100 on Linux).
+const USER_HZ: u64 = 100;
+
+/// CPU time (user + system) of all reaped children so far, in milliseconds.
+///
+/// `None` where `/proc/self/stat` is unavailable (non-Linux).
+fn children_cpu_ms() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    parse_children_cpu_ticks(&stat).map(|ticks| ticks * 1000 / USER_HZ)
+}
+
+/// Extract `cutime + cstime` (fields 16 and 17) from a `/proc/<pid>/stat` line.
+fn parse_children_cpu_ticks(stat: &str) -> Option<u64> {
+    // `comm` (field 2) may contain spaces and parens; fields resume after the last ')'
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(13); // field 3 is index 0
+    let user: u64 = fields.next()?.parse().ok()?;
+    let system: u64 = fields.next()?.parse().ok()?;
+    Some(user + system)
+}
+
+/// Usage for a run that started when children CPU time was `before`.
+fn resource_usage_since(before: Option<u64>) -> Option<ResourceUsage> {
+    let after = children_cpu_ms()?;
+    Some(ResourceUsage {
+        cpu_ms: after.saturating_sub(before?),
+        max_rss_kb: None,
+    })
+}
+
+/// A wrapper's output pipe.
+type OutputPipe = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Take the child's stdout and stderr pipes. With `combined`, both streams
+/// arrive in order on that pipe, reported as stdout; stderr is then empty.
+/// With `discarded`, both are empty.
+fn output_pipes(
+    child: &mut Child,
+    combined: Option<pipe::Receiver>,
+    discarded: bool,
+) -> Result<(OutputPipe, OutputPipe)> {
+    if discarded {
+        return Ok((Box::new(tokio::io::empty()), Box::new(tokio::io::empty())));
+    }
+    if let Some(rx) = combined {
+        return Ok((Box::new(rx), Box::new(tokio::io::empty())));
+    }
+    let stdout = child.stdout.take().context("Failed to open stdout")?;
+    let stderr = child.stderr.take().context("Failed to open stderr")?;
+    Ok((Box::new(stdout), Box::new(stderr)))
+}
+
+/// Hand `code` to the wrapper as `mode` says. With `InputMode::Argv` it's
+/// added to `key`'s arguments after `--`, so code starting with `-` isn't
+/// taken for an option; returns the code still to go on stdin.
+fn pass_code<'a>(mode: InputMode, code: &'a str, key: &mut SlotKey) -> Result<&'a str> {
+    mode.check_code(code)?;
+    if mode == InputMode::Stdin {
+        return Ok(code);
+    }
+    key.args.push("--".to_string());
+    key.args.push(code.to_string());
+    Ok("")
+}
+
+/// A run's secrets, written to a file only the daemon's user can read and
+/// removed when dropped. The wrapper binds it into the sandbox read-only.
+#[derive(Debug)]
+struct SecretFile {
+    path: std::path::PathBuf,
+}
+
+impl SecretFile {
+    /// Write `secrets` to a fresh file, or `None` when there are none.
+    async fn create(secrets: &SecretEnv) -> Result<Option<Self>> {
+        if secrets.is_empty() {
+            return Ok(None);
+        }
+        let path =
+            std::env::temp_dir().join(format!("nix-sandbox-secrets-{}", uuid::Uuid::new_v4()));
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to create secrets file {}", path.display()))?;
+        // Own the path from here, so a failed write still removes it
+        let this = Self { path };
+        file.write_all(&secrets.encode()).await?;
+        file.flush().await?;
+        Ok(Some(this))
+    }
+
+    /// The variable telling the wrapper where the file is.
+    fn env_var(&self) -> (String, String) {
+        (
+            SECRET_FILE_VAR.to_string(),
+            self.path.to_string_lossy().into_owned(),
+        )
+    }
+}
+
+impl Drop for SecretFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "Failed to remove secrets file");
+        }
+    }
+}
+
+/// Size of each write to the wrapper's stdin.
+const STDIN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Write `code`, then any `input`, to the wrapper's stdin in chunks, then
+/// close it to signal EOF.
+///
+/// A program that exits or closes stdin before reading everything isn't an
+/// error; whatever it printed is still collected.
+async fn write_stdin(mut pipe: ChildStdin, code: &str, input: Option<&str>) -> std::io::Result<()> {
+    let data = [code.as_bytes(), input.map_or(&[][..], str::as_bytes)];
+    for chunk in data.iter().flat_map(|d| d.chunks(STDIN_CHUNK_BYTES)) {
+        match pipe.write_all(chunk).await {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                debug!("Program closed stdin before reading all input");
+                return Ok(());
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Stop a timed-out run: SIGTERM, so it can flush files or remove temp
+/// state, then SIGKILL if it hasn't exited within `grace`.
+///
+/// Both go to the wrapper's whole process group (see [`SlotKey::command`]),
+/// so they reach the program even if the wrapper doesn't forward them.
+async fn terminate(child: &mut Child, grace: Duration) {
+    let Some(group) = child
+        .id()
+        .and_then(|pid| i32::try_from(pid).ok())
+        .map(Pid::from_raw)
+    else {
+        return;
+    };
+    if !grace.is_zero() && signal::killpg(group, Signal::SIGTERM).is_ok() {
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            // Reap the wrapper once it exits; the group lives on while
+            // anything it started still runs
+            let _ = child.try_wait();
+            if signal::killpg(group, None).is_err() {
+                return;
+            }
+            tokio::time::sleep(TERMINATE_POLL).await;
+        }
+        debug!(
+            grace_ms = grace.as_millis(),
+            "Run outlived SIGTERM, killing"
+        );
+    }
+    let _ = signal::killpg(group, Signal::SIGKILL);
+    let _ = child.kill().await;
+}
+
+/// How often `terminate` checks whether a run's processes have exited.
+const TERMINATE_POLL: Duration = Duration::from_millis(20);
+
+/// Read a pipe to EOF into `buf`, forwarding each chunk to `output` as it arrives.
+async fn read_stream<R: AsyncRead + Unpin>(
+    mut reader: R,
+    buf: &mut Vec<u8>,
+    stream: OutputStream,
+    encoding: OutputEncoding,
+    output: Option<&OutputSender>,
+) -> std::io::Result<()> {
+    let mut chunk = vec![0u8; 8192];
+    // Carries a multibyte character split across reads over to the next one
+    let mut decoder = encoding.new_decoder();
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(tx) = output {
+            let capacity = decoder.max_utf8_buffer_length(n).unwrap_or(4 * n + 16);
+            let mut data = String::with_capacity(capacity);
+            let _ = decoder.decode_to_string(&chunk[..n], &mut data, n == 0);
+            if !data.is_empty() {
+                // A closed receiver just means nobody is listening any more
+                let _ = tx.send(OutputChunk { stream, data });
+            }
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::BackendType;
+    use crate::config::{BackendType, InheritEnv};
 
     #[tokio::test]
     async fn test_execute_echo() {
@@ -126,10 +589,897 @@ mod tests {
             timeout_seconds: 5,
             memory_mb: 512,
             interpreter_type: None,
+            ..Default::default()
         };
 
-        let result = backend.execute(&env, "echo hello", None, "/project").await.unwrap();
+        let result = backend
+            .execute(
+                &env,
+                "echo hello",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
         assert_eq!(result.exit_code, 0);
         assert!(result.stdout.contains("hello"));
     }
+
+    #[tokio::test]
+    async fn test_execute_inherits_env() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        std::env::set_var("NSM_TEST_JAIL_INHERIT", "from-host");
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            inherit_env: InheritEnv {
+                vars: vec!["NSM_TEST_JAIL_INHERIT".to_string()],
+            },
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "echo \"$NSM_TEST_JAIL_INHERIT\"",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stdout, "from-host\n");
+    }
+
+    #[tokio::test]
+    async fn test_execute_sets_secret_env() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            ..Default::default()
+        };
+        let options = ExecOptions {
+            secret_env: std::iter::once(("NSM_TEST_SECRET", "hunter2")).collect(),
+            ..ExecOptions::default()
+        };
+
+        // A plain shell stands in for the wrapper: the value must only be in
+        // the file, which is gone once the run ends
+        let result = backend
+            .execute(
+                &env,
+                "echo \"${NSM_TEST_SECRET:-unset}\"; tr '\\0' '\\n' < \"$SANDBOX_SECRET_FILE\"; \
+                 stat -c %a \"$SANDBOX_SECRET_FILE\"; echo \"$SANDBOX_SECRET_FILE\" >&2",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &options,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "unset\nNSM_TEST_SECRET=hunter2\n600\n");
+        assert!(!std::path::Path::new(result.stderr.trim()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_execute_combined_output_keeps_order() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            ..Default::default()
+        };
+        let code = "for i in 1 2 3; do echo out$i; echo err$i >&2; done";
+        let run = |combine_output| {
+            let options = ExecOptions {
+                combine_output,
+                ..ExecOptions::default()
+            };
+            let backend = &backend;
+            let env = &env;
+            async move {
+                backend
+                    .execute(
+                        env,
+                        code,
+                        env.effective_timeout(None),
+                        None,
+                        &Mounts::default(),
+                        &options,
+                        None,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let result = run(true).await;
+        assert_eq!(result.stdout, "out1\nerr1\nout2\nerr2\nout3\nerr3\n");
+        assert_eq!(result.stderr, "");
+
+        let result = run(false).await;
+        assert_eq!(result.stdout, "out1\nout2\nout3\n");
+        assert_eq!(result.stderr, "err1\nerr2\nerr3\n");
+    }
+
+    #[tokio::test]
+    async fn test_execute_discard_output() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            ..Default::default()
+        };
+        // Asking for combined output too mustn't leave the program a
+        // combined pipe nobody reads (it would die of SIGPIPE)
+        for combine_output in [false, true] {
+            let options = ExecOptions {
+                discard_output: true,
+                combine_output,
+                ..ExecOptions::default()
+            };
+
+            let result = backend
+                .execute(
+                    &env,
+                    "seq 100000; echo oops >&2; exit 3",
+                    env.effective_timeout(None),
+                    None,
+                    &Mounts::default(),
+                    &options,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(result.exit_code, 3, "combine_output: {combine_output}");
+            assert_eq!((result.stdout.as_str(), result.stderr.as_str()), ("", ""));
+        }
+    }
+
+    #[test]
+    fn slot_key_sets_network_policy() {
+        let backend = JailBackend::new();
+        for (network, expected) in [(false, "0"), (true, "1")] {
+            let env = EnvironmentMeta {
+                exec: "/bin/sh".to_string(),
+                network,
+                ..Default::default()
+            };
+            let key = backend.slot_key(&env, &Mounts::default(), &ExecOptions::default());
+            let vars: Vec<_> = key
+                .env
+                .iter()
+                .filter(|(k, _)| k == "SANDBOX_NETWORK")
+                .map(|(_, v)| v.as_str())
+                .collect();
+            assert_eq!(vars, [expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn interpreter_args_reach_wrapper_env() {
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            interpreter_args: vec!["-u".to_string(), "-X dev".to_string()],
+            ..sh_env()
+        };
+        let result = backend
+            .execute(
+                &env,
+                "printf '%s' \"$SANDBOX_INTERPRETER_ARGS\"",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "-u\n-X dev");
+    }
+
+    #[tokio::test]
+    async fn test_execute_latin1_output() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            output_encoding: OutputEncoding::for_label("latin1").unwrap(),
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "printf 'caf\\351'; printf '\\374ber' >&2",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "caf\u{e9}");
+        assert_eq!(result.stderr, "\u{fc}ber");
+        assert_eq!(result.stdout_bytes(), b"caf\xe9");
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 1,
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "sleep 10",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, crate::backend::TIMEOUT_EXIT_CODE);
+        assert!(result.stderr.contains("timed out after 1s"));
+        assert!(result.duration < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_sigterm_lets_program_clean_up() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("cleaned");
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 1,
+            ..Default::default()
+        };
+        let code = format!(
+            "trap 'kill $!; echo done > {}; exit 0' TERM; sleep 10 & wait",
+            marker.display()
+        );
+
+        let result = backend
+            .execute(
+                &env,
+                &code,
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert!(result.duration < Duration::from_secs(3));
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "done\n");
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_signals_whole_process_group() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("cleaned");
+        let straggler = dir.path().join("straggler");
+        let backend = JailBackend::new().with_kill_grace(Duration::from_millis(500));
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 1,
+            ..Default::default()
+        };
+        // The outer shell stands in for a wrapper that doesn't forward
+        // signals: only the group signal reaches the programs it started
+        let code = format!(
+            "sh -c 'trap \"echo done > {}; exit 0\" TERM; sleep 10 & wait' & \
+             sh -c 'trap \"\" TERM; echo $$ > {}; sleep 10' & wait",
+            marker.display(),
+            straggler.display()
+        );
+
+        let result = backend
+            .execute(
+                &env,
+                &code,
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert!(result.duration < Duration::from_secs(3));
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !marker.exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "done\n");
+        // The one ignoring SIGTERM got SIGKILL with the rest of the group
+        let pid = std::fs::read_to_string(&straggler).unwrap();
+        let proc_dir = format!("/proc/{}", pid.trim());
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while std::path::Path::new(&proc_dir).exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!std::path::Path::new(&proc_dir).exists());
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_kills_after_grace() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new().with_kill_grace(Duration::from_millis(200));
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 1,
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "trap '' TERM; sleep 10",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert!(result.duration < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_keeps_partial_output() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 1,
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "echo early; echo warning >&2; sleep 10; echo late",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.stdout, "early\n");
+        assert_eq!(result.stderr, "warning\nExecution timed out after 1s");
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_stdin() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/cat".to_string(), // Echoes code followed by input
+            session_exec: None,
+            timeout_seconds: 5,
+            memory_mb: 512,
+            interpreter_type: None,
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "code\n",
+                env.effective_timeout(None),
+                Some("input data\n"),
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stdout, "code\ninput data\n");
+    }
+
+    #[tokio::test]
+    async fn test_execute_echoes_large_stdin() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/cat".to_string(), // Echoes code followed by input
+            timeout_seconds: 10,
+            ..Default::default()
+        };
+
+        // Far beyond a pipe buffer in both directions: `cat` blocks on its
+        // stdout long before it has read all of its stdin
+        let input = "0123456789abcdef".repeat(256 * 1024);
+        let result = backend
+            .execute(
+                &env,
+                "code\n",
+                env.effective_timeout(None),
+                Some(&input),
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(!result.timed_out);
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stdout.len(), "code\n".len() + input.len());
+        assert!(result.stdout.ends_with("cdef"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_streams_output() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            session_exec: None,
+            timeout_seconds: 5,
+            memory_mb: 512,
+            interpreter_type: None,
+            ..Default::default()
+        };
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let result = backend
+            .execute(
+                &env,
+                "echo one; sleep 0.2; echo two >&2",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                Some(&tx),
+            )
+            .await
+            .unwrap();
+        drop(tx);
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                OutputChunk {
+                    stream: OutputStream::Stdout,
+                    data: "one\n".to_string()
+                },
+                OutputChunk {
+                    stream: OutputStream::Stderr,
+                    data: "two\n".to_string()
+                },
+            ]
+        );
+        assert_eq!(result.stdout, "one\n");
+    }
+
+    #[tokio::test]
+    async fn test_execute_reports_resource_usage() {
+        // This test requires a working jail wrapper, skip in CI
+        if std::env::var("NIX_SANDBOX_TEST").is_err() {
+            return;
+        }
+
+        let backend = JailBackend::new();
+        let env = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            session_exec: None,
+            timeout_seconds: 5,
+            memory_mb: 512,
+            interpreter_type: None,
+            ..Default::default()
+        };
+
+        let result = backend
+            .execute(
+                &env,
+                "true",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        let usage = result.resource_usage.expect("usage should be measured");
+        assert!(usage.cpu_ms < 5_000);
+    }
+
+    #[test]
+    fn parse_children_cpu_ticks_from_stat() {
+        // comm with spaces and a ')' must not shift the fields
+        let stat = "1234 (my (proc) x) S 1 1234 1234 0 -1 4194560 100 200 0 0 \
+                    7 3 11 22 20 0 1 0 100 1000 50";
+        assert_eq!(parse_children_cpu_ticks(stat), Some(33));
+        assert_eq!(parse_children_cpu_ticks("garbage"), None);
+    }
+
+    fn sh_env() -> EnvironmentMeta {
+        EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: "/bin/sh".to_string(),
+            timeout_seconds: 5,
+            ..Default::default()
+        }
+    }
+
+    /// Environment whose wrapper prints its first argument, then echoes stdin.
+    fn echo_env(dir: &std::path::Path, input_mode: InputMode) -> EnvironmentMeta {
+        use std::os::unix::fs::PermissionsExt;
+
+        let exec = dir.join("run");
+        std::fs::write(
+            &exec,
+            "#!/bin/sh\n[ \"$1\" = -- ] && shift\nprintf 'arg:%s\\n' \"$1\"\nexec cat\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&exec, std::fs::Permissions::from_mode(0o755)).unwrap();
+        EnvironmentMeta {
+            exec: exec.to_string_lossy().into_owned(),
+            input_mode,
+            ..sh_env()
+        }
+    }
+
+    #[tokio::test]
+    async fn input_mode_stdin_or_argv() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = JailBackend::new();
+        let run = |env: EnvironmentMeta, stdin: Option<&'static str>| {
+            let backend = backend.clone();
+            async move {
+                backend
+                    .execute(
+                        &env,
+                        "print(1)",
+                        env.effective_timeout(None),
+                        stdin,
+                        &Mounts::default(),
+                        &ExecOptions::default(),
+                        None,
+                    )
+                    .await
+                    .unwrap()
+                    .stdout
+            }
+        };
+
+        let stdin_env = echo_env(dir.path(), InputMode::Stdin);
+        assert_eq!(run(stdin_env, None).await, "arg:\nprint(1)");
+        let argv_env = echo_env(dir.path(), InputMode::Argv);
+        assert_eq!(run(argv_env.clone(), None).await, "arg:print(1)\n");
+        // Input data alone goes on stdin
+        assert_eq!(run(argv_env, Some("data")).await, "arg:print(1)\ndata");
+    }
+
+    #[test]
+    fn argv_code_limits() {
+        let mut key = SlotKey {
+            exec: "/bin/true".to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+        };
+        assert_eq!(pass_code(InputMode::Stdin, "x", &mut key).unwrap(), "x");
+        assert!(key.args.is_empty());
+        assert_eq!(pass_code(InputMode::Argv, "-x", &mut key).unwrap(), "");
+        assert_eq!(key.args, ["--", "-x"]);
+        key.args.clear();
+
+        let err = pass_code(InputMode::Argv, "a\0b", &mut key).unwrap_err();
+        assert!(err.to_string().contains("NUL"), "{err}");
+        let long = "x".repeat(crate::config::MAX_ARG_BYTES);
+        let err = pass_code(InputMode::Argv, &long, &mut key).unwrap_err();
+        assert!(err.to_string().contains("at most 131071"), "{err}");
+        assert!(key.args.is_empty());
+    }
+
+    /// Wait for the background refill to put a warm process in the slot.
+    async fn wait_warm(pool: &WarmPool, key: &SlotKey) {
+        for _ in 0..100 {
+            if pool.idle(key) > 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("pool never warmed up");
+    }
+
+    #[tokio::test]
+    async fn pool_reuses_warm_wrapper() {
+        let backend = JailBackend::with_pool(1);
+        let pool = backend.pool.clone().unwrap();
+        let env = sh_env();
+        let mounts = Mounts::default();
+        let options = ExecOptions::default();
+        let key = backend.slot_key(&env, &mounts, &options);
+
+        let run = || {
+            backend.execute(
+                &env,
+                "echo $$",
+                env.effective_timeout(None),
+                None,
+                &mounts,
+                &options,
+                None,
+            )
+        };
+
+        let cold = run().await.unwrap();
+        assert_eq!(pool.hits(), 0);
+        wait_warm(&pool, &key).await;
+
+        let warm = run().await.unwrap();
+        assert_eq!(pool.hits(), 1);
+        assert_eq!(warm.exit_code, 0);
+        assert_ne!(cold.stdout, warm.stdout, "each run gets a fresh process");
+
+        // Runs with input data bypass the pool
+        backend
+            .execute(
+                &env,
+                "cat",
+                env.effective_timeout(None),
+                Some("x"),
+                &mounts,
+                &options,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(pool.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn pool_discards_exited_wrappers() {
+        let pool = Arc::new(WarmPool::new(2));
+        let key = SlotKey {
+            exec: "/bin/true".to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+        };
+
+        assert!(pool.take(&key).is_none());
+        wait_warm(&pool, &key).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Every pooled `true` has exited by now, so none is handed out
+        assert!(pool.take(&key).is_none());
+        assert_eq!(pool.hits(), 0);
+    }
+
+    #[test]
+    fn zero_pool_size_disables_pool() {
+        assert!(JailBackend::with_pool(0).pool.is_none());
+        assert!(JailBackend::with_pool(2).pool.is_some());
+    }
+
+    fn quick_retry(retries: u32) -> SpawnRetry {
+        SpawnRetry {
+            retries,
+            base_delay: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_retries_transient_failure() {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        // While the script is still open for writing, exec fails with
+        // ETXTBSY ("Text file busy"); it succeeds once the file is closed
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run");
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"#!/bin/sh\necho spawned\n").unwrap();
+        file.set_permissions(std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let closer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(file);
+        });
+
+        let backend = JailBackend::new().with_spawn_retry(quick_retry(3));
+        let env = EnvironmentMeta {
+            exec: path.to_string_lossy().into_owned(),
+            ..sh_env()
+        };
+        let result = backend
+            .execute(
+                &env,
+                "",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "spawned\n");
+        closer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spawn_does_not_retry_missing_wrapper() {
+        let backend = JailBackend::new().with_spawn_retry(SpawnRetry {
+            retries: 5,
+            base_delay: Duration::from_secs(10),
+        });
+        let env = EnvironmentMeta {
+            exec: "/nonexistent/run".to_string(),
+            ..sh_env()
+        };
+
+        // A retry would sleep for 10s; failing fast proves there was none
+        let err = tokio::time::timeout(
+            Duration::from_secs(2),
+            backend.execute(
+                &env,
+                "",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            ),
+        )
+        .await
+        .expect("missing wrapper should not be retried")
+        .unwrap_err();
+        assert!(err.to_string().contains("Failed to spawn jail wrapper"));
+    }
+
+    #[tokio::test]
+    async fn wrapper_runs_one_level_deeper() {
+        let backend = JailBackend::new().with_depth(SandboxDepth::new(Some("1"), 3));
+        let env = sh_env();
+        let result = backend
+            .execute(
+                &env,
+                "echo $NIX_SANDBOX_DEPTH",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout.trim(), "2");
+    }
+
+    #[tokio::test]
+    async fn execution_refused_at_max_depth() {
+        let backend = JailBackend::new().with_depth(SandboxDepth::new(Some("3"), 3));
+        let env = sh_env();
+        let err = backend
+            .execute(
+                &env,
+                "echo nested",
+                env.effective_timeout(None),
+                None,
+                &Mounts::default(),
+                &ExecOptions::default(),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("max_sandbox_depth is 3"), "{err}");
+    }
+
+    #[test]
+    fn transient_spawn_errors() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_transient(&Error::from(ErrorKind::WouldBlock)));
+        assert!(is_transient(&Error::from(ErrorKind::OutOfMemory)));
+        assert!(!is_transient(&Error::from(ErrorKind::NotFound)));
+        assert!(!is_transient(&Error::from(ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&Error::from_raw_os_error(ENOEXEC)));
+    }
 }
@@ -0,0 +1,125 @@
+//! Pre-warmed jail wrapper processes.
+//!
+//! The wrapper does nothing with the code until it arrives on stdin, so a
+//! process spawned ahead of time has already paid for namespace setup and
+//! interpreter start-up. Warm processes are keyed by wrapper path and the
+//! environment they were spawned with; a process that exited while waiting
+//! is discarded when taken, and every take refills the slot in the background.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::process::{Child, Command};
+use tracing::{debug, warn};
+
+/// What a warm process was spawned with; it can only serve identical runs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SlotKey {
+    pub exec: String,
+    /// Arguments to the wrapper; only code passed on argv, which no warm
+    /// process can have.
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+impl SlotKey {
+    /// Command that runs the wrapper with this key's arguments and environment.
+    ///
+    /// The wrapper leads a process group of its own, so a timed-out run can
+    /// be signalled as a whole rather than just the wrapper.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.exec);
+        cmd.args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .envs(self.env.iter().cloned())
+            .process_group(0);
+        cmd
+    }
+}
+
+/// Up to `size` idle wrapper processes per slot key.
+#[derive(Debug)]
+pub struct WarmPool {
+    size: usize,
+    slots: Mutex<HashMap<SlotKey, Vec<Child>>>,
+    /// Executions served by a warm process.
+    hits: AtomicUsize,
+}
+
+impl WarmPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            slots: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a live warm process for `key`, if one is ready, and start
+    /// topping the slot back up.
+    pub fn take(self: &Arc<Self>, key: &SlotKey) -> Option<Child> {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        let idle = slots.entry(key.clone()).or_default();
+        let child = std::iter::from_fn(|| idle.pop()).find_map(|mut child| {
+            if matches!(child.try_wait(), Ok(None)) {
+                Some(child)
+            } else {
+                debug!(exec = %key.exec, "Discarding exited warm wrapper");
+                None
+            }
+        });
+        drop(slots);
+
+        if child.is_some() {
+            let hits = self.hits.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!(exec = %key.exec, hits, "Using warm wrapper");
+        }
+
+        let pool = Arc::clone(self);
+        let key = key.clone();
+        tokio::spawn(async move { pool.fill(&key) });
+
+        child
+    }
+
+    /// Spawn wrappers until the slot for `key` holds `size` processes.
+    fn fill(&self, key: &SlotKey) {
+        let idle = self.idle(key);
+        let mut spawned = Vec::new();
+        for _ in idle..self.size {
+            match key.command().kill_on_drop(true).spawn() {
+                Ok(child) => spawned.push(child),
+                Err(e) => {
+                    warn!(exec = %key.exec, error = %e, "Failed to spawn warm wrapper");
+                    break;
+                }
+            }
+        }
+
+        // Concurrent fills may overshoot; the extras are killed on drop
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = slots.entry(key.clone()).or_default();
+        slot.extend(spawned);
+        slot.truncate(self.size);
+        drop(slots);
+    }
+
+    /// Number of idle processes currently held for `key`.
+    pub fn idle(&self, key: &SlotKey) -> usize {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .map_or(0, Vec::len)
+    }
+
+    /// Executions served by a warm process so far.
+    #[cfg(test)]
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
@@ -1,13 +1,16 @@
 //! Configuration loaded from Nix-generated metadata.
 //!
 //! The Nix wrapper passes environment metadata via the `NIX_SANDBOX_METADATA`
-//! environment variable as JSON.
+//! environment variable as JSON. Outside Nix, the same shape can be written
+//! as a TOML file and passed with `--config`.
 
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
 /// Top-level configuration for the daemon.
@@ -20,9 +23,411 @@ pub struct Config {
     #[serde(default)]
     pub project: Option<ProjectConfig>,
 
+    /// Additional named host directories to mount (`[[mounts]]`).
+    #[serde(default, rename = "mounts")]
+    pub extra_mounts: Vec<MountConfig>,
+
+    /// Writable scratch directory configuration (optional).
+    #[serde(default)]
+    pub scratch: Option<ScratchConfig>,
+
     /// Session persistence configuration (optional).
     #[serde(default)]
     pub session: Option<SessionConfigToml>,
+
+    /// Pre-warmed wrapper pool configuration (optional).
+    #[serde(default)]
+    pub pool: Option<PoolConfig>,
+
+    /// Server-wide resource limits (optional).
+    #[serde(default)]
+    pub limits: Option<LimitsConfig>,
+
+    /// Agent interpreter per environment name, for environments without
+    /// `interpreter_type` (`[interpreter_map]`).
+    #[serde(default)]
+    pub interpreter_map: HashMap<String, String>,
+
+    /// Append-only log of run calls (optional).
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+
+    /// How run output is presented to clients (optional).
+    #[serde(default)]
+    pub output: Option<OutputConfig>,
+
+    /// Replaces the built-in server instructions (optional). `{env_list}`
+    /// and `{project_mount}` are filled in.
+    #[serde(default)]
+    pub instructions_template: Option<String>,
+
+    /// Environment for run calls that don't name one (optional).
+    #[serde(default)]
+    pub default_env: Option<String>,
+
+    /// Which wins when a custom sandbox has a bundled environment's name.
+    #[serde(default)]
+    pub sandbox_merge: MergePolicy,
+}
+
+/// How discovered sandboxes are merged with the bundled environments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    /// A custom sandbox replaces the bundled environment of the same name.
+    #[default]
+    OverrideBundled,
+    /// Bundled environments and their aliases are kept; custom sandboxes
+    /// only add new names.
+    PreserveBundled,
+}
+
+/// Presentation of run output (`[output]`).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OutputConfig {
+    /// Inserted verbatim between stdout and stderr in text results when
+    /// both are non-empty. Empty concatenates them.
+    #[serde(default)]
+    pub stderr_delimiter: Option<String>,
+
+    /// Which part of over-long output to keep.
+    #[serde(default)]
+    pub truncate: TruncateStrategy,
+}
+
+/// Which part of output beyond `max_output_bytes` survives truncation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TruncateStrategy {
+    /// The beginning.
+    #[default]
+    Head,
+    /// The end, where final errors and results usually are.
+    Tail,
+    /// Both ends, with the middle elided.
+    Middle,
+}
+
+/// Character encoding an environment's programs write their output in.
+///
+/// Configured by any WHATWG encoding label (`"utf-8"`, `"latin1"`,
+/// `"shift_jis"`, `"gbk"`, ...). As in browsers, `"latin1"` and
+/// `"iso-8859-1"` mean windows-1252, which differs from ISO-8859-1 only in
+/// the C1 control range 0x80-0x9F.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputEncoding(&'static encoding_rs::Encoding);
+
+impl Default for OutputEncoding {
+    fn default() -> Self {
+        Self(encoding_rs::UTF_8)
+    }
+}
+
+impl OutputEncoding {
+    /// The encoding `label` names, if it's one output can be decoded from.
+    pub fn for_label(label: &str) -> Option<Self> {
+        encoding_rs::Encoding::for_label_no_replacement(label.as_bytes()).map(Self)
+    }
+
+    /// Canonical name, e.g. `windows-1252` for `latin1`.
+    pub fn name(self) -> &'static str {
+        self.0.name()
+    }
+
+    pub fn is_utf8(self) -> bool {
+        self.0 == encoding_rs::UTF_8
+    }
+
+    /// Decode `bytes` as text, replacing anything undecodable with U+FFFD.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        self.0.decode_without_bom_handling(bytes).0.into_owned()
+    }
+
+    /// Decoder for output arriving in chunks, which may split characters.
+    pub fn new_decoder(self) -> encoding_rs::Decoder {
+        self.0.new_decoder_without_bom_handling()
+    }
+
+    /// `SANDBOX_OUTPUT_ENCODING` for a session agent, which decodes its
+    /// interpreters' output itself.
+    pub fn agent_var(self) -> (String, String) {
+        (OUTPUT_ENCODING_VAR.to_string(), self.name().to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputEncoding {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let label = String::deserialize(deserializer)?;
+        Self::for_label(&label)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown output_encoding '{label}'")))
+    }
+}
+
+/// Variable telling a session agent which encoding to decode output from.
+pub const OUTPUT_ENCODING_VAR: &str = "SANDBOX_OUTPUT_ENCODING";
+
+/// How ephemeral runs hand code to an environment's wrapper.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// On stdin, ahead of any input data.
+    #[default]
+    Stdin,
+    /// As the wrapper's last command-line argument, after `--`; stdin gets
+    /// only input data. Other users on the host can read a command line (via
+    /// `ps` or `/proc`), so code sent this way isn't private.
+    Argv,
+}
+
+/// Longest single argument Linux accepts (`MAX_ARG_STRLEN`), NUL included.
+pub const MAX_ARG_BYTES: usize = 32 * 4096;
+
+impl InputMode {
+    /// Check that `code` can be passed this way: argv can't carry NUL bytes
+    /// or an argument of `MAX_ARG_BYTES` or more.
+    pub fn check_code(self, code: &str) -> Result<()> {
+        if self == Self::Stdin {
+            return Ok(());
+        }
+        if code.contains('\0') {
+            anyhow::bail!("Code contains a NUL byte, which input_mode \"argv\" can't pass");
+        }
+        if code.len() >= MAX_ARG_BYTES {
+            anyhow::bail!(
+                "Code is {} bytes; input_mode \"argv\" passes at most {}",
+                code.len(),
+                MAX_ARG_BYTES - 1
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Default for `[output] stderr_delimiter`.
+pub const DEFAULT_STDERR_DELIMITER: &str = "\n--- stderr ---\n";
+
+/// Audit log of run calls (`[audit]`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// JSONL file each execution appends a record to. `~`, `$VAR` and
+    /// `${VAR}` are expanded.
+    pub path: PathBuf,
+
+    /// Digest recorded in place of the code.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+}
+
+/// Digest algorithm for audit records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+/// Server-wide resource limits, across all environments (`[limits]`).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LimitsConfig {
+    /// Maximum executions (ephemeral and session) running at once.
+    #[serde(default)]
+    pub max_concurrent_executions: Option<usize>,
+
+    /// What to do with a call that arrives while the cap is reached.
+    #[serde(default)]
+    pub when_busy: BusyPolicy,
+
+    /// Ceiling on the summed `memory_mb` of live sessions. At the ceiling,
+    /// idle sessions are evicted (least recently used first) to make room.
+    #[serde(default)]
+    pub max_session_memory_mb: Option<u64>,
+
+    /// Largest `code` a run call may send, in bytes (default 4MB).
+    #[serde(default)]
+    pub max_code_bytes: Option<usize>,
+
+    /// How many sandboxes deep the daemon may spawn wrappers (default 3).
+    #[serde(default)]
+    pub max_sandbox_depth: Option<u32>,
+
+    /// Seconds a timed-out run gets between SIGTERM and SIGKILL (default
+    /// 2; 0 kills at once).
+    #[serde(default)]
+    pub kill_grace_seconds: Option<u64>,
+
+    /// Prefixes a `secret_env` name must start with, e.g. `["GH_", "AWS_"]`.
+    /// Empty (the default) accepts any name that isn't denied.
+    #[serde(default)]
+    pub secret_env_prefixes: Vec<String>,
+}
+
+/// Default for `[limits] max_code_bytes`.
+pub const DEFAULT_MAX_CODE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Default for `[limits] kill_grace_seconds`.
+pub const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Default for `[limits] max_sandbox_depth`.
+pub const DEFAULT_MAX_SANDBOX_DEPTH: u32 = 3;
+
+/// Env var carrying the nesting depth of a spawned wrapper.
+pub const SANDBOX_DEPTH_VAR: &str = "NIX_SANDBOX_DEPTH";
+
+/// Env var telling a wrapper whether its environment may use the network.
+pub const SANDBOX_NETWORK_VAR: &str = "SANDBOX_NETWORK";
+
+/// Env var carrying the path of a run's secrets file (see [`SecretEnv`]).
+pub const SECRET_FILE_VAR: &str = "SANDBOX_SECRET_FILE";
+
+/// Names that change how a shell, the dynamic loader, or an interpreter
+/// starts up, so they may never be set from a call or inherited from the host.
+const DENIED_ENV_VARS: &[&str] = &[
+    "BASHOPTS",
+    "BASH_ENV",
+    "CDPATH",
+    "ENV",
+    "GLOBIGNORE",
+    "HOME",
+    "IFS",
+    "NODE_OPTIONS",
+    "PATH",
+    "PERL5OPT",
+    "PROMPT_COMMAND",
+    "PS4",
+    "PYTHONPATH",
+    "PYTHONSTARTUP",
+    "SHELLOPTS",
+    "TMPDIR",
+];
+
+/// Prefixes of names denied like [`DENIED_ENV_VARS`].
+const DENIED_ENV_PREFIXES: &[&str] = &["LD_", "DYLD_", "BASH_FUNC_"];
+
+/// Env var carrying an environment's `interpreter_args`, one per line.
+pub const INTERPRETER_ARGS_VAR: &str = "SANDBOX_INTERPRETER_ARGS";
+
+/// Deserialize `interpreter_args`, rejecting an argument with a newline,
+/// which would split in two in [`INTERPRETER_ARGS_VAR`].
+fn deserialize_interpreter_args<'de, D>(de: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let args = Vec::<String>::deserialize(de)?;
+    if let Some(arg) = args.iter().find(|arg| arg.contains('\n')) {
+        return Err(serde::de::Error::custom(format!(
+            "argument {arg:?} contains a newline"
+        )));
+    }
+    Ok(args)
+}
+
+/// Deserialize a count of seconds that must be at least 1.
+fn deserialize_positive<'de, D>(de: D) -> std::result::Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match u64::deserialize(de)? {
+        0 => Err(serde::de::Error::custom("must be at least 1")),
+        n => Ok(n),
+    }
+}
+
+/// Env var that turns on lenient config parsing, like `--lenient-config`.
+pub const LENIENT_CONFIG_VAR: &str = "NIX_SANDBOX_LENIENT_CONFIG";
+
+/// What config parsing does with keys the daemon doesn't know.
+///
+/// Strict by default, so a typo like `memory_md` fails loudly instead of
+/// silently leaving `memory_mb` at its default. Lenient parsing is for
+/// metadata from a newer wrapper that emits fields this daemon predates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownFields {
+    /// Fail, naming every unknown key.
+    #[default]
+    Reject,
+    /// Log each unknown key and ignore it.
+    Warn,
+}
+
+impl UnknownFields {
+    /// `Warn` if `NIX_SANDBOX_LENIENT_CONFIG` is `1` or `true`, else `Reject`.
+    pub fn from_env() -> Self {
+        match std::env::var(LENIENT_CONFIG_VAR).as_deref() {
+            Ok("1" | "true") => Self::Warn,
+            _ => Self::Reject,
+        }
+    }
+}
+
+/// How deep inside its own sandboxes the daemon is running.
+///
+/// Every wrapper and agent the daemon spawns gets `NIX_SANDBOX_DEPTH` set
+/// one above the daemon's own. A daemon started inside a sandbox (say, by a
+/// wrapper whose `exec` re-enters it) inherits the count and refuses to
+/// spawn past `max`, so a misconfigured wrapper can't nest without bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxDepth {
+    /// Depth of this process (0 outside any sandbox).
+    pub current: u32,
+    /// Deepest level a spawned wrapper may run at.
+    pub max: u32,
+}
+
+impl Default for SandboxDepth {
+    fn default() -> Self {
+        Self {
+            current: 0,
+            max: DEFAULT_MAX_SANDBOX_DEPTH,
+        }
+    }
+}
+
+impl SandboxDepth {
+    /// Depth from a `NIX_SANDBOX_DEPTH` value. An unparseable value is
+    /// treated as already at the limit.
+    pub fn new(value: Option<&str>, max: u32) -> Self {
+        let current = value.map_or(0, |v| v.trim().parse().unwrap_or(u32::MAX));
+        Self { current, max }
+    }
+
+    /// Fail if spawning another sandbox would exceed `max`.
+    pub fn check(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.current < self.max,
+            "Refusing to start a sandbox nested {} deep (max_sandbox_depth is {}): \
+             a sandbox wrapper is probably re-entering the daemon",
+            self.current.saturating_add(1),
+            self.max
+        );
+        Ok(())
+    }
+
+    /// The `NIX_SANDBOX_DEPTH` entry for a spawned wrapper.
+    pub fn child_var(&self) -> (String, String) {
+        (
+            SANDBOX_DEPTH_VAR.to_string(),
+            self.current.saturating_add(1).to_string(),
+        )
+    }
+}
+
+/// Handling of calls over `max_concurrent_executions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyPolicy {
+    /// Fail the call with a "server busy" error.
+    #[default]
+    Reject,
+    /// Wait for a running execution to finish.
+    Queue,
+}
+
+/// Pre-warmed wrapper pool for ephemeral runs (as read from TOML/JSON).
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolConfig {
+    /// Warm wrapper processes kept per environment (0 disables the pool).
+    pub size: usize,
 }
 
 /// Session persistence configuration (as read from TOML/JSON).
@@ -35,6 +440,94 @@ pub struct SessionConfigToml {
     /// Maximum session lifetime in seconds, regardless of activity.
     #[serde(default = "default_max_lifetime")]
     pub max_lifetime_seconds: u64,
+
+    /// Maximum number of live sessions; the least recently used is evicted.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+
+    /// Directory for persisted session metadata (optional).
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+
+    /// Seconds between keepalive pings to idle sessions (optional).
+    #[serde(default)]
+    pub keepalive_interval_seconds: Option<u64>,
+
+    /// Seconds an agent may take to answer one request before it's treated
+    /// as hung (optional; defaults to 600, 0 disables).
+    #[serde(default)]
+    pub request_timeout_seconds: Option<u64>,
+
+    /// Interpreters sessions may request (optional; defaults to
+    /// python, bash, and node).
+    #[serde(default)]
+    pub allowed_interpreters: Option<Vec<String>>,
+
+    /// Environments sessions may be created for (optional; defaults to all).
+    #[serde(default)]
+    pub session_allow: Option<Vec<String>>,
+
+    /// Environments sessions may never be created for.
+    #[serde(default)]
+    pub session_deny: Vec<String>,
+
+    /// Seconds to wait for a new session's agent to report ready; raise it
+    /// for environments that are slow to start.
+    #[serde(default = "default_agent_ready_timeout")]
+    pub agent_ready_timeout_seconds: u64,
+
+    /// Seconds between sweeps for idle and expired sessions (at least 1).
+    #[serde(default = "default_reaper_interval")]
+    pub reaper_interval_seconds: u64,
+
+    /// Executions a session may start per `rate_limit_window_seconds`
+    /// (optional; 0 or absent disables the limit).
+    #[serde(default)]
+    pub rate_limit_executions: Option<u32>,
+
+    /// Window for `rate_limit_executions`, in seconds (at least 1).
+    #[serde(
+        default = "default_rate_limit_window",
+        deserialize_with = "deserialize_positive"
+    )]
+    pub rate_limit_window_seconds: u64,
+
+    /// Seconds idle after which a session's agent is paused instead of
+    /// reaped at the idle timeout (optional; absent reaps as usual).
+    #[serde(default)]
+    pub suspend_idle_seconds: Option<u64>,
+
+    /// Executions each session remembers for `session_history` (0, the
+    /// default, keeps none).
+    #[serde(default)]
+    pub history_size: usize,
+
+    /// Keep the code itself in session history, not just its digest.
+    #[serde(default)]
+    pub history_store_code: bool,
+}
+
+impl Default for SessionConfigToml {
+    fn default() -> Self {
+        Self {
+            idle_timeout_seconds: default_idle_timeout(),
+            max_lifetime_seconds: default_max_lifetime(),
+            max_sessions: default_max_sessions(),
+            state_dir: None,
+            keepalive_interval_seconds: None,
+            request_timeout_seconds: None,
+            allowed_interpreters: None,
+            session_allow: None,
+            session_deny: Vec::new(),
+            agent_ready_timeout_seconds: default_agent_ready_timeout(),
+            reaper_interval_seconds: default_reaper_interval(),
+            rate_limit_executions: None,
+            rate_limit_window_seconds: default_rate_limit_window(),
+            suspend_idle_seconds: None,
+            history_size: 0,
+            history_store_code: false,
+        }
+    }
 }
 
 /// Project directory configuration.
@@ -59,14 +552,273 @@ pub struct ProjectConfig {
     pub inherit_env: InheritEnv,
 }
 
+impl Default for ProjectConfig {
+    fn default() -> Self {
+        Self {
+            path: default_project_path(),
+            mount_point: default_mount_point(),
+            use_flake: false,
+            inherit_env: InheritEnv::default(),
+        }
+    }
+}
+
+/// An additional named host directory to mount into the sandbox.
+///
+/// Mounted after the `[project]` directory, which (if configured) is always
+/// the first entry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MountConfig {
+    /// Unique name for the mount, e.g. `data`.
+    pub name: String,
+
+    /// Host path of the directory.
+    pub path: PathBuf,
+
+    /// Mount point inside the sandbox.
+    pub mount_point: String,
+
+    /// Whether the mount is read-only (the default).
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+}
+
+/// Writable scratch directory configuration.
+///
+/// Unlike the project, scratch is mounted read-write. The same host directory
+/// backs every call, so files persist across calls in a session (and are
+/// visible to other sessions and ephemeral runs) — it is not reset per call.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScratchConfig {
+    /// Host path of the scratch directory.
+    pub path: PathBuf,
+
+    /// Mount point inside the sandbox.
+    #[serde(default = "default_scratch_mount_point")]
+    pub mount_point: String,
+}
+
+/// A resolved project mount: host directory and where it appears.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectMount {
+    pub name: String,
+    pub path: PathBuf,
+    pub mount_point: String,
+    pub read_only: bool,
+}
+
+/// Host directories to bind into a sandbox, resolved once per call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mounts {
+    /// Project mounts; the legacy single project (if any) comes first.
+    pub projects: Vec<ProjectMount>,
+
+    /// Scratch directory, mounted read-write at `scratch_mount`.
+    pub scratch_dir: Option<PathBuf>,
+    pub scratch_mount: String,
+}
+
+impl Mounts {
+    /// Env vars telling the Nix wrappers what to bind at runtime.
+    ///
+    /// Each project mount is passed as `PROJECT_DIR_<i>`/`PROJECT_MOUNT_<i>`/
+    /// `PROJECT_READ_ONLY_<i>`. The first is also passed as
+    /// `PROJECT_DIR`/`PROJECT_MOUNT` for wrappers built before multiple
+    /// mounts existed. Scratch is `SCRATCH_DIR`/`SCRATCH_MOUNT`.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(first) = self.projects.first() {
+            vars.push((
+                "PROJECT_DIR".to_string(),
+                first.path.to_string_lossy().into_owned(),
+            ));
+            vars.push(("PROJECT_MOUNT".to_string(), first.mount_point.clone()));
+        }
+        for (i, mount) in self.projects.iter().enumerate() {
+            vars.push((
+                format!("PROJECT_DIR_{i}"),
+                mount.path.to_string_lossy().into_owned(),
+            ));
+            vars.push((format!("PROJECT_MOUNT_{i}"), mount.mount_point.clone()));
+            vars.push((
+                format!("PROJECT_READ_ONLY_{i}"),
+                if mount.read_only { "1" } else { "0" }.to_string(),
+            ));
+        }
+        if let Some(dir) = &self.scratch_dir {
+            vars.push((
+                "SCRATCH_DIR".to_string(),
+                dir.to_string_lossy().into_owned(),
+            ));
+            vars.push(("SCRATCH_MOUNT".to_string(), self.scratch_mount.clone()));
+        }
+        vars
+    }
+
+    /// Resolve a requested working directory to an absolute sandbox path.
+    ///
+    /// Relative paths are taken from `/workspace`, the default working
+    /// directory. The result must lie under `/workspace`, the scratch mount,
+    /// or a project mount point; `..` components are rejected outright.
+    pub fn resolve_workdir(&self, workdir: &str) -> Result<String> {
+        let mut resolved = PathBuf::from(DEFAULT_WORKDIR);
+        for component in Path::new(workdir).components() {
+            match component {
+                Component::RootDir => resolved = PathBuf::from("/"),
+                Component::Normal(part) => resolved.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    anyhow::bail!("Working directory '{workdir}' must not contain '..'")
+                }
+            }
+        }
+
+        let scratch = self.scratch_dir.as_ref().map(|_| &self.scratch_mount);
+        let allowed = std::iter::once(DEFAULT_WORKDIR)
+            .chain(scratch.map(String::as_str))
+            .chain(self.projects.iter().map(|p| p.mount_point.as_str()));
+        let mut roots = Vec::new();
+        for root in allowed {
+            if resolved.starts_with(root) {
+                return Ok(resolved.to_string_lossy().into_owned());
+            }
+            roots.push(root);
+        }
+        anyhow::bail!(
+            "Working directory '{workdir}' is outside the sandbox mounts ({})",
+            roots.join(", ")
+        )
+    }
+}
+
+/// Check `name` is a usable env var name the daemon doesn't set itself.
+/// `what` names the setting in errors.
+pub fn validate_env_var_name(name: &str, what: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    anyhow::ensure!(valid, "Invalid {what} name '{name}'");
+    let reserved = ["SANDBOX_", "PROJECT_", "SCRATCH_"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        || name == SANDBOX_DEPTH_VAR;
+    anyhow::ensure!(
+        !reserved,
+        "{what} name '{name}' is reserved for the sandbox wrapper"
+    );
+    let denied = DENIED_ENV_VARS.contains(&name)
+        || DENIED_ENV_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+    anyhow::ensure!(
+        !denied,
+        "{what} name '{name}' is not allowed: it changes how programs start"
+    );
+    Ok(())
+}
+
+/// Secret environment variables for one call, set in the sandbox but kept
+/// out of logs: `Debug` shows only the names.
+///
+/// The values never go on a command line or into the wrapper's own
+/// environment, where `ps` or `/proc` would show them. The backend writes
+/// them to a private file instead ([`SecretEnv::encode`]) and passes its
+/// path in [`SECRET_FILE_VAR`]; the runner exports them inside the sandbox.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct SecretEnv(BTreeMap<String, String>);
+
+impl SecretEnv {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check the names are usable env var names the daemon doesn't set
+    /// itself, starting with one of `prefixes` unless that's empty.
+    pub fn validate(&self, prefixes: &[String]) -> Result<()> {
+        for name in self.0.keys() {
+            validate_env_var_name(name, "secret_env")?;
+            anyhow::ensure!(
+                prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p.as_str())),
+                "secret_env name '{name}' must start with one of: {}",
+                prefixes.join(", ")
+            );
+            anyhow::ensure!(
+                !self.0[name].contains('\0'),
+                "secret_env value for '{name}' contains a NUL byte"
+            );
+        }
+        Ok(())
+    }
+
+    /// The `(name, value)` pairs, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Contents of the secrets file: `NAME=value` entries, each ended by a
+    /// NUL byte so values may hold newlines.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, value) in self.iter() {
+            out.extend_from_slice(name.as_bytes());
+            out.push(b'=');
+            out.extend_from_slice(value.as_bytes());
+            out.push(0);
+        }
+        out
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for SecretEnv {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Debug for SecretEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
 /// Environment variables to inherit into the sandbox.
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct InheritEnv {
     /// List of environment variable names to pass through.
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_inherit_env_vars")]
     pub vars: Vec<String>,
 }
 
+impl InheritEnv {
+    /// Check every name with [`validate_env_var_name`]: a host value must
+    /// not override the mount variables or start programs differently.
+    pub fn validate(&self) -> Result<()> {
+        self.vars
+            .iter()
+            .try_for_each(|name| validate_env_var_name(name, "inherit_env"))
+    }
+}
+
+/// Deserialize `inherit_env.vars`, rejecting names `InheritEnv::validate`
+/// refuses.
+fn deserialize_inherit_env_vars<'de, D>(de: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let inherit = InheritEnv {
+        vars: Vec::<String>::deserialize(de)?,
+    };
+    inherit.validate().map_err(serde::de::Error::custom)?;
+    Ok(inherit.vars)
+}
+
 fn default_project_path() -> PathBuf {
     ".".into()
 }
@@ -75,37 +827,177 @@ fn default_mount_point() -> String {
     "/project".into()
 }
 
+const fn default_read_only() -> bool {
+    true
+}
+
+/// Working directory of every run unless a call asks for another.
+pub const DEFAULT_WORKDIR: &str = "/workspace";
+
+fn default_scratch_mount_point() -> String {
+    DEFAULT_WORKDIR.into()
+}
+
+/// Expand a leading `~`, `$VAR`, and `${VAR}` against the host environment.
+///
+/// A `$` not followed by a variable name is kept literally. Undefined
+/// variables are an error rather than silently expanding to nothing.
+pub fn expand_path(path: &str) -> Result<PathBuf> {
+    let lookup = |name: &str| {
+        std::env::var(name)
+            .map_err(|_| anyhow::anyhow!("Undefined variable `${name}` in path '{path}'"))
+    };
+
+    let mut out = String::with_capacity(path.len());
+    let rest = if path == "~" || path.starts_with("~/") {
+        out.push_str(&lookup("HOME")?);
+        &path[1..]
+    } else {
+        path
+    };
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => anyhow::bail!("Unclosed `${{` in path '{path}'"),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                out.push('$');
+                continue;
+            }
+        }
+        out.push_str(&lookup(&name)?);
+    }
+
+    Ok(PathBuf::from(out))
+}
+
+/// Expand a configured path and resolve it against the current directory if relative.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let path = expand_path(&path.to_string_lossy())?;
+    Ok(if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    })
+}
+
 impl Config {
+    /// Start building a config in code instead of from `NIX_SANDBOX_METADATA`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
     /// Load configuration from the `NIX_SANDBOX_METADATA` environment variable.
-    pub fn from_env() -> Result<Self> {
+    pub fn from_env(unknown: UnknownFields) -> Result<Self> {
         let metadata_json = std::env::var("NIX_SANDBOX_METADATA")
             .context("NIX_SANDBOX_METADATA not set - are you running via the Nix wrapper?")?;
 
-        let config: Self =
-            serde_json::from_str(&metadata_json).context("Failed to parse NIX_SANDBOX_METADATA")?;
+        parse_config(&metadata_json, unknown).context("Failed to parse NIX_SANDBOX_METADATA")
+    }
+
+    /// Load configuration from a TOML file shaped like the metadata JSON.
+    pub fn from_file(path: &Path, unknown: UnknownFields) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        parse_config_toml(&text, unknown)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Number of warm wrapper processes to keep per environment.
+    ///
+    /// Priority: TOML `[pool]` config > `NIX_SANDBOX_POOL_SIZE` env var.
+    /// Defaults to 0 (no pool).
+    pub fn pool_size(&self) -> usize {
+        self.pool.as_ref().map_or_else(
+            || {
+                std::env::var("NIX_SANDBOX_POOL_SIZE")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0)
+            },
+            |pool| pool.size,
+        )
+    }
+
+    /// Text between stdout and stderr in text results.
+    pub fn stderr_delimiter(&self) -> &str {
+        self.output
+            .as_ref()
+            .and_then(|o| o.stderr_delimiter.as_deref())
+            .unwrap_or(DEFAULT_STDERR_DELIMITER)
+    }
+
+    /// How over-long output is truncated (`[output] truncate`).
+    pub fn truncate_strategy(&self) -> TruncateStrategy {
+        self.output.as_ref().map(|o| o.truncate).unwrap_or_default()
+    }
+
+    /// Largest `code` a run call may send, in bytes.
+    pub fn max_code_bytes(&self) -> usize {
+        self.limits
+            .as_ref()
+            .and_then(|l| l.max_code_bytes)
+            .unwrap_or(DEFAULT_MAX_CODE_BYTES)
+    }
+
+    /// How long a timed-out run may take to exit after SIGTERM before it is
+    /// killed.
+    pub fn kill_grace(&self) -> Duration {
+        self.limits
+            .as_ref()
+            .and_then(|l| l.kill_grace_seconds)
+            .map_or(DEFAULT_KILL_GRACE, Duration::from_secs)
+    }
 
-        Ok(config)
+    /// Nesting depth of this process, from `NIX_SANDBOX_DEPTH`, with the
+    /// configured limit.
+    pub fn sandbox_depth(&self) -> SandboxDepth {
+        let max = self
+            .limits
+            .as_ref()
+            .and_then(|l| l.max_sandbox_depth)
+            .unwrap_or(DEFAULT_MAX_SANDBOX_DEPTH);
+        SandboxDepth::new(std::env::var(SANDBOX_DEPTH_VAR).ok().as_deref(), max)
     }
 
     /// Resolve the project directory to an absolute path.
     ///
-    /// Priority: `PROJECT_DIR` env var > TOML `[project]` config.
-    pub fn resolved_project_dir(&self) -> Option<PathBuf> {
+    /// Priority: `PROJECT_DIR` env var > TOML `[project]` config. Both are
+    /// expanded with `expand_path`; an undefined variable is an error.
+    pub fn resolved_project_dir(&self) -> Result<Option<PathBuf>> {
         // Env var takes priority (MCP-conventional configuration)
         if let Ok(dir) = std::env::var("PROJECT_DIR") {
-            let path = PathBuf::from(&dir);
+            let path = expand_path(&dir).context("Invalid PROJECT_DIR")?;
             if path.is_dir() {
-                return Some(path);
+                return Ok(Some(path));
             }
         }
         // Fall back to TOML config
-        self.project.as_ref().map(|p| {
-            if p.path.is_absolute() {
-                p.path.clone()
-            } else {
-                std::env::current_dir().unwrap_or_default().join(&p.path)
-            }
-        })
+        self.project
+            .as_ref()
+            .map(|p| resolve_path(&p.path).context("Invalid project path"))
+            .transpose()
     }
 
     /// Get the project mount point inside the sandbox.
@@ -115,27 +1007,103 @@ impl Config {
         std::env::var("PROJECT_MOUNT").unwrap_or_else(|_| {
             self.project
                 .as_ref()
-                .map(|p| p.mount_point.clone())
-                .unwrap_or_else(|| "/project".into())
+                .map_or_else(|| "/project".into(), |p| p.mount_point.clone())
+        })
+    }
+
+    /// Resolve all project mounts: the `[project]` directory (if any) as the
+    /// first entry, named `project` and read-only, followed by `[[mounts]]`.
+    ///
+    /// Names and mount points must be unique.
+    pub fn project_mounts(&self) -> Result<Vec<ProjectMount>> {
+        let mut mounts = Vec::new();
+        if let Some(path) = self.resolved_project_dir()? {
+            mounts.push(ProjectMount {
+                name: "project".to_string(),
+                path,
+                mount_point: self.project_mount(),
+                read_only: true,
+            });
+        }
+
+        for extra in &self.extra_mounts {
+            let path = resolve_path(&extra.path)
+                .with_context(|| format!("Invalid path for mount '{}'", extra.name))?;
+            if let Some(dup) = mounts
+                .iter()
+                .find(|m| m.name == extra.name || m.mount_point == extra.mount_point)
+            {
+                anyhow::bail!(
+                    "Mount '{}' conflicts with mount '{}' (names and mount points must be unique)",
+                    extra.name,
+                    dup.name
+                );
+            }
+            mounts.push(ProjectMount {
+                name: extra.name.clone(),
+                path,
+                mount_point: extra.mount_point.clone(),
+                read_only: extra.read_only,
+            });
+        }
+        Ok(mounts)
+    }
+
+    /// Resolve the scratch directory to an absolute path.
+    ///
+    /// Priority: `SCRATCH_DIR` env var > TOML `[scratch]` config, expanded
+    /// like the project directory.
+    pub fn resolved_scratch_dir(&self) -> Result<Option<PathBuf>> {
+        if let Ok(dir) = std::env::var("SCRATCH_DIR") {
+            let path = expand_path(&dir).context("Invalid SCRATCH_DIR")?;
+            if path.is_dir() {
+                return Ok(Some(path));
+            }
+        }
+        self.scratch
+            .as_ref()
+            .map(|s| resolve_path(&s.path).context("Invalid scratch path"))
+            .transpose()
+    }
+
+    /// Get the scratch mount point inside the sandbox.
+    ///
+    /// Priority: `SCRATCH_MOUNT` env var > TOML config > default `/workspace`.
+    pub fn scratch_mount(&self) -> String {
+        std::env::var("SCRATCH_MOUNT").unwrap_or_else(|_| {
+            self.scratch
+                .as_ref()
+                .map_or_else(default_scratch_mount_point, |s| s.mount_point.clone())
+        })
+    }
+
+    /// Resolve all runtime mounts for a call.
+    pub fn mounts(&self) -> Result<Mounts> {
+        Ok(Mounts {
+            projects: self.project_mounts()?,
+            scratch_dir: self.resolved_scratch_dir()?,
+            scratch_mount: self.scratch_mount(),
         })
     }
 
     /// Scan a directory for sandbox artifacts and return discovered environments.
     ///
     /// Each subdirectory should contain:
-    /// - `metadata.json` with name, interpreter_type, timeout_seconds, memory_mb
+    /// - `metadata.json` with `name`, `interpreter_type`, `timeout_seconds`, `memory_mb`
     /// - `bin/run` — ephemeral execution wrapper
     /// - `bin/session-run` (optional) — session execution wrapper
     ///
-    /// Invalid entries are logged and skipped.
-    pub fn scan_sandbox_dir(dir: &Path) -> HashMap<String, EnvironmentMeta> {
-        let mut envs = HashMap::new();
+    /// Invalid entries are logged and listed in `skipped`; with
+    /// `UnknownFields::Reject`, so are entries with unknown keys in
+    /// `metadata.json`.
+    pub fn scan_sandbox_dir(dir: &Path, unknown: UnknownFields) -> SandboxScan {
+        let mut scan = SandboxScan::default();
 
         let entries = match std::fs::read_dir(dir) {
             Ok(entries) => entries,
             Err(e) => {
                 debug!(path = %dir.display(), error = %e, "Cannot read sandbox directory");
-                return envs;
+                return scan;
             }
         };
 
@@ -144,12 +1112,21 @@ impl Config {
                 Ok(e) => e,
                 Err(e) => {
                     warn!(error = %e, "Error reading sandbox directory entry");
+                    scan.skip(dir, format!("cannot read directory entry: {e}"));
                     continue;
                 }
             };
 
             let path = entry.path();
             if !path.is_dir() {
+                // A symlink that doesn't lead anywhere is likely a broken
+                // sandbox; plain files just aren't sandboxes
+                if path.is_symlink() {
+                    if let Err(e) = std::fs::metadata(&path) {
+                        warn!(path = %path.display(), error = %e, "Skipping sandbox: cannot resolve symlink");
+                        scan.skip(&path, format!("cannot resolve symlink: {e}"));
+                    }
+                }
                 continue;
             }
 
@@ -159,31 +1136,38 @@ impl Config {
                 Ok(s) => s,
                 Err(e) => {
                     warn!(path = %meta_path.display(), error = %e, "Skipping sandbox: cannot read metadata.json");
+                    scan.skip(&path, format!("cannot read metadata.json: {e}"));
                     continue;
                 }
             };
 
-            let artifact_meta: SandboxArtifactMeta = match serde_json::from_str(&meta_str) {
+            let de = &mut serde_json::Deserializer::from_str(&meta_str);
+            let artifact_meta: SandboxArtifactMeta = match deserialize_checked(de, unknown) {
                 Ok(m) => m,
                 Err(e) => {
                     warn!(path = %meta_path.display(), error = %e, "Skipping sandbox: invalid metadata.json");
+                    scan.skip(&path, format!("invalid metadata.json: {e}"));
                     continue;
                 }
             };
 
-            // Verify bin/run exists
+            // Verify bin/run is usable, and bin/session-run if present
             let run_path = path.join("bin/run");
-            if !run_path.exists() {
-                warn!(sandbox = %artifact_meta.name, path = %run_path.display(), "Skipping sandbox: bin/run not found");
+            if let Some(problem) = check_wrapper(&run_path) {
+                warn!(sandbox = %artifact_meta.name, path = %run_path.display(), %problem, "Skipping sandbox: unusable bin/run");
+                scan.skip(&path, format!("bin/run {problem}"));
                 continue;
             }
 
-            // Check for optional bin/session-run
             let session_run_path = path.join("bin/session-run");
-            let session_exec = if session_run_path.exists() {
-                Some(session_run_path.to_string_lossy().into_owned())
-            } else {
-                None
+            let session_exec = match check_wrapper(&session_run_path) {
+                None => Some(session_run_path.to_string_lossy().into_owned()),
+                Some(problem) if problem == WRAPPER_NOT_FOUND => None,
+                Some(problem) => {
+                    warn!(sandbox = %artifact_meta.name, path = %session_run_path.display(), %problem, "Skipping sandbox: unusable bin/session-run");
+                    scan.skip(&path, format!("bin/session-run {problem}"));
+                    continue;
+                }
             };
 
             let env_meta = EnvironmentMeta {
@@ -191,34 +1175,555 @@ impl Config {
                 exec: run_path.to_string_lossy().into_owned(),
                 session_exec,
                 timeout_seconds: artifact_meta.timeout_seconds,
+                max_timeout_seconds: artifact_meta.max_timeout_seconds,
                 memory_mb: artifact_meta.memory_mb,
                 interpreter_type: Some(artifact_meta.interpreter_type),
+                max_output_bytes: artifact_meta.max_output_bytes,
+                vsock: None,
+                tcp: None,
+                inherit_env: InheritEnv::default(),
+                aliases: artifact_meta.aliases,
+                preamble: artifact_meta.preamble,
+                setup_exec: None,
+                setup_timeout_seconds: default_setup_timeout(),
+                output_encoding: artifact_meta.output_encoding,
+                network: artifact_meta.network,
+                input_mode: artifact_meta.input_mode,
+                interpreter_args: artifact_meta.interpreter_args,
             };
 
             info!(name = %artifact_meta.name, path = %path.display(), "Discovered sandbox");
-            envs.insert(artifact_meta.name, env_meta);
+            scan.environments.insert(artifact_meta.name, env_meta);
         }
 
-        envs
+        scan
     }
 
     /// Merge discovered sandbox environments into the config.
     ///
-    /// Custom sandboxes override bundled presets on name collision (with info log).
-    pub fn merge_environments(&mut self, extra: HashMap<String, EnvironmentMeta>) {
+    /// When a custom sandbox's name matches a bundled environment or one of
+    /// its aliases, `policy` decides whether the custom sandbox overrides it
+    /// or is skipped. Returns the skipped sandboxes, by their `bin/run`.
+    pub fn merge_environments(
+        &mut self,
+        extra: HashMap<String, EnvironmentMeta>,
+        policy: MergePolicy,
+    ) -> Vec<SkippedSandbox> {
+        let bundled_aliases: HashMap<String, String> = self
+            .environments
+            .iter()
+            .flat_map(|(name, meta)| meta.aliases.iter().map(move |a| (a.clone(), name.clone())))
+            .collect();
+
+        let mut skipped = Vec::new();
         for (name, meta) in extra {
-            if self.environments.contains_key(&name) {
-                info!(name = %name, "Custom sandbox overrides bundled environment");
+            let taken_by = if self.environments.contains_key(&name) {
+                Some(format!("bundled environment '{name}'"))
+            } else {
+                bundled_aliases
+                    .get(&name)
+                    .map(|env| format!("alias of bundled environment '{env}'"))
+            };
+            if let Some(taken_by) = taken_by {
+                if policy == MergePolicy::PreserveBundled {
+                    warn!(name = %name, %taken_by, "Skipping custom sandbox whose name is taken");
+                    skipped.push(SkippedSandbox {
+                        path: PathBuf::from(&meta.exec),
+                        reason: format!("name '{name}' is taken by {taken_by}"),
+                    });
+                    continue;
+                }
+                info!(name = %name, %taken_by, "Custom sandbox overrides bundled name");
             }
             self.environments.insert(name, meta);
         }
+        skipped
+    }
+
+    /// Scan `dir` for sandbox artifacts and merge them into the config,
+    /// returning the sandboxes that were skipped. A missing directory is
+    /// skipped.
+    pub fn load_sandbox_dir(&mut self, dir: &Path, unknown: UnknownFields) -> Vec<SkippedSandbox> {
+        if !dir.is_dir() {
+            debug!(dir = %dir.display(), "Sandbox directory does not exist, skipping scan");
+            return Vec::new();
+        }
+        let mut scan = Self::scan_sandbox_dir(dir, unknown);
+        if !scan.environments.is_empty() {
+            info!(count = scan.environments.len(), dir = %dir.display(), "Discovered custom sandboxes");
+            let environments = std::mem::take(&mut scan.environments);
+            let rejected = self.merge_environments(environments, self.sandbox_merge);
+            scan.skipped.extend(rejected);
+        }
+        scan.skipped
+    }
+
+    /// Map each environment alias to the name of the environment it refers to.
+    ///
+    /// A real environment name always wins over an alias, and an alias listed
+    /// by several environments goes to the first by name. Both are logged.
+    pub fn alias_map(&self) -> HashMap<String, String> {
+        let mut names: Vec<&String> = self.environments.keys().collect();
+        names.sort();
+
+        let mut aliases = HashMap::new();
+        for name in names {
+            for alias in &self.environments[name].aliases {
+                if self.environments.contains_key(alias) {
+                    warn!(alias = %alias, env = %name, "Ignoring alias that matches an environment name");
+                    continue;
+                }
+                match aliases.entry(alias.clone()) {
+                    Entry::Occupied(existing) => {
+                        warn!(alias = %alias, env = %name, used_by = %existing.get(), "Ignoring alias already used by another environment");
+                    }
+                    Entry::Vacant(slot) => {
+                        slot.insert(name.clone());
+                    }
+                }
+            }
+        }
+        aliases
+    }
+
+    /// Check that every environment's `exec` and `session_exec` exist and
+    /// are executable. Issues are ordered by environment name.
+    pub fn validate_paths(&self) -> Vec<ValidationIssue> {
+        let mut names: Vec<&String> = self.environments.keys().collect();
+        names.sort();
+
+        let mut issues = Vec::new();
+        for name in names {
+            let meta = &self.environments[name];
+            let paths = std::iter::once(("exec", &meta.exec))
+                .chain(meta.session_exec.as_ref().map(|p| ("session_exec", p)));
+            for (field, path) in paths {
+                if let Some(problem) = check_executable(Path::new(path)) {
+                    issues.push(ValidationIssue {
+                        env_name: name.clone(),
+                        field,
+                        path: path.clone(),
+                        problem,
+                    });
+                }
+            }
+        }
+        issues
+    }
+
+    /// Fold the global `[project] inherit_env` list into every environment.
+    ///
+    /// Global vars come first, followed by each environment's own additions.
+    /// Call after all environments (including scanned sandboxes) are merged.
+    pub fn apply_global_inherit_env(&mut self) {
+        let Some(project) = &self.project else {
+            return;
+        };
+        for meta in self.environments.values_mut() {
+            let mut vars = project.inherit_env.vars.clone();
+            for var in meta.inherit_env.vars.drain(..) {
+                if !vars.contains(&var) {
+                    vars.push(var);
+                }
+            }
+            meta.inherit_env.vars = vars;
+        }
     }
 
     /// Create a config from a JSON string (for testing).
     #[cfg(test)]
     pub fn from_json(json: &str) -> Result<Self> {
-        let config: Self = serde_json::from_str(json).context("Failed to parse JSON")?;
-        Ok(config)
+        parse_config(json, UnknownFields::Reject).context("Failed to parse JSON")
+    }
+}
+
+/// Builds a [`Config`] in code, for embedding the daemon as a library.
+///
+/// Produces the same config the Nix wrapper's metadata JSON would. Paths
+/// aren't touched until use, so `build` only checks what can be checked
+/// up front.
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct ConfigBuilder {
+    environments: HashMap<String, EnvironmentMeta>,
+    project: Option<ProjectConfig>,
+    extra_mounts: Vec<MountConfig>,
+    scratch: Option<ScratchConfig>,
+    session: Option<SessionConfigToml>,
+    pool: Option<PoolConfig>,
+    limits: Option<LimitsConfig>,
+    interpreter_map: HashMap<String, String>,
+    audit: Option<AuditConfig>,
+    output: Option<OutputConfig>,
+    instructions_template: Option<String>,
+    default_env: Option<String>,
+    sandbox_merge: MergePolicy,
+}
+
+impl ConfigBuilder {
+    /// Add an environment, replacing any existing one with the same name.
+    pub fn environment(mut self, name: impl Into<String>, meta: EnvironmentMeta) -> Self {
+        self.environments.insert(name.into(), meta);
+        self
+    }
+
+    /// Set the `[project]` directory.
+    pub fn project(mut self, project: ProjectConfig) -> Self {
+        self.project = Some(project);
+        self
+    }
+
+    /// Add a `[[mounts]]` entry.
+    pub fn mount(mut self, mount: MountConfig) -> Self {
+        self.extra_mounts.push(mount);
+        self
+    }
+
+    /// Set the `[scratch]` directory.
+    pub fn scratch(mut self, scratch: ScratchConfig) -> Self {
+        self.scratch = Some(scratch);
+        self
+    }
+
+    /// Set the `[session]` settings.
+    pub fn session(mut self, session: SessionConfigToml) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Set the `[pool]` settings.
+    pub const fn pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Set the `[limits]` settings.
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Set the `[audit]` log.
+    pub fn audit(mut self, audit: AuditConfig) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Set the `[output]` settings.
+    pub fn output(mut self, output: OutputConfig) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Replace the server instructions with `template`.
+    pub fn instructions_template(mut self, template: impl Into<String>) -> Self {
+        self.instructions_template = Some(template.into());
+        self
+    }
+
+    /// Use environment `name` for run calls that don't name one.
+    pub fn default_env(mut self, name: impl Into<String>) -> Self {
+        self.default_env = Some(name.into());
+        self
+    }
+
+    /// Decide which wins when a custom sandbox has a bundled name.
+    pub const fn sandbox_merge(mut self, policy: MergePolicy) -> Self {
+        self.sandbox_merge = policy;
+        self
+    }
+
+    /// Map an environment name to an agent interpreter (`[interpreter_map]`).
+    pub fn interpreter(
+        mut self,
+        env_name: impl Into<String>,
+        interpreter: impl Into<String>,
+    ) -> Self {
+        self.interpreter_map
+            .insert(env_name.into(), interpreter.into());
+        self
+    }
+
+    /// Check the settings and produce the config.
+    ///
+    /// Requires at least one environment, each with an `exec` and a
+    /// `timeout_seconds` of at least 1 (and no higher than
+    /// `max_timeout_seconds`), and absolute, unique mount points.
+    pub fn build(self) -> Result<Config> {
+        anyhow::ensure!(!self.environments.is_empty(), "Config has no environments");
+
+        let mut names: Vec<&String> = self.environments.keys().collect();
+        names.sort();
+        for name in names {
+            let meta = &self.environments[name];
+            anyhow::ensure!(!meta.exec.is_empty(), "Environment '{name}' has no `exec`");
+            meta.inherit_env
+                .validate()
+                .with_context(|| format!("Environment '{name}'"))?;
+            anyhow::ensure!(
+                meta.timeout_seconds > 0,
+                "Environment '{name}': `timeout_seconds` must be at least 1"
+            );
+            if let Some(max) = meta.max_timeout_seconds {
+                anyhow::ensure!(
+                    max >= meta.timeout_seconds,
+                    "Environment '{name}': `max_timeout_seconds` ({max}) is below \
+                     `timeout_seconds` ({})",
+                    meta.timeout_seconds
+                );
+            }
+        }
+
+        if let Some(project) = &self.project {
+            project.inherit_env.validate().context("[project]")?;
+        }
+
+        let project_mount = self.project.as_ref().map(|p| ("project", &p.mount_point));
+        let mount_points = project_mount
+            .into_iter()
+            .chain(
+                self.extra_mounts
+                    .iter()
+                    .map(|m| (m.name.as_str(), &m.mount_point)),
+            )
+            .chain(self.scratch.as_ref().map(|s| ("scratch", &s.mount_point)));
+        let mut seen: Vec<(&str, &String)> = Vec::new();
+        for (name, point) in mount_points {
+            anyhow::ensure!(
+                Path::new(point).is_absolute(),
+                "Mount '{name}': mount point '{point}' must be an absolute path"
+            );
+            if let Some((dup, _)) = seen.iter().find(|(n, p)| *n == name || *p == point) {
+                anyhow::bail!(
+                    "Mount '{name}' conflicts with mount '{dup}' (names and mount points must be unique)"
+                );
+            }
+            seen.push((name, point));
+        }
+
+        Ok(Config {
+            environments: self.environments,
+            project: self.project,
+            extra_mounts: self.extra_mounts,
+            scratch: self.scratch,
+            session: self.session,
+            pool: self.pool,
+            limits: self.limits,
+            interpreter_map: self.interpreter_map,
+            audit: self.audit,
+            output: self.output,
+            instructions_template: self.instructions_template,
+            default_env: self.default_env,
+            sandbox_merge: self.sandbox_merge,
+        })
+    }
+}
+
+/// A broken executable path found by `Config::validate_paths`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub env_name: String,
+    /// Which field holds the path (`exec` or `session_exec`).
+    pub field: &'static str,
+    pub path: String,
+    pub problem: String,
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "environment '{}', `{}` {}: {}",
+            self.env_name, self.field, self.path, self.problem
+        )
+    }
+}
+
+/// Describe why `path` can't be executed, or `None` if it can.
+pub fn check_executable(path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some("does not exist".into()),
+        Err(e) => Some(format!("cannot be read ({e})")),
+        Ok(meta) if !meta.is_file() => Some("is not a file".into()),
+        Ok(meta) if meta.permissions().mode() & 0o111 == 0 => Some("is not executable".into()),
+        Ok(_) => None,
+    }
+}
+
+/// `check_wrapper`'s problem for a wrapper script that isn't there at all.
+const WRAPPER_NOT_FOUND: &str = "not found";
+
+/// Describe why a sandbox artifact's wrapper script can't be used, or `None`
+/// if it's an executable file. Nix builds these as symlinks into other store
+/// paths, so symlinks are followed wherever they lead, but must resolve.
+fn check_wrapper(path: &Path) -> Option<String> {
+    if std::fs::symlink_metadata(path).is_err() {
+        return Some(WRAPPER_NOT_FOUND.into());
+    }
+    if path.is_symlink() && !path.exists() {
+        return Some("is a dangling symlink".into());
+    }
+    check_executable(path)
+}
+
+/// Parse config JSON, naming the offending environment and field on error.
+///
+/// serde's own messages list the allowed values for enums (e.g. `backend`),
+/// so they're kept as-is and prefixed with where the error occurred.
+fn parse_config(json: &str, unknown: UnknownFields) -> Result<Config> {
+    // serde_json's messages already end with "at line L column C"
+    deserialize_checked(&mut serde_json::Deserializer::from_str(json), unknown)
+}
+
+/// Parse a TOML config file, naming the offending environment and field on
+/// error like `parse_config`.
+fn parse_config_toml(text: &str, unknown: UnknownFields) -> Result<Config> {
+    deserialize_checked(toml::Deserializer::new(text), unknown)
+}
+
+/// Deserialize `T`, naming where an error occurred and handling keys `T`
+/// doesn't have according to `unknown`.
+///
+/// `#[serde(deny_unknown_fields)]` can't be switched off at runtime, so
+/// ignored keys are collected as they're skipped and checked afterwards.
+fn deserialize_checked<'de, D, T>(de: D, unknown: UnknownFields) -> Result<T>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let mut ignored = Vec::new();
+    let mut record = |path: serde_ignored::Path| {
+        let mut segments = Vec::new();
+        path_segments(&path, &mut segments);
+        ignored.push(describe_segments(&segments));
+    };
+    let de = serde_ignored::Deserializer::new(de, &mut record);
+    let value = serde_path_to_error::deserialize(de)
+        .map_err(|e| anyhow::anyhow!("{}: {}", describe_path(e.path()), e.inner()))?;
+
+    match unknown {
+        UnknownFields::Reject if !ignored.is_empty() => anyhow::bail!(
+            "unknown key(s): {} (use --lenient-config or {LENIENT_CONFIG_VAR}=1 to ignore them)",
+            ignored.join("; ")
+        ),
+        UnknownFields::Reject => {}
+        UnknownFields::Warn => {
+            for key in &ignored {
+                warn!(key = %key, "Ignoring unknown config key");
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Flatten an ignored-key path into its map keys and sequence indices.
+fn path_segments(path: &serde_ignored::Path, out: &mut Vec<String>) {
+    use serde_ignored::Path;
+
+    match path {
+        Path::Root => {}
+        Path::Seq { parent, index } => {
+            path_segments(parent, out);
+            out.push(index.to_string());
+        }
+        Path::Map { parent, key } => {
+            path_segments(parent, out);
+            out.push(key.clone());
+        }
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => {
+            path_segments(parent, out);
+        }
+    }
+}
+
+/// Describe a deserialization path, e.g. "environment 'python', field `backend`".
+fn describe_path(path: &serde_path_to_error::Path) -> String {
+    use serde_path_to_error::Segment;
+
+    let segments: Vec<String> = path
+        .iter()
+        .map(|segment| match segment {
+            Segment::Seq { index } => index.to_string(),
+            Segment::Map { key } => key.clone(),
+            Segment::Enum { variant } => variant.clone(),
+            Segment::Unknown => "?".to_string(),
+        })
+        .collect();
+    describe_segments(&segments)
+}
+
+/// Describe a path given as its segments; see `describe_path`.
+fn describe_segments(segments: &[String]) -> String {
+    match segments {
+        [] => "top level".to_string(),
+        [envs, name] if envs == "environments" => format!("environment '{name}'"),
+        [envs, name, rest @ ..] if envs == "environments" => {
+            format!("environment '{name}', field `{}`", rest.join("."))
+        }
+        _ => format!("field `{}`", segments.join(".")),
+    }
+}
+
+/// Everything needed to rebuild the config with a fresh sandbox scan.
+#[derive(Debug, Clone)]
+pub struct SandboxSource {
+    /// Config as loaded at startup, before any sandboxes were merged in.
+    pub base: Config,
+
+    /// Directory scanned for sandbox artifacts (`NIX_SANDBOX_DIR`).
+    pub dir: PathBuf,
+
+    /// How unknown keys in each sandbox's `metadata.json` are handled.
+    pub unknown_fields: UnknownFields,
+}
+
+impl SandboxSource {
+    /// The base config with the sandboxes currently in `dir` merged in.
+    pub fn load(&self) -> Config {
+        self.load_reporting().0
+    }
+
+    /// Like `load`, also returning the sandboxes the scan skipped.
+    pub fn load_reporting(&self) -> (Config, Vec<SkippedSandbox>) {
+        let mut config = self.base.clone();
+        let skipped = config.load_sandbox_dir(&self.dir, self.unknown_fields);
+        config.apply_global_inherit_env();
+        (config, skipped)
+    }
+}
+
+/// Result of scanning a sandbox directory.
+#[derive(Debug, Default)]
+pub struct SandboxScan {
+    /// Valid sandboxes, by name.
+    pub environments: HashMap<String, EnvironmentMeta>,
+    /// Entries that looked like sandboxes but couldn't be used.
+    pub skipped: Vec<SkippedSandbox>,
+}
+
+impl SandboxScan {
+    fn skip(&mut self, path: &Path, reason: String) {
+        self.skipped.push(SkippedSandbox {
+            path: path.to_path_buf(),
+            reason,
+        });
+    }
+}
+
+/// A sandbox directory entry the scan left out, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedSandbox {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl std::fmt::Display for SkippedSandbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
     }
 }
 
@@ -229,8 +1734,24 @@ struct SandboxArtifactMeta {
     interpreter_type: String,
     #[serde(default = "default_timeout")]
     timeout_seconds: u64,
+    #[serde(default)]
+    max_timeout_seconds: Option<u64>,
     #[serde(default = "default_memory")]
     memory_mb: u64,
+    #[serde(default = "default_max_output_bytes")]
+    max_output_bytes: usize,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    preamble: Option<String>,
+    #[serde(default)]
+    output_encoding: OutputEncoding,
+    #[serde(default)]
+    network: bool,
+    #[serde(default)]
+    input_mode: InputMode,
+    #[serde(default, deserialize_with = "deserialize_interpreter_args")]
+    interpreter_args: Vec<String>,
 }
 
 /// Metadata for a single execution environment.
@@ -252,6 +1773,11 @@ pub struct EnvironmentMeta {
     #[serde(default = "default_timeout")]
     pub timeout_seconds: u64,
 
+    /// Ceiling for per-call timeout overrides, in seconds.
+    /// If absent, calls may only shorten `timeout_seconds`.
+    #[serde(default)]
+    pub max_timeout_seconds: Option<u64>,
+
     /// Memory limit in megabytes.
     #[serde(default = "default_memory")]
     pub memory_mb: u64,
@@ -261,27 +1787,231 @@ pub struct EnvironmentMeta {
     /// If None, falls back to name-based matching for bundled presets.
     #[serde(default)]
     pub interpreter_type: Option<String>,
+
+    /// Maximum output size (in bytes) returned to the MCP client.
+    /// Larger output is truncated with a marker reporting this limit.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+
+    /// Vsock address of the session agent (microvm backend only).
+    #[serde(default)]
+    pub vsock: Option<VsockAddr>,
+
+    /// TCP address of the session agent (remote backend only).
+    #[serde(default)]
+    pub tcp: Option<TcpAddr>,
+
+    /// Host environment variables to pass into this environment, in
+    /// addition to the global `[project] inherit_env` list.
+    #[serde(default)]
+    pub inherit_env: InheritEnv,
+
+    /// Alternative names clients may use for this environment.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+
+    /// Code run once in each new session before the first call's code
+    /// (e.g. common imports), so what it defines stays available.
+    #[serde(default)]
+    pub preamble: Option<String>,
+
+    /// Command the daemon runs once, before the first run in this
+    /// environment (e.g. to warm a cache in the scratch directory). It runs
+    /// directly on the host as the daemon's user, outside any sandbox.
+    #[serde(default)]
+    pub setup_exec: Option<String>,
+
+    /// How long `setup_exec` may run, in seconds (at least 1).
+    #[serde(
+        default = "default_setup_timeout",
+        deserialize_with = "deserialize_positive"
+    )]
+    pub setup_timeout_seconds: u64,
+
+    /// Encoding programs' stdout and stderr are decoded from: by the daemon
+    /// for ephemeral runs, by the agent for sessions' subprocess interpreters.
+    #[serde(default)]
+    pub output_encoding: OutputEncoding,
+
+    /// Whether code here may use the network. The wrapper enforces it; the
+    /// daemon passes it on as `SANDBOX_NETWORK` (`1` or `0`).
+    #[serde(default)]
+    pub network: bool,
+
+    /// Whether ephemeral runs pass code on stdin (the default) or argv.
+    /// Session agents always take code over their protocol.
+    #[serde(default)]
+    pub input_mode: InputMode,
+
+    /// Extra interpreter flags (e.g. `-u`) for ephemeral runs, which the
+    /// wrapper applies. The daemon passes them on as
+    /// `SANDBOX_INTERPRETER_ARGS`; none may contain a newline.
+    #[serde(default, deserialize_with = "deserialize_interpreter_args")]
+    pub interpreter_args: Vec<String>,
 }
 
-/// Available isolation backends.
+impl EnvironmentMeta {
+    /// Timeout for one call: the per-call override if given (clamped to
+    /// `1..=max_timeout_seconds`), otherwise `timeout_seconds`.
+    pub fn effective_timeout(&self, requested: Option<u64>) -> Duration {
+        let max = self.max_timeout_seconds.unwrap_or(self.timeout_seconds);
+        let secs = requested.map_or(self.timeout_seconds, |t| t.clamp(1, max.max(1)));
+        Duration::from_secs(secs)
+    }
+
+    /// Whether calls may open sessions here: it has a session wrapper or
+    /// an agent address.
+    pub const fn supports_sessions(&self) -> bool {
+        self.session_exec.is_some() || self.vsock.is_some() || self.tcp.is_some()
+    }
+
+    /// `SANDBOX_NETWORK` for this environment's wrapper.
+    pub fn network_var(&self) -> (String, String) {
+        let value = if self.network { "1" } else { "0" };
+        (SANDBOX_NETWORK_VAR.to_string(), value.to_string())
+    }
+
+    /// `SANDBOX_INTERPRETER_ARGS` for this environment's wrapper, if it
+    /// has any `interpreter_args`.
+    pub fn interpreter_args_var(&self) -> Option<(String, String)> {
+        (!self.interpreter_args.is_empty()).then(|| {
+            (
+                INTERPRETER_ARGS_VAR.to_string(),
+                self.interpreter_args.join("\n"),
+            )
+        })
+    }
+
+    /// Host values of the `inherit_env` vars to set on the spawned wrapper.
+    ///
+    /// Unset vars are skipped. `SANDBOX_INHERIT_ENV` lists the names that
+    /// were found so the jail wrapper can forward them into the sandbox.
+    pub fn inherited_env(&self) -> Vec<(String, String)> {
+        let mut vars: Vec<(String, String)> = self
+            .inherit_env
+            .vars
+            .iter()
+            .filter_map(|name| std::env::var(name).ok().map(|v| (name.clone(), v)))
+            .collect();
+        if !vars.is_empty() {
+            let names = vars
+                .iter()
+                .map(|(k, _)| k.as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+            vars.push(("SANDBOX_INHERIT_ENV".to_string(), names));
+        }
+        vars
+    }
+}
+
+/// Address of an agent listening on `AF_VSOCK` inside a microVM.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct VsockAddr {
+    /// Context ID of the guest VM.
+    pub cid: u32,
+
+    /// Port the agent listens on.
+    #[serde(default = "default_vsock_port")]
+    pub port: u32,
+}
+
+/// Address of an agent listening on TCP, usually on another host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TcpAddr {
+    /// Host name or IP address of the agent.
+    pub host: String,
+
+    /// Port the agent listens on.
+    pub port: u16,
+
+    /// Connect over TLS, verifying the agent's certificate.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// PEM file of CA certificates to trust, instead of the web roots.
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Name to verify the agent's certificate against (defaults to `host`).
+    #[serde(default)]
+    pub server_name: Option<String>,
+
+    /// File holding the token the agent was started with
+    /// (`SANDBOX_AGENT_TOKEN`), sent before anything else on the connection.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+}
+
+impl Default for EnvironmentMeta {
+    fn default() -> Self {
+        Self {
+            backend: BackendType::Jail,
+            exec: String::new(),
+            session_exec: None,
+            timeout_seconds: default_timeout(),
+            max_timeout_seconds: None,
+            memory_mb: default_memory(),
+            interpreter_type: None,
+            max_output_bytes: default_max_output_bytes(),
+            vsock: None,
+            tcp: None,
+            inherit_env: InheritEnv::default(),
+            aliases: Vec::new(),
+            preamble: None,
+            setup_exec: None,
+            setup_timeout_seconds: default_setup_timeout(),
+            output_encoding: OutputEncoding::default(),
+            network: false,
+            input_mode: InputMode::default(),
+            interpreter_args: Vec::new(),
+        }
+    }
+}
+
+/// Available isolation backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BackendType {
     /// jail.nix backend (bubblewrap, namespace isolation).
     Jail,
     /// microvm.nix backend (hardware VM isolation).
-    #[allow(dead_code)]
     Microvm,
+    /// Session agent on another host, reached over TCP.
+    Remote,
+}
+
+impl BackendType {
+    /// Name as written in config.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Jail => "jail",
+            Self::Microvm => "microvm",
+            Self::Remote => "remote",
+        }
+    }
 }
 
 const fn default_timeout() -> u64 {
     30
 }
 
+const fn default_setup_timeout() -> u64 {
+    600
+}
+
 const fn default_memory() -> u64 {
     512
 }
 
+const fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+const fn default_vsock_port() -> u32 {
+    5000
+}
+
 const fn default_idle_timeout() -> u64 {
     300
 }
@@ -290,6 +2020,22 @@ const fn default_max_lifetime() -> u64 {
     3600
 }
 
+pub const fn default_max_sessions() -> usize {
+    16
+}
+
+const fn default_agent_ready_timeout() -> u64 {
+    30
+}
+
+const fn default_reaper_interval() -> u64 {
+    60
+}
+
+const fn default_rate_limit_window() -> u64 {
+    60
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -329,251 +2075,1690 @@ mod tests {
         // interpreter_type defaults to None when not in JSON
         assert!(python.interpreter_type.is_none());
 
+        // max_output_bytes defaults to 1MB
+        assert_eq!(python.max_output_bytes, 1024 * 1024);
+
         // No project config
         assert!(config.project.is_none());
+        assert!(config.pool.is_none());
     }
 
     #[test]
-    fn parse_metadata_with_interpreter_type() {
-        let json = r#"{
-            "environments": {
-                "data-science": {
-                    "backend": "jail",
-                    "exec": "/nix/store/xxx/bin/run",
-                    "interpreter_type": "python"
-                }
-            }
-        }"#;
-
-        let config = Config::from_json(json).unwrap();
-        let ds = &config.environments["data-science"];
-        assert_eq!(ds.interpreter_type.as_deref(), Some("python"));
-    }
-
-    #[test]
-    fn scan_empty_dir() {
+    fn parse_config_file() {
         let dir = tempfile::tempdir().unwrap();
-        let envs = Config::scan_sandbox_dir(dir.path());
-        assert!(envs.is_empty());
-    }
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+[environments.python]
+backend = "jail"
+exec = "/opt/sandboxes/python/bin/run"
+session_exec = "/opt/sandboxes/python/bin/session-run"
+timeout_seconds = 60
+interpreter_type = "python"
+
+[environments.shell]
+backend = "jail"
+exec = "/opt/sandboxes/shell/bin/run"
+
+[project]
+path = "/srv/app"
+mount_point = "/app"
+inherit_env = { vars = ["RUST_LOG"] }
+
+[session]
+idle_timeout_seconds = 120
+max_sessions = 4
+session_deny = ["shell"]
+
+[limits]
+max_code_bytes = 65536
+
+[interpreter_map]
+shell = "bash"
+"#,
+        )
+        .unwrap();
 
-    #[test]
-    fn scan_nonexistent_dir() {
-        let envs = Config::scan_sandbox_dir(std::path::Path::new("/nonexistent/path"));
-        assert!(envs.is_empty());
+        let config = Config::from_file(&path, UnknownFields::Reject).unwrap();
+        assert_eq!(config.environments.len(), 2);
+        let python = &config.environments["python"];
+        assert_eq!(python.timeout_seconds, 60);
+        assert_eq!(
+            python.session_exec.as_deref(),
+            Some("/opt/sandboxes/python/bin/session-run")
+        );
+        assert_eq!(config.environments["shell"].timeout_seconds, 30);
+
+        let project = config.project.as_ref().unwrap();
+        assert_eq!(project.path, PathBuf::from("/srv/app"));
+        assert_eq!(project.mount_point, "/app");
+        assert_eq!(project.inherit_env.vars, vec!["RUST_LOG"]);
+
+        let session = config.session.as_ref().unwrap();
+        assert_eq!(session.idle_timeout_seconds, 120);
+        assert_eq!(session.max_lifetime_seconds, 3600);
+        assert_eq!(session.max_sessions, 4);
+        assert_eq!(session.session_deny, vec!["shell"]);
+        assert_eq!(config.max_code_bytes(), 65536);
+        assert_eq!(config.interpreter_map["shell"], "bash");
     }
 
     #[test]
-    fn scan_valid_sandbox() {
+    fn parse_config_file_errors() {
         let dir = tempfile::tempdir().unwrap();
-        let sandbox = dir.path().join("data-science");
-        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
-
-        // Write metadata.json
+        let path = dir.path().join("config.toml");
         std::fs::write(
-            sandbox.join("metadata.json"),
-            r#"{"name": "data-science", "interpreter_type": "python", "timeout_seconds": 60, "memory_mb": 1024}"#,
-        ).unwrap();
+            &path,
+            "[environments.python]\nbackend = \"docker\"\nexec = \"/bin/run\"\n",
+        )
+        .unwrap();
 
-        // Create bin/run (just needs to exist)
-        std::fs::write(sandbox.join("bin/run"), "#!/bin/sh\n").unwrap();
+        let err = format!(
+            "{:#}",
+            Config::from_file(&path, UnknownFields::Reject).unwrap_err()
+        );
+        assert!(err.starts_with("Failed to parse config file"), "{err}");
+        assert!(
+            err.contains("environment 'python', field `backend`"),
+            "{err}"
+        );
 
-        let envs = Config::scan_sandbox_dir(dir.path());
-        assert_eq!(envs.len(), 1);
-        assert!(envs.contains_key("data-science"));
+        let err =
+            Config::from_file(&dir.path().join("missing.toml"), UnknownFields::Reject).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to read config file"));
+    }
 
-        let meta = &envs["data-science"];
-        assert_eq!(meta.interpreter_type.as_deref(), Some("python"));
-        assert_eq!(meta.timeout_seconds, 60);
-        assert_eq!(meta.memory_mb, 1024);
-        assert!(meta.exec.ends_with("bin/run"));
-        assert!(meta.session_exec.is_none());
+    #[test]
+    fn strict_parsing_rejects_unknown_fields() {
+        let json = r#"{
+            "environments": {
+                "python": {"backend": "jail", "exec": "/bin/run", "memory_md": 1024}
+            },
+            "project": {"path": "/code", "mount_pont": "/src"}
+        }"#;
+
+        let err = format!("{:#}", Config::from_json(json).unwrap_err());
+        assert!(
+            err.contains("environment 'python', field `memory_md`"),
+            "{err}"
+        );
+        assert!(err.contains("field `project.mount_pont`"), "{err}");
+        assert!(err.contains("--lenient-config"), "{err}");
     }
 
     #[test]
-    fn scan_sandbox_with_session() {
-        let dir = tempfile::tempdir().unwrap();
-        let sandbox = dir.path().join("my-env");
-        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+    fn lenient_parsing_ignores_unknown_fields() {
+        let json = r#"{
+            "environments": {
+                "python": {"backend": "jail", "exec": "/bin/run", "memory_md": 1024}
+            },
+            "newer_section": {"enabled": true}
+        }"#;
 
+        let config = parse_config(json, UnknownFields::Warn).unwrap();
+        assert_eq!(config.environments["python"].memory_mb, default_memory());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
         std::fs::write(
-            sandbox.join("metadata.json"),
-            r#"{"name": "my-env", "interpreter_type": "bash"}"#,
+            &path,
+            "[environments.python]\nbackend = \"jail\"\nexec = \"/bin/run\"\ntimeout_secs = 5\n",
         )
         .unwrap();
-        std::fs::write(sandbox.join("bin/run"), "#!/bin/sh\n").unwrap();
-        std::fs::write(sandbox.join("bin/session-run"), "#!/bin/sh\n").unwrap();
+        assert!(Config::from_file(&path, UnknownFields::Reject).is_err());
+        assert!(Config::from_file(&path, UnknownFields::Warn).is_ok());
+    }
 
-        let envs = Config::scan_sandbox_dir(dir.path());
-        let meta = &envs["my-env"];
-        assert!(meta.session_exec.is_some());
+    #[test]
+    fn parse_output_config() {
+        let config = Config::from_json(r#"{ "environments": {} }"#).unwrap();
+        assert_eq!(config.stderr_delimiter(), DEFAULT_STDERR_DELIMITER);
+
+        let json = r#"{ "environments": {}, "output": { "stderr_delimiter": "" } }"#;
+        assert_eq!(Config::from_json(json).unwrap().stderr_delimiter(), "");
+
+        let json = r#"{ "environments": {}, "output": { "stderr_delimiter": "\n[err]\n" } }"#;
+        assert_eq!(
+            Config::from_json(json).unwrap().stderr_delimiter(),
+            "\n[err]\n"
+        );
+        assert_eq!(config.truncate_strategy(), TruncateStrategy::Head);
+
+        let json = r#"{ "environments": {}, "output": { "truncate": "tail" } }"#;
+        assert_eq!(
+            Config::from_json(json).unwrap().truncate_strategy(),
+            TruncateStrategy::Tail
+        );
+        let json = r#"{ "environments": {}, "output": { "truncate": "both" } }"#;
+        assert!(Config::from_json(json).is_err());
     }
 
     #[test]
-    fn scan_skips_missing_bin_run() {
+    fn parse_limits_config() {
+        let json = r#"{
+            "environments": {},
+            "limits": {
+                "max_concurrent_executions": 4,
+                "when_busy": "queue",
+                "max_session_memory_mb": 2048,
+                "max_code_bytes": 65536,
+                "max_sandbox_depth": 1,
+                "kill_grace_seconds": 5
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(config.max_code_bytes(), 65536);
+        assert_eq!(config.kill_grace(), Duration::from_secs(5));
+        assert_eq!(config.sandbox_depth().max, 1);
+        let limits = config.limits.unwrap();
+        assert_eq!(limits.max_concurrent_executions, Some(4));
+        assert_eq!(limits.when_busy, BusyPolicy::Queue);
+        assert_eq!(limits.max_session_memory_mb, Some(2048));
+
+        let json = r#"{ "environments": {}, "limits": {} }"#;
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(config.max_code_bytes(), DEFAULT_MAX_CODE_BYTES);
+        assert_eq!(config.kill_grace(), DEFAULT_KILL_GRACE);
+        let limits = config.limits.unwrap();
+        assert_eq!(limits.when_busy, BusyPolicy::Reject);
+        assert!(limits.max_concurrent_executions.is_none());
+    }
+
+    #[test]
+    fn parse_pool_config() {
+        let json = r#"{
+            "environments": {},
+            "pool": { "size": 2 }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(config.pool_size(), 2);
+    }
+
+    #[test]
+    fn validate_paths_reports_broken_paths() {
+        use std::os::unix::fs::PermissionsExt;
+
         let dir = tempfile::tempdir().unwrap();
-        let sandbox = dir.path().join("broken");
-        std::fs::create_dir_all(&sandbox).unwrap();
+        let good = dir.path().join("run");
+        let not_exec = dir.path().join("session-run");
+        std::fs::write(&good, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&good, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(&not_exec, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&not_exec, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let env = |exec: &Path, session_exec: Option<&Path>| EnvironmentMeta {
+            exec: exec.display().to_string(),
+            session_exec: session_exec.map(|p| p.display().to_string()),
+            ..Default::default()
+        };
+        let config = Config {
+            environments: HashMap::from([
+                ("a-ok".to_string(), env(&good, Some(&good))),
+                (
+                    "b-broken".to_string(),
+                    env(&dir.path().join("missing"), Some(&not_exec)),
+                ),
+                ("c-dir".to_string(), env(dir.path(), None)),
+            ]),
+            project: None,
+            extra_mounts: Vec::new(),
+            scratch: None,
+            session: None,
+            pool: None,
+            limits: None,
+            interpreter_map: HashMap::new(),
+            audit: None,
+            output: None,
+            instructions_template: None,
+            default_env: None,
+            sandbox_merge: MergePolicy::default(),
+        };
 
-        std::fs::write(
-            sandbox.join("metadata.json"),
-            r#"{"name": "broken", "interpreter_type": "python"}"#,
-        )
-        .unwrap();
-        // No bin/run — should be skipped
+        let issues: Vec<_> = config
+            .validate_paths()
+            .into_iter()
+            .map(|i| (i.env_name, i.field, i.problem))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                ("b-broken".to_string(), "exec", "does not exist".to_string()),
+                (
+                    "b-broken".to_string(),
+                    "session_exec",
+                    "is not executable".to_string()
+                ),
+                ("c-dir".to_string(), "exec", "is not a file".to_string()),
+            ]
+        );
+    }
 
-        let envs = Config::scan_sandbox_dir(dir.path());
-        assert!(envs.is_empty());
+    #[test]
+    fn validation_issue_display() {
+        let issue = ValidationIssue {
+            env_name: "python".to_string(),
+            field: "exec",
+            path: "/nix/store/gone/bin/run".to_string(),
+            problem: "does not exist".to_string(),
+        };
+        assert_eq!(
+            issue.to_string(),
+            "environment 'python', `exec` /nix/store/gone/bin/run: does not exist"
+        );
     }
 
-    // Validate custom sandboxes override bundled presets on name collision.
-    // Create a Config with a "python" environment, merge in another "python"
-    // from scanning, and assert the merged version wins.
     #[test]
-    fn merge_environments_override() {
+    fn parse_metadata_with_interpreter_type() {
         let json = r#"{
             "environments": {
-                "python": {
+                "data-science": {
                     "backend": "jail",
-                    "exec": "/nix/store/xxx-python-sandbox/bin/run",
-                    "timeout_seconds": 30,
-                    "memory_mb": 512
+                    "exec": "/nix/store/xxx/bin/run",
+                    "interpreter_type": "python"
                 }
             }
         }"#;
 
-        // create a Config with a bundled "python" environment,
-        let mut config = Config::from_json(json).unwrap();
-
-        // then call merge_environments() with a HashMap containing a different "python" entry (e.g., different exec path)
-        let env_meta = EnvironmentMeta {
-            backend: BackendType::Jail,
-            exec: String::from("/custom/bin/run"),
-            interpreter_type: Some("python".to_string()),
-            session_exec: Some("/some/path".to_string()),
-            timeout_seconds: 30,
-            memory_mb: 512,
-        };
-        let envs = HashMap::from([(String::from("python"), env_meta)]);
-
-        // and assert the merged version wins.
-        config.merge_environments(envs);
-        assert_eq!(config.environments["python"].exec, "/custom/bin/run");
+        let config = Config::from_json(json).unwrap();
+        let ds = &config.environments["data-science"];
+        assert_eq!(ds.interpreter_type.as_deref(), Some("python"));
     }
 
     #[test]
-    fn merge_environments_additive() {
-        // merge in "ruby" alongside "python" override, assert config now has both
+    fn parse_metadata_with_max_output_bytes() {
         let json = r#"{
             "environments": {
-                "python": {
+                "data-science": {
                     "backend": "jail",
-                    "exec": "/nix/store/xxx-python-sandbox/bin/run",
-                    "timeout_seconds": 30,
-                    "memory_mb": 512
+                    "exec": "/nix/store/xxx/bin/run",
+                    "max_output_bytes": 10485760
                 }
             }
         }"#;
 
-        let mut config = Config::from_json(json).unwrap();
-
-        let env_meta_python = EnvironmentMeta {
-            backend: BackendType::Jail,
-            exec: String::from("/custom/bin/run"),
-            interpreter_type: Some("python".to_string()),
-            session_exec: Some("/some/path".to_string()),
-            timeout_seconds: 30,
-            memory_mb: 512,
-        };
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(
+            config.environments["data-science"].max_output_bytes,
+            10 * 1024 * 1024
+        );
+    }
 
-        let env_meta_ruby = EnvironmentMeta {
-            backend: BackendType::Jail,
-            exec: String::from("/custom-ruby/bin/run"),
-            interpreter_type: Some("bash".to_string()),
-            session_exec: Some("/some/other/path".to_string()),
-            timeout_seconds: 30,
-            memory_mb: 512,
-        };
-        let envs = HashMap::from([
-            (String::from("python"), env_meta_python),
-            (String::from("ruby"), env_meta_ruby),
-        ]);
+    #[test]
+    fn parse_metadata_with_vsock() {
+        let json = r#"{
+            "environments": {
+                "vm-python": {
+                    "backend": "microvm",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "vsock": { "cid": 3 }
+                }
+            }
+        }"#;
 
-        config.merge_environments(envs);
-        assert_eq!(config.environments["python"].exec, "/custom/bin/run");
-        assert_eq!(config.environments["ruby"].exec, "/custom-ruby/bin/run");
+        let config = Config::from_json(json).unwrap();
+        let env = &config.environments["vm-python"];
+        assert_eq!(env.backend, BackendType::Microvm);
+        assert_eq!(env.vsock, Some(VsockAddr { cid: 3, port: 5000 }));
     }
 
     #[test]
-    fn resolved_project_dir_from_config() {
+    fn parse_metadata_with_tcp() {
         let json = r#"{
-            "environments": {},
-            "project": {
-                "path": "/home/user/myproject",
-                "mount_point": "/project"
+            "environments": {
+                "remote-python": {
+                    "backend": "remote",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "tcp": { "host": "agents.internal", "port": 7000, "tls": true }
+                }
             }
         }"#;
+
         let config = Config::from_json(json).unwrap();
-        // Falls back to config when PROJECT_DIR is not set
+        let env = &config.environments["remote-python"];
+        assert_eq!(env.backend, BackendType::Remote);
         assert_eq!(
-            config.resolved_project_dir(),
-            Some(PathBuf::from("/home/user/myproject"))
+            env.tcp,
+            Some(TcpAddr {
+                host: "agents.internal".to_string(),
+                port: 7000,
+                tls: true,
+                ..TcpAddr::default()
+            })
         );
     }
 
+    /// Write an executable wrapper script, as a sandbox artifact has.
+    fn write_script(path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::write(path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
     #[test]
-    fn resolved_project_dir_none_without_config() {
-        let json = r#"{"environments": {}}"#;
-        let config = Config::from_json(json).unwrap();
-        assert!(config.resolved_project_dir().is_none());
+    fn scan_sandbox_with_max_output_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("strict");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "strict", "interpreter_type": "bash", "max_output_bytes": 4096}"#,
+        )
+        .unwrap();
+        write_script(&sandbox.join("bin/run"));
+
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        assert_eq!(envs["strict"].max_output_bytes, 4096);
     }
 
     #[test]
-    fn project_mount_from_config() {
+    fn parse_metadata_with_preamble() {
         let json = r#"{
-            "environments": {},
-            "project": {
-                "path": "/tmp",
-                "mount_point": "/custom-mount"
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "preamble": "import numpy as np"
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
             }
         }"#;
+
         let config = Config::from_json(json).unwrap();
-        // Falls back to config mount_point when PROJECT_MOUNT is not set
-        assert_eq!(config.project_mount(), "/custom-mount");
+        assert_eq!(
+            config.environments["python"].preamble.as_deref(),
+            Some("import numpy as np")
+        );
+        assert!(config.environments["shell"].preamble.is_none());
     }
 
     #[test]
-    fn project_mount_default() {
-        let json = r#"{"environments": {}}"#;
+    fn parse_metadata_with_output_encoding() {
+        let json = r#"{
+            "environments": {
+                "legacy": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "output_encoding": "latin1"
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            }
+        }"#;
+
         let config = Config::from_json(json).unwrap();
-        assert_eq!(config.project_mount(), "/project");
+        assert_eq!(
+            config.environments["legacy"].output_encoding.name(),
+            "windows-1252"
+        );
+        assert!(config.environments["shell"].output_encoding.is_utf8());
+
+        let json =
+            r#"{ "environments": { "x": { "exec": "/run", "output_encoding": "klingon" } } }"#;
+        let err = Config::from_json(json).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown output_encoding 'klingon'"));
     }
 
     #[test]
-    fn parse_metadata_with_project() {
+    fn parse_metadata_with_input_mode() {
         let json = r#"{
             "environments": {
+                "legacy": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "input_mode": "argv"
+                },
                 "shell": {
                     "backend": "jail",
-                    "exec": "/nix/store/yyy-shell-sandbox/bin/run"
-                }
-            },
-            "project": {
-                "path": "/home/user/myproject",
-                "mount_point": "/project",
-                "use_flake": true,
-                "inherit_env": {
-                    "vars": ["DATABASE_URL", "RUST_LOG"]
+                    "exec": "/nix/store/yyy/bin/run"
                 }
             }
         }"#;
 
         let config = Config::from_json(json).unwrap();
+        assert_eq!(config.environments["legacy"].input_mode, InputMode::Argv);
+        assert_eq!(config.environments["shell"].input_mode, InputMode::Stdin);
+    }
 
-        let project = config.project.as_ref().expect("project should be set");
-        assert_eq!(project.path, PathBuf::from("/home/user/myproject"));
-        assert_eq!(project.mount_point, "/project");
+    #[test]
+    fn parse_metadata_with_interpreter_args() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "interpreter_args": ["-u", "-O"]
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        let python = &config.environments["python"];
+        assert_eq!(python.interpreter_args, ["-u", "-O"]);
+        assert_eq!(
+            python.interpreter_args_var(),
+            Some((INTERPRETER_ARGS_VAR.to_string(), "-u\n-O".to_string()))
+        );
+        assert_eq!(config.environments["shell"].interpreter_args_var(), None);
+
+        let json =
+            r#"{ "environments": { "x": { "exec": "/run", "interpreter_args": ["-c\nx"] } } }"#;
+        let err = Config::from_json(json).unwrap_err();
+        assert!(format!("{err:#}").contains("newline"), "{err}");
+    }
+
+    #[test]
+    fn parse_metadata_with_network() {
+        let json = r#"{
+            "environments": {
+                "fetcher": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "network": true
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        assert!(config.environments["fetcher"].network);
+        assert!(!config.environments["shell"].network);
+
+        let json = r#"{ "environments": { "x": { "exec": "/run", "network": "yes" } } }"#;
+        assert!(Config::from_json(json).is_err());
+    }
+
+    #[test]
+    fn latin1_decodes_bytes_utf8_would_replace() {
+        let bytes = b"caf\xe9 \xa9 2024";
+        assert_eq!(
+            OutputEncoding::for_label("latin1").unwrap().decode(bytes),
+            "caf\u{e9} \u{a9} 2024"
+        );
+        assert_eq!(
+            OutputEncoding::default().decode(bytes),
+            "caf\u{FFFD} \u{FFFD} 2024"
+        );
+    }
+
+    #[test]
+    fn multibyte_encodings_decode_across_chunks() {
+        let sjis = OutputEncoding::for_label("shift_jis").unwrap();
+        assert_eq!(sjis.decode(b"\x82\xa0\x82\xa2"), "\u{3042}\u{3044}");
+
+        // A character split between two reads still comes out whole
+        let mut decoder = sjis.new_decoder();
+        let mut text = String::with_capacity(16);
+        for chunk in [&b"\x82\xa0\x82"[..], b"\xa2"] {
+            let _ = decoder.decode_to_string(chunk, &mut text, false);
+        }
+        assert_eq!(text, "\u{3042}\u{3044}");
+    }
+
+    #[test]
+    fn parse_metadata_with_aliases() {
+        let json = r#"{
+            "environments": {
+                "python-data-science": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "aliases": ["py", "ds"]
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(
+            config.environments["python-data-science"].aliases,
+            ["py", "ds"]
+        );
+        assert!(config.environments["shell"].aliases.is_empty());
+
+        let aliases = config.alias_map();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases["py"], "python-data-science");
+        assert_eq!(aliases["ds"], "python-data-science");
+    }
+
+    #[test]
+    fn alias_map_prefers_real_names() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "aliases": ["shell", "py"]
+                },
+                "python-alt": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run",
+                    "aliases": ["py", "alt"]
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/zzz/bin/run"
+                }
+            }
+        }"#;
+
+        let aliases = Config::from_json(json).unwrap().alias_map();
+        // "shell" is a real environment, so it is never an alias
+        assert!(!aliases.contains_key("shell"));
+        // Shared aliases go to the first environment by name
+        assert_eq!(aliases["py"], "python");
+        assert_eq!(aliases["alt"], "python-alt");
+    }
+
+    #[test]
+    fn scan_sandbox_with_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("data-science");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "data-science", "interpreter_type": "python", "aliases": ["ds"],
+                "preamble": "import pandas as pd"}"#,
+        )
+        .unwrap();
+        write_script(&sandbox.join("bin/run"));
+
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        assert_eq!(envs["data-science"].aliases, ["ds"]);
+        assert_eq!(
+            envs["data-science"].preamble.as_deref(),
+            Some("import pandas as pd")
+        );
+    }
+
+    #[test]
+    fn scan_empty_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        assert!(envs.is_empty());
+    }
+
+    #[test]
+    fn scan_nonexistent_dir() {
+        let envs = Config::scan_sandbox_dir(
+            std::path::Path::new("/nonexistent/path"),
+            UnknownFields::Reject,
+        )
+        .environments;
+        assert!(envs.is_empty());
+    }
+
+    #[test]
+    fn scan_valid_sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("data-science");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+
+        // Write metadata.json
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "data-science", "interpreter_type": "python", "timeout_seconds": 60, "memory_mb": 1024}"#,
+        ).unwrap();
+
+        // Create bin/run (just needs to exist)
+        write_script(&sandbox.join("bin/run"));
+
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        assert_eq!(envs.len(), 1);
+        assert!(envs.contains_key("data-science"));
+
+        let meta = &envs["data-science"];
+        assert_eq!(meta.interpreter_type.as_deref(), Some("python"));
+        assert_eq!(meta.timeout_seconds, 60);
+        assert_eq!(meta.memory_mb, 1024);
+        assert!(meta.exec.ends_with("bin/run"));
+        assert!(meta.session_exec.is_none());
+    }
+
+    #[test]
+    fn scan_sandbox_with_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("my-env");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "my-env", "interpreter_type": "bash"}"#,
+        )
+        .unwrap();
+        write_script(&sandbox.join("bin/run"));
+        write_script(&sandbox.join("bin/session-run"));
+
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        let meta = &envs["my-env"];
+        assert!(meta.session_exec.is_some());
+    }
+
+    #[test]
+    fn scan_skips_missing_bin_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("broken");
+        std::fs::create_dir_all(&sandbox).unwrap();
+
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "broken", "interpreter_type": "python"}"#,
+        )
+        .unwrap();
+        // No bin/run — should be skipped
+
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject).environments;
+        assert!(envs.is_empty());
+    }
+
+    #[test]
+    fn scan_skips_dangling_wrapper_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["dangling-run", "dangling-session"] {
+            let sandbox = dir.path().join(name);
+            std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+            std::fs::write(
+                sandbox.join("metadata.json"),
+                format!(r#"{{"name": "{name}", "interpreter_type": "bash"}}"#),
+            )
+            .unwrap();
+        }
+        let gone = dir.path().join("gone");
+        std::os::unix::fs::symlink(&gone, dir.path().join("dangling-run/bin/run")).unwrap();
+        write_script(&dir.path().join("dangling-session/bin/run"));
+        std::os::unix::fs::symlink(&gone, dir.path().join("dangling-session/bin/session-run"))
+            .unwrap();
+        // A symlinked sandbox directory that leads nowhere
+        std::os::unix::fs::symlink(&gone, dir.path().join("dangling-dir")).unwrap();
+
+        let scan = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject);
+        assert!(scan.environments.is_empty());
+        let mut reasons: Vec<&str> = scan.skipped.iter().map(|s| s.reason.as_str()).collect();
+        reasons.sort_unstable();
+        assert_eq!(reasons.len(), 3);
+        assert_eq!(reasons[0], "bin/run is a dangling symlink");
+        assert_eq!(reasons[1], "bin/session-run is a dangling symlink");
+        assert!(
+            reasons[2].starts_with("cannot resolve symlink"),
+            "{reasons:?}"
+        );
+    }
+
+    #[test]
+    fn scan_accepts_symlinked_wrappers() {
+        let dir = tempfile::tempdir().unwrap();
+        // Wrappers live in another store path, as Nix builds them
+        let store = dir.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        write_script(&store.join("run"));
+
+        let sandboxes = dir.path().join("sandboxes");
+        let sandbox = sandboxes.join("linked");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "linked", "interpreter_type": "bash"}"#,
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(store.join("run"), sandbox.join("bin/run")).unwrap();
+        std::os::unix::fs::symlink(store.join("run"), sandbox.join("bin/session-run")).unwrap();
+
+        let envs = Config::scan_sandbox_dir(&sandboxes, UnknownFields::Reject).environments;
+        assert!(envs["linked"].exec.ends_with("linked/bin/run"));
+        assert!(envs["linked"].session_exec.is_some());
+    }
+
+    #[test]
+    fn scan_skips_non_executable_bin_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("plain");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "plain", "interpreter_type": "bash"}"#,
+        )
+        .unwrap();
+        std::fs::write(sandbox.join("bin/run"), "#!/bin/sh\n").unwrap();
+
+        let scan = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject);
+        assert!(scan.environments.is_empty());
+        assert_eq!(scan.skipped[0].reason, "bin/run is not executable");
+    }
+
+    #[test]
+    fn scan_unknown_metadata_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("typo");
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            r#"{"name": "typo", "interpreter_type": "python", "memory_md": 1024}"#,
+        )
+        .unwrap();
+        write_script(&sandbox.join("bin/run"));
+
+        assert!(Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject)
+            .environments
+            .is_empty());
+        let envs = Config::scan_sandbox_dir(dir.path(), UnknownFields::Warn).environments;
+        assert_eq!(envs["typo"].memory_mb, default_memory());
+    }
+
+    #[test]
+    fn scan_reports_skipped_sandboxes() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, metadata: Option<&str>, run: bool| {
+            let sandbox = dir.path().join(name);
+            std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+            if let Some(metadata) = metadata {
+                std::fs::write(sandbox.join("metadata.json"), metadata).unwrap();
+            }
+            if run {
+                write_script(&sandbox.join("bin/run"));
+            }
+        };
+        write(
+            "good",
+            Some(r#"{"name": "good", "interpreter_type": "bash"}"#),
+            true,
+        );
+        write("no-metadata", None, true);
+        write("bad-json", Some("{"), true);
+        write(
+            "no-run",
+            Some(r#"{"name": "no-run", "interpreter_type": "bash"}"#),
+            false,
+        );
+        // Plain files aren't sandboxes, so aren't reported either
+        std::fs::write(dir.path().join("README"), "").unwrap();
+
+        let scan = Config::scan_sandbox_dir(dir.path(), UnknownFields::Reject);
+        assert_eq!(scan.environments.keys().collect::<Vec<_>>(), ["good"]);
+
+        let mut skipped: Vec<(String, &str)> = scan
+            .skipped
+            .iter()
+            .map(|s| {
+                let name = s.path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, s.reason.split(':').next().unwrap())
+            })
+            .collect();
+        skipped.sort();
+        assert_eq!(
+            skipped,
+            [
+                ("bad-json".to_string(), "invalid metadata.json"),
+                ("no-metadata".to_string(), "cannot read metadata.json"),
+                ("no-run".to_string(), "bin/run not found"),
+            ]
+        );
+    }
+
+    // Validate custom sandboxes override bundled presets on name collision.
+    // Create a Config with a "python" environment, merge in another "python"
+    // from scanning, and assert the merged version wins.
+    #[test]
+    fn merge_environments_override() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx-python-sandbox/bin/run",
+                    "timeout_seconds": 30,
+                    "memory_mb": 512
+                }
+            }
+        }"#;
+
+        // create a Config with a bundled "python" environment,
+        let mut config = Config::from_json(json).unwrap();
+
+        // then call merge_environments() with a HashMap containing a different "python" entry (e.g., different exec path)
+        let env_meta = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: String::from("/custom/bin/run"),
+            interpreter_type: Some("python".to_string()),
+            session_exec: Some("/some/path".to_string()),
+            timeout_seconds: 30,
+            memory_mb: 512,
+            ..Default::default()
+        };
+        let envs = HashMap::from([(String::from("python"), env_meta)]);
+
+        // and assert the merged version wins.
+        config.merge_environments(envs, MergePolicy::OverrideBundled);
+        assert_eq!(config.environments["python"].exec, "/custom/bin/run");
+    }
+
+    #[test]
+    fn merge_environments_additive() {
+        // merge in "ruby" alongside "python" override, assert config now has both
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx-python-sandbox/bin/run",
+                    "timeout_seconds": 30,
+                    "memory_mb": 512
+                }
+            }
+        }"#;
+
+        let mut config = Config::from_json(json).unwrap();
+
+        let env_meta_python = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: String::from("/custom/bin/run"),
+            interpreter_type: Some("python".to_string()),
+            session_exec: Some("/some/path".to_string()),
+            timeout_seconds: 30,
+            memory_mb: 512,
+            ..Default::default()
+        };
+
+        let env_meta_ruby = EnvironmentMeta {
+            backend: BackendType::Jail,
+            exec: String::from("/custom-ruby/bin/run"),
+            interpreter_type: Some("bash".to_string()),
+            session_exec: Some("/some/other/path".to_string()),
+            timeout_seconds: 30,
+            memory_mb: 512,
+            ..Default::default()
+        };
+        let envs = HashMap::from([
+            (String::from("python"), env_meta_python),
+            (String::from("ruby"), env_meta_ruby),
+        ]);
+
+        config.merge_environments(envs, MergePolicy::OverrideBundled);
+        assert_eq!(config.environments["python"].exec, "/custom/bin/run");
+        assert_eq!(config.environments["ruby"].exec, "/custom-ruby/bin/run");
+    }
+
+    #[test]
+    fn merge_environments_preserve_bundled() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx-python-sandbox/bin/run"
+                }
+            },
+            "sandbox_merge": "preserve_bundled"
+        }"#;
+        let mut config = Config::from_json(json).unwrap();
+        assert_eq!(config.sandbox_merge, MergePolicy::PreserveBundled);
+
+        let custom = |exec: &str| EnvironmentMeta {
+            exec: exec.to_string(),
+            ..Default::default()
+        };
+        let envs = HashMap::from([
+            (String::from("python"), custom("/custom/bin/run")),
+            (String::from("ruby"), custom("/custom-ruby/bin/run")),
+        ]);
+
+        let skipped = config.merge_environments(envs, config.sandbox_merge);
+        assert_eq!(
+            config.environments["python"].exec,
+            "/nix/store/xxx-python-sandbox/bin/run"
+        );
+        assert_eq!(config.environments["ruby"].exec, "/custom-ruby/bin/run");
+        assert_eq!(
+            skipped,
+            [SkippedSandbox {
+                path: PathBuf::from("/custom/bin/run"),
+                reason: "name 'python' is taken by bundled environment 'python'".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn merge_environments_preserve_bundled_aliases() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx-python-sandbox/bin/run",
+                    "aliases": ["py"]
+                }
+            },
+            "sandbox_merge": "preserve_bundled"
+        }"#;
+        let mut config = Config::from_json(json).unwrap();
+        let custom = EnvironmentMeta {
+            exec: "/custom-py/bin/run".to_string(),
+            ..Default::default()
+        };
+
+        let skipped = config.merge_environments(
+            HashMap::from([("py".to_string(), custom.clone())]),
+            config.sandbox_merge,
+        );
+        assert!(!config.environments.contains_key("py"));
+        assert_eq!(
+            skipped[0].reason,
+            "name 'py' is taken by alias of bundled environment 'python'"
+        );
+
+        // Overriding lets the custom sandbox shadow the alias
+        let skipped = config.merge_environments(
+            HashMap::from([("py".to_string(), custom)]),
+            MergePolicy::OverrideBundled,
+        );
+        assert!(skipped.is_empty());
+        assert_eq!(config.alias_map().get("py"), None);
+        assert_eq!(config.environments["py"].exec, "/custom-py/bin/run");
+    }
+
+    #[test]
+    fn resolved_project_dir_from_config() {
+        let json = r#"{
+            "environments": {},
+            "project": {
+                "path": "/home/user/myproject",
+                "mount_point": "/project"
+            }
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        // Falls back to config when PROJECT_DIR is not set
+        assert_eq!(
+            config.resolved_project_dir().unwrap(),
+            Some(PathBuf::from("/home/user/myproject"))
+        );
+    }
+
+    #[test]
+    fn expand_path_tilde_and_home() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand_path("~").unwrap(), PathBuf::from(&home));
+        assert_eq!(
+            expand_path("~/code").unwrap(),
+            PathBuf::from(format!("{home}/code"))
+        );
+        assert_eq!(
+            expand_path("$HOME/code").unwrap(),
+            PathBuf::from(format!("{home}/code"))
+        );
+        // `~` only expands at the start
+        assert_eq!(expand_path("/a/~/b").unwrap(), PathBuf::from("/a/~/b"));
+    }
+
+    #[test]
+    fn expand_path_braced_variable() {
+        std::env::set_var("XDG_CONFIG_HOME", "/tmp/nsm-xdg");
+        assert_eq!(
+            expand_path("${XDG_CONFIG_HOME}/nix-sandbox-mcp").unwrap(),
+            PathBuf::from("/tmp/nsm-xdg/nix-sandbox-mcp")
+        );
+    }
+
+    #[test]
+    fn expand_path_literal_unchanged() {
+        assert_eq!(
+            expand_path("/home/user/myproject").unwrap(),
+            PathBuf::from("/home/user/myproject")
+        );
+        assert_eq!(expand_path("./rel").unwrap(), PathBuf::from("./rel"));
+        assert_eq!(expand_path("/cost$/x").unwrap(), PathBuf::from("/cost$/x"));
+    }
+
+    #[test]
+    fn expand_path_undefined_variable_is_error() {
+        std::env::remove_var("NSM_TEST_UNDEFINED");
+        let err = expand_path("$NSM_TEST_UNDEFINED/code").unwrap_err();
+        assert!(err.to_string().contains("`$NSM_TEST_UNDEFINED`"), "{err}");
+
+        let err = expand_path("${HOME").unwrap_err();
+        assert!(err.to_string().contains("Unclosed"), "{err}");
+    }
+
+    #[test]
+    fn resolved_project_dir_expands_variables() {
+        let json = r#"{
+            "environments": {},
+            "project": { "path": "$HOME/myproject" }
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            config.resolved_project_dir().unwrap(),
+            Some(PathBuf::from(format!("{home}/myproject")))
+        );
+    }
+
+    #[test]
+    fn resolved_project_dir_none_without_config() {
+        let json = r#"{"environments": {}}"#;
+        let config = Config::from_json(json).unwrap();
+        assert!(config.resolved_project_dir().unwrap().is_none());
+    }
+
+    #[test]
+    fn project_mount_from_config() {
+        let json = r#"{
+            "environments": {},
+            "project": {
+                "path": "/tmp",
+                "mount_point": "/custom-mount"
+            }
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        // Falls back to config mount_point when PROJECT_MOUNT is not set
+        assert_eq!(config.project_mount(), "/custom-mount");
+    }
+
+    #[test]
+    fn project_mount_default() {
+        let json = r#"{"environments": {}}"#;
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(config.project_mount(), "/project");
+    }
+
+    #[test]
+    fn parse_metadata_with_project() {
+        let json = r#"{
+            "environments": {
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy-shell-sandbox/bin/run"
+                }
+            },
+            "project": {
+                "path": "/home/user/myproject",
+                "mount_point": "/project",
+                "use_flake": true,
+                "inherit_env": {
+                    "vars": ["DATABASE_URL", "RUST_LOG"]
+                }
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+
+        let project = config.project.as_ref().expect("project should be set");
+        assert_eq!(project.path, PathBuf::from("/home/user/myproject"));
+        assert_eq!(project.mount_point, "/project");
         assert!(project.use_flake);
         assert_eq!(project.inherit_env.vars, vec!["DATABASE_URL", "RUST_LOG"]);
     }
+
+    #[test]
+    fn parse_error_names_environment_and_field() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run"
+                },
+                "data-science": {
+                    "backend": "docker",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            }
+        }"#;
+
+        let err = format!("{:#}", Config::from_json(json).unwrap_err());
+        assert!(err.contains("environment 'data-science'"), "{err}");
+        assert!(err.contains("field `backend`"), "{err}");
+        assert!(err.contains("`jail`") && err.contains("`microvm`"), "{err}");
+        assert!(err.contains("line 8"), "{err}");
+    }
+
+    #[test]
+    fn parse_error_names_environment_with_missing_field() {
+        let json = r#"{
+            "environments": {
+                "shell": { "backend": "jail", "exce": "/nix/store/xxx/bin/run" }
+            }
+        }"#;
+
+        let err = format!("{:#}", Config::from_json(json).unwrap_err());
+        assert!(err.contains("environment 'shell'"), "{err}");
+        assert!(err.contains("missing field `exec`"), "{err}");
+    }
+
+    #[test]
+    fn parse_error_outside_environments() {
+        let json = r#"{
+            "environments": {},
+            "session": { "idle_timeout_seconds": "soon" }
+        }"#;
+
+        let err = format!("{:#}", Config::from_json(json).unwrap_err());
+        assert!(
+            err.contains("field `session.idle_timeout_seconds`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn inherit_env_global_then_per_env() {
+        let json = r#"{
+            "environments": {
+                "python": {
+                    "backend": "jail",
+                    "exec": "/nix/store/xxx/bin/run",
+                    "inherit_env": { "vars": ["PIP_INDEX_URL", "RUST_LOG"] }
+                },
+                "shell": {
+                    "backend": "jail",
+                    "exec": "/nix/store/yyy/bin/run"
+                }
+            },
+            "project": {
+                "inherit_env": { "vars": ["RUST_LOG", "DATABASE_URL"] }
+            }
+        }"#;
+
+        let mut config = Config::from_json(json).unwrap();
+        config.apply_global_inherit_env();
+
+        assert_eq!(
+            config.environments["python"].inherit_env.vars,
+            vec!["RUST_LOG", "DATABASE_URL", "PIP_INDEX_URL"]
+        );
+        assert_eq!(
+            config.environments["shell"].inherit_env.vars,
+            vec!["RUST_LOG", "DATABASE_URL"]
+        );
+    }
+
+    #[test]
+    fn inherit_env_rejects_reserved_and_loader_names() {
+        for name in [
+            "PROJECT_DIR",
+            "SCRATCH_DIR",
+            "SANDBOX_INHERIT_ENV",
+            "LD_PRELOAD",
+            "1X",
+        ] {
+            let per_env = format!(
+                r#"{{ "environments": {{ "x": {{ "backend": "jail", "exec": "/run",
+                    "inherit_env": {{ "vars": ["RUST_LOG", "{name}"] }} }} }} }}"#
+            );
+            let global = format!(
+                r#"{{ "environments": {{}}, "project": {{ "inherit_env": {{ "vars": ["{name}"] }} }} }}"#
+            );
+            for json in [per_env, global] {
+                let err = format!("{:#}", Config::from_json(&json).unwrap_err());
+                assert!(err.contains(&format!("inherit_env name '{name}'")), "{err}");
+            }
+        }
+
+        let env = EnvironmentMeta {
+            exec: "/run".to_string(),
+            inherit_env: InheritEnv {
+                vars: vec!["PROJECT_MOUNT".to_string()],
+            },
+            ..Default::default()
+        };
+        let err = ConfigBuilder::default()
+            .environment("x", env)
+            .build()
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Environment 'x'"), "{err:#}");
+    }
+
+    #[test]
+    fn effective_timeout_default_when_omitted() {
+        let meta = EnvironmentMeta {
+            timeout_seconds: 30,
+            max_timeout_seconds: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(meta.effective_timeout(None), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn effective_timeout_valid_override() {
+        let meta = EnvironmentMeta {
+            timeout_seconds: 30,
+            max_timeout_seconds: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(meta.effective_timeout(Some(5)), Duration::from_secs(5));
+        assert_eq!(meta.effective_timeout(Some(120)), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn effective_timeout_clamped_to_max() {
+        let meta = EnvironmentMeta {
+            timeout_seconds: 30,
+            max_timeout_seconds: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(meta.effective_timeout(Some(9999)), Duration::from_secs(300));
+        assert_eq!(meta.effective_timeout(Some(0)), Duration::from_secs(1));
+
+        // Without a configured ceiling, overrides can only shorten
+        let meta = EnvironmentMeta {
+            timeout_seconds: 30,
+            ..Default::default()
+        };
+        assert_eq!(meta.effective_timeout(Some(60)), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn inherited_env_reads_host_values() {
+        std::env::set_var("NSM_TEST_INHERIT_SET", "value");
+        std::env::remove_var("NSM_TEST_INHERIT_UNSET");
+
+        let meta = EnvironmentMeta {
+            inherit_env: InheritEnv {
+                vars: vec![
+                    "NSM_TEST_INHERIT_SET".to_string(),
+                    "NSM_TEST_INHERIT_UNSET".to_string(),
+                ],
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            meta.inherited_env(),
+            vec![
+                ("NSM_TEST_INHERIT_SET".to_string(), "value".to_string()),
+                (
+                    "SANDBOX_INHERIT_ENV".to_string(),
+                    "NSM_TEST_INHERIT_SET".to_string()
+                ),
+            ]
+        );
+        assert!(EnvironmentMeta::default().inherited_env().is_empty());
+    }
+
+    #[test]
+    fn parse_metadata_with_scratch() {
+        let json = r#"{
+            "environments": {},
+            "scratch": {
+                "path": "/tmp/sandbox-scratch",
+                "mount_point": "/scratch"
+            }
+        }"#;
+
+        let config = Config::from_json(json).unwrap();
+
+        let scratch = config.scratch.as_ref().expect("scratch should be set");
+        assert_eq!(scratch.path, PathBuf::from("/tmp/sandbox-scratch"));
+        assert_eq!(scratch.mount_point, "/scratch");
+    }
+
+    #[test]
+    fn project_mounts_legacy_form() {
+        let json = r#"{
+            "environments": {},
+            "project": {
+                "path": "/home/user/myproject",
+                "mount_point": "/src"
+            }
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(
+            config.project_mounts().unwrap(),
+            vec![ProjectMount {
+                name: "project".to_string(),
+                path: PathBuf::from("/home/user/myproject"),
+                mount_point: "/src".to_string(),
+                read_only: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn project_mounts_list_form() {
+        let json = r#"{
+            "environments": {},
+            "project": { "path": "/home/user/myproject" },
+            "mounts": [
+                { "name": "data", "path": "/srv/data", "mount_point": "/data" },
+                { "name": "out", "path": "/srv/out", "mount_point": "/out", "read_only": false }
+            ]
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        let mounts = config.project_mounts().unwrap();
+
+        let summary: Vec<_> = mounts
+            .iter()
+            .map(|m| (m.name.as_str(), m.mount_point.as_str(), m.read_only))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("project", "/project", true),
+                ("data", "/data", true),
+                ("out", "/out", false),
+            ]
+        );
+        assert_eq!(mounts[1].path, PathBuf::from("/srv/data"));
+    }
+
+    #[test]
+    fn project_mounts_without_legacy_project() {
+        let json = r#"{
+            "environments": {},
+            "mounts": [{ "name": "data", "path": "/srv/data", "mount_point": "/data" }]
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        let mounts = config.project_mounts().unwrap();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].name, "data");
+    }
+
+    #[test]
+    fn project_mounts_reject_duplicates() {
+        let json = r#"{
+            "environments": {},
+            "project": { "path": "/home/user/myproject" },
+            "mounts": [{ "name": "data", "path": "/srv/data", "mount_point": "/project" }]
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        let err = config.project_mounts().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Mount 'data' conflicts with mount 'project'"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn mounts_entry_requires_mount_point() {
+        let json = r#"{
+            "environments": {},
+            "mounts": [{ "name": "data", "path": "/srv/data" }]
+        }"#;
+        let err = Config::from_json(json).unwrap_err();
+        assert!(format!("{err:#}").contains("mount_point"), "{err:#}");
+    }
+
+    #[test]
+    fn resolved_scratch_dir_from_config() {
+        let json = r#"{
+            "environments": {},
+            "scratch": { "path": "/tmp/sandbox-scratch" }
+        }"#;
+        let config = Config::from_json(json).unwrap();
+        assert_eq!(
+            config.resolved_scratch_dir().unwrap(),
+            Some(PathBuf::from("/tmp/sandbox-scratch"))
+        );
+        assert_eq!(config.scratch_mount(), "/workspace");
+    }
+
+    #[test]
+    fn resolved_scratch_dir_none_without_config() {
+        let json = r#"{"environments": {}}"#;
+        let config = Config::from_json(json).unwrap();
+        assert!(config.resolved_scratch_dir().unwrap().is_none());
+        assert_eq!(config.scratch_mount(), "/workspace");
+    }
+
+    #[test]
+    fn mounts_env_vars() {
+        let mounts = Mounts {
+            projects: vec![
+                ProjectMount {
+                    name: "project".to_string(),
+                    path: PathBuf::from("/home/user/myproject"),
+                    mount_point: "/project".to_string(),
+                    read_only: true,
+                },
+                ProjectMount {
+                    name: "data".to_string(),
+                    path: PathBuf::from("/srv/data"),
+                    mount_point: "/data".to_string(),
+                    read_only: false,
+                },
+            ],
+            scratch_dir: Some(PathBuf::from("/tmp/sandbox-scratch")),
+            scratch_mount: "/workspace".to_string(),
+        };
+        assert_eq!(
+            mounts.env_vars(),
+            vec![
+                (
+                    "PROJECT_DIR".to_string(),
+                    "/home/user/myproject".to_string()
+                ),
+                ("PROJECT_MOUNT".to_string(), "/project".to_string()),
+                (
+                    "PROJECT_DIR_0".to_string(),
+                    "/home/user/myproject".to_string()
+                ),
+                ("PROJECT_MOUNT_0".to_string(), "/project".to_string()),
+                ("PROJECT_READ_ONLY_0".to_string(), "1".to_string()),
+                ("PROJECT_DIR_1".to_string(), "/srv/data".to_string()),
+                ("PROJECT_MOUNT_1".to_string(), "/data".to_string()),
+                ("PROJECT_READ_ONLY_1".to_string(), "0".to_string()),
+                (
+                    "SCRATCH_DIR".to_string(),
+                    "/tmp/sandbox-scratch".to_string()
+                ),
+                ("SCRATCH_MOUNT".to_string(), "/workspace".to_string()),
+            ]
+        );
+
+        // Nothing configured: nothing to bind
+        assert!(Mounts::default().env_vars().is_empty());
+    }
+
+    #[test]
+    fn resolve_workdir_within_mounts() {
+        let mounts = Mounts {
+            projects: vec![ProjectMount {
+                name: "project".to_string(),
+                path: PathBuf::from("/home/user/myproject"),
+                mount_point: "/project".to_string(),
+                read_only: true,
+            }],
+            scratch_dir: Some(PathBuf::from("/tmp/sandbox-scratch")),
+            scratch_mount: "/scratch".to_string(),
+        };
+        assert_eq!(
+            mounts.resolve_workdir("/project/src").unwrap(),
+            "/project/src"
+        );
+        assert_eq!(mounts.resolve_workdir("/scratch").unwrap(), "/scratch");
+        assert_eq!(
+            mounts.resolve_workdir("build/./out").unwrap(),
+            "/workspace/build/out"
+        );
+    }
+
+    #[test]
+    fn sandbox_depth_limits_nesting() {
+        let top = SandboxDepth::new(None, 2);
+        assert_eq!(top.current, 0);
+        assert!(top.check().is_ok());
+        assert_eq!(
+            top.child_var(),
+            ("NIX_SANDBOX_DEPTH".to_string(), "1".to_string())
+        );
+
+        assert!(SandboxDepth::new(Some("1"), 2).check().is_ok());
+        let err = SandboxDepth::new(Some("2"), 2).check().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Refusing to start a sandbox nested 3 deep (max_sandbox_depth is 2)"));
+
+        // Garbage fails closed
+        assert!(SandboxDepth::new(Some("deep"), 2).check().is_err());
+    }
+
+    #[test]
+    fn builder_builds_config() {
+        let config = Config::builder()
+            .environment(
+                "python",
+                EnvironmentMeta {
+                    exec: "/bin/run".to_string(),
+                    interpreter_type: Some("python".to_string()),
+                    ..Default::default()
+                },
+            )
+            .project(ProjectConfig {
+                path: PathBuf::from("/srv/app"),
+                ..Default::default()
+            })
+            .mount(MountConfig {
+                name: "data".to_string(),
+                path: PathBuf::from("/srv/data"),
+                mount_point: "/data".to_string(),
+                read_only: true,
+            })
+            .session(SessionConfigToml {
+                max_sessions: 2,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(config.environments["python"].exec, "/bin/run");
+        assert_eq!(config.project_mount(), "/project");
+        assert_eq!(config.extra_mounts.len(), 1);
+        let session = config.session.unwrap();
+        assert_eq!(session.max_sessions, 2);
+        assert_eq!(session.idle_timeout_seconds, 300);
+    }
+
+    #[test]
+    fn builder_rejects_invalid_config() {
+        let env = |exec: &str| EnvironmentMeta {
+            exec: exec.to_string(),
+            ..Default::default()
+        };
+
+        let err = Config::builder().build().unwrap_err();
+        assert_eq!(err.to_string(), "Config has no environments");
+
+        let err = Config::builder()
+            .environment("python", env(""))
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Environment 'python' has no `exec`");
+
+        let err = Config::builder()
+            .environment(
+                "python",
+                EnvironmentMeta {
+                    max_timeout_seconds: Some(10),
+                    ..env("/bin/run")
+                },
+            )
+            .build()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`max_timeout_seconds` (10) is below"));
+
+        let err = Config::builder()
+            .environment("python", env("/bin/run"))
+            .scratch(ScratchConfig {
+                path: PathBuf::from("/tmp/scratch"),
+                mount_point: "scratch".to_string(),
+            })
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("must be an absolute path"));
+
+        let err = Config::builder()
+            .environment("python", env("/bin/run"))
+            .project(ProjectConfig::default())
+            .mount(MountConfig {
+                name: "data".to_string(),
+                path: PathBuf::from("/srv/data"),
+                mount_point: "/project".to_string(),
+                read_only: true,
+            })
+            .build()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Mount 'data' conflicts with mount 'project'"));
+    }
+
+    #[test]
+    fn resolve_workdir_rejects_escapes() {
+        let mounts = Mounts::default();
+        let err = mounts.resolve_workdir("../../etc").unwrap_err();
+        assert!(err.to_string().contains("must not contain '..'"));
+        assert!(mounts.resolve_workdir("/project/../etc").is_err());
+
+        // Unconfigured mounts aren't roots; neither are lookalike prefixes
+        let err = mounts.resolve_workdir("/etc").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("outside the sandbox mounts (/workspace)"));
+        assert!(mounts.resolve_workdir("/scratch").is_err());
+        assert!(mounts.resolve_workdir("/workspace-other").is_err());
+    }
+
+    #[test]
+    fn secret_env_hides_values() {
+        let secrets: SecretEnv = [("GITHUB_TOKEN", "ghp_secret"), ("API_KEY", "k")]
+            .into_iter()
+            .collect();
+        assert_eq!(format!("{secrets:?}"), r#"{"API_KEY", "GITHUB_TOKEN"}"#);
+        assert_eq!(secrets.encode(), b"API_KEY=k\0GITHUB_TOKEN=ghp_secret\0");
+        assert!(secrets.validate(&[]).is_ok());
+        assert!(SecretEnv::default().encode().is_empty());
+
+        for name in [
+            "",
+            "1X",
+            "A-B",
+            "A B",
+            "SANDBOX_WORKDIR",
+            SANDBOX_DEPTH_VAR,
+            "BASH_ENV",
+            "ENV",
+            "LD_PRELOAD",
+            "LD_LIBRARY_PATH",
+            "PATH",
+            "IFS",
+            "SHELLOPTS",
+            "BASHOPTS",
+            "BASH_FUNC_ls%%",
+            "BASH_FUNC_x",
+        ] {
+            let secrets: SecretEnv = std::iter::once((name, "v")).collect();
+            assert!(
+                secrets.validate(&[]).is_err(),
+                "{name:?} should be rejected"
+            );
+        }
+
+        let nul: SecretEnv = std::iter::once(("TOKEN", "a\0b")).collect();
+        assert!(nul.validate(&[]).is_err());
+    }
+
+    #[test]
+    fn secret_env_prefixes_restrict_names() {
+        let prefixes = ["GH_".to_string(), "AWS_".to_string()];
+        let ok: SecretEnv = [("GH_TOKEN", "x"), ("AWS_KEY", "y")].into_iter().collect();
+        assert!(ok.validate(&prefixes).is_ok());
+        let other: SecretEnv = std::iter::once(("TOKEN", "x")).collect();
+        let err = other.validate(&prefixes).unwrap_err().to_string();
+        assert!(err.contains("must start with one of: GH_, AWS_"), "{err}");
+    }
 }
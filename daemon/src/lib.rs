@@ -5,8 +5,10 @@
 //! - MCP server implementation using rmcp
 //! - Backend trait and implementations for sandboxed execution
 
+pub mod audit;
 pub mod backend;
 pub mod config;
 pub mod mcp;
+pub mod probe;
 pub mod session;
 pub mod transport;
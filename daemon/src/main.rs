@@ -8,24 +8,58 @@ use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use tracing::{debug, info};
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
 use nix_sandbox_mcp_daemon::{
     backend::JailBackend,
-    config::Config,
-    mcp,
+    config::{Config, SandboxSource, SkippedSandbox, UnknownFields},
+    mcp, probe,
     session::{SessionConfig, SessionManager},
 };
 
+// CLI flags are independent switches; bools are the natural clap shape
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Parser, Debug)]
 #[command(name = "nix-sandbox-mcp-daemon")]
 #[command(about = "MCP server for Nix-based sandboxed code execution")]
 struct Args {
     /// Run in stdio mode (for MCP clients)
-    #[arg(long)]
+    #[arg(long, conflicts_with = "socket")]
     stdio: bool,
 
+    /// Listen for MCP clients on a UNIX domain socket at PATH; clients
+    /// share sessions, which live until SIGINT/SIGTERM
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Validate configuration and environment paths, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Run a no-op in every environment and fail if any can't run it;
+    /// exits afterwards unless `--stdio` or `--socket` is also given
+    #[arg(long)]
+    probe: bool,
+
+    /// Print a table of the configured environments, then exit
+    #[arg(long)]
+    list: bool,
+
+    /// Keep sessions and the reaper running after the client disconnects,
+    /// until SIGINT/SIGTERM, instead of tearing them down
+    #[arg(long)]
+    persist: bool,
+
+    /// TOML config file to load instead of `NIX_SANDBOX_METADATA`
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Ignore unknown config keys instead of failing (also
+    /// `NIX_SANDBOX_LENIENT_CONFIG=1`), for metadata from a newer wrapper
+    #[arg(long)]
+    lenient_config: bool,
+
     /// Log level (trace, debug, info, warn, error)
     #[arg(long, default_value = "info")]
     log_level: String,
@@ -33,9 +67,55 @@ struct Args {
 
 /// Get a path from an environment variable, falling back to root.
 fn dirs_or_default(var: &str) -> PathBuf {
-    std::env::var(var)
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("/"))
+    std::env::var(var).map_or_else(|_| PathBuf::from("/"), PathBuf::from)
+}
+
+/// Report skipped sandboxes and broken environment paths to stderr; error
+/// if any paths are broken.
+fn check_paths(config: &Config, skipped: &[SkippedSandbox]) -> Result<()> {
+    for sandbox in skipped {
+        eprintln!("warning: skipped sandbox {sandbox}");
+    }
+    let issues = config.validate_paths();
+    for issue in &issues {
+        eprintln!("error: {issue}");
+    }
+
+    let envs = config.environments.len();
+    if issues.is_empty() {
+        eprintln!("Checked {envs} environment(s): all paths OK");
+        Ok(())
+    } else {
+        anyhow::bail!(
+            "Checked {envs} environment(s): {} invalid path(s)",
+            issues.len()
+        )
+    }
+}
+
+/// Probe every environment in turn, reporting to stderr; error if any fail.
+async fn probe_environments(config: &Config, backend: &JailBackend) -> Result<()> {
+    let mounts = config.mounts().context("Invalid mount configuration")?;
+    let mut names: Vec<&String> = config.environments.keys().collect();
+    names.sort();
+
+    let mut failed = 0;
+    for name in &names {
+        match probe::probe_environment(backend, config, name, &mounts).await {
+            Ok(elapsed) => eprintln!("ok: {name} ({}ms)", elapsed.as_millis()),
+            Err(e) => {
+                eprintln!("error: {name}: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        eprintln!("Probed {} environment(s): all OK", names.len());
+        Ok(())
+    } else {
+        anyhow::bail!("Probed {} environment(s): {failed} failed", names.len())
+    }
 }
 
 #[tokio::main]
@@ -50,47 +130,91 @@ async fn main() -> Result<()> {
         .with_writer(std::io::stderr)
         .init();
 
-    // Load environment metadata from Nix wrapper
-    let mut config = Config::from_env().context("Failed to load configuration")?;
-
-    // Scan for custom sandbox artifacts
-    let sandbox_dir = std::env::var("NIX_SANDBOX_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            dirs_or_default("HOME")
-                .join(".config/nix-sandbox-mcp/sandboxes")
-        });
-
-    if sandbox_dir.is_dir() {
-        let extra = Config::scan_sandbox_dir(&sandbox_dir);
-        if !extra.is_empty() {
-            info!(count = extra.len(), dir = %sandbox_dir.display(), "Discovered custom sandboxes");
-            config.merge_environments(extra);
-        }
+    let unknown_fields = if args.lenient_config {
+        UnknownFields::Warn
     } else {
-        debug!(dir = %sandbox_dir.display(), "Sandbox directory does not exist, skipping scan");
+        UnknownFields::from_env()
+    };
+
+    // Load environment metadata from --config, else from the Nix wrapper
+    let base = args
+        .config
+        .as_deref()
+        .map_or_else(
+            || Config::from_env(unknown_fields),
+            |path| Config::from_file(path, unknown_fields),
+        )
+        .context("Failed to load configuration")?;
+
+    // Scan for custom sandbox artifacts (re-scanned by the `reload` tool)
+    let sandbox_dir = std::env::var("NIX_SANDBOX_DIR").map_or_else(
+        |_| dirs_or_default("HOME").join(".config/nix-sandbox-mcp/sandboxes"),
+        PathBuf::from,
+    );
+    let source = SandboxSource {
+        base,
+        dir: sandbox_dir,
+        unknown_fields,
+    };
+    let (config, skipped) = source.load_reporting();
+    if !skipped.is_empty() {
+        warn!(
+            count = skipped.len(),
+            dir = %source.dir.display(),
+            "Skipped invalid sandboxes; run with --check for details"
+        );
     }
 
+    // Fail fast on unresolvable project/scratch paths (e.g. undefined $VARs)
+    config.mounts().context("Invalid mount configuration")?;
+
     info!(
         environments = ?config.environments.keys().collect::<Vec<_>>(),
         "Loaded configuration"
     );
 
+    if args.check {
+        return check_paths(&config, &skipped);
+    }
+    if args.list {
+        print!("{}", mcp::environment_table(&config));
+        return Ok(());
+    }
+
     // Initialize backend
-    let backend = JailBackend::new();
+    let pool_size = config.pool_size();
+    if pool_size > 0 {
+        info!(pool_size, "Keeping warm jail wrappers");
+    }
+    let depth = config.sandbox_depth();
+    let backend = JailBackend::with_pool(pool_size)
+        .with_depth(depth)
+        .with_kill_grace(config.kill_grace());
+
+    if args.probe {
+        probe_environments(&config, &backend).await?;
+        if !args.stdio && args.socket.is_none() {
+            return Ok(());
+        }
+    }
 
     // Initialize session manager (TOML config takes priority, then env vars)
-    let session_config = config
+    let mut session_config = config
         .session
         .as_ref()
-        .map(SessionConfig::from_toml)
-        .unwrap_or_else(SessionConfig::from_env);
+        .map_or_else(SessionConfig::from_env, SessionConfig::from_toml);
+    session_config.max_session_memory_mb =
+        config.limits.as_ref().and_then(|l| l.max_session_memory_mb);
+    session_config.depth = depth;
+    session_config.interpreter_map = config.interpreter_map.clone();
     let session_manager = Arc::new(SessionManager::new(session_config));
 
     if args.stdio {
-        mcp::serve_stdio(config, backend, session_manager).await?;
+        mcp::serve_stdio(config, backend, session_manager, source, args.persist).await?;
+    } else if let Some(path) = &args.socket {
+        mcp::serve_socket(config, backend, session_manager, source, path).await?;
     } else {
-        anyhow::bail!("Only --stdio mode is currently supported");
+        anyhow::bail!("Pass --stdio or --socket <PATH> to serve MCP");
     }
 
     Ok(())
@@ -1,44 +1,103 @@
 //! MCP server implementation using rmcp.
 //!
-//! Exposes sandboxed execution environments as MCP tools.
+//! Exposes sandboxed execution environments as MCP tools, and their
+//! metadata as MCP resources (`sandbox://env/<name>`).
 //! Routes to either ephemeral execution (`IsolationBackend`) or
 //! persistent sessions (`SessionManager`) based on the `session` parameter.
+//! Ephemeral output is streamed as progress notifications when the client
+//! sends a progress token. Sessions being created and closed are announced
+//! with `notifications/sandbox/session`.
 
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::future::Future;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use rmcp::handler::server::router::tool::ToolRouter;
 use rmcp::handler::server::wrapper::Parameters;
-use rmcp::model::{CallToolResult, Content, Implementation, ServerCapabilities, ServerInfo};
+use rmcp::model::{
+    AnnotateAble, CallToolResult, Content, CustomNotification, Implementation, ListResourcesResult,
+    Meta, PaginatedRequestParams, ProgressNotificationParam, ProgressToken, RawResource,
+    ReadResourceRequestParams, ReadResourceResult, Resource, ResourceContents, ServerCapabilities,
+    ServerInfo, ServerNotification,
+};
 use rmcp::schemars;
-use rmcp::transport::stdio;
+use rmcp::service::{Peer, RequestContext, RoleServer};
+use rmcp::transport::{stdio, IntoTransport};
 use rmcp::{tool, tool_handler, tool_router, ErrorData as McpError, ServerHandler, ServiceExt};
 use schemars::JsonSchema;
 use serde::Deserialize;
-use tracing::{error, info};
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, OnceCell, Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::audit::{unix_now_ms, AuditRecord, AuditSink};
+use crate::backend::{
+    ExecError, ExecOptions, ExecutionResult, IsolationBackend, OutputChunk, OutputSender,
+    OutputStream,
+};
+use crate::config::{
+    BackendType, BusyPolicy, Config, EnvironmentMeta, InputMode, Mounts, SandboxSource, SecretEnv,
+    TruncateStrategy,
+};
+use crate::session::{env_to_interpreter, Reaper, ResetOutcome, SessionEvent, SessionManager};
+use crate::transport::protocol::{MIN_SUPPORTED_PROTOCOL, SUPPORTED_PROTOCOL};
+
+/// Method of the notification sent when a session is created or closed.
+const SESSION_EVENT_METHOD: &str = "notifications/sandbox/session";
+
+/// URI prefix of environment resources; the environment name follows.
+const ENV_RESOURCE_PREFIX: &str = "sandbox://env/";
 
-use crate::backend::IsolationBackend;
-use crate::config::Config;
-use crate::session::SessionManager;
+/// Tail of a failed `setup_exec`'s stderr kept for the error message.
+const SETUP_STDERR_BYTES: usize = 16 * 1024;
 
 /// MCP server for sandboxed code execution.
 #[derive(Clone)]
 pub struct SandboxServer<B: Clone> {
-    config: Arc<Config>,
+    /// Current config; `reload` swaps in a new one.
+    catalog: Arc<RwLock<Arc<Catalog>>>,
+    /// Where `reload` re-scans for sandboxes (`None` = reload unavailable).
+    source: Option<Arc<SandboxSource>>,
     backend: Arc<B>,
     session_manager: Arc<SessionManager>,
+    /// Slots for `[limits] max_concurrent_executions` (`None` = uncapped).
+    execution_slots: Option<Arc<Semaphore>>,
+    /// When the server was created (for `ping` uptime).
+    start_time: Instant,
+    /// Where each run call is recorded (`None` = no audit log).
+    audit: Option<Arc<AuditSink>>,
+    /// Per environment, set once its `setup_exec` has succeeded.
+    setup_done: Arc<Mutex<HashMap<String, Arc<OnceCell<()>>>>>,
     tool_router: ToolRouter<Self>,
 }
 
 /// Parameters for the run tool.
+// Independent tool flags, each a plain JSON boolean for the client
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct RunParams {
-    /// The code to run in the sandbox.
-    #[schemars(description = "The code to run in the sandbox")]
-    pub code: String,
+    /// The code to run in the sandbox, or fragments to run in order in a session.
+    #[schemars(
+        description = "The code to run in the sandbox. With a session, may be an array of code fragments, run in order as one call with nothing interleaved between them"
+    )]
+    pub code: Code,
 
-    /// Execution environment (required): python, node, shell, or custom.
-    #[schemars(description = "Execution environment (required): python, node, shell, or custom")]
-    pub env: String,
+    /// Execution environment: python, node, shell, or custom. May be
+    /// omitted when the server has a `default_env`.
+    #[serde(default)]
+    #[schemars(
+        description = "Execution environment: python, node, shell, or custom. May be omitted if the server has a default environment"
+    )]
+    pub env: Option<String>,
 
     /// Optional session ID for persistent state across calls.
     /// When provided, interpreter state (variables, imports, files in /workspace)
@@ -49,57 +108,711 @@ pub struct RunParams {
         description = "Optional session ID for persistent state across calls. When provided, variables and /workspace files persist between calls with the same session ID. Each session is bound to its creation environment."
     )]
     pub session: Option<String>,
+
+    /// Optional input data written to the program's stdin after the code.
+    /// Only supported for ephemeral execution.
+    #[serde(default)]
+    #[schemars(
+        description = "Optional input data passed to the program's stdin (ephemeral execution only)"
+    )]
+    pub stdin: Option<String>,
+
+    /// Optional per-call timeout override in seconds.
+    #[serde(default)]
+    #[schemars(
+        description = "Optional timeout in seconds for this call, overriding the environment default (capped at the environment's maximum)"
+    )]
+    pub timeout_seconds: Option<u64>,
+
+    /// How to format the result: "text" (default) or "json".
+    #[serde(default)]
+    #[schemars(
+        description = "Result format: \"text\" (default, readable output) or \"json\" (one JSON object with exit_code, stdout, stderr, truncated)"
+    )]
+    pub output_format: OutputFormat,
+
+    /// Return stdout and stderr as base64 of their exact bytes, for programs
+    /// that write binary data. Only supported for ephemeral execution.
+    #[serde(default)]
+    #[schemars(
+        description = "Return stdout and stderr base64-encoded, byte for byte, in one JSON object (for binary output such as images; ephemeral execution only)"
+    )]
+    pub binary: bool,
+
+    /// Working directory inside the sandbox, instead of /workspace.
+    /// Only supported for ephemeral execution.
+    #[serde(default)]
+    #[schemars(
+        description = "Optional working directory inside the sandbox: relative to /workspace, or an absolute path under /workspace, the scratch mount, or a project mount (ephemeral execution only)"
+    )]
+    pub workdir: Option<String>,
+
+    /// For fragment arrays: keep running after a fragment exits nonzero.
+    #[serde(default)]
+    #[schemars(
+        description = "When code is an array of fragments, keep running the remaining fragments after one fails (default: stop at the first nonzero exit)"
+    )]
+    pub continue_on_error: bool,
+
+    /// Close an existing session bound to a different environment and
+    /// start a fresh one in `env` under the same ID.
+    #[serde(default)]
+    #[schemars(
+        description = "With session: if the session is bound to a different environment, discard it (and its state) and start a fresh one in env under the same ID. Without it, such a call fails."
+    )]
+    pub rebind: bool,
+
+    /// Retry once in a fresh session if the session's agent has died.
+    #[serde(default)]
+    #[schemars(
+        description = "With session: if the session's agent has died, start a fresh session under the same ID and run the code there once (earlier state is lost, and stderr says so). Without it, such a call fails."
+    )]
+    pub recreate_on_death: bool,
+
+    /// Secret env vars for the program, e.g. tokens. Names may be logged,
+    /// values never are. Only supported for ephemeral execution.
+    #[serde(default)]
+    #[schemars(
+        with = "HashMap<String, String>",
+        description = "Optional secret environment variables (e.g. API tokens) set for the program; values are never logged (ephemeral execution only)"
+    )]
+    pub secret_env: SecretEnv,
+
+    /// Merge stderr into stdout as it's written, keeping the interleaving.
+    /// Only supported for ephemeral execution.
+    #[serde(default)]
+    #[schemars(
+        description = "Merge stderr into stdout in the order they were written, instead of returning them separately (ephemeral execution only)"
+    )]
+    pub combine_output: bool,
+
+    /// Capture stdout and stderr. When false they're discarded unread and
+    /// only the exit code comes back, for checks whose output doesn't
+    /// matter. Only supported for ephemeral execution.
+    #[serde(default = "default_capture_output")]
+    #[schemars(
+        description = "Capture stdout and stderr (default true). Set false to discard them and get only the exit code, e.g. for health checks with chatty output (ephemeral execution only)"
+    )]
+    pub capture_output: bool,
+
+    /// Run blank code anyway, e.g. just to start a session. Blank code is
+    /// otherwise rejected.
+    #[serde(default)]
+    #[schemars(
+        description = "Run even if code is empty or whitespace, e.g. to start a session without running anything (default: such calls are rejected)"
+    )]
+    pub allow_empty: bool,
+}
+
+const fn default_capture_output() -> bool {
+    true
+}
+
+impl RunParams {
+    /// Runtime mounts for this call: project/scratch dirs from `config`.
+    fn mounts(config: &Config) -> Result<Mounts, McpError> {
+        config.mounts().map_err(|e| {
+            McpError::internal_error(format!("Invalid mount configuration: {e:#}"), None)
+        })
+    }
+
+    /// This call's working directory (resolved within `mounts`), secrets,
+    /// and output mode, for an ephemeral run.
+    fn exec_options(&self, config: &Config, mounts: &Mounts) -> Result<ExecOptions, McpError> {
+        let workdir = match &self.workdir {
+            Some(workdir) => Some(mounts.resolve_workdir(workdir).map_err(|e| {
+                warn!(workdir = %workdir, error = %e, "Rejecting working directory");
+                McpError::invalid_params(e.to_string(), None)
+            })?),
+            None => None,
+        };
+        let prefixes = config
+            .limits
+            .as_ref()
+            .map_or(&[][..], |l| l.secret_env_prefixes.as_slice());
+        self.secret_env
+            .validate(prefixes)
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+        Ok(ExecOptions {
+            workdir,
+            secret_env: self.secret_env.clone(),
+            combine_output: self.combine_output,
+            discard_output: !self.capture_output,
+        })
+    }
+
+    /// Reject blank code (unless `allow_empty`), code over `max_code_bytes`,
+    /// and code an ephemeral run can't pass as `input_mode` says, before
+    /// any sandbox is spawned.
+    fn check_code(&self, max_code_bytes: usize, input_mode: InputMode) -> Result<(), McpError> {
+        let code = &self.code;
+        if code.is_blank() && !self.allow_empty {
+            return Err(McpError::invalid_params(
+                "Code is empty. Pass allow_empty: true to run it anyway \
+                 (e.g. just to start a session).",
+                None,
+            ));
+        }
+
+        // Reject oversized code here rather than deep in the transport
+        if code.len() > max_code_bytes {
+            warn!(
+                code_len = code.len(),
+                max_code_bytes, "Rejecting code over the size limit"
+            );
+            return Err(McpError::invalid_params(
+                format!(
+                    "Code is {} bytes, over the {} limit (max_code_bytes). \
+                     Send large data through a mounted file or stdin instead.",
+                    code.len(),
+                    format_size(max_code_bytes)
+                ),
+                None,
+            ));
+        }
+
+        // Sessions take code over the agent protocol, whatever the mode
+        if let (Code::Single(code), None) = (code, &self.session) {
+            input_mode
+                .check_code(code)
+                .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+        }
+        Ok(())
+    }
+
+    /// The first option set that sessions can't honour, if any.
+    fn ephemeral_only_option(&self) -> Option<&'static str> {
+        if self.stdin.is_some() {
+            Some("stdin")
+        } else if self.binary {
+            Some("binary output")
+        } else if self.workdir.is_some() {
+            Some("workdir")
+        } else if !self.secret_env.is_empty() {
+            Some("secret_env")
+        } else if self.combine_output {
+            Some("combine_output")
+        } else if !self.capture_output {
+            Some("capture_output: false")
+        } else {
+            None
+        }
+    }
+
+    /// Where the call runs, rejecting combinations neither place can run.
+    fn dispatch(
+        &self,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+    ) -> Result<Dispatch<'_>, McpError> {
+        if let Some(session_id) = &self.session {
+            if let Some(option) = self.ephemeral_only_option() {
+                return Err(McpError::invalid_params(
+                    format!("{option} is only supported for ephemeral execution (omit session)"),
+                    None,
+                ));
+            }
+            return Ok(Dispatch::Session(session_id));
+        }
+        let Code::Single(code) = &self.code else {
+            return Err(McpError::invalid_params(
+                "code fragments (an array) are only supported in a session",
+                None,
+            ));
+        };
+        // There's no local wrapper to run it in
+        if env_meta.backend == BackendType::Remote {
+            return Err(McpError::invalid_params(
+                format!(
+                    "Environment '{env_name}' runs on a remote agent, which only hosts \
+                     sessions; pass session"
+                ),
+                None,
+            ));
+        }
+        Ok(Dispatch::Ephemeral(code))
+    }
+}
+
+/// Where a run call goes, once its parameters are checked.
+enum Dispatch<'a> {
+    /// To the session with this ID.
+    Session(&'a str),
+    /// To the backend, as this single program.
+    Ephemeral(&'a str),
+}
+
+/// Code for the run tool: one program, or fragments for a session.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Code {
+    /// A single program.
+    Single(String),
+    /// Fragments run back to back in one session call.
+    Fragments(Vec<String>),
+}
+
+impl Code {
+    /// Total size in bytes, across all fragments.
+    fn len(&self) -> usize {
+        match self {
+            Self::Single(code) => code.len(),
+            Self::Fragments(fragments) => fragments.iter().map(String::len).sum(),
+        }
+    }
+
+    /// Whether there's nothing but whitespace to run.
+    fn is_blank(&self) -> bool {
+        match self {
+            Self::Single(code) => code.trim().is_empty(),
+            Self::Fragments(fragments) => fragments.iter().all(|f| f.trim().is_empty()),
+        }
+    }
+}
+
+impl From<&str> for Code {
+    fn from(code: &str) -> Self {
+        Self::Single(code.to_string())
+    }
+}
+
+impl From<String> for Code {
+    fn from(code: String) -> Self {
+        Self::Single(code)
+    }
+}
+
+/// Result format of the run tool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Combined output text, plus a structured JSON block.
+    #[default]
+    Text,
+    /// A single JSON object with separate stdout and stderr.
+    Json,
+}
+
+/// Parameters for the `describe_environment` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DescribeEnvironmentParams {
+    /// Environment name or alias.
+    #[schemars(description = "Environment name or alias to describe")]
+    pub env: String,
+
+    /// Leave out host paths (wrapper scripts in the Nix store). Defaults to true.
+    #[serde(default = "default_redact_paths")]
+    #[schemars(
+        description = "Omit host paths such as the Nix store wrapper scripts (default true)"
+    )]
+    pub redact_paths: bool,
+}
+
+/// Parameters for the `find_environments` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FindEnvironmentsParams {
+    /// Glob (`py*`, `data-?`) or, without glob characters, a substring.
+    #[schemars(
+        description = "Glob pattern such as 'py*', or a plain substring, to match environment names against"
+    )]
+    pub pattern: String,
+}
+
+const fn default_redact_paths() -> bool {
+    true
+}
+
+/// Parameters for the `restart_session` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RestartSessionParams {
+    /// Session to restart.
+    #[schemars(description = "Session ID to restart")]
+    pub session: String,
+}
+
+/// Parameters for the `reset_session` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ResetSessionParams {
+    /// Session to reset.
+    #[schemars(description = "Session ID to reset")]
+    pub session: String,
+}
+
+/// Parameters for the `set_session_env` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SetSessionEnvParams {
+    /// Session to set the variable in.
+    #[schemars(description = "Session ID to set the variable in")]
+    pub session: String,
+    /// Variable name.
+    #[schemars(
+        description = "Variable name: letters, digits, and underscores, not starting with a digit"
+    )]
+    pub key: String,
+    /// Variable value.
+    #[schemars(description = "Value to set")]
+    pub value: String,
+}
+
+/// Parameters for the `get_session_env` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct GetSessionEnvParams {
+    /// Session to read the variable from.
+    #[schemars(description = "Session ID to read the variable from")]
+    pub session: String,
+    /// Variable name.
+    #[schemars(description = "Variable name")]
+    pub key: String,
+}
+
+/// Parameters for the cancel tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CancelParams {
+    /// Session whose running execution should be interrupted.
+    #[schemars(description = "Session ID whose running execution should be interrupted")]
+    pub session: String,
+}
+
+/// Parameters for the `session_info` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SessionInfoParams {
+    /// Session to describe.
+    #[schemars(description = "Session ID to describe")]
+    pub session: String,
+}
+
+/// Parameters for the `session_history` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SessionHistoryParams {
+    /// Session whose executions to list.
+    #[schemars(description = "Session ID whose recent executions to list")]
+    pub session: String,
+}
+
+/// Parameters for the `close_session` tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CloseSessionParams {
+    /// Session to close.
+    #[schemars(description = "Session ID to close")]
+    pub session: String,
 }
 
-/// Maximum output size returned to the MCP client (1 MB).
-const MAX_OUTPUT_SIZE: usize = 1024 * 1024;
+/// Format a byte count for humans: whole MB/KB when exact, bytes otherwise.
+fn format_size(bytes: usize) -> String {
+    const KB: usize = 1024;
+    const MB: usize = 1024 * KB;
+    if bytes >= MB && bytes % MB == 0 {
+        format!("{}MB", bytes / MB)
+    } else if bytes >= KB && bytes % KB == 0 {
+        format!("{}KB", bytes / KB)
+    } else {
+        format!("{bytes} bytes")
+    }
+}
 
-/// Truncate a string to a byte-safe limit, appending a marker if truncated.
-fn truncate_output(s: &str, max_bytes: usize) -> String {
+/// Truncate a string to a byte-safe limit, keeping the part `strategy`
+/// picks and marking where the rest was cut.
+///
+/// Returns the output and whether anything was cut.
+fn truncate_output(s: &str, max_bytes: usize, strategy: TruncateStrategy) -> (String, bool) {
     if s.len() <= max_bytes {
-        return s.to_string();
+        return (s.to_string(), false);
     }
-    // Find a char boundary at or before max_bytes
-    let mut end = max_bytes;
-    while end > 0 && !s.is_char_boundary(end) {
+    let marker = format!("[truncated — output exceeded {}]", format_size(max_bytes));
+    let output = match strategy {
+        TruncateStrategy::Head => format!("{}\n\n{marker}", head_of(s, max_bytes)),
+        TruncateStrategy::Tail => format!("{marker}\n\n{}", tail_of(s, max_bytes)),
+        TruncateStrategy::Middle => {
+            let head = head_of(s, max_bytes / 2);
+            let tail = tail_of(s, max_bytes - head.len());
+            format!("{head}\n\n{marker}\n\n{tail}")
+        }
+    };
+    (output, true)
+}
+
+/// Longest prefix of `s` within `max_bytes`, ending on a char boundary.
+fn head_of(s: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(s.len());
+    while !s.is_char_boundary(end) {
         end -= 1;
     }
-    format!("{}\n\n[truncated — output exceeded 1MB]", &s[..end])
+    &s[..end]
+}
+
+/// Longest suffix of `s` within `max_bytes`, starting on a char boundary.
+fn tail_of(s: &str, max_bytes: usize) -> &str {
+    let mut start = s.len().saturating_sub(max_bytes);
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+/// `code_hash` of a run call's code; fragments are hashed as if joined by
+/// newlines.
+fn audit_code_hash(audit: &AuditSink, code: &Code) -> String {
+    match code {
+        Code::Single(code) => audit.code_hash(code.as_bytes()),
+        Code::Fragments(fragments) => audit.code_hash(fragments.join("\n").as_bytes()),
+    }
+}
+
+/// Build the progress notification for the `seq`-th output chunk.
+///
+/// Progress counts chunks so it increases monotonically; the chunk text is
+/// carried in `message`, with stderr chunks prefixed so they're distinguishable.
+fn chunk_to_progress(
+    token: &ProgressToken,
+    seq: u32,
+    chunk: OutputChunk,
+) -> ProgressNotificationParam {
+    let message = match chunk.stream {
+        OutputStream::Stdout => chunk.data,
+        OutputStream::Stderr => format!("[stderr] {}", chunk.data),
+    };
+    ProgressNotificationParam {
+        progress_token: token.clone(),
+        progress: f64::from(seq),
+        total: None,
+        message: Some(message),
+    }
+}
+
+/// Split `max` bytes between stdout and stderr: each gets at least half,
+/// and whatever one doesn't use goes to the other.
+fn split_budget(stdout_len: usize, stderr_len: usize, max: usize) -> (usize, usize) {
+    let stdout_budget = max - stderr_len.min(max / 2);
+    let stderr_budget = max - stdout_len.min(stdout_budget);
+    (stdout_budget, stderr_budget)
 }
 
 /// Format an execution result into an MCP `CallToolResult`.
-fn format_result(exit_code: i32, stdout: String, stderr: String) -> CallToolResult {
-    let is_error = exit_code != 0;
+///
+/// The text joins stdout and stderr with `stderr_delimiter` (`[output]`)
+/// when both have output; a second, short block carries the metadata. The
+/// structured content has both, with the streams apart. Text and streams
+/// are each held to `max_output_bytes` (per environment), truncated per
+/// `truncate`.
+fn format_result(
+    result: &ExecutionResult,
+    max_output_bytes: usize,
+    stderr_delimiter: &str,
+    truncate: TruncateStrategy,
+) -> CallToolResult {
+    let is_error = result.exit_code != 0;
+    let ExecutionResult { stdout, stderr, .. } = result;
 
     let output = if stderr.is_empty() {
-        stdout
+        stdout.clone()
     } else if stdout.is_empty() {
-        stderr
+        stderr.clone()
     } else {
-        format!("{stdout}\n--- stderr ---\n{stderr}")
+        format!("{stdout}{stderr_delimiter}{stderr}")
     };
 
-    let output = truncate_output(&output, MAX_OUTPUT_SIZE);
+    let (output, output_truncated) = truncate_output(&output, max_output_bytes, truncate);
+    let (stdout_budget, stderr_budget) = split_budget(stdout.len(), stderr.len(), max_output_bytes);
+    let (stdout, stdout_truncated) = truncate_output(stdout, stdout_budget, truncate);
+    let (stderr, stderr_truncated) = truncate_output(stderr, stderr_budget, truncate);
+
+    let mut metadata = serde_json::json!({
+        "exit_code": result.exit_code,
+        "timed_out": result.timed_out,
+        "duration_ms": u64::try_from(result.duration.as_millis()).unwrap_or(u64::MAX),
+        "truncated": output_truncated || stdout_truncated || stderr_truncated,
+    });
+    if let Some(usage) = result.resource_usage {
+        metadata["resource_usage"] = serde_json::json!({
+            "cpu_ms": usage.cpu_ms,
+            "max_rss_kb": usage.max_rss_kb,
+        });
+    }
+    if let Some(codes) = &result.fragment_exit_codes {
+        metadata["fragment_exit_codes"] = serde_json::json!(codes);
+    }
 
-    if is_error {
-        CallToolResult::error(vec![Content::text(output)])
+    let content = vec![Content::text(output), Content::text(metadata.to_string())];
+    let mut call_result = if is_error {
+        CallToolResult::error(content)
     } else {
-        CallToolResult::success(vec![Content::text(output)])
+        CallToolResult::success(content)
+    };
+    // Same result for programmatic clients, without the stderr delimiter
+    metadata["stdout"] = serde_json::json!(stdout);
+    metadata["stderr"] = serde_json::json!(stderr);
+    call_result.structured_content = Some(metadata);
+    call_result
+}
+
+/// Format an execution result as a single JSON content block
+/// (`output_format: "json"`), with stdout and stderr kept apart.
+fn format_json_result(
+    result: &ExecutionResult,
+    max_output_bytes: usize,
+    truncate: TruncateStrategy,
+) -> CallToolResult {
+    let (stdout, stdout_truncated) = truncate_output(&result.stdout, max_output_bytes, truncate);
+    let (stderr, stderr_truncated) = truncate_output(&result.stderr, max_output_bytes, truncate);
+    let mut json = serde_json::json!({
+        "exit_code": result.exit_code,
+        "stdout": stdout,
+        "stderr": stderr,
+        "truncated": stdout_truncated || stderr_truncated,
+    });
+    if let Some(codes) = &result.fragment_exit_codes {
+        json["fragment_exit_codes"] = serde_json::json!(codes);
+    }
+    json_call_result(json, result.exit_code)
+}
+
+/// Format an execution result as a single JSON content block with stdout
+/// and stderr base64-encoded (`binary: true`). Each stream is cut at
+/// `max_output_bytes` before encoding.
+fn format_binary_result(result: &ExecutionResult, max_output_bytes: usize) -> CallToolResult {
+    let stdout = result.stdout_bytes();
+    let stderr = result.stderr_bytes();
+    let json = serde_json::json!({
+        "exit_code": result.exit_code,
+        "encoding": "base64",
+        "stdout": BASE64_STANDARD.encode(&stdout[..stdout.len().min(max_output_bytes)]),
+        "stderr": BASE64_STANDARD.encode(&stderr[..stderr.len().min(max_output_bytes)]),
+        "truncated": stdout.len() > max_output_bytes || stderr.len() > max_output_bytes,
+    });
+    json_call_result(json, result.exit_code)
+}
+
+/// Run `setup_exec` for `env_name`, failing on a nonzero exit or after
+/// `timeout`.
+///
+/// Not sandboxed: the command runs on the host as the daemon's user, like
+/// the wrappers themselves. Only the last `SETUP_STDERR_BYTES` of its
+/// stderr are kept.
+async fn run_setup(env_name: &str, setup_exec: &str, timeout: Duration) -> anyhow::Result<()> {
+    info!(env = %env_name, setup_exec, "Running environment setup");
+    let mut child = tokio::process::Command::new(setup_exec)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start setup for environment '{env_name}'"))?;
+    let stderr = child.stderr.take().context("setup stderr not captured")?;
+
+    let run = async { tokio::try_join!(child.wait(), read_tail(stderr, SETUP_STDERR_BYTES)) };
+    let (status, stderr) = tokio::time::timeout(timeout, run)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Setup for environment '{env_name}' timed out after {}s; your code did not run.",
+                timeout.as_secs()
+            )
+        })?
+        .with_context(|| format!("Failed to wait for setup of environment '{env_name}'"))?;
+    if !status.success() {
+        let status = status.code().map_or_else(
+            || "a signal".to_string(),
+            |code| format!("exit code {code}"),
+        );
+        anyhow::bail!(
+            "Setup for environment '{env_name}' failed with {status}; your code did not run.\n{}",
+            String::from_utf8_lossy(&stderr).trim_end()
+        );
     }
+    Ok(())
+}
+
+/// Read `reader` to the end, keeping only its last `limit` bytes.
+async fn read_tail(
+    mut reader: impl tokio::io::AsyncRead + Unpin,
+    limit: usize,
+) -> std::io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut tail = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(tail);
+        }
+        tail.extend_from_slice(&chunk[..n]);
+        if tail.len() > limit {
+            tail.drain(..tail.len() - limit);
+        }
+    }
+}
+
+/// Result for an execution that failed without producing output.
+///
+/// The structured content carries the failure's category (see
+/// [`ExecError`]) and whether retrying may help, so clients can tell a
+/// broken environment from a transient fault.
+fn execution_error_result(e: &anyhow::Error) -> CallToolResult {
+    let kind = ExecError::find(e);
+    let category = kind.map_or("other", ExecError::category);
+    let retryable = kind.is_some_and(ExecError::retryable);
+    error!(error = %format!("{e:#}"), category, "Execution failed");
+
+    let mut result = CallToolResult::error(vec![Content::text(format!("Execution error: {e:#}"))]);
+    result.structured_content = Some(serde_json::json!({
+        "error": {
+            "category": category,
+            "retryable": retryable,
+            "message": format!("{e:#}"),
+        },
+    }));
+    result
+}
+
+/// One JSON content block, also set as the structured content.
+fn json_call_result(json: serde_json::Value, exit_code: i32) -> CallToolResult {
+    let content = vec![Content::text(json.to_string())];
+    let mut call_result = if exit_code == 0 {
+        CallToolResult::success(content)
+    } else {
+        CallToolResult::error(content)
+    };
+    call_result.structured_content = Some(json);
+    call_result
 }
 
 #[tool_router]
 impl<B: IsolationBackend + Clone + Send + Sync + 'static> SandboxServer<B> {
     /// Create a new sandbox server.
     pub fn new(config: Config, backend: B, session_manager: Arc<SessionManager>) -> Self {
+        let execution_slots = config
+            .limits
+            .as_ref()
+            .and_then(|l| l.max_concurrent_executions)
+            .map(|max| Arc::new(Semaphore::new(max)));
         Self {
-            config: Arc::new(config),
+            catalog: Arc::new(RwLock::new(Arc::new(Catalog::new(config)))),
+            source: None,
             backend: Arc::new(backend),
             session_manager,
+            execution_slots,
+            start_time: Instant::now(),
+            audit: None,
+            setup_done: Arc::default(),
             tool_router: Self::tool_router(),
         }
     }
 
+    /// Enable the `reload` tool, re-scanning sandboxes from `source`.
+    #[must_use]
+    pub fn with_sandbox_source(mut self, source: SandboxSource) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Record every run call to `sink`.
+    #[must_use]
+    pub fn with_audit_sink(mut self, sink: AuditSink) -> Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
     /// Run code in the specified sandbox environment.
     #[tool(
         description = "Run code in an isolated Nix sandbox with deterministic environments.
@@ -114,243 +827,3985 @@ Use built-in tools for: file edits, git, commands that need the user's real envi
     async fn run(
         &self,
         Parameters(params): Parameters<RunParams>,
+        meta: Meta,
+        client: Peer<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(token) = meta.get_progress_token() else {
+            return self.run_code(params, None).await;
+        };
+
+        // Forward output chunks as progress notifications, in arrival order
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let forwarder = tokio::spawn(async move {
+            let mut seq = 0;
+            while let Some(chunk) = rx.recv().await {
+                seq += 1;
+                let param = chunk_to_progress(&token, seq, chunk);
+                if let Err(e) = client.notify_progress(param).await {
+                    debug!(error = %e, "Failed to send progress notification");
+                }
+            }
+        });
+
+        let result = self.run_code(params, Some(&tx)).await;
+
+        // Closing the channel lets the forwarder drain and finish, so every
+        // notification is sent before the final result
+        drop(tx);
+        let _ = forwarder.await;
+        result
+    }
+
+    /// Run code, sending output chunks to `output` as they arrive.
+    ///
+    /// Only ephemeral execution streams; session output arrives with the
+    /// final result.
+    async fn run_code(
+        &self,
+        params: RunParams,
+        output: Option<&OutputSender>,
+    ) -> Result<CallToolResult, McpError> {
+        // Every log line of this call carries the ID, and session agents get
+        // it as the request id, so one call can be followed across both
+        let request_id = Uuid::new_v4().to_string();
+        let span = info_span!("run", %request_id);
+        self.run_code_as(params, output, &request_id)
+            .instrument(span)
+            .await
+    }
+
+    /// `run_code` for the call identified by `request_id`.
+    async fn run_code_as(
+        &self,
+        params: RunParams,
+        output: Option<&OutputSender>,
+        request_id: &str,
+    ) -> Result<CallToolResult, McpError> {
+        let received = Instant::now();
+        let catalog = self.catalog();
+        let result = self
+            .run_checked(&catalog, &params, output, request_id, received)
+            .await;
+        if let Err(e) = &result {
+            let env = catalog
+                .requested_environment(params.env.as_deref())
+                .unwrap_or_default();
+            self.audit_rejected(request_id, env, &params, &e.message, received)
+                .await;
+        }
+        result
+    }
+
+    /// `run_code_as` short of auditing rejections: an `Err` means the call
+    /// was rejected before anything ran. Rejections reported as a result
+    /// (server busy, failed setup) are audited here.
+    async fn run_checked(
+        &self,
+        catalog: &Catalog,
+        params: &RunParams,
+        output: Option<&OutputSender>,
+        request_id: &str,
+        received: Instant,
     ) -> Result<CallToolResult, McpError> {
-        let env_name = &params.env;
         let code = &params.code;
 
-        // Look up environment
-        let env_meta = self.config.environments.get(env_name).ok_or_else(|| {
-            let available: Vec<_> = self.config.environments.keys().collect();
-            McpError::invalid_params(
-                format!("Unknown environment: '{env_name}'. Available: {available:?}"),
-                None,
-            )
-        })?;
+        // Look up environment; sessions bind to the real name, not the alias
+        let requested = catalog.requested_environment(params.env.as_deref())?;
+        let env = self
+            .environment_for(catalog, requested, params.session.as_deref())
+            .await;
+        let (env_name, env_meta) = env.ok_or_else(|| catalog.unknown_environment(requested))?;
+        params.check_code(catalog.config.max_code_bytes(), env_meta.input_mode)?;
+        // Before taking a slot or running setup, which an invalid call mustn't do
+        let dispatch = params.dispatch(env_name, env_meta)?;
 
         info!(
             env = %env_name,
             code_len = code.len(),
             session = ?params.session,
+            secret_env = ?params.secret_env,
             "Running code"
         );
 
-        // Resolve project dir for runtime mounting
-        let project_dir = self.config.resolved_project_dir();
-        let project_mount = self.config.project_mount();
+        let timeout = env_meta.effective_timeout(params.timeout_seconds);
+
+        let mounts = RunParams::mounts(&catalog.config)?;
+        let options = params.exec_options(&catalog.config, &mounts)?;
+
+        // Held until the execution finishes, for sessions and ephemeral runs alike
+        let _slot = match self.acquire_execution_slot().await {
+            Ok(slot) => slot,
+            Err(busy) => {
+                self.audit_rejected(request_id, env_name, params, "server busy", received)
+                    .await;
+                return Ok(busy);
+            }
+        };
+
+        if let Err(e) = self.ensure_setup(env_name, env_meta).await {
+            let reason = format!("setup failed: {e:#}");
+            self.audit_rejected(request_id, env_name, params, &reason, received)
+                .await;
+            return Ok(execution_error_result(&e));
+        }
 
         // Dispatch: session → SessionManager, no session → ephemeral backend
-        let result = if let Some(ref session_id) = params.session {
-            self.session_manager
-                .execute(
-                    session_id,
-                    env_name,
-                    env_meta,
-                    code,
-                    project_dir.as_deref(),
-                    &project_mount,
+        let started = Instant::now();
+        let result = match dispatch {
+            Dispatch::Session(session_id) => {
+                self.run_in_session(
+                    session_id, request_id, env_name, env_meta, params, timeout, &mounts,
                 )
                 .await
-        } else {
-            self.backend
-                .execute(env_meta, code, project_dir.as_deref(), &project_mount)
-                .await
+            }
+            Dispatch::Ephemeral(code) => {
+                self.backend
+                    .execute(
+                        env_meta,
+                        code,
+                        timeout,
+                        params.stdin.as_deref(),
+                        &mounts,
+                        &options,
+                        output,
+                    )
+                    .await
+            }
         };
+        self.audit_run(request_id, env_name, env_meta, params, &result, started)
+            .await;
 
         Ok(match result {
-            Ok(exec_result) => format_result(
-                exec_result.exit_code,
-                exec_result.stdout,
-                exec_result.stderr,
-            ),
-            Err(e) => {
-                error!(error = %e, "Execution failed");
-                CallToolResult::error(vec![Content::text(format!("Execution error: {e}"))])
+            Ok(exec_result) => {
+                info!(
+                    exit_code = exec_result.exit_code,
+                    duration_ms = exec_result.duration.as_millis(),
+                    timed_out = exec_result.timed_out,
+                    "Execution finished"
+                );
+                let max = env_meta.max_output_bytes;
+                let truncate = catalog.config.truncate_strategy();
+                if params.binary {
+                    format_binary_result(&exec_result, max)
+                } else {
+                    match params.output_format {
+                        OutputFormat::Text => format_result(
+                            &exec_result,
+                            max,
+                            catalog.config.stderr_delimiter(),
+                            truncate,
+                        ),
+                        OutputFormat::Json => format_json_result(&exec_result, max, truncate),
+                    }
+                }
             }
+            Err(e) => execution_error_result(&e),
         })
     }
-}
 
-#[tool_handler]
-impl<B: IsolationBackend + Clone + Send + Sync + 'static> ServerHandler for SandboxServer<B> {
-    fn get_info(&self) -> ServerInfo {
-        let envs: Vec<_> = self.config.environments.keys().collect();
+    /// Run the environment's `setup_exec` if it hasn't succeeded yet in
+    /// this daemon's lifetime.
+    ///
+    /// Concurrent first runs wait for one setup rather than each starting
+    /// their own. A failed setup isn't recorded, so the next run tries again.
+    async fn ensure_setup(&self, env_name: &str, env_meta: &EnvironmentMeta) -> anyhow::Result<()> {
+        let Some(setup_exec) = &env_meta.setup_exec else {
+            return Ok(());
+        };
+        let done = Arc::clone(
+            self.setup_done
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(env_name.to_string())
+                .or_default(),
+        );
+        let timeout = Duration::from_secs(env_meta.setup_timeout_seconds);
+        done.get_or_try_init(|| run_setup(env_name, setup_exec, timeout))
+            .await?;
+        Ok(())
+    }
 
-        // Build environment descriptions
-        let env_list = envs
-            .iter()
-            .map(|e| format!("- {e}"))
+    /// Append the outcome of a run call to the audit log, if there is one.
+    async fn audit_run(
+        &self,
+        request_id: &str,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        params: &RunParams,
+        result: &anyhow::Result<ExecutionResult>,
+        started: Instant,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let max = env_meta.max_output_bytes;
+        let exec = result.as_ref().ok();
+        audit
+            .record(&AuditRecord {
+                timestamp_ms: unix_now_ms(),
+                request_id,
+                env: env_name,
+                session: params.session.as_deref(),
+                code_hash: audit_code_hash(audit, &params.code),
+                exit_code: exec.map(|r| r.exit_code),
+                duration_ms: exec
+                    .map_or_else(|| started.elapsed(), |r| r.duration)
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(u64::MAX),
+                timed_out: exec.is_some_and(|r| r.timed_out),
+                truncated: exec.is_some_and(|r| {
+                    if params.binary {
+                        r.stdout_bytes().len() > max || r.stderr_bytes().len() > max
+                    } else {
+                        r.stdout.len() > max || r.stderr.len() > max
+                    }
+                }),
+                error: result.as_ref().err().map(|e| format!("{e:#}")),
+            })
+            .await;
+    }
+
+    /// Record a run call rejected before it ran, if there's an audit log.
+    async fn audit_rejected(
+        &self,
+        request_id: &str,
+        env_name: &str,
+        params: &RunParams,
+        reason: &str,
+        received: Instant,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        audit
+            .record(&AuditRecord {
+                timestamp_ms: unix_now_ms(),
+                request_id,
+                env: env_name,
+                session: params.session.as_deref(),
+                code_hash: audit_code_hash(audit, &params.code),
+                exit_code: None,
+                duration_ms: received
+                    .elapsed()
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(u64::MAX),
+                timed_out: false,
+                truncated: false,
+                error: Some(reason.to_string()),
+            })
+            .await;
+    }
+
+    /// Dispatch `params.code` to a session: a single program, or fragments
+    /// as one batch.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_in_session(
+        &self,
+        session_id: &str,
+        request_id: &str,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        params: &RunParams,
+        timeout: Duration,
+        mounts: &Mounts,
+    ) -> anyhow::Result<ExecutionResult> {
+        if params.rebind {
+            self.session_manager.rebind(session_id, env_name).await;
+        }
+        match &params.code {
+            Code::Single(code) => {
+                self.session_manager
+                    .execute(
+                        session_id,
+                        request_id,
+                        env_name,
+                        env_meta,
+                        code,
+                        timeout,
+                        mounts,
+                        params.recreate_on_death,
+                    )
+                    .await
+            }
+            Code::Fragments(fragments) => {
+                self.session_manager
+                    .execute_batch(
+                        session_id,
+                        request_id,
+                        env_name,
+                        env_meta,
+                        fragments,
+                        !params.continue_on_error,
+                        timeout,
+                        mounts,
+                        params.recreate_on_death,
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// List live sessions with their age, idle time, and time until reaped.
+    #[tool(
+        description = "List active sessions: environment, age, idle time, and seconds until the session is reaped."
+    )]
+    async fn list_sessions(&self) -> Result<CallToolResult, McpError> {
+        let infos = self.session_manager.list().await;
+        if infos.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "No active sessions",
+            )]));
+        }
+
+        let lines = infos
+            .iter()
+            .map(|i| {
+                format!(
+                    "- {} (env: {}) age {}s, idle {}s, reaped in {}s{}",
+                    i.id,
+                    i.env_name,
+                    i.age.as_secs(),
+                    i.idle.as_secs(),
+                    i.expires_in.as_secs(),
+                    if i.suspended { ", suspended" } else { "" }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(CallToolResult::success(vec![Content::text(lines)]))
+    }
+
+    /// Count live sessions per environment.
+    #[tool(
+        description = "Count active sessions per environment, with the creation times (Unix seconds) of the oldest and newest session."
+    )]
+    async fn session_stats(&self) -> Result<CallToolResult, McpError> {
+        let stats = self.session_manager.stats().await;
+        let unix_secs = |t: Option<std::time::SystemTime>| {
+            t.map(|t| {
+                t.duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            })
+        };
+        let (oldest, newest) = (
+            unix_secs(stats.oldest_created_at),
+            unix_secs(stats.newest_created_at),
+        );
+
+        let mut text = format!("{} active session(s)", stats.total());
+        if let (Some(oldest), Some(newest)) = (oldest, newest) {
+            let per_env = stats
+                .by_env
+                .iter()
+                .map(|(env, count)| format!("{env}: {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let _ = write!(
+                text,
+                " ({per_env}); oldest created at {oldest}, newest at {newest}"
+            );
+        }
+        let mut result = CallToolResult::success(vec![Content::text(text)]);
+        result.structured_content = Some(serde_json::json!({
+            "total": stats.total(),
+            "by_env": stats.by_env,
+            "oldest_created_at": oldest,
+            "newest_created_at": newest,
+        }));
+        Ok(result)
+    }
+
+    /// Report which agent process backs a session.
+    #[tool(
+        description = "Show the process behind a session: agent PID and start time (Unix seconds), plus age and idle time. A changed PID or start time between calls means the session was recreated and lost its state."
+    )]
+    async fn session_info(
+        &self,
+        Parameters(params): Parameters<SessionInfoParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(info) = self.session_manager.info(&params.session).await else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Session '{}' not found",
+                params.session
+            ))]));
+        };
+
+        let started_at = info
+            .started_at
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let pid = info
+            .pid
+            .map_or_else(|| "not local".to_string(), |pid| pid.to_string());
+        let mut result = CallToolResult::success(vec![Content::text(format!(
+            "Session '{}' (env: {}): pid {pid}, started at {started_at}, age {}s, idle {}s",
+            info.id,
+            info.env_name,
+            info.age.as_secs(),
+            info.idle.as_secs()
+        ))]);
+        result.structured_content = Some(serde_json::json!({
+            "session": info.id,
+            "env": info.env_name,
+            "pid": info.pid,
+            "started_at": started_at,
+            "age_secs": info.age.as_secs(),
+            "idle_secs": info.idle.as_secs(),
+            "suspended": info.suspended,
+        }));
+        Ok(result)
+    }
+
+    /// List a session's recent executions.
+    #[tool(
+        description = "List a session's recent executions, oldest first: request ID, code digest, exit code, and finish time (Unix milliseconds). Empty unless the server keeps session history."
+    )]
+    async fn session_history(
+        &self,
+        Parameters(params): Parameters<SessionHistoryParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(history) = self.session_manager.history(&params.session).await else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Session '{}' not found",
+                params.session
+            ))]));
+        };
+
+        let text = if history.is_empty() {
+            format!("No history for session '{}'", params.session)
+        } else {
+            history
+                .iter()
+                .map(|e| {
+                    let exit = e
+                        .exit_code
+                        .map_or_else(|| "timed out".to_string(), |code| format!("exit {code}"));
+                    format!(
+                        "{} {} {}: {exit}",
+                        e.timestamp_ms, e.request_id, e.code_hash
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let mut result = CallToolResult::success(vec![Content::text(text)]);
+        result.structured_content = Some(serde_json::json!({
+            "session": params.session,
+            "executions": history,
+        }));
+        Ok(result)
+    }
+
+    /// Close a session and shut down its interpreter.
+    #[tool(
+        description = "Close a session and discard its interpreter state. Closing an unknown session is not an error."
+    )]
+    async fn close_session(
+        &self,
+        Parameters(params): Parameters<CloseSessionParams>,
+    ) -> Result<CallToolResult, McpError> {
+        Ok(match self.session_manager.close(&params.session).await {
+            Ok(true) => CallToolResult::success(vec![Content::text(format!(
+                "Session '{}' closed",
+                params.session
+            ))]),
+            Ok(false) => CallToolResult::success(vec![Content::text(format!(
+                "No session '{}' to close",
+                params.session
+            ))]),
+            Err(e) => CallToolResult::error(vec![Content::text(format!("Close failed: {e}"))]),
+        })
+    }
+
+    /// Restart a session with a fresh interpreter, keeping its ID and environment.
+    #[tool(
+        description = "Restart a session's interpreter, discarding its state (variables, imports) but keeping the session ID and environment. Use when a session is stuck or polluted."
+    )]
+    async fn restart_session(
+        &self,
+        Parameters(params): Parameters<RestartSessionParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let restart = async {
+            let env_name = self
+                .session_manager
+                .env_name(&params.session)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Session '{}' not found", params.session))?;
+            let catalog = self.catalog();
+            let env_meta = catalog.environment(&env_name).ok_or_else(|| {
+                anyhow::anyhow!("Environment '{env_name}' is no longer configured")
+            })?;
+            let mounts = catalog.config.mounts()?;
+            self.session_manager
+                .restart(&params.session, env_meta, &mounts)
+                .await
+        };
+
+        Ok(match restart.await {
+            Ok(()) => CallToolResult::success(vec![Content::text(format!(
+                "Session '{}' restarted with a fresh interpreter",
+                params.session
+            ))]),
+            Err(e) => CallToolResult::error(vec![Content::text(format!("Restart failed: {e:#}"))]),
+        })
+    }
+
+    /// Clear a session's interpreter state in place, without a respawn.
+    #[tool(
+        description = "Reset a session's interpreter in place: clears variables and imports but keeps the agent process and /workspace files. Cheaper than restart_session; fails if the session's agent doesn't support it."
+    )]
+    async fn reset_session(
+        &self,
+        Parameters(params): Parameters<ResetSessionParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let request_id = Uuid::new_v4().to_string();
+        Ok(
+            match self
+                .session_manager
+                .reset(&params.session, &request_id)
+                .await
+            {
+                Ok(ResetOutcome::Reset) => CallToolResult::success(vec![Content::text(format!(
+                    "Session '{}' reset; /workspace files were kept",
+                    params.session
+                ))]),
+                Ok(ResetOutcome::Unsupported) => {
+                    CallToolResult::error(vec![Content::text(format!(
+                        "Session '{}' doesn't support reset; use restart_session instead",
+                        params.session
+                    ))])
+                }
+                Err(e) => {
+                    CallToolResult::error(vec![Content::text(format!("Reset failed: {e:#}"))])
+                }
+            },
+        )
+    }
+
+    /// Set an environment variable for a session's later executions.
+    #[tool(
+        description = "Set an environment variable in a session; every later execution in the session sees it (e.g. a feature flag). The session must already exist."
+    )]
+    async fn set_session_env(
+        &self,
+        Parameters(params): Parameters<SetSessionEnvParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let request_id = Uuid::new_v4().to_string();
+        let set = self
+            .session_manager
+            .set_env(&params.session, &request_id, &params.key, &params.value)
+            .await;
+        Ok(match set {
+            Ok(()) => CallToolResult::success(vec![Content::text(format!(
+                "Set {} in session '{}'",
+                params.key, params.session
+            ))]),
+            Err(e) => CallToolResult::error(vec![Content::text(format!(
+                "Setting session env failed: {e:#}"
+            ))]),
+        })
+    }
+
+    /// Read an environment variable as a session's agent sees it.
+    #[tool(
+        description = "Read an environment variable in a session, as set by set_session_env or inherited by the session."
+    )]
+    async fn get_session_env(
+        &self,
+        Parameters(params): Parameters<GetSessionEnvParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let request_id = Uuid::new_v4().to_string();
+        let value = match self
+            .session_manager
+            .get_env(&params.session, &request_id, &params.key)
+            .await
+        {
+            Ok(value) => value,
+            Err(e) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Reading session env failed: {e:#}"
+                ))]))
+            }
+        };
+
+        let text = value.as_ref().map_or_else(
+            || format!("{} is not set in session '{}'", params.key, params.session),
+            |value| format!("{}={value}", params.key),
+        );
+        let mut result = CallToolResult::success(vec![Content::text(text)]);
+        result.structured_content = Some(serde_json::json!({
+            "key": params.key,
+            "value": value,
+        }));
+        Ok(result)
+    }
+
+    /// Return the full metadata of one environment.
+    #[tool(
+        description = "Describe one environment: backend, interpreter, session support, limits, and aliases. Host paths are omitted unless redact_paths is false."
+    )]
+    async fn describe_environment(
+        &self,
+        Parameters(params): Parameters<DescribeEnvironmentParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let catalog = self.catalog();
+        let (name, meta) = catalog
+            .resolve(&params.env)
+            .ok_or_else(|| catalog.unknown_environment(&params.env))?;
+
+        let mut json = environment_metadata(name, meta, &catalog.config.interpreter_map);
+        json["interpreter_type"] = meta.interpreter_type.clone().into();
+        json["aliases"] = catalog.aliases_of(name).into();
+        json["network"] = meta.network.into();
+        json["interpreter_args"] = meta.interpreter_args.clone().into();
+        if !params.redact_paths {
+            json["exec"] = meta.exec.clone().into();
+            json["session_exec"] = meta.session_exec.clone().into();
+        }
+        Ok(json_call_result(json, 0))
+    }
+
+    /// List environment names matching a pattern, without running anything.
+    #[tool(
+        description = "Find environments whose names match a glob ('py*') or contain a substring. Only lists names; run still needs the exact name."
+    )]
+    async fn find_environments(
+        &self,
+        Parameters(params): Parameters<FindEnvironmentsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let catalog = self.catalog();
+        let names = catalog.find(&params.pattern).map_err(|e| {
+            McpError::invalid_params(format!("Invalid pattern '{}': {e}", params.pattern), None)
+        })?;
+
+        let text = if names.is_empty() {
+            format!("No environments match '{}'", params.pattern)
+        } else {
+            names.join("\n")
+        };
+        let mut result = CallToolResult::success(vec![Content::text(text)]);
+        result.structured_content = Some(serde_json::json!({
+            "pattern": params.pattern,
+            "environments": names,
+        }));
+        Ok(result)
+    }
+
+    /// Report server health without touching any sandbox.
+    #[tool(
+        description = "Health check: server uptime, active session count, and configured environment count. Spawns nothing."
+    )]
+    async fn ping(&self) -> Result<CallToolResult, McpError> {
+        let uptime_seconds = self.start_time.elapsed().as_secs();
+        let sessions = self.session_manager.session_count().await;
+        let environments = self.catalog().config.environments.len();
+
+        let mut result = CallToolResult::success(vec![Content::text(format!(
+            "ok: up {uptime_seconds}s, {sessions} active session(s), {environments} environment(s)"
+        ))]);
+        result.structured_content = Some(serde_json::json!({
+            "uptime_seconds": uptime_seconds,
+            "active_sessions": sessions,
+            "environments": environments,
+        }));
+        Ok(result)
+    }
+
+    /// Report exactly which daemon build is serving, for bug reports.
+    #[tool(
+        description = "Daemon build info: crate version, git commit, rustc version, and the agent protocol versions it speaks."
+    )]
+    async fn version(&self) -> Result<CallToolResult, McpError> {
+        let version = env!("CARGO_PKG_VERSION");
+        let commit = env!("NIX_SANDBOX_GIT_COMMIT");
+        let rustc = env!("NIX_SANDBOX_RUSTC_VERSION");
+
+        let mut result = CallToolResult::success(vec![Content::text(format!(
+            "nix-sandbox-mcp-daemon {version} ({commit}), built with {rustc}, \
+             agent protocol v{MIN_SUPPORTED_PROTOCOL}-v{SUPPORTED_PROTOCOL}"
+        ))]);
+        result.structured_content = Some(serde_json::json!({
+            "version": version,
+            "git_commit": commit,
+            "rustc": rustc,
+            "agent_protocol": {
+                "min": MIN_SUPPORTED_PROTOCOL,
+                "max": SUPPORTED_PROTOCOL,
+            },
+        }));
+        Ok(result)
+    }
+
+    /// Re-scan the sandbox directory and swap in the resulting environments.
+    #[tool(
+        description = "Re-scan the custom sandbox directory so newly built sandboxes can be used without restarting the server. Sessions keep working even if their environment was removed."
+    )]
+    async fn reload(&self) -> Result<CallToolResult, McpError> {
+        let Some(source) = &self.source else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Reload failed: no sandbox directory configured",
+            )]));
+        };
+        let config = source.load();
+        let bound: HashSet<String> = self
+            .session_manager
+            .list()
+            .await
+            .into_iter()
+            .map(|info| info.env_name)
+            .collect();
+
+        let mut current = self.catalog.write().unwrap_or_else(PoisonError::into_inner);
+        let next = current.reloaded(config, &bound);
+        let old = &current.config.environments;
+        let new = &next.config.environments;
+        let mut added: Vec<&str> = new
+            .keys()
+            .filter(|name| !old.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        let mut removed: Vec<&str> = old
+            .keys()
+            .filter(|name| !new.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        added.sort_unstable();
+        removed.sort_unstable();
+
+        let mut text = format!(
+            "Reloaded sandboxes from {}: {} environment(s)",
+            source.dir.display(),
+            new.len()
+        );
+        if !added.is_empty() {
+            let _ = write!(text, "\nAdded: {}", added.join(", "));
+        }
+        if !removed.is_empty() {
+            let _ = write!(text, "\nRemoved: {}", removed.join(", "));
+        }
+        info!(?added, ?removed, "Reloaded sandboxes");
+        *current = Arc::new(next);
+        drop(current);
+
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// Interrupt the execution currently running in a session.
+    #[tool(
+        description = "Interrupt code currently running in a session. The pending run call returns with exit code 130; session state is kept."
+    )]
+    async fn cancel(
+        &self,
+        Parameters(params): Parameters<CancelParams>,
+    ) -> Result<CallToolResult, McpError> {
+        Ok(match self.session_manager.cancel(&params.session).await {
+            Ok(()) => CallToolResult::success(vec![Content::text(format!(
+                "Cancellation requested for session '{}'",
+                params.session
+            ))]),
+            Err(e) => CallToolResult::error(vec![Content::text(format!("Cancel failed: {e}"))]),
+        })
+    }
+}
+
+impl<B: Clone + Sync> SandboxServer<B> {
+    /// Snapshot of the current config.
+    fn catalog(&self) -> Arc<Catalog> {
+        Arc::clone(&self.catalog.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Environment a run should use: a configured name or alias, or a
+    /// retired environment that `session` is still bound to.
+    async fn environment_for<'a>(
+        &self,
+        catalog: &'a Catalog,
+        name: &str,
+        session: Option<&str>,
+    ) -> Option<(&'a str, &'a EnvironmentMeta)> {
+        if let Some(env) = catalog.resolve(name) {
+            return Some(env);
+        }
+        let (name, meta) = catalog.retired.get_key_value(name)?;
+        let bound = self.session_manager.env_name(session?).await?;
+        (bound == *name).then_some((name.as_str(), meta))
+    }
+
+    /// Take one of the `max_concurrent_executions` slots, if capped.
+    ///
+    /// With the cap reached, waits for a slot under `BusyPolicy::Queue`, and
+    /// otherwise returns the "server busy" result to send back instead.
+    async fn acquire_execution_slot(&self) -> Result<Option<SemaphorePermit<'_>>, CallToolResult> {
+        let Some(slots) = &self.execution_slots else {
+            return Ok(None);
+        };
+        let limits = self.catalog().config.limits.clone().unwrap_or_default();
+
+        if let Ok(permit) = slots.try_acquire() {
+            return Ok(Some(permit));
+        }
+        if limits.when_busy == BusyPolicy::Queue {
+            debug!("Execution limit reached, queueing");
+            // The semaphore is never closed, so acquire can't fail
+            return Ok(slots.acquire().await.ok());
+        }
+
+        warn!("Execution limit reached, rejecting call");
+        Err(CallToolResult::error(vec![Content::text(format!(
+            "Server busy: {} executions already running (max_concurrent_executions). \
+             Retry later.",
+            limits.max_concurrent_executions.unwrap_or_default()
+        ))]))
+    }
+
+    /// One resource per configured environment, sorted by name.
+    fn environment_resources(&self) -> Vec<Resource> {
+        let catalog = self.catalog();
+        let mut names: Vec<_> = catalog.config.environments.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let mut resource = RawResource::new(format!("{ENV_RESOURCE_PREFIX}{name}"), name);
+                resource.description = Some(format!("Metadata for the '{name}' environment"));
+                resource.mime_type = Some("application/json".into());
+                resource.no_annotation()
+            })
+            .collect()
+    }
+
+    /// Read the environment resource at `uri`.
+    fn read_environment_resource(&self, uri: &str) -> Result<ReadResourceResult, McpError> {
+        let catalog = self.catalog();
+        let meta = uri
+            .strip_prefix(ENV_RESOURCE_PREFIX)
+            .and_then(|name| Some((name, catalog.config.environments.get(name)?)));
+        let Some((name, meta)) = meta else {
+            return Err(McpError::resource_not_found(
+                format!("Unknown resource: {uri}"),
+                None,
+            ));
+        };
+
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::TextResourceContents {
+                uri: uri.to_string(),
+                mime_type: Some("application/json".into()),
+                text: environment_metadata(name, meta, &catalog.config.interpreter_map).to_string(),
+                meta: None,
+            }],
+        })
+    }
+}
+
+/// A loaded config and what is derived from it; `reload` replaces it whole.
+struct Catalog {
+    config: Config,
+    /// Alias → real environment name.
+    aliases: HashMap<String, String>,
+    /// Environments dropped by a reload, kept for sessions still bound to them.
+    retired: HashMap<String, EnvironmentMeta>,
+}
+
+impl Catalog {
+    fn new(config: Config) -> Self {
+        let aliases = config.alias_map();
+        Self {
+            config,
+            aliases,
+            retired: HashMap::new(),
+        }
+    }
+
+    /// Catalog for `config`, retiring any environment it drops that a
+    /// session still uses (`bound` holds the sessions' environment names).
+    fn reloaded(&self, config: Config, bound: &HashSet<String>) -> Self {
+        let mut next = Self::new(config);
+        next.retired = self
+            .config
+            .environments
+            .iter()
+            .chain(&self.retired)
+            .filter(|(name, _)| {
+                bound.contains(*name) && !next.config.environments.contains_key(*name)
+            })
+            .map(|(name, meta)| (name.clone(), meta.clone()))
+            .collect();
+        next
+    }
+
+    /// Look up a configured environment by name or alias, returning its
+    /// real name.
+    fn resolve(&self, name: &str) -> Option<(&str, &EnvironmentMeta)> {
+        let name = self.aliases.get(name).map_or(name, String::as_str);
+        self.config
+            .environments
+            .get_key_value(name)
+            .map(|(name, meta)| (name.as_str(), meta))
+    }
+
+    /// Aliases that resolve to environment `name` (colliding ones excluded).
+    fn aliases_of(&self, name: &str) -> Vec<&str> {
+        self.config.environments[name]
+            .aliases
+            .iter()
+            .filter(|alias| self.aliases.get(*alias).is_some_and(|n| n == name))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Environment names matching `pattern`, sorted. A pattern with glob
+    /// characters (`*`, `?`, `[`) must match the whole name; any other is
+    /// matched as a substring.
+    fn find(&self, pattern: &str) -> Result<Vec<&str>, glob::PatternError> {
+        let matches: Box<dyn Fn(&str) -> bool> = if pattern.contains(['*', '?', '[']) {
+            let glob = glob::Pattern::new(pattern)?;
+            Box::new(move |name| glob.matches(name))
+        } else {
+            Box::new(|name| name.contains(pattern))
+        };
+        let mut names: Vec<_> = self
+            .config
+            .environments
+            .keys()
+            .map(String::as_str)
+            .filter(|name| matches(name))
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Error for a name that is neither an environment nor an alias.
+    ///
+    /// The available names are also the error's `data`, as a JSON array,
+    /// so clients can pick one without parsing the message.
+    fn unknown_environment(&self, name: &str) -> McpError {
+        let mut available: Vec<_> = self.config.environments.keys().collect();
+        available.sort();
+        McpError::invalid_params(
+            format!("Unknown environment: '{name}'. Available: {available:?}"),
+            Some(serde_json::json!(available)),
+        )
+    }
+
+    /// The environment a run call asked for, else `default_env`. Fails if
+    /// there's neither.
+    fn requested_environment<'a>(&'a self, env: Option<&'a str>) -> Result<&'a str, McpError> {
+        if let Some(env) = env.or(self.config.default_env.as_deref()) {
+            return Ok(env);
+        }
+        let mut available: Vec<_> = self.config.environments.keys().collect();
+        available.sort();
+        Err(McpError::invalid_params(
+            format!("No environment given and no default_env configured. Available: {available:?}"),
+            Some(serde_json::json!(available)),
+        ))
+    }
+
+    /// A configured or retired environment, by real name.
+    fn environment(&self, name: &str) -> Option<&EnvironmentMeta> {
+        self.config
+            .environments
+            .get(name)
+            .or_else(|| self.retired.get(name))
+    }
+}
+
+/// Client-facing metadata for one environment (no host paths).
+fn environment_metadata(
+    name: &str,
+    meta: &EnvironmentMeta,
+    interpreter_map: &HashMap<String, String>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "name": name,
+        "backend": meta.backend,
+        "interpreter": env_to_interpreter(name, meta, interpreter_map),
+        "sessions": meta.supports_sessions(),
+        "limits": {
+            "timeout_seconds": meta.timeout_seconds,
+            "max_timeout_seconds": meta.max_timeout_seconds.unwrap_or(meta.timeout_seconds),
+            "memory_mb": meta.memory_mb,
+            "max_output_bytes": meta.max_output_bytes,
+        },
+    })
+}
+
+/// Plain-text table of `config`'s environments, sorted by name, for `--list`.
+pub fn environment_table(config: &Config) -> String {
+    let mut names: Vec<&String> = config.environments.keys().collect();
+    names.sort();
+
+    let header = [
+        "NAME",
+        "BACKEND",
+        "INTERPRETER",
+        "SESSIONS",
+        "TIMEOUT",
+        "MEMORY",
+    ]
+    .map(String::from);
+    let rows: Vec<[String; 6]> = names
+        .into_iter()
+        .map(|name| {
+            let meta = &config.environments[name];
+            let sessions = meta.supports_sessions();
+            [
+                name.clone(),
+                meta.backend.as_str().to_string(),
+                env_to_interpreter(name, meta, &config.interpreter_map),
+                if sessions { "yes" } else { "no" }.to_string(),
+                format!("{}s", meta.timeout_seconds),
+                format!("{} MB", meta.memory_mb),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    std::iter::once(&header)
+        .chain(&rows)
+        .fold(String::new(), |mut out, row| {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ");
+            out.push_str(line.trim_end());
+            out.push('\n');
+            out
+        })
+}
+
+/// Built-in server instructions: the environments, how to call `run`,
+/// sessions, and the configured mounts.
+fn default_instructions(config: &Config, env_list: &str) -> String {
+    let env_usage = if config.default_env.is_some() {
+        "optional, defaults to the one marked [default]"
+    } else {
+        "required"
+    };
+    let mut desc = format!(
+        "Run commands in isolated Nix sandbox environments.\n\
+         \n\
+         Available environments:\n\
+         {env_list}\n\
+         \n\
+         Use the 'run' tool with:\n\
+         - code: the code to run\n\
+         - env: one of the available environments ({env_usage})\n\
+         \n\
+         Choose the environment based on what tools your code needs."
+    );
+
+    // Add session info
+    desc.push_str(
+        "\n\nEphemeral by default: each call starts clean. \
+         Use sessions for multi-step work (install deps → run → inspect). \
+         Pass a `session` ID to persist variables, imports, and /workspace files across calls. \
+         Each session is bound to its creation environment.\
+         \n\nOn failure, check stderr and exit code before retrying.",
+    );
+
+    // Add project info if configured (env var or TOML)
+    for mount in config.project_mounts().unwrap_or_default() {
+        let access = if mount.read_only {
+            "read-only"
+        } else {
+            "read-write"
+        };
+        if mount.name == "project" {
+            let _ = write!(
+                desc,
+                "\n\nProject directory mounted at {} ({access}).",
+                mount.mount_point
+            );
+        } else {
+            let _ = write!(
+                desc,
+                "\n\nDirectory '{}' mounted at {} ({access}).",
+                mount.name, mount.mount_point
+            );
+        }
+    }
+    if matches!(config.resolved_scratch_dir(), Ok(Some(_))) {
+        let _ = write!(
+            desc,
+            "\n\nScratch directory mounted at {} (read-write, persists across calls).",
+            config.scratch_mount()
+        );
+    }
+    desc
+}
+
+/// Replace each `{name}` in `template` with its value from `values`, in a
+/// single pass (values aren't searched for placeholders). Unknown
+/// placeholders are left as written.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let filled = values.iter().find_map(|(name, value)| {
+            let tail = rest
+                .strip_prefix('{')?
+                .strip_prefix(name)?
+                .strip_prefix('}')?;
+            Some((value, tail))
+        });
+        if let Some((value, tail)) = filled {
+            out.push_str(value);
+            rest = tail;
+        } else {
+            out.push('{');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[tool_handler]
+impl<B: IsolationBackend + Clone + Send + Sync + 'static> ServerHandler for SandboxServer<B> {
+    fn get_info(&self) -> ServerInfo {
+        let catalog = self.catalog();
+        let config = &catalog.config;
+        let envs: Vec<_> = config.environments.keys().collect();
+
+        // Build environment descriptions
+        let env_list = envs
+            .iter()
+            .map(|e| {
+                let aliases = catalog.aliases_of(e);
+                let default = if config.default_env.as_deref() == Some(e.as_str()) {
+                    " [default]"
+                } else {
+                    ""
+                };
+                if aliases.is_empty() {
+                    format!("- {e}{default}")
+                } else {
+                    format!("- {e}{default} (aliases: {})", aliases.join(", "))
+                }
+            })
             .collect::<Vec<_>>()
             .join("\n");
 
-        // Build base description
-        let mut desc = format!(
-            "Run commands in isolated Nix sandbox environments.\n\
-             \n\
-             Available environments:\n\
-             {env_list}\n\
-             \n\
-             Use the 'run' tool with:\n\
-             - code: the code to run\n\
-             - env: one of the available environments (required)\n\
-             \n\
-             Choose the environment based on what tools your code needs."
-        );
-
-        // Add session info
-        desc.push_str(
-            "\n\nEphemeral by default: each call starts clean. \
-             Use sessions for multi-step work (install deps → run → inspect). \
-             Pass a `session` ID to persist variables, imports, and /workspace files across calls. \
-             Each session is bound to its creation environment.\
-             \n\nOn failure, check stderr and exit code before retrying.",
-        );
-
-        // Add project info if configured (env var or TOML)
-        if self.config.resolved_project_dir().is_some() {
-            desc.push_str(&format!(
-                "\n\nProject directory mounted at {} (read-only).",
-                self.config.project_mount()
-            ));
+        let desc = config.instructions_template.as_deref().map_or_else(
+            || default_instructions(config, &env_list),
+            |template| {
+                let project_mount = config
+                    .project_mounts()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|m| m.name == "project")
+                    .map(|m| m.mount_point)
+                    .unwrap_or_default();
+                fill_template(
+                    template,
+                    &[("env_list", &env_list), ("project_mount", &project_mount)],
+                )
+            },
+        );
+
+        ServerInfo {
+            protocol_version: rmcp::model::ProtocolVersion::V_2024_11_05,
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
+            server_info: Implementation {
+                name: "nix-sandbox-mcp".into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+                title: None,
+                icons: None,
+                website_url: None,
+            },
+            instructions: Some(desc),
+        }
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParams>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        Ok(ListResourcesResult::with_all_items(
+            self.environment_resources(),
+        ))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParams,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        self.read_environment_resource(&request.uri)
+    }
+}
+
+/// Serve the sandbox server over stdio.
+///
+/// Starts the session reaper, serves MCP, then cleans up all sessions on disconnect.
+pub async fn serve_stdio<B: IsolationBackend + Clone + Send + Sync + 'static>(
+    config: Config,
+    backend: B,
+    session_manager: Arc<SessionManager>,
+    source: SandboxSource,
+    persist: bool,
+) -> anyhow::Result<()> {
+    info!("Starting MCP server on stdio");
+    serve(config, backend, session_manager, source, persist, stdio()).await
+}
+
+/// Serve the sandbox server to the single client on `transport`.
+///
+/// Starts the session reaper, serves MCP, then cleans up all sessions on
+/// disconnect, or with `persist` on shutdown.
+pub async fn serve<B, T, E, A>(
+    config: Config,
+    backend: B,
+    session_manager: Arc<SessionManager>,
+    source: SandboxSource,
+    persist: bool,
+    transport: T,
+) -> anyhow::Result<()>
+where
+    B: IsolationBackend + Clone + Send + Sync + 'static,
+    T: IntoTransport<RoleServer, E, A> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let server = build_server(config, backend, &session_manager, source)?;
+    let reaper_handle = session_manager.start_reaper();
+
+    let service = match server.serve(transport).await {
+        Ok(service) => service,
+        Err(e) => {
+            reaper_handle.stop().await;
+            anyhow::bail!("Failed to start MCP server: {e}");
+        }
+    };
+
+    // Tell the client about sessions coming and going
+    let (clients, events_handle) = notify_clients(&session_manager);
+    add_client(&clients, service.peer().clone());
+
+    let cancel = service.cancellation_token();
+    let result = run_until_shutdown(
+        async {
+            service
+                .waiting()
+                .await
+                .map(|_| ())
+                .map_err(|e| anyhow::anyhow!("MCP server error: {e}"))
+        },
+        shutdown_signal(),
+        move || cancel.cancel(),
+        reaper_handle,
+        &session_manager,
+        persist,
+    )
+    .await;
+    events_handle.abort();
+    result
+}
+
+/// Serve the sandbox server on a UNIX domain socket at `path`, to any
+/// number of clients at once.
+///
+/// Sessions belong to the daemon, not to a connection: every client sees
+/// the same session IDs, and a disconnect leaves sessions running, as with
+/// `--persist`. Sessions are cleaned up, and the socket removed, on
+/// SIGINT/SIGTERM.
+pub async fn serve_socket<B: IsolationBackend + Clone + Send + Sync + 'static>(
+    config: Config,
+    backend: B,
+    session_manager: Arc<SessionManager>,
+    source: SandboxSource,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let server = build_server(config, backend, &session_manager, source)?;
+    let listener = bind_socket(path)?;
+    info!(path = %path.display(), "Starting MCP server on UNIX socket");
+
+    let reaper_handle = session_manager.start_reaper();
+    let (clients, events_handle) = notify_clients(&session_manager);
+    let result = run_until_shutdown(
+        accept_connections(listener, server, clients),
+        shutdown_signal(),
+        // Dropping the accept loop stops its connections
+        || {},
+        reaper_handle,
+        &session_manager,
+        false,
+    )
+    .await;
+    events_handle.abort();
+
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!(path = %path.display(), error = %e, "Failed to remove socket");
+    }
+    result
+}
+
+/// The server as configured, with the audit sink opened.
+fn build_server<B: IsolationBackend + Clone + Send + Sync + 'static>(
+    config: Config,
+    backend: B,
+    session_manager: &Arc<SessionManager>,
+    source: SandboxSource,
+) -> anyhow::Result<SandboxServer<B>> {
+    let audit = config.audit.as_ref().map(AuditSink::open).transpose()?;
+    let mut server = SandboxServer::new(config, backend, Arc::clone(session_manager))
+        .with_sandbox_source(source);
+    if let Some(audit) = audit {
+        server = server.with_audit_sink(audit);
+    }
+    Ok(server)
+}
+
+/// Listen on `path`, replacing a stale socket left by an earlier run.
+/// Only the daemon's user may connect.
+fn bind_socket(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        anyhow::ensure!(
+            meta.file_type().is_socket(),
+            "Refusing to replace {}: not a socket",
+            path.display()
+        );
+        anyhow::ensure!(
+            std::os::unix::net::UnixStream::connect(path).is_err(),
+            "Socket {} is in use by another server",
+            path.display()
+        );
+        std::fs::remove_file(path)
+            .with_context(|| format!("Cannot remove stale socket {}", path.display()))?;
+    }
+
+    // Restrict the socket before listening, so no one else can connect in between
+    let cannot_listen = || format!("Cannot listen on {}", path.display());
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).with_context(cannot_listen)?;
+    socket
+        .bind(&SockAddr::unix(path).with_context(cannot_listen)?)
+        .with_context(cannot_listen)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Cannot restrict permissions of {}", path.display()))?;
+    socket.listen(SOCKET_BACKLOG).with_context(cannot_listen)?;
+    socket.set_nonblocking(true).with_context(cannot_listen)?;
+    let listener = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(socket));
+    UnixListener::from_std(listener).with_context(cannot_listen)
+}
+
+/// Pending connections the MCP socket queues before refusing more.
+const SOCKET_BACKLOG: i32 = 128;
+
+/// Pause after a failed `accept`, e.g. when out of file descriptors, so the
+/// loop doesn't spin while the condition lasts.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve each client that connects to `listener` on its own task. A failed
+/// accept is logged and retried after a pause, since it's usually
+/// transient. Dropping the future closes every connection.
+async fn accept_connections<B: IsolationBackend + Clone + Send + Sync + 'static>(
+    listener: UnixListener,
+    server: SandboxServer<B>,
+    clients: Clients,
+) -> anyhow::Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept connection");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        while connections.try_join_next().is_some() {}
+
+        let server = server.clone();
+        let clients = Arc::clone(&clients);
+        connections.spawn(async move {
+            let service = match server.serve(stream).await {
+                Ok(service) => service,
+                Err(e) => {
+                    warn!(error = %e, "MCP client failed to initialize");
+                    return;
+                }
+            };
+            info!("MCP client connected");
+            add_client(&clients, service.peer().clone());
+            if let Err(e) = service.waiting().await {
+                warn!(error = %e, "MCP connection failed");
+            }
+            info!("MCP client disconnected, keeping its sessions");
+        });
+    }
+}
+
+/// Connected clients that are told about session events.
+type Clients = Arc<Mutex<Vec<Peer<RoleServer>>>>;
+
+fn add_client(clients: &Clients, client: Peer<RoleServer>) {
+    clients
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(client);
+}
+
+/// Send `session_manager`'s events to every client added to the returned
+/// list, until the returned task is aborted.
+fn notify_clients(session_manager: &SessionManager) -> (Clients, tokio::task::JoinHandle<()>) {
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    session_manager.attach_notifier(events_tx);
+    let clients = Clients::default();
+    let handle = tokio::spawn(forward_session_events(events_rx, Arc::clone(&clients)));
+    (clients, handle)
+}
+
+/// Send each session event to the clients as a custom notification,
+/// forgetting clients that have disconnected.
+async fn forward_session_events(
+    mut events: mpsc::UnboundedReceiver<SessionEvent>,
+    clients: Clients,
+) {
+    while let Some(event) = events.recv().await {
+        let peers: Vec<Peer<RoleServer>> = {
+            let mut clients = clients.lock().unwrap_or_else(PoisonError::into_inner);
+            clients.retain(|client| !client.is_transport_closed());
+            clients.clone()
+        };
+        for client in peers {
+            if let Err(e) = client.send_notification(session_notification(&event)).await {
+                debug!(error = %e, "Failed to send session notification");
+            }
+        }
+    }
+}
+
+/// The notification for one session event; its params are the event.
+fn session_notification(event: &SessionEvent) -> ServerNotification {
+    ServerNotification::CustomNotification(CustomNotification::new(
+        SESSION_EVENT_METHOD,
+        serde_json::to_value(event).ok(),
+    ))
+}
+
+/// Run until the server stops (client disconnect) or `shutdown` fires,
+/// then stop the reaper and destroy all sessions.
+///
+/// The reaper is stopped gracefully, so a sweep already shutting sessions
+/// down finishes before `destroy_all` takes over.
+///
+/// Whichever happens first wins the `select!`, so cleanup runs exactly once.
+/// On shutdown, `cancel_server` stops the still-running server. A
+/// disconnect is handled by [`handle_disconnect`].
+async fn run_until_shutdown(
+    server: impl Future<Output = anyhow::Result<()>>,
+    shutdown: impl Future<Output = ()>,
+    cancel_server: impl FnOnce(),
+    reaper_handle: Reaper,
+    session_manager: &SessionManager,
+    persist: bool,
+) -> anyhow::Result<()> {
+    tokio::pin!(shutdown);
+    let result = tokio::select! {
+        result = server => {
+            handle_disconnect(persist, &mut shutdown).await;
+            result
+        }
+        () = &mut shutdown => {
+            info!("Shutdown signal received, cleaning up sessions");
+            cancel_server();
+            Ok(())
+        }
+    };
+
+    reaper_handle.stop().await;
+    session_manager.destroy_all().await;
+
+    result
+}
+
+/// Decide when teardown follows a client disconnect: right away, or with
+/// `persist` (`--persist`), only once `shutdown` fires. Until then the
+/// reaper keeps running and sessions stay alive for a reconnecting client.
+async fn handle_disconnect(persist: bool, shutdown: impl Future<Output = ()>) {
+    if persist {
+        info!("MCP client disconnected, keeping sessions until shutdown (--persist)");
+        shutdown.await;
+        info!("Shutdown signal received, cleaning up sessions");
+    } else {
+        info!("MCP client disconnected, cleaning up sessions");
+    }
+}
+
+/// Resolve on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(error = %e, "Cannot install SIGTERM handler, only handling SIGINT");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ResourceUsage;
+    use crate::config::{
+        BackendType, EnvironmentMeta, MergePolicy, Mounts, ProjectConfig, UnknownFields,
+        DEFAULT_STDERR_DELIMITER,
+    };
+    use crate::session::SessionConfig;
+    use crate::transport::protocol::{AgentRequest, AgentResponse};
+    use crate::transport::Transport;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct MockBackend;
+
+    #[async_trait]
+    impl IsolationBackend for MockBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            code: &str,
+            _timeout: Duration,
+            stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            Ok(ExecutionResult {
+                exit_code: 0,
+                stdout: format!("executed: {code}{}", stdin.unwrap_or_default()),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Backend that reports the working directory it was asked to use.
+    #[derive(Clone)]
+    struct WorkdirBackend;
+
+    #[async_trait]
+    impl IsolationBackend for WorkdirBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            _code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            let workdir = options
+                .env_vars()
+                .into_iter()
+                .find(|(name, _)| name == "SANDBOX_WORKDIR")
+                .map_or_else(|| "default".to_string(), |(_, dir)| dir);
+            Ok(ExecutionResult {
+                stdout: workdir,
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Backend that echoes the secret env vars it would set, as `NAME=value` lines.
+    #[derive(Clone)]
+    struct SecretEchoBackend;
+
+    #[async_trait]
+    impl IsolationBackend for SecretEchoBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            _code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            tracing::debug!(?options, "Executing");
+            let stdout = options
+                .secret_env
+                .iter()
+                .fold(String::new(), |mut out, (name, value)| {
+                    let _ = writeln!(out, "{name}={value}");
+                    out
+                });
+            Ok(ExecutionResult {
+                stdout,
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Log sink for a test subscriber.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Backend that reports the timeout it was given.
+    #[derive(Clone)]
+    struct TimeoutBackend;
+
+    #[async_trait]
+    impl IsolationBackend for TimeoutBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            _code: &str,
+            timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            Ok(ExecutionResult {
+                stdout: timeout.as_secs().to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Backend that streams each whitespace-separated word of the code as a chunk.
+    #[derive(Clone)]
+    struct ChunkingBackend;
+
+    #[async_trait]
+    impl IsolationBackend for ChunkingBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            for (i, word) in code.split_whitespace().enumerate() {
+                let stream = if i % 2 == 0 {
+                    OutputStream::Stdout
+                } else {
+                    OutputStream::Stderr
+                };
+                if let Some(tx) = output {
+                    tx.send(OutputChunk {
+                        stream,
+                        data: word.to_string(),
+                    })?;
+                }
+            }
+            Ok(ExecutionResult {
+                exit_code: 0,
+                stdout: code.to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn test_config() -> Config {
+        let mut environments = HashMap::new();
+        environments.insert(
+            "test".to_string(),
+            EnvironmentMeta {
+                backend: BackendType::Jail,
+                exec: "/bin/test".to_string(),
+                session_exec: None,
+                timeout_seconds: 30,
+                memory_mb: 512,
+                interpreter_type: None,
+                ..Default::default()
+            },
+        );
+        Config {
+            environments,
+            project: None,
+            extra_mounts: Vec::new(),
+            scratch: None,
+            session: None,
+            pool: None,
+            limits: None,
+            interpreter_map: HashMap::new(),
+            audit: None,
+            output: None,
+            instructions_template: None,
+            default_env: None,
+            sandbox_merge: MergePolicy::default(),
+        }
+    }
+
+    #[test]
+    fn test_environment_table() {
+        let mut config = test_config();
+        config.environments.insert(
+            "python".to_string(),
+            EnvironmentMeta {
+                session_exec: Some("/bin/session".to_string()),
+                timeout_seconds: 120,
+                memory_mb: 2048,
+                ..Default::default()
+            },
+        );
+        config.environments.insert(
+            "remote".to_string(),
+            EnvironmentMeta {
+                backend: BackendType::Remote,
+                tcp: Some(crate::config::TcpAddr::default()),
+                interpreter_type: Some("python".to_string()),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            environment_table(&config),
+            "NAME    BACKEND  INTERPRETER  SESSIONS  TIMEOUT  MEMORY\n\
+             python  jail     python       yes       120s     2048 MB\n\
+             remote  remote   python       yes       30s      512 MB\n\
+             test    jail     test         no        30s      512 MB\n"
+        );
+    }
+
+    #[test]
+    fn test_session_notification() {
+        let event = SessionEvent {
+            session: "s1".to_string(),
+            env: "python".to_string(),
+            event: crate::session::SessionEventKind::Closed,
+            reason: "idle timeout".to_string(),
+        };
+        let ServerNotification::CustomNotification(notification) = session_notification(&event)
+        else {
+            panic!("expected a custom notification");
+        };
+        assert_eq!(notification.method, "notifications/sandbox/session");
+        assert_eq!(
+            notification.params,
+            Some(serde_json::json!({
+                "session": "s1",
+                "env": "python",
+                "event": "closed",
+                "reason": "idle timeout",
+            }))
+        );
+    }
+
+    fn test_session_manager() -> Arc<SessionManager> {
+        Arc::new(SessionManager::new(SessionConfig::default()))
+    }
+
+    #[test]
+    fn test_truncate_output_custom_limit() {
+        let (output, truncated) = truncate_output("hello world", 5, TruncateStrategy::Head);
+        assert!(truncated);
+        assert!(output.starts_with("hello\n\n"));
+        assert!(output.ends_with("[truncated — output exceeded 5 bytes]"));
+
+        // Under the limit: unchanged
+        assert_eq!(
+            truncate_output("hello", 5, TruncateStrategy::Head),
+            ("hello".to_string(), false)
+        );
+
+        // Cut lands inside a multi-byte char: back off to the boundary
+        let (output, _) = truncate_output("aé", 2, TruncateStrategy::Head);
+        assert!(output.starts_with("a\n\n"));
+    }
+
+    #[test]
+    fn test_truncate_output_tail() {
+        let (output, truncated) = truncate_output("hello world", 5, TruncateStrategy::Tail);
+        assert!(truncated);
+        assert_eq!(output, "[truncated — output exceeded 5 bytes]\n\nworld");
+
+        // Cut lands inside a multi-byte char: move forward to the boundary
+        let (output, _) = truncate_output("éa", 2, TruncateStrategy::Tail);
+        assert!(output.ends_with("\n\na"), "{output}");
+        let (output, _) = truncate_output("aé", 2, TruncateStrategy::Tail);
+        assert!(output.ends_with("\n\né"), "{output}");
+    }
+
+    #[test]
+    fn test_truncate_output_middle() {
+        let (output, truncated) = truncate_output("hello world", 6, TruncateStrategy::Middle);
+        assert!(truncated);
+        assert_eq!(
+            output,
+            "hel\n\n[truncated — output exceeded 6 bytes]\n\nrld"
+        );
+
+        // Both cuts land inside "é" (2 bytes): the head backs off, the tail
+        // moves forward, and neither splits a char
+        let (output, _) = truncate_output("aébcéff", 4, TruncateStrategy::Middle);
+        assert_eq!(output, "a\n\n[truncated — output exceeded 4 bytes]\n\nff");
+        assert_eq!(
+            truncate_output("hello", 5, TruncateStrategy::Middle),
+            ("hello".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_truncation_marker_reports_limit() {
+        let big = "x".repeat(3000);
+        assert!(truncate_output(&big, 2048, TruncateStrategy::Head)
+            .0
+            .ends_with("exceeded 2KB]"));
+        assert!(
+            truncate_output(&big, 1024 * 1024 - 1, TruncateStrategy::Head)
+                .0
+                .eq(&big)
+        );
+        assert_eq!(format_size(1024 * 1024), "1MB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10MB");
+        assert_eq!(format_size(1500), "1500 bytes");
+    }
+
+    #[test]
+    fn test_format_result_respects_limit() {
+        let exec = ExecutionResult {
+            stdout: "abcdefghij".to_string(),
+            ..Default::default()
+        };
+        let result = format_result(&exec, 4, DEFAULT_STDERR_DELIMITER, TruncateStrategy::Head);
+        let text = result.content[0].as_text().unwrap().text.clone();
+        assert!(text.starts_with("abcd\n\n[truncated"));
+    }
+
+    #[test]
+    fn test_format_result_structured() {
+        let exec = ExecutionResult {
+            exit_code: 2,
+            stdout: "out".to_string(),
+            stderr: "err".to_string(),
+            duration: std::time::Duration::from_millis(1500),
+            timed_out: false,
+            resource_usage: None,
+            raw_stdout: None,
+            raw_stderr: None,
+            fragment_exit_codes: None,
+        };
+        let result = format_result(
+            &exec,
+            1024,
+            DEFAULT_STDERR_DELIMITER,
+            TruncateStrategy::Head,
+        );
+        assert!(result.is_error.unwrap());
+
+        // Human-readable text keeps the stderr delimiter
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "out\n--- stderr ---\nerr");
+
+        // The metadata block leaves the output to the text
+        let mut expected = serde_json::json!({
+            "exit_code": 2,
+            "timed_out": false,
+            "duration_ms": 1500,
+            "truncated": false,
+        });
+        let json: serde_json::Value =
+            serde_json::from_str(&result.content[1].as_text().unwrap().text).unwrap();
+        assert_eq!(json, expected);
+        expected["stdout"] = "out".into();
+        expected["stderr"] = "err".into();
+        assert_eq!(result.structured_content, Some(expected));
+    }
+
+    #[test]
+    fn test_format_result_holds_streams_to_one_cap() {
+        let exec = ExecutionResult {
+            stdout: "o".repeat(100),
+            stderr: "e".repeat(100),
+            ..Default::default()
+        };
+        let result = format_result(&exec, 60, "", TruncateStrategy::Head);
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["truncated"], true);
+        let kept = |stream: &str| {
+            let text = structured[stream].as_str().unwrap();
+            text.bytes().take_while(u8::is_ascii_lowercase).count()
+        };
+        assert_eq!((kept("stdout"), kept("stderr")), (30, 30));
+        assert!(!result.content[1].as_text().unwrap().text.contains("ooo"));
+
+        // A short stream leaves the rest of the cap to the other
+        assert_eq!(split_budget(10, 100, 60), (30, 50));
+        assert_eq!(split_budget(100, 10, 60), (50, 10));
+    }
+
+    #[test]
+    fn test_format_result_stderr_delimiter() {
+        let exec = ExecutionResult {
+            stdout: "out\n".to_string(),
+            stderr: "err\n".to_string(),
+            ..Default::default()
+        };
+        let text = |delimiter| {
+            format_result(&exec, 1024, delimiter, TruncateStrategy::Head).content[0]
+                .as_text()
+                .unwrap()
+                .text
+                .clone()
+        };
+        assert_eq!(
+            text(DEFAULT_STDERR_DELIMITER),
+            "out\n\n--- stderr ---\nerr\n"
+        );
+        assert_eq!(text(""), "out\nerr\n");
+        assert_eq!(text("[stderr]\n"), "out\n[stderr]\nerr\n");
+
+        // No delimiter when only one stream has output
+        let exec = ExecutionResult {
+            stderr: "err".to_string(),
+            ..Default::default()
+        };
+        let result = format_result(&exec, 1024, "[stderr]\n", TruncateStrategy::Head);
+        assert_eq!(result.content[0].as_text().unwrap().text, "err");
+    }
+
+    #[test]
+    fn test_format_json_result() {
+        let exec = ExecutionResult {
+            exit_code: 1,
+            stdout: "out".to_string(),
+            stderr: "err".to_string(),
+            ..Default::default()
+        };
+        let result = format_json_result(&exec, 1024, TruncateStrategy::Head);
+        assert!(result.is_error.unwrap());
+        assert_eq!(result.content.len(), 1);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&result.content[0].as_text().unwrap().text).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "exit_code": 1,
+                "stdout": "out",
+                "stderr": "err",
+                "truncated": false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_format_json_result_truncated() {
+        let exec = ExecutionResult {
+            stdout: "ok".to_string(),
+            stderr: "abcdefghij".to_string(),
+            ..Default::default()
+        };
+        let json = format_json_result(&exec, 4, TruncateStrategy::Head)
+            .structured_content
+            .unwrap();
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["stdout"], "ok");
+        assert!(json["stderr"]
+            .as_str()
+            .unwrap()
+            .starts_with("abcd\n\n[truncated"));
+    }
+
+    #[tokio::test]
+    async fn test_run_output_formats() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = |output_format| RunParams {
+            code: "echo hi".into(),
+            env: Some("test".to_string()),
+            session: None,
+            stdin: None,
+            timeout_seconds: None,
+            output_format,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let text = server
+            .run_code(params(OutputFormat::Text), None)
+            .await
+            .unwrap();
+        assert_eq!(text.content.len(), 2);
+        assert_eq!(text.content[0].as_text().unwrap().text, "executed: echo hi");
+
+        let json = server
+            .run_code(params(OutputFormat::Json), None)
+            .await
+            .unwrap();
+        assert_eq!(json.content.len(), 1);
+        let value: serde_json::Value =
+            serde_json::from_str(&json.content[0].as_text().unwrap().text).unwrap();
+        assert_eq!(value["stdout"], "executed: echo hi");
+        assert_eq!(value["truncated"], false);
+    }
+
+    /// Backend whose stdout is bytes that aren't valid UTF-8.
+    #[derive(Clone)]
+    struct BinaryBackend;
+
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\xff\x00";
+
+    #[async_trait]
+    impl IsolationBackend for BinaryBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            _code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            let (stdout, raw_stdout) = crate::backend::decode_output(
+                PNG_HEADER.to_vec(),
+                crate::config::OutputEncoding::default(),
+            );
+            Ok(ExecutionResult {
+                stdout,
+                raw_stdout,
+                stderr: "warning".to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_binary_output_roundtrips() {
+        let server = SandboxServer::new(test_config(), BinaryBackend, test_session_manager());
+        let mut params = run_params("cat image.png");
+        params.binary = true;
+
+        let result = server.run_code(params, None).await.unwrap();
+        assert_eq!(result.content.len(), 1);
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["encoding"], "base64");
+        assert_eq!(json["truncated"], false);
+        let stdout = BASE64_STANDARD
+            .decode(json["stdout"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(stdout, PNG_HEADER);
+        let stderr = BASE64_STANDARD
+            .decode(json["stderr"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(stderr, b"warning");
+
+        // Text mode still gets the lossy rendering
+        let text = server
+            .run_code(run_params("cat image.png"), None)
+            .await
+            .unwrap();
+        assert!(text.content[0].as_text().unwrap().text.contains('\u{fffd}'));
+    }
+
+    #[test]
+    fn test_format_binary_result_truncated() {
+        let exec = ExecutionResult {
+            raw_stdout: Some(vec![0xff; 10]),
+            ..Default::default()
+        };
+        let json = format_binary_result(&exec, 4).structured_content.unwrap();
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["stdout"], BASE64_STANDARD.encode([0xff; 4]));
+    }
+
+    #[tokio::test]
+    async fn test_binary_rejected_for_sessions() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let mut params = run_params("x");
+        params.binary = true;
+        params.session = Some("s1".to_string());
+        assert!(server.run_code(params, None).await.is_err());
+    }
+
+    #[test]
+    fn test_output_format_defaults_to_text() {
+        let params: RunParams = serde_json::from_str(r#"{"code": "1", "env": "python"}"#).unwrap();
+        assert_eq!(params.output_format, OutputFormat::Text);
+        let params: RunParams =
+            serde_json::from_str(r#"{"code": "1", "env": "python", "output_format": "json"}"#)
+                .unwrap();
+        assert_eq!(params.output_format, OutputFormat::Json);
+    }
+
+    #[test]
+    fn test_code_accepts_fragments() {
+        let params: RunParams =
+            serde_json::from_str(r#"{"code": ["x = 1", "print(x)"], "env": "python"}"#).unwrap();
+        assert_eq!(
+            params.code,
+            Code::Fragments(vec!["x = 1".to_string(), "print(x)".to_string()])
+        );
+        assert_eq!(params.code.len(), 13);
+        assert!(!params.continue_on_error);
+    }
+
+    #[tokio::test]
+    async fn test_fragments_rejected_without_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let mut params = run_params("x");
+        params.code = Code::Fragments(vec!["a".to_string(), "b".to_string()]);
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(
+            err.message.contains("only supported in a session"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_format_result_fragment_exit_codes() {
+        let exec = ExecutionResult {
+            exit_code: 1,
+            fragment_exit_codes: Some(vec![0, 1]),
+            ..Default::default()
+        };
+        let result = format_result(
+            &exec,
+            1024,
+            DEFAULT_STDERR_DELIMITER,
+            TruncateStrategy::Head,
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["fragment_exit_codes"], serde_json::json!([0, 1]));
+        let json = format_json_result(&exec, 1024, TruncateStrategy::Head)
+            .structured_content
+            .unwrap();
+        assert_eq!(json["fragment_exit_codes"], serde_json::json!([0, 1]));
+    }
+
+    #[test]
+    fn test_format_result_resource_usage() {
+        let exec = ExecutionResult {
+            resource_usage: Some(ResourceUsage {
+                cpu_ms: 42,
+                max_rss_kb: None,
+            }),
+            ..Default::default()
+        };
+        let structured = format_result(
+            &exec,
+            1024,
+            DEFAULT_STDERR_DELIMITER,
+            TruncateStrategy::Head,
+        )
+        .structured_content
+        .unwrap();
+        assert_eq!(
+            structured["resource_usage"],
+            serde_json::json!({ "cpu_ms": 42, "max_rss_kb": null })
+        );
+
+        // Omitted entirely when the backend couldn't measure it
+        let structured = format_result(
+            &ExecutionResult::default(),
+            1024,
+            DEFAULT_STDERR_DELIMITER,
+            TruncateStrategy::Head,
+        )
+        .structured_content
+        .unwrap();
+        assert!(structured.get("resource_usage").is_none());
+    }
+
+    #[test]
+    fn test_format_result_timed_out() {
+        let exec = ExecutionResult {
+            exit_code: 124,
+            stderr: "Execution timed out after 1s".to_string(),
+            duration: std::time::Duration::from_secs(1),
+            timed_out: true,
+            ..Default::default()
+        };
+        let result = format_result(
+            &exec,
+            1024,
+            DEFAULT_STDERR_DELIMITER,
+            TruncateStrategy::Head,
+        );
+        assert!(result.is_error.unwrap());
+
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["timed_out"], true);
+        assert_eq!(structured["exit_code"], 124);
+        assert_eq!(structured["duration_ms"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_run_success() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            code: "echo hello".into(),
+            env: Some("test".to_string()),
+            session: None,
+            stdin: None,
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_run_workdir() {
+        let server = SandboxServer::new(test_config(), WorkdirBackend, test_session_manager());
+        let run = |workdir: Option<&str>| RunParams {
+            workdir: workdir.map(String::from),
+            ..run_params("pwd")
+        };
+
+        let result = server.run_code(run(None), None).await.unwrap();
+        assert_eq!(result.content[0].as_text().unwrap().text, "default");
+
+        let result = server.run_code(run(Some("build")), None).await.unwrap();
+        assert_eq!(
+            result.content[0].as_text().unwrap().text,
+            "/workspace/build"
+        );
+
+        let err = server
+            .run_code(run(Some("../../etc")), None)
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("must not contain '..'"));
+
+        let err = server.run_code(run(Some("/etc")), None).await.unwrap_err();
+        assert!(err.message.contains("outside the sandbox mounts"));
+    }
+
+    #[tokio::test]
+    async fn test_secret_env_is_set_but_never_logged() {
+        use tracing::instrument::WithSubscriber;
+
+        let logs = LogBuffer::default();
+        let subscriber = {
+            let logs = logs.clone();
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_writer(move || logs.clone())
+                .finish()
+        };
+        let server = SandboxServer::new(test_config(), SecretEchoBackend, test_session_manager());
+        let params = RunParams {
+            secret_env: std::iter::once(("GITHUB_TOKEN", "ghp_s3cret")).collect(),
+            ..run_params("env")
+        };
+
+        let result = server
+            .run_code(params, None)
+            .with_subscriber(subscriber)
+            .await
+            .unwrap();
+
+        let output = &result.content[0].as_text().unwrap().text;
+        assert!(output.contains("GITHUB_TOKEN=ghp_s3cret"), "{output}");
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Running code"), "nothing captured: {logs}");
+        assert!(logs.contains("GITHUB_TOKEN"), "{logs}");
+        assert!(!logs.contains("ghp_s3cret"), "secret leaked: {logs}");
+    }
+
+    #[tokio::test]
+    async fn test_secret_env_rejected_for_sessions() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            session: Some("s1".to_string()),
+            secret_env: std::iter::once(("TOKEN", "x")).collect(),
+            ..run_params("env")
+        };
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(
+            err.message.contains("secret_env is only supported"),
+            "{err:?}"
+        );
+
+        for name in ["SANDBOX_WORKDIR", "LD_PRELOAD", "BASH_ENV"] {
+            let params = RunParams {
+                secret_env: std::iter::once((name, "/etc")).collect(),
+                ..run_params("env")
+            };
+            assert!(server.run_code(params, None).await.is_err(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_secret_env_prefixes_enforced() {
+        let mut config = test_config();
+        config.limits = Some(crate::config::LimitsConfig {
+            secret_env_prefixes: vec!["GH_".to_string()],
+            ..Default::default()
+        });
+        let server = SandboxServer::new(config, SecretEchoBackend, test_session_manager());
+        let params = RunParams {
+            secret_env: std::iter::once(("TOKEN", "x")).collect(),
+            ..run_params("env")
+        };
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(
+            err.message.contains("must start with one of: GH_"),
+            "{err:?}"
+        );
+
+        let params = RunParams {
+            secret_env: std::iter::once(("GH_TOKEN", "x")).collect(),
+            ..run_params("env")
+        };
+        assert!(server.run_code(params, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_remote_env_rejects_ephemeral_runs() {
+        let mut config = test_config();
+        config.environments.insert(
+            "remote".to_string(),
+            EnvironmentMeta {
+                backend: BackendType::Remote,
+                tcp: Some(crate::config::TcpAddr::default()),
+                ..Default::default()
+            },
+        );
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+        let params = RunParams {
+            env: Some("remote".to_string()),
+            ..run_params("print(1)")
+        };
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(err.message.contains("only hosts sessions"), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn test_combine_output_rejected_for_sessions() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            session: Some("s1".to_string()),
+            combine_output: true,
+            ..run_params("echo hi")
+        };
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(
+            err.message.contains("combine_output is only supported"),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_capture_output_false_discards_output() {
+        let params: RunParams =
+            serde_json::from_value(serde_json::json!({"code": "true", "env": "test"})).unwrap();
+        assert!(params.capture_output);
+
+        let params = RunParams {
+            capture_output: false,
+            allow_empty: false,
+            ..run_params("seq 100000")
+        };
+        let mounts = RunParams::mounts(&test_config()).unwrap();
+        assert!(
+            params
+                .exec_options(&test_config(), &mounts)
+                .unwrap()
+                .discard_output
+        );
+
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            session: Some("s1".to_string()),
+            ..params
+        };
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert!(
+            err.message
+                .contains("capture_output: false is only supported"),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_with_built_config() {
+        let config = Config::builder()
+            .environment(
+                "py",
+                EnvironmentMeta {
+                    exec: "/bin/run".to_string(),
+                    ..Default::default()
+                },
+            )
+            .build()
+            .unwrap();
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+        let params = RunParams {
+            env: Some("py".to_string()),
+            ..run_params("print(1)")
+        };
+
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        assert_eq!(
+            result.content[0].as_text().unwrap().text,
+            "executed: print(1)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_unknown_env() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            code: "echo hello".into(),
+            env: Some("unknown".to_string()),
+            session: None,
+            stdin: None,
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let result = server.run_code(params, None).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_env_lists_available_as_data() {
+        let mut config = test_config();
+        let other = config.environments["test"].clone();
+        config.environments.insert("alpha".to_string(), other);
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+        let params = RunParams {
+            env: Some("unknown".to_string()),
+            ..run_params("echo hello")
+        };
+
+        let err = server.run_code(params, None).await.unwrap_err();
+        assert_eq!(err.data, Some(serde_json::json!(["alpha", "test"])));
+        assert_eq!(
+            err.message,
+            "Unknown environment: 'unknown'. Available: [\"alpha\", \"test\"]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_env_used_when_env_omitted() {
+        /// Backend that reports which environment ran the code.
+        #[derive(Clone)]
+        struct ExecBackend;
+
+        #[async_trait]
+        impl IsolationBackend for ExecBackend {
+            async fn execute(
+                &self,
+                env: &EnvironmentMeta,
+                _code: &str,
+                _timeout: Duration,
+                _stdin: Option<&str>,
+                _mounts: &Mounts,
+                _options: &ExecOptions,
+                _output: Option<&OutputSender>,
+            ) -> anyhow::Result<ExecutionResult> {
+                Ok(ExecutionResult {
+                    stdout: env.exec.clone(),
+                    ..Default::default()
+                })
+            }
+        }
+
+        let mut config = test_config();
+        config.environments.insert(
+            "alpha".to_string(),
+            EnvironmentMeta {
+                exec: "/bin/alpha".to_string(),
+                ..config.environments["test"].clone()
+            },
+        );
+        let omitted = || RunParams {
+            env: None,
+            ..run_params("1")
+        };
+
+        // Without a default, omitting env is an error
+        let server = SandboxServer::new(config.clone(), ExecBackend, test_session_manager());
+        let err = server.run_code(omitted(), None).await.unwrap_err();
+        assert_eq!(
+            err.message,
+            "No environment given and no default_env configured. Available: [\"alpha\", \"test\"]"
+        );
+
+        let test_exec = config.environments["test"].exec.clone();
+        config.default_env = Some("alpha".to_string());
+        let server = SandboxServer::new(config, ExecBackend, test_session_manager());
+        let ran_in = |result: CallToolResult| result.content[0].as_text().unwrap().text.clone();
+
+        // An explicit env still wins over the default
+        let result = server.run_code(run_params("1"), None).await.unwrap();
+        assert_eq!(ran_in(result), test_exec);
+
+        let result = server.run_code(omitted(), None).await.unwrap();
+        assert_eq!(ran_in(result), "/bin/alpha");
+    }
+
+    #[tokio::test]
+    async fn test_session_without_session_exec() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            code: "x = 42".into(),
+            env: Some("test".to_string()),
+            session: Some("mysession".to_string()),
+            stdin: None,
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        // Should fail because test env has no session_exec
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(result.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_run_streams_chunks_in_order() {
+        let server = SandboxServer::new(test_config(), ChunkingBackend, test_session_manager());
+        let params = RunParams {
+            code: "a b c d".into(),
+            env: Some("test".to_string()),
+            session: None,
+            stdin: None,
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let result = server.run_code(params, Some(&tx)).await.unwrap();
+        drop(tx);
+        assert!(!result.is_error.unwrap_or(false));
+
+        let token = ProgressToken(rmcp::model::NumberOrString::Number(7));
+        let mut messages = Vec::new();
+        let mut seq = 0;
+        while let Some(chunk) = rx.recv().await {
+            seq += 1;
+            let param = chunk_to_progress(&token, seq, chunk);
+            assert_eq!(param.progress_token, token);
+            assert!((param.progress - f64::from(seq)).abs() < f64::EPSILON);
+            messages.push(param.message.unwrap());
+        }
+        assert_eq!(messages, vec!["a", "[stderr] b", "c", "[stderr] d"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unknown_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = Parameters(CancelParams {
+            session: "nope".to_string(),
+        });
+
+        let result = server.cancel(params).await.unwrap();
+        assert!(result.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_empty() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+
+        let result = server.list_sessions().await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "No active sessions");
+    }
+
+    #[tokio::test]
+    async fn test_session_stats_groups_by_env() {
+        let manager = test_session_manager();
+        for (id, env) in [("s1", "python"), ("s2", "shell"), ("s3", "python")] {
+            manager
+                .insert_session(
+                    id,
+                    env,
+                    Box::new(FlagTransport(Arc::new(AtomicBool::new(false)))),
+                )
+                .await;
+        }
+        let server = SandboxServer::new(test_config(), MockBackend, manager);
+
+        let result = server.session_stats().await.unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["total"], 3);
+        assert_eq!(json["by_env"], serde_json::json!({"python": 2, "shell": 1}));
+        assert!(
+            json["oldest_created_at"].as_u64().unwrap()
+                <= json["newest_created_at"].as_u64().unwrap()
+        );
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(
+            text.starts_with("3 active session(s) (python: 2, shell: 1)"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_info_unknown_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = Parameters(SessionInfoParams {
+            session: "nope".to_string(),
+        });
+
+        let result = server.session_info(params).await.unwrap();
+        assert!(result.is_error.unwrap_or(false));
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "Session 'nope' not found");
+    }
+
+    #[tokio::test]
+    async fn test_session_history_unknown_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = Parameters(SessionHistoryParams {
+            session: "nope".to_string(),
+        });
+
+        let result = server.session_history(params).await.unwrap();
+        assert!(result.is_error.unwrap_or(false));
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "Session 'nope' not found");
+    }
+
+    #[tokio::test]
+    async fn test_close_unknown_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = Parameters(CloseSessionParams {
+            session: "nope".to_string(),
+        });
+
+        let result = server.close_session(params).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(text.contains("No session 'nope'"));
+    }
+
+    #[tokio::test]
+    async fn test_restart_unknown_session() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = Parameters(RestartSessionParams {
+            session: "nope".to_string(),
+        });
+
+        let result = server.restart_session(params).await.unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(text.contains("Session 'nope' not found"), "{text}");
+    }
+
+    /// A setup script that appends a line to `count` per run, then exits
+    /// with `exit_code`.
+    fn setup_script(dir: &std::path::Path, exit_code: i32) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("setup");
+        let count = dir.join("count");
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho run >> '{}'\necho 'pip: no network' >&2\nexit {exit_code}\n",
+                count.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn setup_runs(dir: &std::path::Path) -> usize {
+        std::fs::read_to_string(dir.join("count")).map_or(0, |count| count.lines().count())
+    }
+
+    #[tokio::test]
+    async fn test_setup_exec_runs_once_per_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config();
+        config.environments.get_mut("test").unwrap().setup_exec = Some(setup_script(dir.path(), 0));
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        for _ in 0..3 {
+            let result = server.run_code(run_params("print(1)"), None).await.unwrap();
+            assert!(!result.is_error.unwrap_or(false));
+        }
+        assert_eq!(setup_runs(dir.path()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_call_never_runs_setup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config();
+        config.environments.get_mut("test").unwrap().setup_exec = Some(setup_script(dir.path(), 0));
+        let mut remote = config.environments["test"].clone();
+        remote.backend = BackendType::Remote;
+        config.environments.insert("remote".to_string(), remote);
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let mut with_session = run_params("print(1)");
+        with_session.session = Some("s1".to_string());
+        with_session.stdin = Some("input".to_string());
+        let mut fragments = run_params("");
+        fragments.code = Code::Fragments(vec!["x = 1".to_string()]);
+        let mut on_remote = run_params("print(1)");
+        on_remote.env = Some("remote".to_string());
+
+        for params in [with_session, fragments, on_remote] {
+            server.run_code(params, None).await.unwrap_err();
+        }
+        assert_eq!(setup_runs(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_setup_exec_failure_is_reported_and_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config();
+        config.environments.get_mut("test").unwrap().setup_exec = Some(setup_script(dir.path(), 3));
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let result = server.run_code(run_params("print(1)"), None).await.unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(
+            text.contains(
+                "Setup for environment 'test' failed with exit code 3; your code did not run.\n\
+                 pip: no network"
+            ),
+            "{text}"
+        );
+
+        // Not marked done, so the next run tries again
+        server.run_code(run_params("print(1)"), None).await.unwrap();
+        assert_eq!(setup_runs(dir.path()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_setup_exec_timeout_is_configurable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let setup = dir.path().join("setup");
+        std::fs::write(&setup, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&setup, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = test_config();
+        let env = config.environments.get_mut("test").unwrap();
+        env.setup_exec = Some(setup.to_string_lossy().into_owned());
+        env.setup_timeout_seconds = 1;
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let result = server.run_code(run_params("print(1)"), None).await.unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(text.contains("timed out after 1s"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn test_read_tail_keeps_last_bytes() {
+        let data: Vec<u8> = (0..=255).cycle().take(20_000).collect();
+        let tail = read_tail(&data[..], 100).await.unwrap();
+        assert_eq!(tail, data[data.len() - 100..]);
+        assert_eq!(read_tail(&b"short"[..], 100).await.unwrap(), b"short");
+    }
+
+    #[tokio::test]
+    async fn test_session_env_tools_report_errors() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+
+        let result = server
+            .set_session_env(Parameters(SetSessionEnvParams {
+                session: "nope".to_string(),
+                key: "X".to_string(),
+                value: "1".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "Setting session env failed: Session 'nope' not found");
+
+        let result = server
+            .get_session_env(Parameters(GetSessionEnvParams {
+                session: "nope".to_string(),
+                key: "bad-name".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(
+            text,
+            "Reading session env failed: Invalid session env name 'bad-name'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_session_unsupported_or_unknown() {
+        let manager = test_session_manager();
+        manager
+            .insert_session(
+                "s1",
+                "test",
+                Box::new(FlagTransport(Arc::new(AtomicBool::new(false)))),
+            )
+            .await;
+        let server = SandboxServer::new(test_config(), MockBackend, manager);
+
+        let reset = |session: &str| {
+            Parameters(ResetSessionParams {
+                session: session.to_string(),
+            })
+        };
+        let result = server.reset_session(reset("s1")).await.unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(
+            text,
+            "Session 's1' doesn't support reset; use restart_session instead"
+        );
+
+        let result = server.reset_session(reset("nope")).await.unwrap();
+        assert!(result.is_error.unwrap());
+        let text = &result.content[0].as_text().unwrap().text;
+        assert_eq!(text, "Reset failed: Session 'nope' not found");
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout_override() {
+        let mut config = test_config();
+        config
+            .environments
+            .get_mut("test")
+            .unwrap()
+            .max_timeout_seconds = Some(60);
+        let server = SandboxServer::new(config, TimeoutBackend, test_session_manager());
+
+        for (requested, expected) in [(None, "30"), (Some(5), "5"), (Some(600), "60")] {
+            let params = RunParams {
+                code: "sleep 1".into(),
+                env: Some("test".to_string()),
+                session: None,
+                stdin: None,
+                timeout_seconds: requested,
+                output_format: OutputFormat::Text,
+                binary: false,
+                workdir: None,
+                continue_on_error: false,
+                rebind: false,
+                recreate_on_death: false,
+                secret_env: SecretEnv::default(),
+                combine_output: false,
+                capture_output: true,
+                allow_empty: false,
+            };
+            let result = server.run_code(params, None).await.unwrap();
+            let text = &result.content[0].as_text().unwrap().text;
+            assert_eq!(text, expected, "requested {requested:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_with_stdin() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            code: "cat".into(),
+            env: Some("test".to_string()),
+            session: None,
+            stdin: Some(" input".to_string()),
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        let text = result.content[0].as_text().unwrap().text.clone();
+        assert_eq!(text, "executed: cat input");
+    }
+
+    #[tokio::test]
+    async fn test_session_rejects_stdin() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let params = RunParams {
+            code: "cat".into(),
+            env: Some("test".to_string()),
+            session: Some("mysession".to_string()),
+            stdin: Some("input".to_string()),
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
+        };
+
+        let result = server.run_code(params, None).await;
+        assert!(result.is_err());
+    }
+
+    /// Transport that only records whether it was shut down.
+    struct FlagTransport(Arc<AtomicBool>);
+
+    #[async_trait]
+    impl Transport for FlagTransport {
+        async fn request(&self, _req: &AgentRequest) -> anyhow::Result<AgentResponse> {
+            anyhow::bail!("not connected")
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> anyhow::Result<()> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    /// Transport that records `Execute` ids and answers once `barrier` is
+    /// reached by every caller.
+    struct RecordingTransport {
+        ids: Arc<std::sync::Mutex<Vec<String>>>,
+        barrier: Arc<tokio::sync::Barrier>,
+    }
+
+    #[async_trait]
+    impl Transport for RecordingTransport {
+        async fn request(&self, req: &AgentRequest) -> anyhow::Result<AgentResponse> {
+            let AgentRequest::Execute { id, .. } = req else {
+                return Ok(AgentResponse::Pong);
+            };
+            self.ids.lock().unwrap().push(id.clone());
+            self.barrier.wait().await;
+            Ok(AgentResponse::Result {
+                id: id.clone(),
+                stdout: String::new(),
+                stderr: String::new(),
+                exit_code: 0,
+            })
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_runs_get_distinct_request_ids() {
+        let mut config = test_config();
+        config.environments.insert(
+            "python".to_string(),
+            EnvironmentMeta {
+                session_exec: Some("/bin/session".to_string()),
+                interpreter_type: Some("python".to_string()),
+                ..Default::default()
+            },
+        );
+        let manager = test_session_manager();
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // Both executions must be in flight at once to get past the barrier
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        for session in ["s1", "s2"] {
+            manager
+                .insert_session(
+                    session,
+                    "python",
+                    Box::new(RecordingTransport {
+                        ids: Arc::clone(&ids),
+                        barrier: Arc::clone(&barrier),
+                    }),
+                )
+                .await;
+        }
+        let server = SandboxServer::new(config, MockBackend, manager);
+
+        let run = |session: &str| {
+            let mut params = run_params("x = 1");
+            params.env = Some("python".to_string());
+            params.session = Some(session.to_string());
+            server.run_code(params, None)
+        };
+        let (first, second) = tokio::join!(run("s1"), run("s2"));
+        assert!(!first.unwrap().is_error.unwrap_or(false));
+        assert!(!second.unwrap().is_error.unwrap_or(false));
+
+        let ids = ids.lock().unwrap().clone();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        for id in &ids {
+            assert!(Uuid::parse_str(id).is_ok(), "not a UUID: {id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_signal_destroys_sessions() {
+        let manager = test_session_manager();
+        let shut_down = Arc::new(AtomicBool::new(false));
+        manager
+            .insert_session(
+                "s1",
+                "test",
+                Box::new(FlagTransport(Arc::clone(&shut_down))),
+            )
+            .await;
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        tx.send(()).unwrap();
+        let mut cancelled = false;
+
+        run_until_shutdown(
+            std::future::pending(),
+            async {
+                let _ = rx.await;
+            },
+            || cancelled = true,
+            manager.start_reaper(),
+            &manager,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert!(cancelled, "server should be cancelled on shutdown");
+        assert!(shut_down.load(Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnect_destroys_sessions_without_cancelling() {
+        let manager = test_session_manager();
+        let shut_down = Arc::new(AtomicBool::new(false));
+        manager
+            .insert_session(
+                "s1",
+                "test",
+                Box::new(FlagTransport(Arc::clone(&shut_down))),
+            )
+            .await;
+
+        let mut cancelled = false;
+        run_until_shutdown(
+            async { Ok(()) },
+            std::future::pending(),
+            || cancelled = true,
+            manager.start_reaper(),
+            &manager,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert!(!cancelled);
+        assert!(shut_down.load(Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn persist_keeps_sessions_after_disconnect() {
+        let manager = test_session_manager();
+        let shut_down = Arc::new(AtomicBool::new(false));
+        manager
+            .insert_session(
+                "s1",
+                "test",
+                Box::new(FlagTransport(Arc::clone(&shut_down))),
+            )
+            .await;
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let mut cancelled = false;
+        let (result, ()) = tokio::join!(
+            run_until_shutdown(
+                async { Ok(()) },
+                async {
+                    let _ = rx.await;
+                },
+                || cancelled = true,
+                manager.start_reaper(),
+                &manager,
+                true,
+            ),
+            async {
+                // The client is gone, but the session is still live
+                assert_eq!(manager.session_count().await, 1);
+                assert!(!shut_down.load(Ordering::SeqCst));
+                tx.send(()).unwrap();
+            }
+        );
+        result.unwrap();
+
+        // Torn down once the shutdown signal came
+        assert!(!cancelled);
+        assert!(shut_down.load(Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+    }
+
+    /// Initialize an MCP client on `stream` and ping the server, returning
+    /// the ping's response. The connection closes on return.
+    async fn initialize_and_ping(stream: tokio::net::UnixStream) -> serde_json::Value {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (reader, mut writer) = stream.into_split();
+        let messages = [
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {
+                "protocolVersion": "2024-11-05",
+                "capabilities": {},
+                "clientInfo": {"name": "test", "version": "0"},
+            }}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}),
+        ];
+        for message in messages {
+            let mut line = serde_json::to_vec(&message).unwrap();
+            line.push(b'\n');
+            writer.write_all(&line).await.unwrap();
         }
 
-        ServerInfo {
-            protocol_version: rmcp::model::ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation {
-                name: "nix-sandbox-mcp".into(),
-                version: env!("CARGO_PKG_VERSION").into(),
-                title: None,
-                icons: None,
-                website_url: None,
+        let mut lines = BufReader::new(reader).lines();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let line = lines.next_line().await.unwrap().expect("server hung up");
+                let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+                if response["id"] == 2 {
+                    return response;
+                }
+            }
+        })
+        .await
+        .expect("no ping response")
+    }
+
+    #[tokio::test]
+    async fn serve_answers_ping_over_a_socketpair() {
+        let (server_end, client_end) = tokio::net::UnixStream::pair().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let source = SandboxSource {
+            base: test_config(),
+            dir: dir.path().to_path_buf(),
+            unknown_fields: UnknownFields::Reject,
+        };
+        let server = tokio::spawn(serve(
+            test_config(),
+            MockBackend,
+            test_session_manager(),
+            source,
+            false,
+            server_end,
+        ));
+
+        let pong = initialize_and_ping(client_end).await;
+        assert_eq!(
+            pong,
+            serde_json::json!({"jsonrpc": "2.0", "id": 2, "result": {}})
+        );
+        // The client hung up, so the server stops
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn socket_serves_several_clients_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mcp.sock");
+
+        std::fs::write(&path, "").unwrap();
+        let err = bind_socket(&path).unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+
+        // A socket left behind by an earlier run is replaced...
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_socket(&path).unwrap();
+        let mode = std::os::unix::fs::PermissionsExt::mode(
+            &std::fs::metadata(&path).unwrap().permissions(),
+        );
+        assert_eq!(mode & 0o777, 0o600);
+        // ...but a live one isn't
+        let err = bind_socket(&path).unwrap_err();
+        assert!(err.to_string().contains("in use"), "{err}");
+
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let accept = tokio::spawn(accept_connections(listener, server, Clients::default()));
+        let first = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let second = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (first, second) = tokio::join!(initialize_and_ping(first), initialize_and_ping(second));
+        assert_eq!(
+            (first["id"].as_u64(), second["id"].as_u64()),
+            (Some(2), Some(2))
+        );
+        accept.abort();
+    }
+
+    #[test]
+    fn resources_match_environments() {
+        let mut config = test_config();
+        config.environments.insert(
+            "python".to_string(),
+            EnvironmentMeta {
+                session_exec: Some("/bin/session".to_string()),
+                ..Default::default()
             },
-            instructions: Some(desc),
+        );
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let uris: Vec<_> = server
+            .environment_resources()
+            .into_iter()
+            .map(|r| r.raw.uri)
+            .collect();
+        assert_eq!(uris, ["sandbox://env/python", "sandbox://env/test"]);
+        assert!(server.get_info().capabilities.resources.is_some());
+    }
+
+    #[test]
+    fn read_environment_resource() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+
+        let result = server
+            .read_environment_resource("sandbox://env/test")
+            .unwrap();
+        let ResourceContents::TextResourceContents { text, .. } = &result.contents[0] else {
+            panic!("expected text contents");
+        };
+        let meta: serde_json::Value = serde_json::from_str(text).unwrap();
+        assert_eq!(
+            meta,
+            serde_json::json!({
+                "name": "test",
+                "backend": "jail",
+                "interpreter": "test",
+                "sessions": false,
+                "limits": {
+                    "timeout_seconds": 30,
+                    "max_timeout_seconds": 30,
+                    "memory_mb": 512,
+                    "max_output_bytes": 1024 * 1024,
+                },
+            })
+        );
+
+        assert!(server
+            .read_environment_resource("sandbox://env/nope")
+            .is_err());
+        assert!(server
+            .read_environment_resource("file:///etc/passwd")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn alias_resolves_to_environment() {
+        let mut config = test_config();
+        config.environments.insert(
+            "python-data-science".to_string(),
+            EnvironmentMeta {
+                exec: "/bin/ds".to_string(),
+                aliases: vec!["py".to_string(), "test".to_string()],
+                ..Default::default()
+            },
+        );
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let catalog = server.catalog();
+        let (name, meta) = catalog.resolve("py").unwrap();
+        assert_eq!(name, "python-data-science");
+        assert_eq!(meta.exec, "/bin/ds");
+
+        // The real "test" environment wins over the colliding alias
+        let (name, meta) = catalog.resolve("test").unwrap();
+        assert_eq!(name, "test");
+        assert_eq!(meta.exec, "/bin/test");
+        assert!(catalog.resolve("nope").is_none());
+
+        let mut params = run_params("print(1)");
+        params.env = Some("py".to_string());
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+
+        let instructions = server.get_info().instructions.unwrap();
+        assert!(instructions.contains("- python-data-science (aliases: py)\n"));
+    }
+
+    #[test]
+    fn instructions_template_fills_placeholders() {
+        let mut config = test_config();
+        config.project = Some(ProjectConfig {
+            path: "/tmp".into(),
+            mount_point: "/code".to_string(),
+            ..Default::default()
+        });
+        config.instructions_template = Some(
+            "Policy: no network.\n{env_list}\nCode is at {project_mount}. {unknown} stays."
+                .to_string(),
+        );
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        assert_eq!(
+            server.get_info().instructions.unwrap(),
+            "Policy: no network.\n- test\nCode is at /code. {unknown} stays."
+        );
+    }
+
+    #[test]
+    fn instructions_fall_back_to_built_in_text() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let instructions = server.get_info().instructions.unwrap();
+        assert!(instructions.starts_with("Run commands in isolated Nix sandbox environments."));
+        assert!(instructions.contains("Available environments:\n- test\n"));
+        assert!(!instructions.contains("Project directory"));
+    }
+
+    #[test]
+    fn fill_template_is_single_pass() {
+        // A value containing a placeholder isn't expanded again
+        let filled = fill_template("{a}{b}{", &[("a", "{b}"), ("b", "x")]);
+        assert_eq!(filled, "{b}x{");
+    }
+
+    fn describe_params(env: &str, redact_paths: bool) -> Parameters<DescribeEnvironmentParams> {
+        Parameters(DescribeEnvironmentParams {
+            env: env.to_string(),
+            redact_paths,
+        })
+    }
+
+    #[tokio::test]
+    async fn describe_environment_by_name_and_alias() {
+        let mut config = test_config();
+        config.environments.insert(
+            "python".to_string(),
+            EnvironmentMeta {
+                exec: "/nix/store/abc-python/bin/run".to_string(),
+                session_exec: Some("/nix/store/abc-python/bin/session-run".to_string()),
+                interpreter_type: Some("python".to_string()),
+                aliases: vec!["py".to_string()],
+                ..Default::default()
+            },
+        );
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let result = server
+            .describe_environment(describe_params("py", true))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["name"], "python");
+        assert_eq!(json["interpreter"], "python");
+        assert_eq!(json["interpreter_type"], "python");
+        assert_eq!(json["sessions"], true);
+        assert_eq!(json["aliases"], serde_json::json!(["py"]));
+        assert_eq!(json["limits"]["timeout_seconds"], 30);
+        assert!(json.get("exec").is_none());
+        assert!(!result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .contains("/nix/store"));
+
+        let json = server
+            .describe_environment(describe_params("python", false))
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["exec"], "/nix/store/abc-python/bin/run");
+        assert_eq!(
+            json["session_exec"],
+            "/nix/store/abc-python/bin/session-run"
+        );
+    }
+
+    #[tokio::test]
+    async fn describe_unknown_environment() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let err = server
+            .describe_environment(describe_params("nope", true))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Unknown environment: 'nope'. Available: [\"test\"]"
+        );
+    }
+
+    async fn find_environments(server: &SandboxServer<MockBackend>, pattern: &str) -> Vec<String> {
+        let json = server
+            .find_environments(Parameters(FindEnvironmentsParams {
+                pattern: pattern.to_string(),
+            }))
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        serde_json::from_value(json["environments"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn find_environments_by_glob_and_substring() {
+        let mut config = test_config();
+        for name in ["python", "python-ml", "node"] {
+            config
+                .environments
+                .insert(name.to_string(), EnvironmentMeta::default());
         }
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        assert_eq!(
+            find_environments(&server, "py*").await,
+            ["python", "python-ml"]
+        );
+        assert_eq!(find_environments(&server, "node").await, ["node"]);
+        assert_eq!(find_environments(&server, "-ml").await, ["python-ml"]);
+        assert!(find_environments(&server, "ruby*").await.is_empty());
+
+        let err = server
+            .find_environments(Parameters(FindEnvironmentsParams {
+                pattern: "[py".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert!(
+            err.message.starts_with("Invalid pattern '[py'"),
+            "{}",
+            err.message
+        );
     }
-}
 
-/// Serve the sandbox server over stdio.
-///
-/// Starts the session reaper, serves MCP, then cleans up all sessions on disconnect.
-pub async fn serve_stdio<B: IsolationBackend + Clone + Send + Sync + 'static>(
-    config: Config,
-    backend: B,
-    session_manager: Arc<SessionManager>,
-) -> anyhow::Result<()> {
-    // Start background reaper
-    let reaper_handle = session_manager.start_reaper();
+    #[test]
+    fn describe_redacts_paths_by_default() {
+        let params: DescribeEnvironmentParams =
+            serde_json::from_str(r#"{"env": "python"}"#).unwrap();
+        assert!(params.redact_paths);
+    }
 
-    let server = SandboxServer::new(config, backend, Arc::clone(&session_manager));
+    /// Write a minimal bash sandbox artifact named `name` into `dir`.
+    fn write_sandbox(dir: &std::path::Path, name: &str) {
+        let sandbox = dir.join(name);
+        std::fs::create_dir_all(sandbox.join("bin")).unwrap();
+        std::fs::write(
+            sandbox.join("metadata.json"),
+            format!(r#"{{"name": "{name}", "interpreter_type": "bash"}}"#),
+        )
+        .unwrap();
+        std::fs::write(sandbox.join("bin/run"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(
+            sandbox.join("bin/run"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+    }
 
-    info!("Starting MCP server on stdio");
+    fn reloadable_server(dir: &std::path::Path) -> SandboxServer<MockBackend> {
+        let source = SandboxSource {
+            base: test_config(),
+            dir: dir.to_path_buf(),
+            unknown_fields: UnknownFields::Reject,
+        };
+        SandboxServer::new(source.load(), MockBackend, test_session_manager())
+            .with_sandbox_source(source)
+    }
 
-    let service = server
-        .serve(stdio())
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to start MCP server: {e}"))?;
+    #[tokio::test]
+    async fn reload_picks_up_new_sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = reloadable_server(dir.path());
+        let mut params = run_params("echo hi");
+        params.env = Some("extra".to_string());
+        assert!(server.run_code(params, None).await.is_err());
 
-    service
-        .waiting()
-        .await
-        .map_err(|e| anyhow::anyhow!("MCP server error: {e}"))?;
+        write_sandbox(dir.path(), "extra");
+        let result = server.reload().await.unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(text.contains("2 environment(s)"), "got: {text}");
+        assert!(text.contains("\nAdded: extra"), "got: {text}");
 
-    // MCP client disconnected — clean up
-    info!("MCP client disconnected, cleaning up sessions");
-    reaper_handle.abort();
-    session_manager.destroy_all().await;
+        let mut params = run_params("echo hi");
+        params.env = Some("extra".to_string());
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        assert!(server
+            .environment_resources()
+            .iter()
+            .any(|r| r.raw.uri == "sandbox://env/extra"));
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn reload_keeps_environment_for_existing_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        write_sandbox(dir.path(), "extra");
+        let server = reloadable_server(dir.path());
+        server
+            .session_manager
+            .insert_session(
+                "s1",
+                "extra",
+                Box::new(FlagTransport(Arc::new(AtomicBool::new(false)))),
+            )
+            .await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::backend::ExecutionResult;
-    use crate::config::{BackendType, EnvironmentMeta};
-    use crate::session::SessionConfig;
-    use async_trait::async_trait;
-    use std::collections::HashMap;
+        std::fs::remove_dir_all(dir.path().join("extra")).unwrap();
+        let result = server.reload().await.unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(text.contains("\nRemoved: extra"), "got: {text}");
+
+        // Gone for new work, still there for the session bound to it
+        let catalog = server.catalog();
+        assert!(catalog.resolve("extra").is_none());
+        assert!(server
+            .environment_for(&catalog, "extra", None)
+            .await
+            .is_none());
+        let (name, _) = server
+            .environment_for(&catalog, "extra", Some("s1"))
+            .await
+            .unwrap();
+        assert_eq!(name, "extra");
+
+        // Dropped on the next reload once the session is gone
+        server.session_manager.close("s1").await.unwrap();
+        server.reload().await.unwrap();
+        assert!(server.catalog().environment("extra").is_none());
+    }
+
+    #[tokio::test]
+    async fn reload_without_source_fails() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+        let result = server.reload().await.unwrap();
+        assert!(result.is_error.unwrap());
+    }
 
+    /// Backend that holds each execution until the gate is opened.
     #[derive(Clone)]
-    struct MockBackend;
+    struct GateBackend(Arc<Semaphore>);
 
     #[async_trait]
-    impl IsolationBackend for MockBackend {
+    impl IsolationBackend for GateBackend {
         async fn execute(
             &self,
             _env: &EnvironmentMeta,
             code: &str,
-            _project_dir: Option<&std::path::Path>,
-            _project_mount: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
         ) -> anyhow::Result<ExecutionResult> {
+            self.0.acquire().await?.forget();
             Ok(ExecutionResult {
-                exit_code: 0,
-                stdout: format!("executed: {code}"),
-                stderr: String::new(),
+                stdout: code.to_string(),
+                ..Default::default()
             })
         }
     }
 
-    fn test_config() -> Config {
-        let mut environments = HashMap::new();
-        environments.insert(
-            "test".to_string(),
-            EnvironmentMeta {
-                backend: BackendType::Jail,
-                exec: "/bin/test".to_string(),
-                session_exec: None,
-                timeout_seconds: 30,
-                memory_mb: 512,
-                interpreter_type: None,
-            },
+    fn limited_server(policy: BusyPolicy) -> (SandboxServer<GateBackend>, Arc<Semaphore>) {
+        let gate = Arc::new(Semaphore::new(0));
+        let mut config = test_config();
+        config.limits = Some(crate::config::LimitsConfig {
+            max_concurrent_executions: Some(1),
+            when_busy: policy,
+            ..Default::default()
+        });
+        let server = SandboxServer::new(
+            config,
+            GateBackend(Arc::clone(&gate)),
+            test_session_manager(),
         );
-        Config {
-            environments,
-            project: None,
+        (server, gate)
+    }
+
+    fn run_params(code: &str) -> RunParams {
+        RunParams {
+            code: code.into(),
+            env: Some("test".to_string()),
             session: None,
+            stdin: None,
+            timeout_seconds: None,
+            output_format: OutputFormat::Text,
+            binary: false,
+            workdir: None,
+            continue_on_error: false,
+            rebind: false,
+            recreate_on_death: false,
+            secret_env: SecretEnv::default(),
+            combine_output: false,
+            capture_output: true,
+            allow_empty: false,
         }
     }
 
-    fn test_session_manager() -> Arc<SessionManager> {
-        Arc::new(SessionManager::new(SessionConfig::default()))
+    /// Builds the error a `FailingBackend` returns.
+    type MakeError = fn() -> anyhow::Error;
+
+    /// Backend whose executions fail with the error its function builds.
+    #[derive(Clone)]
+    struct FailingBackend(MakeError);
+
+    #[async_trait]
+    impl IsolationBackend for FailingBackend {
+        async fn execute(
+            &self,
+            _env: &EnvironmentMeta,
+            _code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> anyhow::Result<ExecutionResult> {
+            Err((self.0)())
+        }
     }
 
     #[tokio::test]
-    async fn test_run_success() {
-        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
-        let params = Parameters(RunParams {
-            code: "echo hello".to_string(),
-            env: "test".to_string(),
-            session: None,
+    async fn test_execution_errors_are_categorized() {
+        let cases: [(MakeError, &str, bool); 5] = [
+            (
+                || ExecError::SpawnFailed(anyhow::anyhow!("no such wrapper")).into(),
+                "spawn_failed",
+                false,
+            ),
+            (
+                || ExecError::Timeout(anyhow::anyhow!("agent silent")).into(),
+                "timeout",
+                true,
+            ),
+            (
+                || ExecError::IoError(anyhow::anyhow!("broken pipe")).into(),
+                "io_error",
+                true,
+            ),
+            (
+                || {
+                    anyhow::Error::from(ExecError::ProtocolError(anyhow::anyhow!("bad frame")))
+                        .context("Failed to communicate with session agent")
+                },
+                "protocol_error",
+                false,
+            ),
+            (|| anyhow::anyhow!("something else"), "other", false),
+        ];
+
+        for (fail, category, retryable) in cases {
+            let server =
+                SandboxServer::new(test_config(), FailingBackend(fail), test_session_manager());
+            let result = server.run_code(run_params("x"), None).await.unwrap();
+            assert!(result.is_error.unwrap_or(false));
+            let error = &result.structured_content.unwrap()["error"];
+            assert_eq!(error["category"], category);
+            assert_eq!(error["retryable"], retryable, "{category}");
+            // The cause stays in the message, even through added context
+            let message = error["message"].as_str().unwrap();
+            assert_eq!(message, format!("{:#}", fail()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_appends_audit_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        std::fs::write(&path, "{\"earlier\":true}\n").unwrap();
+        let sink = AuditSink::open(&crate::config::AuditConfig {
+            path: path.clone(),
+            hash: crate::config::HashAlgorithm::Sha256,
+        })
+        .unwrap();
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager())
+            .with_audit_sink(sink);
+
+        let result = server
+            .run_code(run_params("secret code"), None)
+            .await
+            .unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(log.ends_with('\n'));
+        assert!(!log.contains("secret code"));
+
+        let record: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(record["env"], "test");
+        assert_eq!(record["session"], serde_json::Value::Null);
+        assert_eq!(record["exit_code"], 0);
+        assert_eq!(record["truncated"], false);
+        assert_eq!(record["timed_out"], false);
+        assert!(record["duration_ms"].is_u64());
+        assert!(record["timestamp_ms"].as_u64().unwrap() > 0);
+        assert!(!record["request_id"].as_str().unwrap().is_empty());
+        let hash = record["code_hash"].as_str().unwrap();
+        assert!(hash.starts_with("sha256:"), "{hash}");
+        assert_eq!(hash.len(), "sha256:".len() + 64);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_run_is_audited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit/audit.jsonl");
+        let sink = AuditSink::open(&crate::config::AuditConfig {
+            path: path.clone(),
+            hash: crate::config::HashAlgorithm::Sha256,
+        })
+        .unwrap();
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager())
+            .with_audit_sink(sink);
+
+        let mut params = run_params("print(1)");
+        params.env = Some("nope".to_string());
+        let err = server.run_code(params, None).await.unwrap_err();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(log.trim_end()).unwrap();
+        assert_eq!(record["env"], "nope");
+        assert_eq!(record["exit_code"], serde_json::Value::Null);
+        assert_eq!(record["error"], err.message.as_ref());
+        assert!(record["code_hash"].as_str().unwrap().starts_with("sha256:"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_code_rejected() {
+        let mut config = test_config();
+        config.limits = Some(crate::config::LimitsConfig {
+            max_code_bytes: Some(1024),
+            ..Default::default()
         });
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let err = server
+            .run_code(run_params(&"x".repeat(1025)), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Code is 1025 bytes, over the 1KB limit (max_code_bytes). \
+             Send large data through a mounted file or stdin instead."
+        );
 
-        let result = server.run(params).await.unwrap();
+        let result = server
+            .run_code(run_params(&"x".repeat(1024)), None)
+            .await
+            .unwrap();
         assert!(!result.is_error.unwrap_or(false));
     }
 
     #[tokio::test]
-    async fn test_run_unknown_env() {
+    async fn test_argv_code_limits_rejected() {
+        let mut config = test_config();
+        config.environments.get_mut("test").unwrap().input_mode = InputMode::Argv;
+        let server = SandboxServer::new(config, MockBackend, test_session_manager());
+
+        let err = server.run_code(run_params("a\0b"), None).await.unwrap_err();
+        assert_eq!(err.code, rmcp::model::ErrorCode::INVALID_PARAMS);
+        assert!(err.message.contains("NUL"), "{}", err.message);
+
+        let long = "x".repeat(crate::config::MAX_ARG_BYTES);
+        let err = server.run_code(run_params(&long), None).await.unwrap_err();
+        assert_eq!(err.code, rmcp::model::ErrorCode::INVALID_PARAMS);
+        assert!(err.message.contains("at most 131071"), "{}", err.message);
+    }
+
+    #[tokio::test]
+    async fn test_blank_code_rejected() {
         let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
-        let params = Parameters(RunParams {
-            code: "echo hello".to_string(),
-            env: "unknown".to_string(),
-            session: None,
-        });
 
-        let result = server.run(params).await;
-        assert!(result.is_err());
+        for code in [Code::from(""), Code::from(" \n\t"), Code::Fragments(vec![])] {
+            let mut params = run_params("");
+            params.code = code;
+            let err = server.run_code(params, None).await.unwrap_err();
+            assert_eq!(err.code, rmcp::model::ErrorCode::INVALID_PARAMS);
+            assert!(err.message.starts_with("Code is empty"), "{}", err.message);
+        }
     }
 
     #[tokio::test]
-    async fn test_session_without_session_exec() {
+    async fn test_allow_empty_runs_blank_code() {
         let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
-        let params = Parameters(RunParams {
-            code: "x = 42".to_string(),
-            env: "test".to_string(),
-            session: Some("mysession".to_string()),
+
+        let mut params = run_params("  ");
+        params.allow_empty = true;
+        let result = server.run_code(params, None).await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_rejects_when_busy() {
+        let (server, gate) = limited_server(BusyPolicy::Reject);
+        let first = tokio::spawn({
+            let server = server.clone();
+            async move { server.run_code(run_params("first"), None).await }
         });
+        while server.execution_slots.as_ref().unwrap().available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
 
-        // Should fail because test env has no session_exec
-        let result = server.run(params).await.unwrap();
-        assert!(result.is_error.unwrap_or(false));
+        let busy = server.run_code(run_params("second"), None).await.unwrap();
+        assert!(busy.is_error.unwrap());
+        let text = &busy.content[0].as_text().unwrap().text;
+        assert!(text.starts_with("Server busy: 1 executions already running"));
+
+        // Session calls count against the same cap
+        let mut session_params = run_params("x");
+        session_params.session = Some("s1".to_string());
+        let busy = server.run_code(session_params, None).await.unwrap();
+        assert!(busy.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .starts_with("Server busy"));
+
+        gate.add_permits(1);
+        let first = first.await.unwrap().unwrap();
+        assert!(!first.is_error.unwrap_or(false));
+
+        // The slot is free again
+        gate.add_permits(1);
+        let third = server.run_code(run_params("third"), None).await.unwrap();
+        assert!(!third.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_queues_when_busy() {
+        let (server, gate) = limited_server(BusyPolicy::Queue);
+        let first = tokio::spawn({
+            let server = server.clone();
+            async move { server.run_code(run_params("first"), None).await }
+        });
+        while server.execution_slots.as_ref().unwrap().available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let second = tokio::spawn({
+            let server = server.clone();
+            async move { server.run_code(run_params("second"), None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished(), "second call should wait for a slot");
+
+        gate.add_permits(2);
+        assert!(!first.await.unwrap().unwrap().is_error.unwrap_or(false));
+        assert!(!second.await.unwrap().unwrap().is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_health() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+
+        let result = server.ping().await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        let health = result.structured_content.unwrap();
+        assert_eq!(health["environments"], 1);
+        assert_eq!(health["active_sessions"], 0);
+        assert!(health["uptime_seconds"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_version_reports_build_info() {
+        let server = SandboxServer::new(test_config(), MockBackend, test_session_manager());
+
+        let result = server.version().await.unwrap();
+        assert!(!result.is_error.unwrap_or(false));
+        let info = result.structured_content.unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["agent_protocol"]["max"], SUPPORTED_PROTOCOL);
+        assert!(info["git_commit"].as_str().is_some_and(|c| !c.is_empty()));
+        assert!(info["rustc"].as_str().unwrap().starts_with("rustc"));
     }
 }
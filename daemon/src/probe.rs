@@ -0,0 +1,140 @@
+//! Startup self-test of each environment (`--probe`).
+//!
+//! `--check` only looks at paths; a probe runs a no-op snippet through the
+//! environment's ephemeral backend, which also catches sandboxes whose
+//! wrapper exists but can't start its interpreter.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::backend::{ExecOptions, IsolationBackend};
+use crate::config::{Config, Mounts};
+use crate::session::env_to_interpreter;
+
+/// How long one environment gets to run its probe.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Code that does nothing in `interpreter`. Unknown interpreters get an
+/// empty program.
+fn noop_snippet(interpreter: &str) -> &'static str {
+    match interpreter {
+        "python" => "pass",
+        "bash" => "true",
+        "node" => "0",
+        _ => "",
+    }
+}
+
+/// Run a no-op in `config`'s environment `name` and return how long it took.
+///
+/// Fails if the backend errors, the run times out, or it exits nonzero;
+/// the error carries the end of stderr, where the cause usually is.
+pub async fn probe_environment<B: IsolationBackend + ?Sized>(
+    backend: &B,
+    config: &Config,
+    name: &str,
+    mounts: &Mounts,
+) -> Result<Duration> {
+    let meta = config
+        .environments
+        .get(name)
+        .with_context(|| format!("Unknown environment '{name}'"))?;
+    let interpreter = env_to_interpreter(name, meta, &config.interpreter_map);
+    let started = Instant::now();
+    let result = backend
+        .execute(
+            meta,
+            noop_snippet(&interpreter),
+            PROBE_TIMEOUT,
+            None,
+            mounts,
+            &ExecOptions::default(),
+            None,
+        )
+        .await?;
+
+    if result.timed_out {
+        anyhow::bail!("timed out after {}s", PROBE_TIMEOUT.as_secs());
+    }
+    if result.exit_code != 0 {
+        let stderr = result.stderr.trim_end();
+        let last = stderr.lines().last().unwrap_or("no stderr");
+        anyhow::bail!("exited with code {}: {last}", result.exit_code);
+    }
+    Ok(started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ExecutionResult, OutputSender};
+    use crate::config::EnvironmentMeta;
+    use async_trait::async_trait;
+
+    /// Backend whose result depends on the environment's `exec`.
+    struct ProbeBackend;
+
+    #[async_trait]
+    impl IsolationBackend for ProbeBackend {
+        async fn execute(
+            &self,
+            env: &EnvironmentMeta,
+            code: &str,
+            _timeout: Duration,
+            _stdin: Option<&str>,
+            _mounts: &Mounts,
+            _options: &ExecOptions,
+            _output: Option<&OutputSender>,
+        ) -> Result<ExecutionResult> {
+            match env.exec.as_str() {
+                "ok" => Ok(ExecutionResult {
+                    stdout: code.to_string(),
+                    ..Default::default()
+                }),
+                "broken" => Ok(ExecutionResult {
+                    exit_code: 127,
+                    stderr: "jail: starting\npython3: command not found\n".to_string(),
+                    ..Default::default()
+                }),
+                "hang" => Ok(ExecutionResult {
+                    exit_code: -1,
+                    timed_out: true,
+                    ..Default::default()
+                }),
+                _ => anyhow::bail!("Failed to spawn {}", env.exec),
+            }
+        }
+    }
+
+    async fn probe(exec: &str) -> Result<Duration> {
+        let json =
+            format!(r#"{{"environments": {{"python": {{"backend": "jail", "exec": "{exec}"}}}}}}"#);
+        let config = Config::from_json(&json).unwrap();
+        probe_environment(&ProbeBackend, &config, "python", &Mounts::default()).await
+    }
+
+    #[tokio::test]
+    async fn probe_reports_each_failure_kind() {
+        assert!(probe("ok").await.is_ok());
+
+        let err = probe("broken").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exited with code 127: python3: command not found"
+        );
+
+        let err = probe("hang").await.unwrap_err();
+        assert!(err.to_string().starts_with("timed out"), "{err}");
+
+        let err = probe("/missing").await.unwrap_err();
+        assert_eq!(err.to_string(), "Failed to spawn /missing");
+    }
+
+    #[test]
+    fn noop_snippets_per_interpreter() {
+        assert_eq!(noop_snippet("python"), "pass");
+        assert_eq!(noop_snippet("bash"), "true");
+        assert_eq!(noop_snippet("custom"), "");
+    }
+}
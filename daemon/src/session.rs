@@ -4,20 +4,47 @@
 //! interpreter state (variables, imports, files) across `run()` calls.
 //! Each session is bound to its creation environment — using a different
 //! `env` on an existing session returns an error.
+//!
+//! With a `state_dir` configured, session metadata is persisted so that after
+//! a daemon restart, calls on a pre-restart session get a clear "expired
+//! across restart" error. Only metadata persists — agent processes don't.
+//!
+//! With a notifier attached, the manager reports sessions being created and
+//! reaped as [`SessionEvent`]s, which the MCP server forwards to the client.
+//!
+//! With `suspend_after` set, a session idle that long has its agent paused
+//! with SIGSTOP instead of being reaped at the idle timeout, and resumed with
+//! SIGCONT when next used. It keeps its state until the max lifetime.
+
+mod clock;
+mod history;
+mod persist;
+mod suspend;
 
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result};
-use tokio::sync::{Mutex, RwLock};
+use nix::sys::signal::Signal;
+use serde::Serialize;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
-use crate::backend::ExecutionResult;
-use crate::config::EnvironmentMeta;
-use crate::transport::protocol::{AgentRequest, AgentResponse};
-use crate::transport::{StdioPipeTransport, Transport};
+use crate::backend::{ExecError, ExecutionResult};
+use crate::config::{
+    check_executable, validate_env_var_name, BackendType, EnvironmentMeta, Mounts, SandboxDepth,
+};
+use crate::transport::protocol::{AgentRequest, AgentResponse, FragmentResult, BATCH_PROTOCOL};
+use crate::transport::{StdioPipeTransport, TcpTransport, Transport, VsockTransport};
+use history::History;
+use persist::SessionRecord;
+
+pub use clock::{Clock, MockClock, RealClock};
+pub use history::HistoryEntry;
 
 /// Parsed session configuration with `Duration` fields.
 #[derive(Debug, Clone)]
@@ -28,11 +55,131 @@ pub struct SessionConfig {
     /// Maximum total lifetime of a session, regardless of activity.
     pub max_lifetime: Duration,
 
+    /// Maximum number of live sessions (each is an agent process).
+    /// At capacity, the least recently used session is evicted.
+    pub max_sessions: usize,
+
     /// How long to wait for the agent's Ready message on startup.
     pub agent_ready_timeout: Duration,
 
     /// Interval between reaper sweeps.
     pub reaper_interval: Duration,
+
+    /// How long to wait for a `Pong` when health-checking an existing
+    /// session before reuse. `None` skips the check.
+    pub ping_timeout: Option<Duration>,
+
+    /// Directory for persisted session metadata. `None` disables persistence.
+    pub state_dir: Option<PathBuf>,
+
+    /// Interval between keepalive pings to idle sessions. A session that
+    /// doesn't answer is removed. `None` disables keepalive.
+    pub keepalive_interval: Option<Duration>,
+
+    /// Ceiling on the summed `memory_mb` of live sessions (`[limits]`).
+    /// At the ceiling, the least recently used session is evicted.
+    pub max_session_memory_mb: Option<u64>,
+
+    /// Interpreters a session may run. An environment resolving to any
+    /// other interpreter is rejected before an agent is spawned.
+    pub allowed_interpreters: BTreeSet<String>,
+
+    /// Environments sessions may be created for (`None` = all).
+    pub session_allow: Option<BTreeSet<String>>,
+
+    /// Environments sessions may never be created for, even if allowed above.
+    pub session_deny: BTreeSet<String>,
+
+    /// Protocol-level deadline for one request round-trip to an agent,
+    /// separate from the execution timeout. An agent that misses it is
+    /// killed and the session recreated on next use. `None` waits forever.
+    pub request_timeout: Option<Duration>,
+
+    /// Sandbox nesting of the daemon; agents are spawned one level deeper.
+    pub depth: SandboxDepth,
+
+    /// Interpreter per environment name (`[interpreter_map]`), for
+    /// environments without `interpreter_type`.
+    pub interpreter_map: HashMap<String, String>,
+
+    /// Cap on how fast one session may start executions. `None` is unlimited.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Idle time after which a session's agent is paused (SIGSTOP) rather
+    /// than reaped at `idle_timeout`. Paused sessions resume on next use
+    /// and live until `max_lifetime`. `None` reaps idle sessions as usual.
+    pub suspend_after: Option<Duration>,
+
+    /// Executions each session keeps in its history (0 keeps none).
+    pub history_size: usize,
+
+    /// Keep code verbatim in history rather than only its digest.
+    pub history_code: bool,
+}
+
+/// At most `executions` per `window` for one session, as a token bucket:
+/// a full bucket allows a burst of `executions`, then tokens come back
+/// evenly over `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub executions: u32,
+    pub window: Duration,
+}
+
+/// Rate limit from configured values; no executions (or 0) disables it.
+fn rate_limit_from(executions: Option<u32>, window_secs: u64) -> Option<RateLimit> {
+    executions.filter(|&n| n > 0).map(|executions| RateLimit {
+        executions,
+        window: Duration::from_secs(window_secs),
+    })
+}
+
+/// One session's token bucket, kept as the time the bucket would next be
+/// full again were no more tokens taken (so it needs no float counts).
+#[derive(Debug, Default)]
+struct RateBucket {
+    refilled_at: Option<Instant>,
+}
+
+impl RateBucket {
+    /// Whether the bucket is full at `now`, the same as a fresh one.
+    fn is_full(&self, now: Instant) -> bool {
+        self.refilled_at.map_or(true, |t| t <= now)
+    }
+
+    /// Take a token at `now`, or return how long until one is available.
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let per_token = limit.window / limit.executions.max(1);
+        // A full bucket is `window` of slack; each execution uses `per_token`
+        let refilled_at = self.refilled_at.map_or(now, |t| t.max(now));
+        let debt = refilled_at.saturating_duration_since(now);
+        let slack = limit.window.saturating_sub(per_token);
+        if debt > slack {
+            return Err(debt.saturating_sub(slack));
+        }
+        self.refilled_at = Some(refilled_at + per_token);
+        Ok(())
+    }
+}
+
+/// Interpreters the bundled agent implements.
+const DEFAULT_INTERPRETERS: [&str; 3] = ["python", "bash", "node"];
+
+/// Default agent round-trip deadline; longer than any sane execution timeout.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long a new session's agent gets to answer `ListInterpreters`.
+const LIST_INTERPRETERS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a discarded session's agent gets to shut down before it's
+/// left to be killed when the session is dropped.
+const DISCARD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request timeout from a configured number of seconds (0 disables).
+fn request_timeout_from(secs: Option<u64>) -> Option<Duration> {
+    secs.map_or(Some(DEFAULT_REQUEST_TIMEOUT), |secs| {
+        (secs > 0).then(|| Duration::from_secs(secs))
+    })
 }
 
 impl Default for SessionConfig {
@@ -40,8 +187,23 @@ impl Default for SessionConfig {
         Self {
             idle_timeout: Duration::from_secs(300),
             max_lifetime: Duration::from_secs(3600),
+            max_sessions: crate::config::default_max_sessions(),
             agent_ready_timeout: Duration::from_secs(30),
             reaper_interval: Duration::from_secs(60),
+            ping_timeout: Some(Duration::from_secs(2)),
+            state_dir: None,
+            keepalive_interval: None,
+            max_session_memory_mb: None,
+            allowed_interpreters: DEFAULT_INTERPRETERS.map(String::from).into(),
+            session_allow: None,
+            session_deny: BTreeSet::new(),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            depth: SandboxDepth::default(),
+            interpreter_map: HashMap::new(),
+            rate_limit: None,
+            suspend_after: None,
+            history_size: 0,
+            history_code: false,
         }
     }
 }
@@ -52,28 +214,267 @@ impl SessionConfig {
         Self {
             idle_timeout: Duration::from_secs(toml.idle_timeout_seconds),
             max_lifetime: Duration::from_secs(toml.max_lifetime_seconds),
+            max_sessions: toml.max_sessions,
+            state_dir: toml.state_dir.clone(),
+            keepalive_interval: toml.keepalive_interval_seconds.map(Duration::from_secs),
+            allowed_interpreters: toml.allowed_interpreters.as_ref().map_or_else(
+                || Self::default().allowed_interpreters,
+                |list| list.iter().cloned().collect(),
+            ),
+            session_allow: toml
+                .session_allow
+                .as_ref()
+                .map(|list| list.iter().cloned().collect()),
+            session_deny: toml.session_deny.iter().cloned().collect(),
+            request_timeout: request_timeout_from(toml.request_timeout_seconds),
+            agent_ready_timeout: Duration::from_secs(toml.agent_ready_timeout_seconds),
+            // A zero period would make the reaper's ticker panic
+            reaper_interval: Duration::from_secs(toml.reaper_interval_seconds.max(1)),
+            rate_limit: rate_limit_from(toml.rate_limit_executions, toml.rate_limit_window_seconds),
+            suspend_after: toml.suspend_idle_seconds.map(Duration::from_secs),
+            history_size: toml.history_size,
+            history_code: toml.history_store_code,
             ..Self::default()
         }
     }
 
     /// Create from environment variables, falling back to defaults.
     ///
-    /// Reads `SESSION_IDLE_TIMEOUT` and `SESSION_MAX_LIFETIME` (in seconds).
+    /// Reads `SESSION_IDLE_TIMEOUT` and `SESSION_MAX_LIFETIME` (in seconds),
+    /// `SESSION_MAX_COUNT`, `SESSION_STATE_DIR`, `SESSION_KEEPALIVE_INTERVAL`
+    /// (in seconds), `SESSION_REQUEST_TIMEOUT` (in seconds, 0 disables),
+    /// `SESSION_RATE_LIMIT` and `SESSION_RATE_LIMIT_WINDOW` (in seconds),
+    /// `SESSION_SUSPEND_IDLE` (in seconds), `SESSION_HISTORY_SIZE`,
+    /// `SESSION_HISTORY_CODE` (`true` or `false`), and
+    /// `SESSION_ALLOWED_INTERPRETERS`, `SESSION_ALLOW_ENVS` and
+    /// `SESSION_DENY_ENVS` (comma-separated).
     pub fn from_env() -> Self {
         Self {
             idle_timeout: std::env::var("SESSION_IDLE_TIMEOUT")
                 .ok()
                 .and_then(|v| v.parse().ok())
-                .map(Duration::from_secs)
-                .unwrap_or(Duration::from_secs(300)),
+                .map_or(Duration::from_secs(300), Duration::from_secs),
             max_lifetime: std::env::var("SESSION_MAX_LIFETIME")
                 .ok()
                 .and_then(|v| v.parse().ok())
-                .map(Duration::from_secs)
-                .unwrap_or(Duration::from_secs(3600)),
+                .map_or(Duration::from_secs(3600), Duration::from_secs),
+            max_sessions: std::env::var("SESSION_MAX_COUNT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_else(crate::config::default_max_sessions),
+            state_dir: std::env::var("SESSION_STATE_DIR").ok().map(PathBuf::from),
+            keepalive_interval: std::env::var("SESSION_KEEPALIVE_INTERVAL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs),
+            allowed_interpreters: std::env::var("SESSION_ALLOWED_INTERPRETERS").map_or_else(
+                |_| Self::default().allowed_interpreters,
+                |list| split_list(&list),
+            ),
+            session_allow: std::env::var("SESSION_ALLOW_ENVS")
+                .ok()
+                .map(|list| split_list(&list)),
+            session_deny: std::env::var("SESSION_DENY_ENVS")
+                .map(|list| split_list(&list))
+                .unwrap_or_default(),
+            request_timeout: request_timeout_from(
+                std::env::var("SESSION_REQUEST_TIMEOUT")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+            ),
+            rate_limit: rate_limit_from(
+                std::env::var("SESSION_RATE_LIMIT")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                std::env::var("SESSION_RATE_LIMIT_WINDOW")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|&secs| secs > 0)
+                    .unwrap_or(60),
+            ),
+            suspend_after: std::env::var("SESSION_SUSPEND_IDLE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs),
+            history_size: std::env::var("SESSION_HISTORY_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            history_code: std::env::var("SESSION_HISTORY_CODE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
             ..Self::default()
         }
     }
+
+    /// Check `session_allow` and `session_deny` for `env_name`.
+    fn check_session_policy(&self, env_name: &str) -> Result<()> {
+        if self.session_deny.contains(env_name) {
+            anyhow::bail!(
+                "Sessions are not allowed for environment '{env_name}' (session_deny). \
+                 Omit `session` to run it ephemerally."
+            );
+        }
+        if let Some(allow) = &self.session_allow {
+            if !allow.contains(env_name) {
+                anyhow::bail!(
+                    "Sessions are not allowed for environment '{env_name}' (session_allow: {}). \
+                     Omit `session` to run it ephemerally.",
+                    allow
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a comma-separated env var value, ignoring blank entries.
+fn split_list(list: &str) -> BTreeSet<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// What [`SessionManager::reset`] did. A reset the agent attempted but
+/// couldn't complete is an error instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetOutcome {
+    /// The agent cleared its interpreter state.
+    Reset,
+    /// The agent doesn't announce `reset`; nothing was sent.
+    Unsupported,
+}
+
+/// Point-in-time view of a live session (for `list_sessions` and
+/// `session_info`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: String,
+    pub env_name: String,
+    /// Process ID of the session's agent, if it runs on this host.
+    pub pid: Option<u32>,
+    /// When the agent behind the session started; a restart or a silent
+    /// recreation moves it.
+    pub started_at: SystemTime,
+    /// Time since the session was created.
+    pub age: Duration,
+    /// Time since the session last completed a request.
+    pub idle: Duration,
+    /// Time until the reaper closes it (idle timeout or max lifetime, whichever comes first).
+    pub expires_in: Duration,
+    /// Whether the agent is paused for being idle.
+    pub suspended: bool,
+}
+
+/// How live sessions are spread across environments (for `session_stats`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Live sessions per environment name.
+    pub by_env: BTreeMap<String, usize>,
+    /// Creation time of the longest-lived session.
+    pub oldest_created_at: Option<SystemTime>,
+    /// Creation time of the most recently created session.
+    pub newest_created_at: Option<SystemTime>,
+}
+
+impl SessionStats {
+    /// Live sessions across all environments.
+    pub fn total(&self) -> usize {
+        self.by_env.values().sum()
+    }
+}
+
+/// The running reaper task (see [`SessionManager::start_reaper`]).
+#[derive(Debug)]
+pub struct Reaper {
+    cancel: CancellationToken,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Reaper {
+    /// Stop the reaper, waiting for a sweep in progress to finish first.
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        if let Err(e) = self.task.await {
+            warn!(error = %e, "Reaper task failed");
+        }
+    }
+}
+
+/// A session starting or ending, for clients that track sessions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionEvent {
+    pub session: String,
+    pub env: String,
+    pub event: SessionEventKind,
+    /// Why: "new" or "replaced" (an unresponsive agent) on creation; on
+    /// close, the reaper's reason ("idle timeout", "max lifetime",
+    /// "unresponsive"), "closed", "evicted", "rebound", "agent died", or
+    /// "shutdown".
+    pub reason: String,
+}
+
+/// What happened to the session in a [`SessionEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionEventKind {
+    Created,
+    Closed,
+}
+
+/// Receives [`SessionEvent`]s; see [`SessionManager::attach_notifier`].
+pub type SessionEventSender = tokio::sync::mpsc::UnboundedSender<SessionEvent>;
+
+/// Session lifecycle counters since the manager started (for `metrics()`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionMetrics {
+    /// Sessions started by `get_or_create` (restarts not included).
+    pub created: u64,
+    /// Sessions the reaper removed for exceeding the idle timeout.
+    pub reaped_idle: u64,
+    /// Sessions the reaper removed for exceeding the max lifetime.
+    pub reaped_lifetime: u64,
+    /// Sessions evicted to make room under `max_sessions` or the memory limit.
+    pub evicted: u64,
+    /// Sessions closed by an explicit `close`.
+    pub closed: u64,
+    /// Sessions successfully restarted.
+    pub restarted: u64,
+}
+
+/// Live counters behind [`SessionMetrics`].
+#[derive(Debug, Default)]
+struct MetricCounters {
+    created: AtomicU64,
+    reaped_idle: AtomicU64,
+    reaped_lifetime: AtomicU64,
+    evicted: AtomicU64,
+    closed: AtomicU64,
+    restarted: AtomicU64,
+}
+
+impl MetricCounters {
+    fn bump(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> SessionMetrics {
+        SessionMetrics {
+            created: self.created.load(Ordering::Relaxed),
+            reaped_idle: self.reaped_idle.load(Ordering::Relaxed),
+            reaped_lifetime: self.reaped_lifetime.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            restarted: self.restarted.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /// A persistent sandbox session.
@@ -89,47 +490,373 @@ pub struct Session {
     /// When this session was created.
     pub created_at: Instant,
 
+    /// Wall-clock time of `created_at`, recorded then for reports, so a
+    /// later clock change doesn't shift it.
+    created_wall: SystemTime,
+
+    /// Memory limit of the session's environment, for `max_session_memory_mb`.
+    memory_mb: u64,
+
     /// Last time this session was used (for idle timeout).
     last_used: Mutex<Instant>,
 
-    /// Transport to the agent process.
-    transport: Mutex<Box<dyn Transport>>,
+    /// Transport to the agent process (synchronized internally).
+    transport: Box<dyn Transport>,
+
+    /// Id of the `Execute` or `ExecuteBatch` awaiting a result, if any (for cancel).
+    in_flight: Mutex<Option<String>>,
+
+    /// Whether the environment's preamble has run (or there was none when
+    /// the session was first used).
+    preamble_done: AtomicBool,
+
+    /// Interpreters the agent listed for `ListInterpreters`, if asked.
+    interpreters: OnceLock<Vec<String>>,
+
+    /// Time source for `created_at`, `last_used`, and expiry.
+    clock: Arc<dyn Clock>,
+
+    /// Whether the agent's processes are stopped (see `suspend`).
+    suspended: AtomicBool,
+
+    /// Recent executions, for `session_history`.
+    history: Mutex<History>,
+
+    /// Whether a reply went astray (a request timed out, or the agent
+    /// answered another request), so the agent's next reply can't be
+    /// trusted to be for the next request. The session is replaced.
+    desynced: AtomicBool,
 }
 
 impl Session {
-    fn new(id: String, env_name: String, transport: Box<dyn Transport>) -> Self {
-        let now = Instant::now();
+    fn new(
+        id: String,
+        env_name: String,
+        memory_mb: u64,
+        transport: Box<dyn Transport>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
         Self {
             id,
             env_name,
             created_at: now,
+            created_wall: clock.wall_now(),
+            memory_mb,
             last_used: Mutex::new(now),
-            transport: Mutex::new(transport),
+            transport,
+            in_flight: Mutex::new(None),
+            preamble_done: AtomicBool::new(false),
+            interpreters: OnceLock::new(),
+            clock,
+            suspended: AtomicBool::new(false),
+            history: Mutex::new(History::default()),
+            desynced: AtomicBool::new(false),
         }
     }
 
+    /// Whether the agent is alive and in step with its replies.
+    fn is_usable(&self) -> bool {
+        self.transport.is_alive() && !self.desynced.load(Ordering::Relaxed)
+    }
+
+    /// This session's recent executions, oldest first.
+    pub async fn history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().await.entries()
+    }
+
     /// Send a request to the agent and return the response.
+    ///
+    /// `Execute` and `ExecuteBatch` requests are recorded as in flight until the response
+    /// arrives. Callers that may drop this future (timeouts) must call
+    /// `clear_in_flight` afterwards.
     async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
-        let resp = self.transport.lock().await.request(req).await?;
-        *self.last_used.lock().await = Instant::now();
+        self.resume();
+        if let AgentRequest::Execute { id, .. } | AgentRequest::ExecuteBatch { id, .. } = req {
+            *self.in_flight.lock().await = Some(id.clone());
+        }
+        let resp = self.transport.request(req).await;
+        self.clear_in_flight().await;
+        let resp = resp?;
+        if let (Some(expected), Some(got)) = (req.id(), resp.id()) {
+            if expected != got {
+                self.desynced.store(true, Ordering::Relaxed);
+                return Err(ExecError::ProtocolError(anyhow::anyhow!(
+                    "Agent answered request '{got}' while '{expected}' was pending"
+                ))
+                .into());
+            }
+        }
+        *self.last_used.lock().await = self.clock.now();
         Ok(resp)
     }
 
-    /// Shut down the agent.
+    /// Give up on the request in flight after its timeout: ask the agent to
+    /// interrupt it, and mark the session out of step, since its reply may
+    /// still arrive. The request future must already be dropped.
+    async fn abandon_request(&self) {
+        if let Err(e) = self.cancel().await {
+            debug!(session = %self.id, error = %e, "Could not cancel timed-out request");
+        }
+        self.clear_in_flight().await;
+        self.desynced.store(true, Ordering::Relaxed);
+    }
+
+    /// Run `fragments` in order: one `ExecuteBatch` for agents speaking
+    /// protocol v2, else one `Execute` each. Returns a result per fragment
+    /// that ran.
+    async fn request_batch(
+        &self,
+        request_id: &str,
+        interpreter: String,
+        fragments: &[String],
+        stop_on_error: bool,
+    ) -> Result<Vec<FragmentResult>> {
+        if self.transport.protocol_version() >= BATCH_PROTOCOL {
+            let req = AgentRequest::ExecuteBatch {
+                id: request_id.to_string(),
+                interpreter,
+                fragments: fragments.to_vec(),
+                stop_on_error,
+            };
+            return match self.request(&req).await? {
+                AgentResponse::BatchResult { results, .. } => Ok(results),
+                other => Ok(vec![fragment_result(other)?]),
+            };
+        }
+
+        let mut results = Vec::with_capacity(fragments.len());
+        for code in fragments {
+            let req = AgentRequest::Execute {
+                id: request_id.to_string(),
+                interpreter: interpreter.clone(),
+                code: code.clone(),
+            };
+            let result = fragment_result(self.request(&req).await?)?;
+            let failed = result.exit_code != 0;
+            results.push(result);
+            if failed && stop_on_error {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Ask the agent to clear its interpreter state in-process.
+    ///
+    /// The preamble runs again on the next execution, since whatever it set
+    /// up is gone.
+    async fn reset(&self, request_id: &str) -> Result<ResetOutcome> {
+        if !self.transport.capabilities().is_some_and(|c| c.reset) {
+            return Ok(ResetOutcome::Unsupported);
+        }
+        let req = AgentRequest::Reset {
+            id: request_id.to_string(),
+        };
+        let resp = self
+            .request(&req)
+            .await
+            .context("Failed to communicate with session agent")?;
+        self.preamble_done.store(false, Ordering::Relaxed);
+        match resp {
+            AgentResponse::ResetDone { .. } => Ok(ResetOutcome::Reset),
+            AgentResponse::Error { message } => anyhow::bail!("Agent failed to reset: {message}"),
+            other => Err(ExecError::ProtocolError(anyhow::anyhow!(
+                "Unexpected agent response: {other:?}"
+            ))
+            .into()),
+        }
+    }
+
+    /// Send a `SetEnv` or `GetEnv` and return the variable's value.
+    async fn env_request(&self, req: &AgentRequest) -> Result<Option<String>> {
+        anyhow::ensure!(
+            self.transport.capabilities().is_some_and(|c| c.session_env),
+            "Session '{}' doesn't support session environment variables; \
+             rebuild the sandbox to update its agent",
+            self.id
+        );
+        let resp = self
+            .request(req)
+            .await
+            .context("Failed to communicate with session agent")?;
+        match resp {
+            AgentResponse::EnvValue { value, .. } => Ok(value),
+            AgentResponse::Error { message } => anyhow::bail!("Agent refused: {message}"),
+            other => Err(ExecError::ProtocolError(anyhow::anyhow!(
+                "Unexpected agent response: {other:?}"
+            ))
+            .into()),
+        }
+    }
+
+    /// Forget the in-flight execution.
+    async fn clear_in_flight(&self) {
+        *self.in_flight.lock().await = None;
+    }
+
+    /// Ask the agent to interrupt the in-flight execution.
+    ///
+    /// Goes through `send_control`, so it doesn't wait behind the pending
+    /// `request()`. Errors if nothing is executing.
+    async fn cancel(&self) -> Result<()> {
+        let id =
+            self.in_flight.lock().await.clone().ok_or_else(|| {
+                anyhow::anyhow!("No execution in flight for session '{}'", self.id)
+            })?;
+        self.transport
+            .send_control(&AgentRequest::Cancel { id })
+            .await
+    }
+
+    /// Check that the agent answers a `Ping` within `timeout`.
+    ///
+    /// Bypasses `request()` so a health check doesn't count as activity.
+    async fn ping(&self, timeout: Duration) -> bool {
+        self.resume();
+        if !self.transport.is_alive() {
+            return false;
+        }
+        matches!(
+            tokio::time::timeout(timeout, self.transport.request(&AgentRequest::Ping)).await,
+            Ok(Ok(AgentResponse::Pong))
+        )
+    }
+
+    /// Ask the agent which interpreters it can run, if it takes
+    /// `ListInterpreters`, and remember the answer. If it doesn't, or
+    /// doesn't answer in time, the list from its `Ready` stands; a late
+    /// answer would be taken for the next request's, so the session is
+    /// marked out of step.
+    async fn query_interpreters(&self) {
+        if !self
+            .transport
+            .capabilities()
+            .is_some_and(|c| c.list_interpreters)
+        {
+            return;
+        }
+        let request = self.transport.request(&AgentRequest::ListInterpreters);
+        match tokio::time::timeout(LIST_INTERPRETERS_TIMEOUT, request).await {
+            Ok(Ok(AgentResponse::Interpreters { names })) => {
+                debug!(session = %self.id, interpreters = ?names, "Agent listed interpreters");
+                let _ = self.interpreters.set(names);
+            }
+            Ok(Ok(other)) => {
+                warn!(session = %self.id, response = ?other, "Unexpected reply to ListInterpreters");
+            }
+            Ok(Err(e)) => warn!(session = %self.id, error = %e, "Failed to list interpreters"),
+            Err(_) => {
+                warn!(session = %self.id, "Timed out listing interpreters");
+                self.desynced.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Interpreters the agent can run: the queried list, else the one from
+    /// `Ready`. Empty if the agent never said, meaning any.
+    fn interpreters(&self) -> &[String] {
+        self.interpreters
+            .get()
+            .or_else(|| self.transport.capabilities().map(|c| &c.interpreters))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Shut down the agent, logging how its process ended when known.
     async fn shutdown(&self) -> Result<()> {
-        let transport = self.transport.lock().await;
-        transport.shutdown().await
+        // A stopped agent can't read the shutdown request
+        self.resume();
+        let result = self.transport.shutdown().await;
+        if let Some(status) = self.transport.last_exit() {
+            info!(session = %self.id, %status, "Session agent exited");
+        }
+        result
+    }
+
+    /// Whether the agent runs locally, so it can be paused with signals.
+    fn can_suspend(&self) -> bool {
+        self.transport.pid().is_some()
+    }
+
+    fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    /// Pause the agent's processes with SIGSTOP. Returns whether they were
+    /// paused.
+    fn suspend(&self) -> bool {
+        let Some(pid) = self.transport.pid() else {
+            return false;
+        };
+        match suspend::signal_tree(pid, Signal::SIGSTOP) {
+            Ok(()) => {
+                self.suspended.store(true, Ordering::SeqCst);
+                true
+            }
+            Err(e) => {
+                warn!(session = %self.id, error = %e, "Failed to suspend session");
+                false
+            }
+        }
+    }
+
+    /// Let a paused agent run again with SIGCONT; a no-op if it isn't paused.
+    fn resume(&self) {
+        if !self.suspended.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(pid) = self.transport.pid() {
+            match suspend::signal_tree(pid, Signal::SIGCONT) {
+                Ok(()) => debug!(session = %self.id, "Resumed suspended session"),
+                Err(e) => warn!(session = %self.id, error = %e, "Failed to resume session"),
+            }
+        }
+    }
+
+    /// Snapshot this session's metadata for persistence.
+    async fn record(&self) -> SessionRecord {
+        let now = self.clock.now();
+        SessionRecord {
+            id: self.id.clone(),
+            env_name: self.env_name.clone(),
+            created_at: persist::instant_to_unix(self.created_at, now),
+            last_used: persist::instant_to_unix(*self.last_used.lock().await, now),
+        }
+    }
+
+    /// Snapshot this session's timing against the reaper limits.
+    async fn info(&self, idle_timeout: Duration, max_lifetime: Duration) -> SessionInfo {
+        let now = self.clock.now();
+        let age = now.duration_since(self.created_at);
+        let idle = now.duration_since(*self.last_used.lock().await);
+        let suspended = self.is_suspended();
+        // A suspended session outlives the idle timeout
+        let idle_left = if suspended {
+            Duration::MAX
+        } else {
+            idle_timeout.saturating_sub(idle)
+        };
+        SessionInfo {
+            id: self.id.clone(),
+            env_name: self.env_name.clone(),
+            pid: self.transport.pid(),
+            started_at: self.created_wall,
+            age,
+            idle,
+            expires_in: idle_left.min(max_lifetime.saturating_sub(age)),
+            suspended,
+        }
     }
 
     /// Check if this session has exceeded idle timeout.
     async fn is_idle_expired(&self, timeout: Duration) -> bool {
         let last_used = *self.last_used.lock().await;
-        last_used.elapsed() > timeout
+        self.clock.now().saturating_duration_since(last_used) > timeout
     }
 
     /// Check if this session has exceeded max lifetime.
     fn is_lifetime_expired(&self, max_lifetime: Duration) -> bool {
-        self.created_at.elapsed() > max_lifetime
+        self.clock.now().saturating_duration_since(self.created_at) > max_lifetime
     }
 }
 
@@ -143,19 +870,136 @@ pub struct SessionManager {
     /// concurrent requests for the same session are processed in arrival order.
     /// Different sessions run in parallel (different locks).
     execute_locks: RwLock<HashMap<String, Arc<Mutex<()>>>>,
+    /// Rate-limit bucket per session ID, apart from the session and its
+    /// lock so closing, reaping or rebinding doesn't refill it. Full
+    /// buckets are dropped, as a fresh one is the same.
+    rate_buckets: std::sync::Mutex<HashMap<String, RateBucket>>,
+    /// Sessions persisted by a previous daemon run. Their agents are gone;
+    /// the first call on one reports the loss, then the ID is free again.
+    stale: Mutex<HashMap<String, SessionRecord>>,
+    /// Held from snapshot to write in `save_state`, so the state file is
+    /// written one snapshot at a time, in order.
+    state_lock: Mutex<()>,
+    metrics: MetricCounters,
+    /// Where session events go, once the MCP service is up.
+    notifier: std::sync::Mutex<Option<SessionEventSender>>,
+    /// Time source shared by every session, for expiry and rate limits.
+    clock: Arc<dyn Clock>,
     config: SessionConfig,
 }
 
 impl SessionManager {
     /// Create a new session manager with the given configuration.
+    ///
+    /// If `state_dir` is set, loads sessions persisted by a previous run.
+    /// Records that would already have been reaped are dropped.
     pub fn new(config: SessionConfig) -> Self {
+        Self::with_clock(config, Arc::new(RealClock))
+    }
+
+    /// Like `new`, timing sessions with `clock` instead of the system clock.
+    pub fn with_clock(config: SessionConfig, clock: Arc<dyn Clock>) -> Self {
+        let stale = config
+            .state_dir
+            .as_ref()
+            .map(|dir| match persist::load(&dir.join(persist::STATE_FILE)) {
+                Ok(records) => records
+                    .into_iter()
+                    .filter(|r| !r.is_expired(config.idle_timeout, config.max_lifetime))
+                    .map(|r| (r.id.clone(), r))
+                    .collect(),
+                Err(e) => {
+                    warn!(error = %e, "Ignoring unreadable session state");
+                    HashMap::new()
+                }
+            })
+            .unwrap_or_default();
+
+        if config
+            .keepalive_interval
+            .is_some_and(|interval| interval >= config.idle_timeout)
+        {
+            warn!("Keepalive interval is not shorter than the idle timeout");
+        }
+
+        if !stale.is_empty() {
+            info!(
+                count = stale.len(),
+                "Loaded sessions from previous daemon run"
+            );
+        }
+
         Self {
             sessions: RwLock::new(HashMap::new()),
             execute_locks: RwLock::new(HashMap::new()),
+            rate_buckets: std::sync::Mutex::new(HashMap::new()),
+            stale: Mutex::new(stale),
+            state_lock: Mutex::new(()),
+            metrics: MetricCounters::default(),
+            notifier: std::sync::Mutex::new(None),
+            clock,
             config,
         }
     }
 
+    /// Send session events to `notifier` from now on.
+    ///
+    /// The manager is built before the MCP service that forwards events
+    /// exists, so the channel is attached afterwards.
+    pub fn attach_notifier(&self, notifier: SessionEventSender) {
+        *self
+            .notifier
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(notifier);
+    }
+
+    /// Report a session event, if a notifier is attached.
+    fn notify(&self, session: &Session, event: SessionEventKind, reason: &str) {
+        let notifier = self
+            .notifier
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(notifier) = notifier.as_ref() {
+            // The receiver is gone once the service stops; nothing to tell
+            let _ = notifier.send(SessionEvent {
+                session: session.id.clone(),
+                env: session.env_name.clone(),
+                event,
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    /// Snapshot of the session lifecycle counters.
+    pub fn metrics(&self) -> SessionMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Write live and stale session metadata to the state file, if configured.
+    ///
+    /// Persistence is best-effort: failures are logged, never surfaced.
+    /// The file is written on the blocking pool, off the async workers.
+    async fn save_state(&self) {
+        let Some(dir) = &self.config.state_dir else {
+            return;
+        };
+
+        let _state = self.state_lock.lock().await;
+        let live: Vec<Arc<Session>> = self.sessions.read().await.values().cloned().collect();
+        let mut records: Vec<SessionRecord> = self.stale.lock().await.values().cloned().collect();
+        for session in &live {
+            records.push(session.record().await);
+        }
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let path = dir.join(persist::STATE_FILE);
+        match tokio::task::spawn_blocking(move || persist::save(&path, &records)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(error = %e, "Failed to persist session state"),
+            Err(e) => warn!(error = %e, "Session state writer failed"),
+        }
+    }
+
     /// Get or create the per-session execute lock.
     async fn get_execute_lock(&self, session_id: &str) -> Arc<Mutex<()>> {
         // Fast path: read lock
@@ -174,6 +1018,33 @@ impl SessionManager {
         )
     }
 
+    /// Take an execution from `session_id`'s rate-limit bucket at `now`.
+    fn check_rate_limit(&self, session_id: &str, now: Instant) -> Result<()> {
+        let Some(limit) = self.config.rate_limit else {
+            return Ok(());
+        };
+        let mut buckets = self
+            .rate_buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        buckets.retain(|_, bucket| !bucket.is_full(now));
+        let taken = buckets
+            .entry(session_id.to_string())
+            .or_default()
+            .take(limit, now);
+        drop(buckets);
+        taken.map_err(|wait| {
+            // Whole seconds, rounded up, so retrying then is sure to work
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            anyhow::anyhow!(
+                "Session '{session_id}' is rate limited ({} executions per {}s); \
+                 retry after {secs}s",
+                limit.executions,
+                limit.window.as_secs()
+            )
+        })
+    }
+
     /// Execute code in a session, creating the session if needed.
     ///
     /// Per-session execute lock ensures concurrent requests for the same
@@ -184,170 +1055,1036 @@ impl SessionManager {
     /// - The session exists but is bound to a different environment
     /// - The environment doesn't support sessions (`session_exec` is None)
     /// - The agent process fails to start or respond
+    /// - The session is over its `rate_limit`
+    ///
+    /// `request_id` is sent to the agent as the `Execute` request's id.
+    ///
+    /// If the agent turns out to have died, the session is dropped. With
+    /// `recreate_on_death` the code runs once more in a fresh session (its
+    /// interpreter state is gone, which stderr notes); otherwise the call
+    /// fails saying so.
+    #[allow(clippy::too_many_arguments)]
     pub async fn execute(
         &self,
         session_id: &str,
+        request_id: &str,
         env_name: &str,
         env_meta: &EnvironmentMeta,
         code: &str,
-        project_dir: Option<&Path>,
-        project_mount: &str,
+        timeout: Duration,
+        mounts: &Mounts,
+        recreate_on_death: bool,
     ) -> Result<ExecutionResult> {
         // Per-session lock: serializes all operations on this session.
         // First task to reach here wins; others queue behind it.
         let exec_lock = self.get_execute_lock(session_id).await;
         let _guard = exec_lock.lock().await;
+        self.check_rate_limit(session_id, self.clock.now())?;
 
-        let session = self
-            .get_or_create(session_id, env_name, env_meta, project_dir, project_mount)
+        let mut recreated = false;
+        loop {
+            let (session, interpreter) = self
+                .open_session(session_id, env_name, env_meta, mounts)
+                .await?;
+            self.run_preamble(
+                &session,
+                request_id,
+                &interpreter,
+                env_name,
+                env_meta,
+                timeout,
+            )
             .await?;
 
-        // Map env_name to interpreter name for the agent protocol
-        let interpreter = env_to_interpreter(env_name, env_meta);
+            let req = AgentRequest::Execute {
+                id: request_id.to_string(),
+                interpreter,
+                code: code.to_string(),
+            };
 
-        let req = AgentRequest::Execute {
-            id: session_id.to_string(),
-            interpreter,
-            code: code.to_string(),
-        };
+            let started = Instant::now();
+            let Ok(resp) = tokio::time::timeout(timeout, session.request(&req)).await else {
+                // The request future was dropped before it could clean up
+                session.abandon_request().await;
+                self.record_history(&session, request_id, code, None).await;
+                self.discard_session(&session, "timed out").await;
+                return Ok(timed_out_in_session(&session, timeout, started.elapsed()));
+            };
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let retry = recreate_on_death && !recreated;
+                    self.handle_request_error(&session, e, retry).await?;
+                    recreated = true;
+                    continue;
+                }
+            };
 
-        let timeout = Duration::from_secs(env_meta.timeout_seconds);
-        let resp = tokio::time::timeout(timeout, session.request(&req))
-            .await
-            .map_err(|_| {
-                anyhow::anyhow!(
-                    "Session execution timed out after {}s",
-                    env_meta.timeout_seconds
-                )
-            })?
-            .context("Failed to communicate with session agent")?;
+            self.save_state().await;
 
-        match resp {
-            AgentResponse::Result {
-                stdout,
-                stderr,
-                exit_code,
-                ..
-            } => Ok(ExecutionResult {
-                exit_code,
-                stdout,
-                stderr,
-            }),
-            AgentResponse::Error { message } => Ok(ExecutionResult {
-                exit_code: 1,
-                stdout: String::new(),
-                stderr: message,
-            }),
-            other => anyhow::bail!("Unexpected agent response: {other:?}"),
+            let result = fragment_result(resp)?;
+            self.record_history(&session, request_id, code, Some(result.exit_code))
+                .await;
+            return Ok(ExecutionResult {
+                exit_code: result.exit_code,
+                stdout: result.stdout,
+                stderr: recreated_note(recreated) + &result.stderr,
+                duration: started.elapsed(),
+                ..ExecutionResult::default()
+            });
         }
     }
 
-    /// Get an existing session or create a new one.
+    /// Execute code fragments in order in a session, as one call.
     ///
-    /// Caller must hold the per-session execute lock — this guarantees
-    /// no concurrent creation race for the same session_id.
-    async fn get_or_create(
+    /// Holds the execute lock for the whole batch, so no other call on the
+    /// session runs in between. Agents speaking protocol v2 get a single
+    /// `ExecuteBatch`; older ones are sent one `Execute` per fragment.
+    /// With `stop_on_error`, the fragments after the first nonzero exit
+    /// don't run. `timeout` covers the whole batch. `recreate_on_death` is
+    /// as for [`execute`](Self::execute).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn execute_batch(
         &self,
         session_id: &str,
+        request_id: &str,
         env_name: &str,
         env_meta: &EnvironmentMeta,
-        project_dir: Option<&Path>,
-        project_mount: &str,
-    ) -> Result<Arc<Session>> {
-        // Check for existing session
-        {
-            let sessions = self.sessions.read().await;
-            if let Some(session) = sessions.get(session_id) {
-                if session.env_name != env_name {
-                    anyhow::bail!(
-                        "Session '{}' is bound to environment '{}', not '{}'.\n\
-                         Use a different session ID, or omit session for ephemeral execution.",
-                        session_id,
-                        session.env_name,
-                        env_name
-                    );
-                }
-                return Ok(Arc::clone(session));
-            }
-        }
+        fragments: &[String],
+        stop_on_error: bool,
+        timeout: Duration,
+        mounts: &Mounts,
+        recreate_on_death: bool,
+    ) -> Result<ExecutionResult> {
+        let exec_lock = self.get_execute_lock(session_id).await;
+        let _guard = exec_lock.lock().await;
+        self.check_rate_limit(session_id, self.clock.now())?;
 
-        // Create new session (no race possible — execute lock is held)
-        let session_exec = env_meta.session_exec.as_deref().ok_or_else(|| {
-            anyhow::anyhow!(
-                "Environment '{}' does not support sessions (no session_exec configured)",
-                env_name
+        let mut recreated = false;
+        loop {
+            let (session, interpreter) = self
+                .open_session(session_id, env_name, env_meta, mounts)
+                .await?;
+            self.run_preamble(
+                &session,
+                request_id,
+                &interpreter,
+                env_name,
+                env_meta,
+                timeout,
             )
-        })?;
+            .await?;
 
-        // Build env vars for the agent process (for runtime project mounting)
-        let mut env_vars = Vec::new();
-        if let Some(dir) = project_dir {
-            env_vars.push(("PROJECT_DIR".to_string(), dir.to_string_lossy().into_owned()));
-            env_vars.push(("PROJECT_MOUNT".to_string(), project_mount.to_string()));
-        }
+            let started = Instant::now();
+            let run = session.request_batch(request_id, interpreter, fragments, stop_on_error);
+            let Ok(results) = tokio::time::timeout(timeout, run).await else {
+                session.abandon_request().await;
+                self.record_history(&session, request_id, &fragments.join("\n"), None)
+                    .await;
+                self.discard_session(&session, "timed out").await;
+                return Ok(timed_out_in_session(&session, timeout, started.elapsed()));
+            };
+            let results = match results {
+                Ok(results) => results,
+                Err(e) => {
+                    let retry = recreate_on_death && !recreated;
+                    self.handle_request_error(&session, e, retry).await?;
+                    recreated = true;
+                    continue;
+                }
+            };
 
-        let transport =
-            StdioPipeTransport::spawn(session_exec, self.config.agent_ready_timeout, &env_vars).await
-                .with_context(|| {
-                    format!("Failed to start session agent for '{env_name}'")
-                })?;
+            self.save_state().await;
 
-        let session = Arc::new(Session::new(
-            session_id.to_string(),
-            env_name.to_string(),
-            Box::new(transport),
-        ));
+            let exit_code = results
+                .iter()
+                .map(|r| r.exit_code)
+                .find(|&code| code != 0)
+                .unwrap_or(0);
+            self.record_history(&session, request_id, &fragments.join("\n"), Some(exit_code))
+                .await;
+            return Ok(ExecutionResult {
+                exit_code,
+                stdout: results.iter().map(|r| r.stdout.as_str()).collect(),
+                stderr: results
+                    .iter()
+                    .fold(recreated_note(recreated), |out, r| out + &r.stderr),
+                duration: started.elapsed(),
+                fragment_exit_codes: Some(results.iter().map(|r| r.exit_code).collect()),
+                ..ExecutionResult::default()
+            });
+        }
+    }
 
-        info!(session = %session_id, env = %env_name, "Created new session");
-        let mut sessions = self.sessions.write().await;
-        sessions.insert(session_id.to_string(), Arc::clone(&session));
+    /// Append an execution to `session`'s history, if history is kept.
+    async fn record_history(
+        &self,
+        session: &Session,
+        request_id: &str,
+        code: &str,
+        exit_code: Option<i32>,
+    ) {
+        if self.config.history_size == 0 {
+            return;
+        }
+        let entry = HistoryEntry::new(request_id, code, exit_code, self.config.history_code);
+        session
+            .history
+            .lock()
+            .await
+            .push(entry, self.config.history_size);
+    }
+
+    /// Deal with a failed request on `session`.
+    ///
+    /// While the agent is usable the error is returned as is. The session of
+    /// a dead or out-of-step agent is dropped; then `Ok` (only with
+    /// `recreate`) tells the caller to run again on a fresh session, and
+    /// otherwise the error says the session's state is gone.
+    ///
+    /// Caller must hold the per-session execute lock.
+    async fn handle_request_error(
+        &self,
+        session: &Session,
+        e: anyhow::Error,
+        recreate: bool,
+    ) -> Result<()> {
+        if session.is_usable() {
+            return Err(e.context("Failed to communicate with session agent"));
+        }
+
+        warn!(session = %session.id, error = %format!("{e:#}"), "Session agent failed");
+        let (reason, what) = if session.transport.is_alive() {
+            ("agent out of step", "lost track of its agent's replies")
+        } else {
+            ("agent died", "died")
+        };
+        self.discard_session(session, reason).await;
+
+        if recreate {
+            warn!(session = %session.id, "Recreating session; its interpreter state was lost");
+            return Ok(());
+        }
+        Err(ExecError::IoError(anyhow::anyhow!(
+            "Session '{}' {what} and its interpreter state was lost ({e:#}). The next call \
+             starts a fresh session; pass recreate_on_death to retry automatically.",
+            session.id
+        ))
+        .into())
+    }
+
+    /// Drop `session` and shut its agent down, so the next call with its ID
+    /// starts a fresh one.
+    ///
+    /// Caller must hold the per-session execute lock.
+    async fn discard_session(&self, session: &Session, reason: &str) {
+        self.sessions.write().await.remove(&session.id);
+        self.notify(session, SessionEventKind::Closed, reason);
+        match tokio::time::timeout(DISCARD_SHUTDOWN_TIMEOUT, session.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(session = %session.id, error = %e, "Error shutting down session"),
+            Err(_) => warn!(session = %session.id, "Timed out shutting down session"),
+        }
+        self.save_state().await;
+    }
+
+    /// Check policy and a stale record, then get or create the session and
+    /// pick its interpreter for `env_name`.
+    ///
+    /// Caller must hold the per-session execute lock.
+    async fn open_session(
+        &self,
+        session_id: &str,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        mounts: &Mounts,
+    ) -> Result<(Arc<Session>, String)> {
+        // Policy first, so a disallowed environment never spawns an agent
+        self.config.check_session_policy(env_name)?;
+
+        // A session from before a daemon restart lost its interpreter state.
+        // Report it once; the next call with this ID starts fresh.
+        let stale = self.stale.lock().await.remove(session_id);
+        if let Some(record) = stale {
+            self.save_state().await;
+            anyhow::bail!(
+                "Session '{session_id}' (environment '{}') expired across a daemon restart; \
+                 its interpreter state was lost. Retry to start a fresh session with this ID.",
+                record.env_name
+            );
+        }
+
+        // Map env_name to interpreter name for the agent protocol
+        let interpreter = env_to_interpreter(env_name, env_meta, &self.config.interpreter_map);
+        if !self.config.allowed_interpreters.contains(&interpreter) {
+            anyhow::bail!(
+                "Environment '{env_name}' uses interpreter '{interpreter}', which sessions \
+                 don't allow (allowed: {})",
+                self.config
+                    .allowed_interpreters
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let session = self
+            .get_or_create(session_id, env_name, env_meta, mounts)
+            .await?;
+
+        let supported = session.interpreters();
+        if !supported.is_empty() && !supported.contains(&interpreter) {
+            anyhow::bail!(
+                "Session agent for environment '{env_name}' does not support interpreter \
+                 '{interpreter}' (supported: {})",
+                supported.join(", ")
+            );
+        }
+
+        Ok((session, interpreter))
+    }
+
+    /// Run the environment's preamble if this is the session's first call.
+    ///
+    /// The preamble runs in the session like any code, so what it defines
+    /// persists. A failure is an error naming the preamble rather than a
+    /// result, so it isn't mistaken for the caller's code failing; the
+    /// caller's code doesn't run, and the next call tries the preamble again.
+    ///
+    /// Caller must hold the per-session execute lock.
+    async fn run_preamble(
+        &self,
+        session: &Session,
+        request_id: &str,
+        interpreter: &str,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        timeout: Duration,
+    ) -> Result<()> {
+        if session.preamble_done.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let Some(preamble) = env_meta.preamble.as_deref() else {
+            session.preamble_done.store(true, Ordering::Relaxed);
+            return Ok(());
+        };
+
+        debug!(session = %session.id, env = %env_name, "Running session preamble");
+        let req = AgentRequest::Execute {
+            id: format!("{request_id}-preamble"),
+            interpreter: interpreter.to_string(),
+            code: preamble.to_string(),
+        };
+        let Ok(resp) = tokio::time::timeout(timeout, session.request(&req)).await else {
+            session.abandon_request().await;
+            self.discard_session(session, "preamble timed out").await;
+            anyhow::bail!(
+                "Preamble for environment '{env_name}' timed out after {}s; your code did not \
+                 run and the session was restarted",
+                timeout.as_secs()
+            );
+        };
+        let resp = resp.context("Failed to run session preamble")?;
+
+        let result = fragment_result(resp)?;
+        if result.exit_code != 0 {
+            anyhow::bail!(
+                "Preamble for environment '{env_name}' failed with exit code {}; \
+                 your code did not run.\n{}",
+                result.exit_code,
+                result.stderr.trim_end()
+            );
+        }
+        session.preamble_done.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Get an existing session or create a new one.
+    ///
+    /// Caller must hold the per-session execute lock — this guarantees
+    /// no concurrent creation race for the same `session_id`.
+    async fn get_or_create(
+        &self,
+        session_id: &str,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        mounts: &Mounts,
+    ) -> Result<Arc<Session>> {
+        // Check for existing session
+        let existing = self.sessions.read().await.get(session_id).cloned();
+        let replaced = existing.is_some();
+        if let Some(session) = existing {
+            if session.env_name != env_name {
+                anyhow::bail!(
+                    "Session '{}' is bound to environment '{}', not '{}'.\n\
+                     Use a different session ID, pass rebind to replace it, or omit \
+                     session for ephemeral execution.",
+                    session_id,
+                    session.env_name,
+                    env_name
+                );
+            }
+
+            let healthy = session.is_usable()
+                && match self.config.ping_timeout {
+                    Some(ping_timeout) => session.ping(ping_timeout).await,
+                    None => true,
+                };
+            if healthy {
+                return Ok(session);
+            }
+
+            // Agent crashed, hung, or out of step — replace it rather than fail the call
+            warn!(session = %session_id, "Session agent not usable, recreating");
+            self.sessions.write().await.remove(session_id);
+            let shutdown_timeout = self.config.ping_timeout.unwrap_or(DISCARD_SHUTDOWN_TIMEOUT);
+            match tokio::time::timeout(shutdown_timeout, session.shutdown()).await {
+                Ok(Err(e)) => {
+                    warn!(session = %session_id, error = %e, "Error shutting down dead session");
+                }
+                Err(_) => warn!(session = %session_id, "Timed out shutting down dead session"),
+                Ok(Ok(())) => {}
+            }
+        }
+
+        // Create new session (no race possible — execute lock is held)
+        self.evict_lru_if_full(env_meta.memory_mb).await?;
+        let transport = self
+            .connect(env_name, env_meta, mounts)
+            .await
+            .map_err(ExecError::SpawnFailed)?;
+
+        let session = Arc::new(Session::new(
+            session_id.to_string(),
+            env_name.to_string(),
+            env_meta.memory_mb,
+            transport,
+            Arc::clone(&self.clock),
+        ));
+        session.query_interpreters().await;
+        if !session.is_usable() {
+            if let Err(e) = session.shutdown().await {
+                warn!(session = %session_id, error = %e, "Error shutting down new session");
+            }
+            return Err(ExecError::ProtocolError(anyhow::anyhow!(
+                "Session agent for '{env_name}' did not answer ListInterpreters in time"
+            ))
+            .into());
+        }
+
+        info!(session = %session_id, env = %env_name, "Created new session");
+        self.sessions
+            .write()
+            .await
+            .insert(session_id.to_string(), Arc::clone(&session));
+        MetricCounters::bump(&self.metrics.created, 1);
+        let reason = if replaced { "replaced" } else { "new" };
+        self.notify(&session, SessionEventKind::Created, reason);
+        self.save_state().await;
         Ok(session)
     }
 
+    /// Make room for a new session using `memory_mb` by evicting the least
+    /// recently used sessions, until both `max_sessions` and
+    /// `max_session_memory_mb` allow it.
+    ///
+    /// Called from `get_or_create` with the new session's execute lock held.
+    /// The new session isn't in the map yet, so it can never be the one
+    /// evicted. Sessions that are mid-execution (execute lock taken) are
+    /// skipped; if every session is busy, creation fails.
+    async fn evict_lru_if_full(&self, memory_mb: u64) -> Result<()> {
+        let memory_cap = self.config.max_session_memory_mb;
+        if let Some(cap) = memory_cap.filter(|&cap| memory_mb > cap) {
+            anyhow::bail!(
+                "Session needs {memory_mb} MB, more than the session memory limit ({cap} MB)"
+            );
+        }
+
+        loop {
+            let sessions: Vec<Arc<Session>> =
+                self.sessions.read().await.values().cloned().collect();
+            let used_mb: u64 = sessions.iter().map(|s| s.memory_mb).sum();
+            let count_full = sessions.len() >= self.config.max_sessions;
+            let memory_full = memory_cap.is_some_and(|cap| used_mb + memory_mb > cap);
+            if !count_full && !memory_full {
+                return Ok(());
+            }
+
+            let locks = self.execute_locks.read().await.clone();
+            let mut lru: Option<(Instant, Arc<Session>)> = None;
+            for session in sessions {
+                let busy = locks
+                    .get(&session.id)
+                    .is_some_and(|lock| lock.try_lock().is_err());
+                if busy {
+                    continue;
+                }
+                let last_used = *session.last_used.lock().await;
+                if lru.as_ref().map_or(true, |(oldest, _)| last_used < *oldest) {
+                    lru = Some((last_used, session));
+                }
+            }
+
+            let Some((last_used, victim)) = lru else {
+                if count_full {
+                    anyhow::bail!(
+                        "Session limit reached ({}) and all sessions are busy. \
+                         Close a session or retry later.",
+                        self.config.max_sessions
+                    );
+                }
+                anyhow::bail!(
+                    "Session memory limit reached ({used_mb} of {} MB in use) and all \
+                     sessions are busy. Close a session or retry later.",
+                    memory_cap.unwrap_or_default()
+                );
+            };
+
+            info!(
+                session = %victim.id,
+                idle_secs = self.clock.now().saturating_duration_since(last_used).as_secs(),
+                max_sessions = self.config.max_sessions,
+                used_mb,
+                "Evicting least recently used session"
+            );
+            self.sessions.write().await.remove(&victim.id);
+            self.notify(&victim, SessionEventKind::Closed, "evicted");
+            self.execute_locks.write().await.remove(&victim.id);
+            MetricCounters::bump(&self.metrics.evicted, 1);
+            if let Err(e) = victim.shutdown().await {
+                warn!(session = %victim.id, error = %e, "Error shutting down evicted session");
+            }
+        }
+    }
+
+    /// Start or reach a session agent, picking the transport by backend.
+    ///
+    /// Jail spawns `session_exec` and talks over its pipes; microvm connects
+    /// to the agent already listening on the VM's vsock address.
+    async fn connect(
+        &self,
+        env_name: &str,
+        env_meta: &EnvironmentMeta,
+        mounts: &Mounts,
+    ) -> Result<Box<dyn Transport>> {
+        if env_meta.backend == BackendType::Microvm {
+            let addr = env_meta.vsock.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Environment '{env_name}' does not support sessions (no vsock address configured)"
+                )
+            })?;
+            let transport =
+                VsockTransport::connect(addr.cid, addr.port, self.config.agent_ready_timeout)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to connect to session agent for '{env_name}'")
+                    })?;
+            return Ok(Box::new(transport));
+        }
+        if env_meta.backend == BackendType::Remote {
+            let addr = env_meta.tcp.as_ref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Environment '{env_name}' does not support sessions (no tcp address configured)"
+                )
+            })?;
+            let transport = TcpTransport::connect(addr, self.config.agent_ready_timeout)
+                .await
+                .with_context(|| format!("Failed to connect to session agent for '{env_name}'"))?;
+            return Ok(Box::new(transport));
+        }
+
+        let session_exec = env_meta.session_exec.as_deref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Environment '{env_name}' does not support sessions (no session_exec configured)"
+            )
+        })?;
+        // Blame the config rather than surfacing a bare spawn error
+        if let Some(problem) = check_executable(Path::new(session_exec)) {
+            anyhow::bail!("session_exec for environment '{env_name}' {problem}: {session_exec}");
+        }
+
+        let transport = StdioPipeTransport::spawn(
+            session_exec,
+            self.config.agent_ready_timeout,
+            &agent_env(env_meta, mounts),
+            self.config.depth,
+        )
+        .await
+        .with_context(|| format!("Failed to start session agent for '{env_name}'"))?
+        .with_request_timeout(self.config.request_timeout);
+        Ok(Box::new(transport))
+    }
+
+    /// Interrupt the execution currently running in a session.
+    ///
+    /// Does not take the per-session execute lock — the `execute()` call
+    /// being cancelled holds it. Returns an error if the session doesn't
+    /// exist or has no execution in flight.
+    pub async fn cancel(&self, session_id: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .read()
+            .await
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Session '{session_id}' not found"))?;
+
+        session.cancel().await?;
+        info!(session = %session_id, "Cancellation requested");
+        Ok(())
+    }
+
+    /// Close a session: remove it, drop its execute lock, and shut down its agent.
+    ///
+    /// Returns `false` if no such session exists.
+    pub async fn close(&self, session_id: &str) -> Result<bool> {
+        let session = {
+            let mut sessions = self.sessions.write().await;
+            let removed = sessions.remove(session_id);
+            self.execute_locks.write().await.remove(session_id);
+            drop(sessions);
+            removed
+        };
+        let was_stale = self.stale.lock().await.remove(session_id).is_some();
+
+        let Some(session) = session else {
+            if was_stale {
+                self.save_state().await;
+            }
+            return Ok(was_stale);
+        };
+
+        MetricCounters::bump(&self.metrics.closed, 1);
+        self.notify(&session, SessionEventKind::Closed, "closed");
+        self.save_state().await;
+        info!(session = %session_id, "Closing session");
+        session
+            .shutdown()
+            .await
+            .with_context(|| format!("Error shutting down session '{session_id}'"))?;
+        Ok(true)
+    }
+
+    /// Free `session_id` for use with `env_name`: if the session is bound to
+    /// another environment, shut it down, so the next call creates a fresh
+    /// one there under the same ID.
+    ///
+    /// Returns the environment it was bound to, if it was closed. Waits for
+    /// an execution already running in the session to finish first.
+    pub async fn rebind(&self, session_id: &str, env_name: &str) -> Option<String> {
+        let exec_lock = self.get_execute_lock(session_id).await;
+        let _guard = exec_lock.lock().await;
+
+        // A record lost across a restart would otherwise fail the next call
+        let mut stale = self.stale.lock().await;
+        let stale_env = stale
+            .get(session_id)
+            .filter(|record| record.env_name != env_name)
+            .map(|record| record.env_name.clone());
+        if stale_env.is_some() {
+            stale.remove(session_id);
+        }
+        drop(stale);
+
+        let session = {
+            let mut sessions = self.sessions.write().await;
+            match sessions.get(session_id) {
+                Some(session) if session.env_name != env_name => sessions.remove(session_id),
+                _ => None,
+            }
+        };
+        let Some(session) = session else {
+            if stale_env.is_some() {
+                self.save_state().await;
+            }
+            return stale_env;
+        };
+
+        info!(
+            session = %session_id,
+            from = %session.env_name,
+            to = %env_name,
+            "Rebinding session to a new environment"
+        );
+        MetricCounters::bump(&self.metrics.closed, 1);
+        self.notify(&session, SessionEventKind::Closed, "rebound");
+        self.save_state().await;
+        if let Err(e) = session.shutdown().await {
+            warn!(session = %session_id, error = %e, "Error shutting down rebound session");
+        }
+        Some(session.env_name.clone())
+    }
+
+    /// Environment a live session is bound to, if it exists.
+    pub async fn env_name(&self, session_id: &str) -> Option<String> {
+        self.sessions
+            .read()
+            .await
+            .get(session_id)
+            .map(|s| s.env_name.clone())
+    }
+
+    /// Point-in-time view of one live session.
+    pub async fn info(&self, session_id: &str) -> Option<SessionInfo> {
+        let session = self.sessions.read().await.get(session_id).cloned()?;
+        Some(
+            session
+                .info(self.config.idle_timeout, self.config.max_lifetime)
+                .await,
+        )
+    }
+
+    /// A live session's recent executions, oldest first (see `history_size`).
+    pub async fn history(&self, session_id: &str) -> Option<Vec<HistoryEntry>> {
+        let session = self.sessions.read().await.get(session_id).cloned()?;
+        Some(session.history().await)
+    }
+
+    /// When a live session last completed a request (or was touched).
+    pub async fn last_used(&self, session_id: &str) -> Option<SystemTime> {
+        let session = self.sessions.read().await.get(session_id).cloned()?;
+        let last_used = *session.last_used.lock().await;
+        let elapsed = self.clock.now().saturating_duration_since(last_used);
+        Some(SystemTime::now() - elapsed)
+    }
+
+    /// Mark a session as used now, pushing back its idle deadline without
+    /// running anything. Returns false if no such session is live.
+    ///
+    /// Waits for any running execution, so the touch lands after it
+    /// rather than racing the reaper's view of the session.
+    pub async fn touch(&self, session_id: &str) -> bool {
+        if !self.sessions.read().await.contains_key(session_id) {
+            return false;
+        }
+        let exec_lock = self.get_execute_lock(session_id).await;
+        let _guard = exec_lock.lock().await;
+
+        // Re-check under the lock: it may have been closed or reaped meanwhile
+        let Some(session) = self.sessions.read().await.get(session_id).cloned() else {
+            return false;
+        };
+        *session.last_used.lock().await = self.clock.now();
+        true
+    }
+
+    /// Restart a session: shut down its agent and spawn a fresh one bound
+    /// to the same environment, under the same ID.
+    ///
+    /// Interpreter state is discarded and `created_at`/`last_used` reset.
+    /// Holds the per-session execute lock, so it waits for any running
+    /// execution. If the new agent fails to start, the session is removed.
+    pub async fn restart(
+        &self,
+        session_id: &str,
+        env_meta: &EnvironmentMeta,
+        mounts: &Mounts,
+    ) -> Result<()> {
+        let not_found = || anyhow::anyhow!("Session '{session_id}' not found");
+        if !self.sessions.read().await.contains_key(session_id) {
+            return Err(not_found());
+        }
+
+        let exec_lock = self.get_execute_lock(session_id).await;
+        let _guard = exec_lock.lock().await;
+
+        // Re-check under the lock: it may have been closed or reaped meanwhile
+        let old = self.sessions.write().await.remove(session_id);
+        let Some(old) = old else {
+            return Err(not_found());
+        };
+
+        info!(session = %session_id, env = %old.env_name, "Restarting session");
+        if let Err(e) = old.shutdown().await {
+            warn!(session = %session_id, error = %e, "Error shutting down session for restart");
+        }
+
+        let transport = match self.connect(&old.env_name, env_meta, mounts).await {
+            Ok(transport) => transport,
+            Err(e) => {
+                self.execute_locks.write().await.remove(session_id);
+                self.save_state().await;
+                return Err(e.context(format!("Failed to restart session '{session_id}'")));
+            }
+        };
+
+        let session = Arc::new(Session::new(
+            session_id.to_string(),
+            old.env_name.clone(),
+            env_meta.memory_mb,
+            transport,
+            Arc::clone(&self.clock),
+        ));
+        session.query_interpreters().await;
+        self.sessions
+            .write()
+            .await
+            .insert(session_id.to_string(), session);
+        MetricCounters::bump(&self.metrics.restarted, 1);
+        self.save_state().await;
+        Ok(())
+    }
+
+    /// Clear a session's interpreter state without respawning its agent,
+    /// keeping the session ID, environment, and /workspace files.
+    ///
+    /// Waits for any running execution. Returns `Unsupported` without
+    /// touching the session if its agent can't reset; `restart` can.
+    pub async fn reset(&self, session_id: &str, request_id: &str) -> Result<ResetOutcome> {
+        let (session, _guard) = self.lock_session(session_id).await?;
+        let outcome = session.reset(request_id).await?;
+        if outcome == ResetOutcome::Reset {
+            info!(session = %session_id, "Session reset");
+        }
+        Ok(outcome)
+    }
+
+    /// Set an environment variable in a session's agent, for all its later
+    /// executions. The daemon keeps no copy; the agent holds the value.
+    pub async fn set_env(
+        &self,
+        session_id: &str,
+        request_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        validate_env_var_name(key, "session env")?;
+        anyhow::ensure!(
+            !value.contains('\0'),
+            "Value for session env '{key}' contains a NUL byte"
+        );
+        let (session, _guard) = self.lock_session(session_id).await?;
+        let req = AgentRequest::SetEnv {
+            id: request_id.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        session.env_request(&req).await?;
+        // The value may be sensitive; log only the name
+        info!(session = %session_id, key, "Session env var set");
+        Ok(())
+    }
+
+    /// Read an environment variable from a session's agent; `None` if unset.
+    pub async fn get_env(
+        &self,
+        session_id: &str,
+        request_id: &str,
+        key: &str,
+    ) -> Result<Option<String>> {
+        validate_env_var_name(key, "session env")?;
+        let (session, _guard) = self.lock_session(session_id).await?;
+        let req = AgentRequest::GetEnv {
+            id: request_id.to_string(),
+            key: key.to_string(),
+        };
+        session.env_request(&req).await
+    }
+
+    /// Look up a live session and take its execute lock, waiting for any
+    /// running execution.
+    async fn lock_session(&self, session_id: &str) -> Result<(Arc<Session>, OwnedMutexGuard<()>)> {
+        let not_found = || anyhow::anyhow!("Session '{session_id}' not found");
+        if !self.sessions.read().await.contains_key(session_id) {
+            return Err(not_found());
+        }
+
+        let guard = self.get_execute_lock(session_id).await.lock_owned().await;
+
+        // Re-check under the lock: it may have been closed or reaped meanwhile
+        let session = self.sessions.read().await.get(session_id).cloned();
+        Ok((session.ok_or_else(not_found)?, guard))
+    }
+
+    /// Number of live sessions.
+    pub async fn session_count(&self) -> usize {
+        self.sessions.read().await.len()
+    }
+
+    /// Count live sessions per environment, with the oldest and newest
+    /// creation times.
+    pub async fn stats(&self) -> SessionStats {
+        let mut stats = SessionStats::default();
+        for session in self.sessions.read().await.values() {
+            *stats.by_env.entry(session.env_name.clone()).or_default() += 1;
+            let created_at = session.created_wall;
+            stats.oldest_created_at = Some(
+                stats
+                    .oldest_created_at
+                    .map_or(created_at, |t| t.min(created_at)),
+            );
+            stats.newest_created_at = Some(
+                stats
+                    .newest_created_at
+                    .map_or(created_at, |t| t.max(created_at)),
+            );
+        }
+        stats
+    }
+
+    /// List live sessions, sorted by id.
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let sessions: Vec<Arc<Session>> = self.sessions.read().await.values().cloned().collect();
+
+        let mut infos = Vec::with_capacity(sessions.len());
+        for session in &sessions {
+            infos.push(
+                session
+                    .info(self.config.idle_timeout, self.config.max_lifetime)
+                    .await,
+            );
+        }
+        infos.sort_by(|a, b| a.id.cmp(&b.id));
+        infos
+    }
+
+    /// Register a session backed by an already-connected transport (for testing).
+    #[cfg(test)]
+    pub(crate) async fn insert_session(
+        &self,
+        id: &str,
+        env_name: &str,
+        transport: Box<dyn Transport>,
+    ) {
+        let session = Arc::new(Session::new(
+            id.to_string(),
+            env_name.to_string(),
+            0,
+            transport,
+            Arc::clone(&self.clock),
+        ));
+        self.sessions.write().await.insert(id.to_string(), session);
+    }
+
     /// Clean up expired sessions (called by the reaper task).
     pub async fn cleanup_expired(&self) {
-        let expired_sessions: Vec<Arc<Session>> = {
+        // Forget persisted sessions the reaper would have expired by now
+        let stale_expired = {
+            let mut stale = self.stale.lock().await;
+            let before = stale.len();
+            stale.retain(|_, r| !r.is_expired(self.config.idle_timeout, self.config.max_lifetime));
+            before != stale.len()
+        };
+        if stale_expired {
+            self.save_state().await;
+        }
+
+        let (lifetime_expired, idle_expired, to_suspend) = {
             let sessions = self.sessions.read().await;
-            let mut expired = Vec::new();
+            let mut lifetime = Vec::new();
+            let mut idle = Vec::new();
+            let mut suspend = Vec::new();
             for (id, session) in sessions.iter() {
-                let idle_expired = session.is_idle_expired(self.config.idle_timeout).await;
-                let lifetime_expired =
-                    session.is_lifetime_expired(self.config.max_lifetime);
-
-                if idle_expired || lifetime_expired {
-                    let reason = if lifetime_expired {
-                        "max lifetime"
-                    } else {
-                        "idle timeout"
-                    };
-                    debug!(session = %id, reason = %reason, "Session expired");
-                    expired.push(Arc::clone(session));
+                let suspend_after = self.config.suspend_after.filter(|_| session.can_suspend());
+                if session.is_lifetime_expired(self.config.max_lifetime) {
+                    debug!(session = %id, reason = "max lifetime", "Session expired");
+                    lifetime.push(Arc::clone(session));
+                } else if session.is_suspended() {
+                    // Kept, paused, until its max lifetime
+                } else if let Some(after) = suspend_after {
+                    if session.is_idle_expired(after).await {
+                        suspend.push(Arc::clone(session));
+                    }
+                } else if session.is_idle_expired(self.config.idle_timeout).await {
+                    debug!(session = %id, reason = "idle timeout", "Session expired");
+                    idle.push(Arc::clone(session));
                 }
             }
-            expired
+            drop(sessions);
+            (lifetime, idle, suspend)
         };
+        self.suspend_sessions(&to_suspend).await;
 
-        if expired_sessions.is_empty() {
-            return;
+        let removed = self
+            .remove_sessions(&lifetime_expired, "max lifetime")
+            .await;
+        MetricCounters::bump(&self.metrics.reaped_lifetime, removed);
+        let removed = self.remove_sessions(&idle_expired, "idle timeout").await;
+        MetricCounters::bump(&self.metrics.reaped_idle, removed);
+    }
+
+    /// Pause idle `sessions`, skipping any that started executing since the
+    /// caller looked.
+    async fn suspend_sessions(&self, sessions: &[Arc<Session>]) {
+        for session in sessions {
+            let lock = self.get_execute_lock(&session.id).await;
+            let Ok(_guard) = lock.try_lock() else {
+                continue;
+            };
+            if session.suspend() {
+                info!(session = %session.id, "Suspended idle session");
+            }
+        }
+    }
+
+    /// Ping every session that isn't executing; remove those that don't answer.
+    ///
+    /// Pings bypass `Session::request`, so they don't count as activity and
+    /// idle sessions still expire on schedule.
+    pub async fn keepalive(&self) {
+        let timeout = self.config.ping_timeout.unwrap_or(Duration::from_secs(2));
+        let live: Vec<Arc<Session>> = self.sessions.read().await.values().cloned().collect();
+
+        let mut dead = Vec::new();
+        for session in live {
+            // A paused session answers once resumed; pinging would wake it
+            if session.is_suspended() {
+                continue;
+            }
+            // A busy session's agent answers after the execution, not now
+            let lock = self.get_execute_lock(&session.id).await;
+            let Ok(_guard) = lock.try_lock() else {
+                continue;
+            };
+            if !session.ping(timeout).await {
+                debug!(session = %session.id, "Session failed keepalive ping");
+                dead.push(session);
+            }
+        }
+
+        self.remove_sessions(&dead, "unresponsive").await;
+    }
+
+    /// Drop `sessions` from the maps, then shut their agents down.
+    ///
+    /// Entries replaced since the caller looked (e.g. by a restart) stay.
+    /// Returns how many entries were actually removed.
+    async fn remove_sessions(&self, to_remove: &[Arc<Session>], reason: &str) -> u64 {
+        if to_remove.is_empty() {
+            return 0;
         }
 
         // Remove from maps while holding locks, then drop locks before shutdown
+        let mut removed = 0;
         {
             let mut sessions = self.sessions.write().await;
             let mut locks = self.execute_locks.write().await;
-            for session in &expired_sessions {
-                sessions.remove(&session.id);
-                locks.remove(&session.id);
+            for session in to_remove {
+                if sessions
+                    .get(&session.id)
+                    .is_some_and(|current| Arc::ptr_eq(current, session))
+                {
+                    sessions.remove(&session.id);
+                    locks.remove(&session.id);
+                    removed += 1;
+                    self.notify(session, SessionEventKind::Closed, reason);
+                }
             }
+            drop(locks);
+            drop(sessions);
         }
 
+        self.save_state().await;
+
         // Shutdown outside of locks — async I/O won't block other session operations
-        for session in &expired_sessions {
-            info!(session = %session.id, "Cleaning up expired session");
+        for session in to_remove {
+            info!(session = %session.id, reason, "Cleaning up session");
             if let Err(e) = session.shutdown().await {
                 warn!(session = %session.id, error = %e, "Error shutting down session");
             }
         }
+        removed
     }
 
     /// Destroy all sessions (called on MCP disconnect).
@@ -360,6 +2097,7 @@ impl SessionManager {
 
         for session in &all_sessions {
             info!(session = %session.id, "Destroying session");
+            self.notify(session, SessionEventKind::Closed, "shutdown");
             if let Err(e) = session.shutdown().await {
                 warn!(session = %session.id, error = %e, "Error destroying session");
             }
@@ -368,35 +2106,121 @@ impl SessionManager {
 
     /// Start the background reaper task.
     ///
-    /// Returns a `JoinHandle` that runs until cancelled. The reaper
-    /// checks for expired sessions every `reaper_interval`.
-    pub fn start_reaper(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+    /// Runs until [`Reaper::stop`]. The reaper checks for expired sessions
+    /// every `reaper_interval`, and pings sessions every
+    /// `keepalive_interval` if one is set.
+    pub fn start_reaper(self: &Arc<Self>) -> Reaper {
         let manager = Arc::clone(self);
         let interval = manager.config.reaper_interval;
+        let keepalive_interval = manager.config.keepalive_interval;
+        let cancel = CancellationToken::new();
+        let cancelled = cancel.clone();
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             let mut ticker = tokio::time::interval(interval);
             ticker.tick().await; // First tick is immediate, skip it
+            let mut keepalive = keepalive_interval.map(tokio::time::interval);
+            if let Some(keepalive) = &mut keepalive {
+                keepalive.tick().await;
+            }
+
             loop {
-                ticker.tick().await;
-                debug!("Reaper sweep");
-                manager.cleanup_expired().await;
+                let keepalive_tick = async {
+                    match &mut keepalive {
+                        Some(keepalive) => keepalive.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                // Sweeps run in the arms, not the select, so cancelling
+                // never interrupts one halfway through shutting sessions down
+                tokio::select! {
+                    () = cancelled.cancelled() => break,
+                    _ = ticker.tick() => {
+                        debug!("Reaper sweep");
+                        manager.cleanup_expired().await;
+                    }
+                    _ = keepalive_tick => {
+                        debug!("Keepalive sweep");
+                        manager.keepalive().await;
+                    }
+                }
             }
-        })
+            debug!("Reaper stopped");
+        });
+        Reaper { cancel, task }
     }
 }
 
-/// Map environment name to interpreter name for the agent protocol.
-///
+/// Start of stderr for a call that ran in a session recreated after its
+/// agent died, so the caller knows earlier state is gone.
+fn recreated_note(recreated: bool) -> String {
+    if recreated {
+        "[session recreated: the previous agent failed and its state was lost]\n".to_string()
+    } else {
+        String::new()
+    }
+}
+
+/// Result for a session call that timed out; the session was discarded,
+/// since its agent may still answer the abandoned request.
+fn timed_out_in_session(
+    session: &Session,
+    timeout: Duration,
+    elapsed: Duration,
+) -> ExecutionResult {
+    let mut result = ExecutionResult::timed_out(timeout, elapsed);
+    result.stderr = format!(
+        "{}\n[session '{}' was restarted: its interpreter state was lost]",
+        result.stderr, session.id
+    );
+    result
+}
+
+/// Turn the agent's answer to an `Execute` into a result. An `Error`
+/// becomes a failed result carrying the message.
+fn fragment_result(resp: AgentResponse) -> Result<FragmentResult> {
+    match resp {
+        AgentResponse::Result {
+            stdout,
+            stderr,
+            exit_code,
+            ..
+        } => Ok(FragmentResult {
+            stdout,
+            stderr,
+            exit_code,
+        }),
+        AgentResponse::Error { message } => Ok(FragmentResult {
+            stdout: String::new(),
+            stderr: message,
+            exit_code: 1,
+        }),
+        other => Err(ExecError::ProtocolError(anyhow::anyhow!(
+            "Unexpected agent response: {other:?}"
+        ))
+        .into()),
+    }
+}
+
+/// Map environment name to interpreter name for the agent protocol.
+///
 /// The agent supports "python", "bash", and "node" interpreters.
 /// If `interpreter_type` is set on the environment metadata (from custom
-/// sandbox artifacts), use that directly. Otherwise, fall back to
-/// name-based matching for bundled presets.
-fn env_to_interpreter(env_name: &str, env_meta: &EnvironmentMeta) -> String {
+/// sandbox artifacts), use that directly. Otherwise, use the operator's
+/// `[interpreter_map]` entry, then fall back to name-based matching for
+/// bundled presets.
+pub(crate) fn env_to_interpreter(
+    env_name: &str,
+    env_meta: &EnvironmentMeta,
+    interpreter_map: &HashMap<String, String>,
+) -> String {
     // Custom sandboxes set interpreter_type explicitly
     if let Some(ref itype) = env_meta.interpreter_type {
         return itype.clone();
     }
+    if let Some(interpreter) = interpreter_map.get(env_name) {
+        return interpreter.clone();
+    }
 
     // Bundled preset name-based mapping
     match env_name {
@@ -407,9 +2231,74 @@ fn env_to_interpreter(env_name: &str, env_meta: &EnvironmentMeta) -> String {
     }
 }
 
+/// Env vars for a session agent process: runtime mounts, inherited host
+/// vars, the environment's memory size (informational) and its network
+/// policy for the session wrapper to enforce. `interpreter_args` aren't
+/// passed: they'd go to the agent, not the interpreters it hosts.
+fn agent_env(env_meta: &EnvironmentMeta, mounts: &Mounts) -> Vec<(String, String)> {
+    let mut env_vars = mounts.env_vars();
+    env_vars.extend(env_meta.inherited_env());
+    env_vars.push((
+        "SANDBOX_MEMORY_MB".to_string(),
+        env_meta.memory_mb.to_string(),
+    ));
+    env_vars.push(env_meta.network_var());
+    env_vars.push(env_meta.output_encoding.agent_var());
+    env_vars
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    /// Transport whose `Execute` blocks until a control message arrives.
+    #[derive(Default)]
+    struct MockTransport {
+        controls: std::sync::Mutex<Vec<AgentRequest>>,
+        control_received: Notify,
+        shut_down: std::sync::atomic::AtomicBool,
+        capabilities: Option<crate::transport::Capabilities>,
+    }
+
+    #[async_trait]
+    impl Transport for Arc<MockTransport> {
+        async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+            match req {
+                AgentRequest::Execute { id, .. } => {
+                    self.control_received.notified().await;
+                    Ok(AgentResponse::Result {
+                        id: id.clone(),
+                        stdout: String::new(),
+                        stderr: "Execution cancelled\n".to_string(),
+                        exit_code: 130,
+                    })
+                }
+                _ => Ok(AgentResponse::Pong),
+            }
+        }
+
+        async fn send_control(&self, req: &AgentRequest) -> Result<()> {
+            self.controls.lock().unwrap().push(req.clone());
+            self.control_received.notify_one();
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.shut_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            !self.shut_down.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn capabilities(&self) -> Option<&crate::transport::Capabilities> {
+            self.capabilities.as_ref()
+        }
+    }
 
     fn meta_with_interpreter_type(itype: Option<&str>) -> EnvironmentMeta {
         EnvironmentMeta {
@@ -419,26 +2308,805 @@ mod tests {
             timeout_seconds: 30,
             memory_mb: 512,
             interpreter_type: itype.map(String::from),
+            ..Default::default()
         }
     }
 
     #[test]
     fn test_env_to_interpreter() {
         let meta_none = meta_with_interpreter_type(None);
-        assert_eq!(env_to_interpreter("python", &meta_none), "python");
-        assert_eq!(env_to_interpreter("shell", &meta_none), "bash");
-        assert_eq!(env_to_interpreter("node", &meta_none), "node");
-        assert_eq!(env_to_interpreter("custom", &meta_none), "custom");
+        assert_eq!(
+            env_to_interpreter("python", &meta_none, &HashMap::new()),
+            "python"
+        );
+        assert_eq!(
+            env_to_interpreter("shell", &meta_none, &HashMap::new()),
+            "bash"
+        );
+        assert_eq!(
+            env_to_interpreter("node", &meta_none, &HashMap::new()),
+            "node"
+        );
+        assert_eq!(
+            env_to_interpreter("custom", &meta_none, &HashMap::new()),
+            "custom"
+        );
     }
 
     #[test]
     fn test_env_to_interpreter_with_interpreter_type() {
         let meta_python = meta_with_interpreter_type(Some("python"));
         // interpreter_type overrides name-based matching
-        assert_eq!(env_to_interpreter("data-science", &meta_python), "python");
+        assert_eq!(
+            env_to_interpreter("data-science", &meta_python, &HashMap::new()),
+            "python"
+        );
 
         let meta_bash = meta_with_interpreter_type(Some("bash"));
-        assert_eq!(env_to_interpreter("rust-dev", &meta_bash), "bash");
+        assert_eq!(
+            env_to_interpreter("rust-dev", &meta_bash, &HashMap::new()),
+            "bash"
+        );
+    }
+
+    #[test]
+    fn test_env_to_interpreter_precedence() {
+        let map = HashMap::from([
+            ("shell".to_string(), "node".to_string()),
+            ("zsh".to_string(), "bash".to_string()),
+        ]);
+
+        // interpreter_type beats the config map
+        let meta_python = meta_with_interpreter_type(Some("python"));
+        assert_eq!(env_to_interpreter("shell", &meta_python, &map), "python");
+
+        // The config map beats the bundled preset names
+        let meta_none = meta_with_interpreter_type(None);
+        assert_eq!(env_to_interpreter("shell", &meta_none, &map), "node");
+        assert_eq!(env_to_interpreter("zsh", &meta_none, &map), "bash");
+
+        // Unmapped names fall back to the presets
+        assert_eq!(env_to_interpreter("node", &meta_none, &map), "node");
+        assert_eq!(env_to_interpreter("custom", &meta_none, &map), "custom");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unknown_session() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let err = manager.cancel("missing").await.unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_without_execution_in_flight() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let transport = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        let err = manager.cancel("s1").await.unwrap_err();
+        assert!(err.to_string().contains("No execution in flight"));
+        assert!(transport.controls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_interrupts_in_flight_execution() {
+        let manager = Arc::new(SessionManager::new(SessionConfig::default()));
+        let transport = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        let exec = {
+            let manager = Arc::clone(&manager);
+            tokio::spawn(async move {
+                let meta = meta_with_interpreter_type(None);
+                manager
+                    .execute(
+                        "s1",
+                        "r1",
+                        "python",
+                        &meta,
+                        "while True: pass",
+                        meta.effective_timeout(None),
+                        &Mounts::default(),
+                        false,
+                    )
+                    .await
+            })
+        };
+
+        // Retry until the execute call has registered itself as in flight
+        while manager.cancel("s1").await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        let result = exec.await.unwrap().unwrap();
+        assert_eq!(result.exit_code, 130);
+        let controls = transport.controls.lock().unwrap().clone();
+        assert!(matches!(&controls[..], [AgentRequest::Cancel { id }] if id == "r1"));
+    }
+
+    /// Agent that echoes each fragment and fails on `fail`, answering
+    /// batches itself when it speaks `protocol_version` 2.
+    #[derive(Default)]
+    struct BatchTransport {
+        requests: std::sync::Mutex<Vec<AgentRequest>>,
+        capabilities: Option<crate::transport::Capabilities>,
+        /// The agent's environment, for `SetEnv` and `GetEnv`.
+        env: std::sync::Mutex<HashMap<String, String>>,
+        pid: Option<u32>,
+    }
+
+    impl BatchTransport {
+        fn run(code: &str) -> FragmentResult {
+            FragmentResult {
+                stdout: format!("{code}\n"),
+                stderr: String::new(),
+                exit_code: i32::from(code == "fail"),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transport for Arc<BatchTransport> {
+        async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+            if !matches!(req, AgentRequest::Ping) {
+                self.requests.lock().unwrap().push(req.clone());
+            }
+            match req {
+                AgentRequest::Execute { id, code, .. } => {
+                    let r = BatchTransport::run(code);
+                    Ok(AgentResponse::Result {
+                        id: id.clone(),
+                        stdout: r.stdout,
+                        stderr: r.stderr,
+                        exit_code: r.exit_code,
+                    })
+                }
+                AgentRequest::ExecuteBatch {
+                    id,
+                    fragments,
+                    stop_on_error,
+                    ..
+                } => {
+                    let mut results = Vec::new();
+                    for code in fragments {
+                        results.push(BatchTransport::run(code));
+                        if *stop_on_error && code == "fail" {
+                            break;
+                        }
+                    }
+                    Ok(AgentResponse::BatchResult {
+                        id: id.clone(),
+                        results,
+                    })
+                }
+                AgentRequest::Reset { id } if id == "fail" => Ok(AgentResponse::Error {
+                    message: "namespace is locked".to_string(),
+                }),
+                AgentRequest::Reset { id } => Ok(AgentResponse::ResetDone { id: id.clone() }),
+                AgentRequest::SetEnv { id, key, value } => {
+                    let mut env = self.env.lock().unwrap();
+                    env.insert(key.clone(), value.clone());
+                    Ok(AgentResponse::EnvValue {
+                        id: id.clone(),
+                        key: key.clone(),
+                        value: env.get(key).cloned(),
+                    })
+                }
+                AgentRequest::GetEnv { id, key } => Ok(AgentResponse::EnvValue {
+                    id: id.clone(),
+                    key: key.clone(),
+                    value: self.env.lock().unwrap().get(key).cloned(),
+                }),
+                AgentRequest::ListInterpreters => Ok(AgentResponse::Interpreters {
+                    names: vec!["python".to_string(), "bash".to_string()],
+                }),
+                _ => Ok(AgentResponse::Pong),
+            }
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+
+        fn capabilities(&self) -> Option<&crate::transport::Capabilities> {
+            self.capabilities.as_ref()
+        }
+
+        fn pid(&self) -> Option<u32> {
+            self.pid
+        }
+    }
+
+    async fn run_batch(
+        transport: &Arc<BatchTransport>,
+        fragments: &[&str],
+        stop_on_error: bool,
+    ) -> ExecutionResult {
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(transport)))
+            .await;
+        let meta = meta_with_interpreter_type(None);
+        let fragments: Vec<String> = fragments.iter().map(ToString::to_string).collect();
+        manager
+            .execute_batch(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                &fragments,
+                stop_on_error,
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reset_is_dispatched_to_agent() {
+        let transport = Arc::new(BatchTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                reset: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        let outcome = manager.reset("s1", "r1").await.unwrap();
+        assert_eq!(outcome, ResetOutcome::Reset);
+        let requests = transport.requests.lock().unwrap().clone();
+        assert!(matches!(&requests[..], [AgentRequest::Reset { id }] if id == "r1"));
+
+        let err = manager.reset("s1", "fail").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Agent failed to reset: namespace is locked"
+        );
+        // A failed reset leaves the session in place
+        assert_eq!(manager.session_count().await, 1);
+
+        let err = manager.reset("nope", "r2").await.unwrap_err();
+        assert_eq!(err.to_string(), "Session 'nope' not found");
+    }
+
+    #[tokio::test]
+    async fn test_queried_interpreters_checked_before_execute() {
+        let transport = Arc::new(BatchTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                // Ready claims node too; the query knows better
+                interpreters: vec!["python".into(), "bash".into(), "node".into()],
+                list_interpreters: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "node", Box::new(Arc::clone(&transport)))
+            .await;
+        let session = Arc::clone(&manager.sessions.read().await["s1"]);
+        assert_eq!(session.interpreters(), ["python", "bash", "node"]);
+        session.query_interpreters().await;
+        assert_eq!(session.interpreters(), ["python", "bash"]);
+
+        let meta = meta_with_interpreter_type(Some("node"));
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "node",
+                &meta,
+                "1",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Session agent for environment 'node' does not support interpreter 'node' \
+             (supported: python, bash)"
+        );
+        let requests = transport.requests.lock().unwrap().clone();
+        assert!(matches!(&requests[..], [AgentRequest::ListInterpreters]));
+    }
+
+    #[test]
+    fn test_agent_env_sets_network_policy() {
+        for (network, expected) in [(false, "0"), (true, "1")] {
+            let meta = EnvironmentMeta {
+                network,
+                ..meta_with_interpreter_type(None)
+            };
+            let env = agent_env(&meta, &Mounts::default());
+            assert!(
+                env.contains(&("SANDBOX_NETWORK".to_string(), expected.to_string())),
+                "{env:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_agent_env_sets_output_encoding() {
+        let meta = EnvironmentMeta {
+            output_encoding: crate::config::OutputEncoding::for_label("latin1").unwrap(),
+            ..meta_with_interpreter_type(None)
+        };
+        let env = agent_env(&meta, &Mounts::default());
+        assert!(
+            env.contains(&(
+                "SANDBOX_OUTPUT_ENCODING".to_string(),
+                "windows-1252".to_string()
+            )),
+            "{env:?}"
+        );
+    }
+
+    #[test]
+    fn test_agent_env_omits_interpreter_args() {
+        let meta = EnvironmentMeta {
+            interpreter_args: vec!["-u".to_string()],
+            ..meta_with_interpreter_type(None)
+        };
+        let env = agent_env(&meta, &Mounts::default());
+        assert!(
+            !env.iter().any(|(k, _)| k == "SANDBOX_INTERPRETER_ARGS"),
+            "{env:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_info_pid_stable_across_executions() {
+        let transport = Arc::new(BatchTransport {
+            pid: Some(4242),
+            ..Default::default()
+        });
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let meta = meta_with_interpreter_type(None);
+
+        let mut seen = Vec::new();
+        for request_id in ["r1", "r2"] {
+            manager
+                .execute(
+                    "s1",
+                    request_id,
+                    "python",
+                    &meta,
+                    "1",
+                    meta.effective_timeout(None),
+                    &Mounts::default(),
+                    false,
+                )
+                .await
+                .unwrap();
+            let info = manager.info("s1").await.unwrap();
+            seen.push((info.pid, info.started_at));
+        }
+        assert_eq!(seen[0].0, Some(4242));
+        assert_eq!(seen[0].0, seen[1].0);
+        assert_eq!(seen[0].1, seen[1].1);
+        assert!(manager.info("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_history_bounded_and_ordered() {
+        let manager = SessionManager::new(SessionConfig {
+            history_size: 2,
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session(
+                "s1",
+                "python",
+                Box::new(Arc::new(BatchTransport::default())),
+            )
+            .await;
+        let meta = meta_with_interpreter_type(None);
+
+        for (request_id, code) in [("r1", "1"), ("r2", "2"), ("r3", "3")] {
+            manager
+                .execute(
+                    "s1",
+                    request_id,
+                    "python",
+                    &meta,
+                    code,
+                    meta.effective_timeout(None),
+                    &Mounts::default(),
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let history = manager.history("s1").await.unwrap();
+        let ids: Vec<&str> = history.iter().map(|e| e.request_id.as_str()).collect();
+        assert_eq!(ids, ["r2", "r3"]);
+        assert_eq!(history[1].exit_code, Some(0));
+        assert!(history[1].code_hash.starts_with("sha256:"));
+        assert_eq!(history[1].code, None);
+        assert!(history[0].timestamp_ms <= history[1].timestamp_ms);
+        assert!(manager.history("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stats_counts_sessions_per_env() {
+        let clock = Arc::new(MockClock::new());
+        let manager = SessionManager::with_clock(
+            SessionConfig::default(),
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+        assert_eq!(manager.stats().await, SessionStats::default());
+
+        manager
+            .insert_session(
+                "s1",
+                "python",
+                Box::new(Arc::new(BatchTransport::default())),
+            )
+            .await;
+        clock.advance(Duration::from_secs(30));
+        manager
+            .insert_session(
+                "s2",
+                "python",
+                Box::new(Arc::new(BatchTransport::default())),
+            )
+            .await;
+        manager
+            .insert_session("s3", "shell", Box::new(Arc::new(BatchTransport::default())))
+            .await;
+
+        let stats = manager.stats().await;
+        assert_eq!(
+            stats.by_env,
+            BTreeMap::from([("python".to_string(), 2), ("shell".to_string(), 1)])
+        );
+        assert_eq!(stats.total(), 3);
+        let spread = stats
+            .newest_created_at
+            .unwrap()
+            .duration_since(stats.oldest_created_at.unwrap())
+            .unwrap();
+        assert_eq!(spread, Duration::from_secs(30));
+    }
+
+    /// Whether a process is stopped, waiting briefly for a just-sent
+    /// SIGSTOP or SIGCONT to land.
+    async fn settles_stopped(pid: u32, stopped: bool) -> bool {
+        for _ in 0..50 {
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+            let (_, rest) = stat.rsplit_once(')').unwrap();
+            if rest.trim_start().starts_with('T') == stopped {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_suspended_then_resumed_on_execute() {
+        // Stands in for the agent process the transport would own
+        let mut agent = std::process::Command::new("sleep")
+            .arg("30")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let transport = Arc::new(BatchTransport {
+            pid: Some(agent.id()),
+            ..Default::default()
+        });
+        let clock = Arc::new(MockClock::new());
+        let config = SessionConfig {
+            suspend_after: Some(Duration::from_secs(60)),
+            ..SessionConfig::default()
+        };
+        let manager = SessionManager::with_clock(config, Arc::clone(&clock) as Arc<dyn Clock>);
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        clock.advance(Duration::from_secs(30));
+        manager.cleanup_expired().await;
+        assert!(!manager.info("s1").await.unwrap().suspended);
+
+        // Past the idle timeout too: paused, not reaped
+        clock.advance(Duration::from_secs(600));
+        manager.cleanup_expired().await;
+        assert!(manager.info("s1").await.unwrap().suspended);
+        assert!(settles_stopped(agent.id(), true).await);
+
+        let meta = meta_with_interpreter_type(None);
+        manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "1",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(!manager.info("s1").await.unwrap().suspended);
+        assert!(settles_stopped(agent.id(), false).await);
+
+        // Still bounded by the max lifetime
+        clock.advance(Duration::from_secs(3600));
+        manager.cleanup_expired().await;
+        assert!(manager.info("s1").await.is_none());
+
+        agent.kill().unwrap();
+        agent.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_without_local_agent_is_reaped_not_suspended() {
+        let transport = Arc::new(BatchTransport::default());
+        let clock = Arc::new(MockClock::new());
+        let config = SessionConfig {
+            suspend_after: Some(Duration::from_secs(60)),
+            ..SessionConfig::default()
+        };
+        let manager = SessionManager::with_clock(config, Arc::clone(&clock) as Arc<dyn Clock>);
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        clock.advance(Duration::from_secs(301));
+        manager.cleanup_expired().await;
+        assert!(manager.info("s1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interpreters_not_queried_without_capability() {
+        let transport = Arc::new(BatchTransport::default());
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let session = Arc::clone(&manager.sessions.read().await["s1"]);
+        session.query_interpreters().await;
+
+        assert!(session.interpreters().is_empty());
+        assert!(transport.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_env_is_forwarded_to_agent() {
+        let transport = Arc::new(BatchTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                session_env: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        assert_eq!(
+            manager.get_env("s1", "r1", "FEATURE_X").await.unwrap(),
+            None
+        );
+        manager
+            .set_env("s1", "r2", "FEATURE_X", "on")
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.get_env("s1", "r3", "FEATURE_X").await.unwrap(),
+            Some("on".to_string())
+        );
+
+        let requests = transport.requests.lock().unwrap().clone();
+        assert!(matches!(
+            &requests[..],
+            [
+                AgentRequest::GetEnv { .. },
+                AgentRequest::SetEnv { id, key, value },
+                AgentRequest::GetEnv { .. },
+            ] if id == "r2" && key == "FEATURE_X" && value == "on"
+        ));
+
+        // Bad names and values never reach the agent
+        for key in ["1X", "A-B", "", "SANDBOX_DEPTH", "PROJECT_DIR"] {
+            assert!(
+                manager.set_env("s1", "r4", key, "x").await.is_err(),
+                "{key}"
+            );
+        }
+        let err = manager.set_env("s1", "r4", "X", "a\0b").await.unwrap_err();
+        assert!(err.to_string().contains("NUL"), "{err}");
+        assert_eq!(transport.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_session_env_unsupported() {
+        let transport = Arc::new(BatchTransport::default());
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        let err = manager.set_env("s1", "r1", "X", "1").await.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Session 's1' doesn't support session environment variables"),
+            "{err}"
+        );
+        assert!(transport.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_unsupported_sends_nothing() {
+        let transport = Arc::new(BatchTransport::default());
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+
+        let outcome = manager.reset("s1", "r1").await.unwrap();
+        assert_eq!(outcome, ResetOutcome::Unsupported);
+        assert!(transport.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_is_one_round_trip() {
+        let transport = Arc::new(BatchTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                protocol_version: BATCH_PROTOCOL,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let result = run_batch(&transport, &["a", "fail", "b"], true).await;
+        assert_eq!(result.exit_code, 1);
+        assert_eq!(result.stdout, "a\nfail\n");
+        assert_eq!(result.fragment_exit_codes, Some(vec![0, 1]));
+
+        let requests = transport.requests.lock().unwrap().clone();
+        assert!(matches!(
+            &requests[..],
+            [AgentRequest::ExecuteBatch { id, fragments, stop_on_error: true, .. }]
+                if id == "r1" && fragments.len() == 3
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batch_falls_back_to_sequential_execute() {
+        // No capabilities: a pre-batch agent gets one Execute per fragment
+        let transport = Arc::new(BatchTransport::default());
+        let result = run_batch(&transport, &["a", "fail", "b"], true).await;
+        assert_eq!(result.exit_code, 1);
+        assert_eq!(result.fragment_exit_codes, Some(vec![0, 1]));
+        let requests = transport.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2, "{requests:?}");
+
+        let transport = Arc::new(BatchTransport::default());
+        let result = run_batch(&transport, &["a", "fail", "b"], false).await;
+        assert_eq!(result.exit_code, 1, "first nonzero exit wins");
+        assert_eq!(result.stdout, "a\nfail\nb\n");
+        assert_eq!(result.fragment_exit_codes, Some(vec![0, 1, 0]));
+        let requests = transport.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|r| matches!(r, AgentRequest::Execute { id, .. } if id == "r1")));
+    }
+
+    #[tokio::test]
+    async fn test_preamble_runs_once_per_session() {
+        let transport = Arc::new(BatchTransport::default());
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let meta = EnvironmentMeta {
+            preamble: Some("import numpy as np".to_string()),
+            ..meta_with_interpreter_type(None)
+        };
+
+        for (request_id, code) in [("r1", "np.zeros(1)"), ("r2", "np.ones(1)")] {
+            let result = manager
+                .execute(
+                    "s1",
+                    request_id,
+                    "python",
+                    &meta,
+                    code,
+                    meta.effective_timeout(None),
+                    &Mounts::default(),
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                result.stdout,
+                format!("{code}\n"),
+                "preamble output is not returned"
+            );
+        }
+
+        let codes: Vec<String> = transport
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| match r {
+                AgentRequest::Execute { id, code, .. } => format!("{id}: {code}"),
+                other => panic!("unexpected request {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "r1-preamble: import numpy as np",
+                "r1: np.zeros(1)",
+                "r2: np.ones(1)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preamble_failure_is_reported_distinctly() {
+        let transport = Arc::new(BatchTransport::default());
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let meta = EnvironmentMeta {
+            preamble: Some("fail".to_string()),
+            ..meta_with_interpreter_type(None)
+        };
+
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Preamble for environment 'python' failed with exit code 1"),
+            "{err}"
+        );
+        // The caller's code never reached the agent
+        assert_eq!(transport.requests.lock().unwrap().len(), 1);
     }
 
     #[test]
@@ -448,6 +3116,11 @@ mod tests {
         assert_eq!(config.max_lifetime, Duration::from_secs(3600));
         assert_eq!(config.agent_ready_timeout, Duration::from_secs(30));
         assert_eq!(config.reaper_interval, Duration::from_secs(60));
+        assert!(config.keepalive_interval.is_none());
+        assert_eq!(
+            config.allowed_interpreters,
+            BTreeSet::from(["bash".to_string(), "node".to_string(), "python".to_string()])
+        );
     }
 
     #[test]
@@ -458,6 +3131,7 @@ mod tests {
         assert_eq!(config.max_lifetime, Duration::from_secs(3600));
         assert_eq!(config.agent_ready_timeout, Duration::from_secs(30));
         assert_eq!(config.reaper_interval, Duration::from_secs(60));
+        assert_eq!(config.request_timeout, Some(Duration::from_secs(600)));
     }
 
     #[test]
@@ -465,9 +3139,1546 @@ mod tests {
         let toml = crate::config::SessionConfigToml {
             idle_timeout_seconds: 120,
             max_lifetime_seconds: 1800,
+            max_sessions: 4,
+            state_dir: Some(PathBuf::from("/var/lib/nix-sandbox-mcp")),
+            keepalive_interval_seconds: Some(60),
+            allowed_interpreters: Some(vec!["python".to_string(), "ruby".to_string()]),
+            session_allow: Some(vec!["python".to_string()]),
+            session_deny: vec!["shell".to_string()],
+            request_timeout_seconds: Some(0),
+            agent_ready_timeout_seconds: 30,
+            reaper_interval_seconds: 60,
+            rate_limit_executions: None,
+            rate_limit_window_seconds: 60,
+            suspend_idle_seconds: Some(90),
+            history_size: 10,
+            history_store_code: true,
         };
         let config = SessionConfig::from_toml(&toml);
         assert_eq!(config.idle_timeout, Duration::from_secs(120));
         assert_eq!(config.max_lifetime, Duration::from_secs(1800));
+        assert_eq!(config.max_sessions, 4);
+        assert_eq!(
+            config.state_dir,
+            Some(PathBuf::from("/var/lib/nix-sandbox-mcp"))
+        );
+        assert_eq!(config.keepalive_interval, Some(Duration::from_secs(60)));
+        assert_eq!(
+            config.allowed_interpreters,
+            BTreeSet::from(["python".to_string(), "ruby".to_string()])
+        );
+        assert_eq!(
+            config.session_allow,
+            Some(BTreeSet::from(["python".to_string()]))
+        );
+        assert_eq!(config.session_deny, BTreeSet::from(["shell".to_string()]));
+        assert_eq!(config.request_timeout, None);
+        assert_eq!(config.suspend_after, Some(Duration::from_secs(90)));
+        assert_eq!(config.history_size, 10);
+        assert!(config.history_code);
+    }
+
+    #[test]
+    fn test_session_config_from_toml_timings() {
+        let toml: crate::config::SessionConfigToml = toml::from_str(
+            "idle_timeout_seconds = 90\n\
+             max_lifetime_seconds = 900\n\
+             agent_ready_timeout_seconds = 120\n\
+             reaper_interval_seconds = 5\n",
+        )
+        .unwrap();
+        let config = SessionConfig::from_toml(&toml);
+        assert_eq!(config.idle_timeout, Duration::from_secs(90));
+        assert_eq!(config.max_lifetime, Duration::from_secs(900));
+        assert_eq!(config.agent_ready_timeout, Duration::from_secs(120));
+        assert_eq!(config.reaper_interval, Duration::from_secs(5));
+
+        // Omitted, they keep the built-in defaults
+        let toml: crate::config::SessionConfigToml = toml::from_str("").unwrap();
+        let config = SessionConfig::from_toml(&toml);
+        assert_eq!(config.agent_ready_timeout, Duration::from_secs(30));
+        assert_eq!(config.reaper_interval, Duration::from_secs(60));
+
+        let toml: crate::config::SessionConfigToml =
+            toml::from_str("reaper_interval_seconds = 0").unwrap();
+        assert_eq!(
+            SessionConfig::from_toml(&toml).reaper_interval,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_session_config_rate_limit() {
+        let toml: crate::config::SessionConfigToml = toml::from_str(
+            "rate_limit_executions = 10
+rate_limit_window_seconds = 5",
+        )
+        .unwrap();
+        assert_eq!(
+            SessionConfig::from_toml(&toml).rate_limit,
+            Some(RateLimit {
+                executions: 10,
+                window: Duration::from_secs(5),
+            })
+        );
+
+        let toml: crate::config::SessionConfigToml =
+            toml::from_str("rate_limit_executions = 0").unwrap();
+        assert_eq!(SessionConfig::from_toml(&toml).rate_limit, None);
+
+        let err =
+            toml::from_str::<crate::config::SessionConfigToml>("rate_limit_window_seconds = 0")
+                .unwrap_err();
+        assert!(err.to_string().contains("at least 1"), "{err}");
+    }
+
+    #[test]
+    fn test_rate_bucket_refills_over_window() {
+        let limit = RateLimit {
+            executions: 3,
+            window: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+        let mut bucket = RateBucket::default();
+
+        // A full bucket allows a burst, then one token per 20s
+        for _ in 0..3 {
+            assert!(bucket.take(limit, start).is_ok());
+        }
+        assert_eq!(bucket.take(limit, start), Err(Duration::from_secs(20)));
+        let later = start + Duration::from_secs(5);
+        assert_eq!(bucket.take(limit, later), Err(Duration::from_secs(15)));
+
+        let later = start + Duration::from_secs(20);
+        assert!(bucket.take(limit, later).is_ok());
+        assert!(bucket.take(limit, later).is_err());
+
+        // After a whole idle window the bucket is full again
+        let later = start + Duration::from_secs(120);
+        for _ in 0..3 {
+            assert!(bucket.take(limit, later).is_ok());
+        }
+        assert!(bucket.take(limit, later).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute_burst_is_rate_limited() {
+        let window = Duration::from_secs(60);
+        let manager = SessionManager::new(SessionConfig {
+            rate_limit: Some(RateLimit {
+                executions: 2,
+                window,
+            }),
+            ..SessionConfig::default()
+        });
+        let transport = Arc::new(BatchTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let meta = meta_with_interpreter_type(None);
+        let mounts = Mounts::default();
+        let run = || {
+            manager.execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "a",
+                meta.effective_timeout(None),
+                &mounts,
+                false,
+            )
+        };
+
+        assert!(run().await.is_ok());
+        assert!(run().await.is_ok());
+        let err = run().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Session 's1' is rate limited (2 executions per 60s); retry after 30s"
+        );
+        // The rejected call never reached the agent
+        assert_eq!(transport.requests.lock().unwrap().len(), 2);
+
+        // Closing the session doesn't refill its bucket...
+        manager.close("s1").await.unwrap();
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        assert!(run().await.is_err());
+        assert_eq!(manager.rate_buckets.lock().unwrap().len(), 1);
+
+        // ...but once the window has passed, it has room again
+        let later = Instant::now() + window;
+        assert!(manager.check_rate_limit("s1", later).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_microvm_session_requires_vsock() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let meta = EnvironmentMeta {
+            backend: BackendType::Microvm,
+            session_exec: Some("/bin/true".to_string()),
+            interpreter_type: Some("python".to_string()),
+            ..Default::default()
+        };
+
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "vm",
+                &meta,
+                "x",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no vsock address"));
+    }
+
+    /// Start a fake remote agent on a local port that answers `Execute`
+    /// with "remote: <code>" and returns the port.
+    async fn spawn_tcp_agent() -> u16 {
+        use crate::transport::{recv_message, send_message};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+            send_message(&mut stream, &ready).await.unwrap();
+            while let Ok(bytes) = recv_message(&mut stream).await {
+                let resp = match serde_json::from_slice(&bytes).unwrap() {
+                    AgentRequest::Execute { id, code, .. } => AgentResponse::Result {
+                        id,
+                        stdout: format!("remote: {code}"),
+                        stderr: String::new(),
+                        exit_code: 0,
+                    },
+                    AgentRequest::Ping => AgentResponse::Pong,
+                    _ => break,
+                };
+                send_message(&mut stream, &serde_json::to_vec(&resp).unwrap())
+                    .await
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    fn remote_meta(port: u16) -> EnvironmentMeta {
+        EnvironmentMeta {
+            backend: BackendType::Remote,
+            interpreter_type: Some("python".to_string()),
+            tcp: Some(crate::config::TcpAddr {
+                host: "127.0.0.1".to_string(),
+                port,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_session_connects_over_tcp() {
+        let port = spawn_tcp_agent().await;
+        let manager = SessionManager::new(SessionConfig::default());
+        let mut meta = EnvironmentMeta {
+            backend: BackendType::Remote,
+            interpreter_type: Some("python".to_string()),
+            ..Default::default()
+        };
+        let run = |meta: &EnvironmentMeta| {
+            let meta = meta.clone();
+            let manager = &manager;
+            async move {
+                manager
+                    .execute(
+                        "s1",
+                        "r1",
+                        "remote",
+                        &meta,
+                        "x",
+                        meta.effective_timeout(None),
+                        &Mounts::default(),
+                        false,
+                    )
+                    .await
+            }
+        };
+
+        let err = run(&meta).await.unwrap_err();
+        assert!(err.to_string().contains("no tcp address"));
+
+        meta.tcp = remote_meta(port).tcp;
+        let result = run(&meta).await.unwrap();
+        assert_eq!(result.stdout, "remote: x");
+    }
+
+    #[tokio::test]
+    async fn test_session_env_mismatch_without_rebind() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let old = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&old)))
+            .await;
+
+        let meta = remote_meta(spawn_tcp_agent().await);
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "remote",
+                &meta,
+                "x",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("bound to environment 'python', not 'remote'"),
+            "{err}"
+        );
+        assert!(old.is_alive());
+        assert_eq!(manager.env_name("s1").await.as_deref(), Some("python"));
+    }
+
+    #[tokio::test]
+    async fn test_rebind_replaces_session_in_new_env() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let old = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&old)))
+            .await;
+
+        // Same environment: nothing to do
+        assert_eq!(manager.rebind("s1", "python").await, None);
+        assert!(old.is_alive());
+
+        assert_eq!(
+            manager.rebind("s1", "remote").await.as_deref(),
+            Some("python")
+        );
+        assert!(!old.is_alive(), "old agent should be shut down");
+        assert_eq!(manager.session_count().await, 0);
+
+        let meta = remote_meta(spawn_tcp_agent().await);
+        let result = manager
+            .execute(
+                "s1",
+                "r1",
+                "remote",
+                &meta,
+                "x",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "remote: x");
+        assert_eq!(manager.env_name("s1").await.as_deref(), Some("remote"));
+    }
+
+    /// Transport whose agent has died: every request fails.
+    #[derive(Default)]
+    struct DeadTransport {
+        shut_down: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait]
+    impl Transport for Arc<DeadTransport> {
+        async fn request(&self, _req: &AgentRequest) -> Result<AgentResponse> {
+            anyhow::bail!("Failed to read response from agent")
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            anyhow::bail!("Broken pipe")
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.shut_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    /// Transport that answers pings but whose agent dies running code.
+    #[derive(Default)]
+    struct DyingTransport {
+        died: std::sync::atomic::AtomicBool,
+        shut_down: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait]
+    impl Transport for Arc<DyingTransport> {
+        async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+            if matches!(req, AgentRequest::Ping) && self.is_alive() {
+                return Ok(AgentResponse::Pong);
+            }
+            self.died.store(true, std::sync::atomic::Ordering::SeqCst);
+            anyhow::bail!("Agent terminated mid-response")
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            anyhow::bail!("Broken pipe")
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.shut_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            !self.died.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_agent_fails_without_recreate() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let dying = Arc::new(DyingTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&dying)))
+            .await;
+
+        let meta = meta_with_interpreter_type(None);
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Session 's1' died and its interpreter state was lost"),
+            "{err}"
+        );
+        assert!(matches!(ExecError::find(&err), Some(ExecError::IoError(_))));
+
+        // The dead session is gone, so the next call starts fresh
+        assert!(dying.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(manager.session_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_agent_recreated_on_request() {
+        let port = spawn_tcp_agent().await;
+        let manager = SessionManager::new(SessionConfig::default());
+        let dying = Arc::new(DyingTransport::default());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        manager.attach_notifier(tx);
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&dying)))
+            .await;
+
+        let meta = remote_meta(port);
+        let result = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "remote: print(1)");
+        assert!(
+            result.stderr.starts_with("[session recreated:"),
+            "{}",
+            result.stderr
+        );
+        assert!(dying.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|e| (e.event, e.reason))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (SessionEventKind::Closed, "agent died".to_string()),
+                (SessionEventKind::Created, "new".to_string()),
+            ]
+        );
+
+        // The replacement stays registered under the same ID
+        let session = Arc::clone(&manager.sessions.read().await["s1"]);
+        assert!(session.transport.is_alive());
+        session.shutdown().await.unwrap();
+    }
+
+    /// Write a session wrapper that sends `Ready` and then idles.
+    fn fake_session_exec(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("session-run");
+        std::fs::write(
+            &path,
+            "#!/bin/sh\nprintf '\\000\\000\\000\\020{\"type\":\"ready\"}'\nexec cat >/dev/null\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_non_executable_session_exec_blames_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = dir.path().join("session-run");
+        std::fs::write(&exec, "#!/bin/sh\n").unwrap();
+
+        let manager = SessionManager::new(SessionConfig::default());
+        let meta = EnvironmentMeta {
+            session_exec: Some(exec.to_string_lossy().into_owned()),
+            ..meta_with_interpreter_type(None)
+        };
+        let err = manager
+            .get_or_create("s1", "python", &meta, &Mounts::default())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "session_exec for environment 'python' is not executable: {}",
+                exec.display()
+            )
+        );
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_agent_gets_memory_limit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("memory");
+        let exec = dir.path().join("session-run");
+        std::fs::write(
+            &exec,
+            format!(
+                "#!/bin/sh\necho \"$SANDBOX_MEMORY_MB\" > {}\n\
+                 printf '\\000\\000\\000\\020{{\"type\":\"ready\"}}'\nexec cat >/dev/null\n",
+                env_file.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&exec, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = SessionManager::new(SessionConfig::default());
+        let meta = EnvironmentMeta {
+            session_exec: Some(exec.to_string_lossy().into_owned()),
+            memory_mb: 768,
+            ..meta_with_interpreter_type(None)
+        };
+        let session = manager
+            .get_or_create("s1", "python", &meta, &Mounts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&env_file).unwrap(), "768\n");
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execute_rejects_disallowed_interpreter() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(SessionConfig::default());
+        let meta = EnvironmentMeta {
+            session_exec: Some(fake_session_exec(dir.path())),
+            ..meta_with_interpreter_type(Some("python3 -c"))
+        };
+
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "custom",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment 'custom' uses interpreter 'python3 -c', which sessions don't allow \
+             (allowed: bash, node, python)"
+        );
+        // Rejected before any agent was spawned
+        assert!(manager.list().await.is_empty());
+    }
+
+    /// Run `print(1)` in a fresh session for `env_name`. The session
+    /// wrapper doesn't exist, so a call that passes the policy fails to spawn.
+    async fn execute_in(manager: &SessionManager, env_name: &str) -> Result<ExecutionResult> {
+        let meta = EnvironmentMeta {
+            session_exec: Some("/nonexistent/session-run".to_string()),
+            ..meta_with_interpreter_type(Some("python"))
+        };
+        manager
+            .execute(
+                "s1",
+                "r1",
+                env_name,
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_session_policy_allows_all_by_default() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let err = execute_in(&manager, "shell").await.unwrap_err();
+        assert!(!err.to_string().contains("not allowed"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_session_policy_denies_env() {
+        let manager = SessionManager::new(SessionConfig {
+            session_deny: BTreeSet::from(["shell".to_string()]),
+            ..SessionConfig::default()
+        });
+        let err = execute_in(&manager, "shell").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Sessions are not allowed for environment 'shell' (session_deny). \
+             Omit `session` to run it ephemerally."
+        );
+        // Rejected before any agent was spawned
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_policy_allow_list() {
+        let manager = SessionManager::new(SessionConfig {
+            session_allow: Some(BTreeSet::from(["python".to_string(), "shell".to_string()])),
+            session_deny: BTreeSet::from(["shell".to_string()]),
+            ..SessionConfig::default()
+        });
+
+        let err = execute_in(&manager, "node").await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'node' (session_allow: python, shell)"),
+            "{err}"
+        );
+        // Deny wins over allow
+        let err = execute_in(&manager, "shell").await.unwrap_err();
+        assert!(err.to_string().contains("(session_deny)"), "{err}");
+
+        let err = execute_in(&manager, "python").await.unwrap_err();
+        assert!(!err.to_string().contains("not allowed"), "{err}");
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_accepts_configured_interpreter() {
+        let manager = SessionManager::new(SessionConfig {
+            allowed_interpreters: BTreeSet::from(["ruby".to_string()]),
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session("s1", "rb", Box::new(Arc::new(MockTransport::default())))
+            .await;
+
+        // MockTransport answers Execute only once a control message arrives
+        let meta = meta_with_interpreter_type(Some("ruby"));
+        let result = manager
+            .execute(
+                "s1",
+                "r1",
+                "rb",
+                &meta,
+                "puts 1",
+                Duration::from_millis(50),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+
+        // The defaults no longer apply once the set is configured
+        let meta = meta_with_interpreter_type(Some("python"));
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "rb",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("(allowed: ruby)"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_execute_rejects_unsupported_interpreter() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let transport = MockTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                protocol_version: 1,
+                interpreters: vec!["bash".to_string()],
+                ..Default::default()
+            }),
+            ..MockTransport::default()
+        };
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(transport)))
+            .await;
+
+        let meta = meta_with_interpreter_type(None);
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "print(1)",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("does not support interpreter 'python' (supported: bash)"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_replaces_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(SessionConfig::default());
+        let old = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&old)))
+            .await;
+        let before = Arc::clone(&manager.sessions.read().await["s1"]);
+
+        let meta = EnvironmentMeta {
+            session_exec: Some(fake_session_exec(dir.path())),
+            ..meta_with_interpreter_type(None)
+        };
+        manager
+            .restart("s1", &meta, &Mounts::default())
+            .await
+            .unwrap();
+
+        let after = Arc::clone(&manager.sessions.read().await["s1"]);
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert_eq!(after.env_name, "python");
+        assert!(after.created_at >= before.created_at);
+        assert!(old.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(manager.metrics().restarted, 1);
+        after.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_missing_session() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let meta = meta_with_interpreter_type(None);
+        let err = manager
+            .restart("nope", &meta, &Mounts::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Session 'nope' not found"));
+        assert!(manager.execute_locks.read().await.is_empty());
+    }
+
+    /// Transport whose agent takes a while to shut down.
+    #[derive(Default)]
+    struct SlowShutdownTransport {
+        shutting_down: std::sync::atomic::AtomicBool,
+        shut_down: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait]
+    impl Transport for Arc<SlowShutdownTransport> {
+        async fn request(&self, _req: &AgentRequest) -> Result<AgentResponse> {
+            Ok(AgentResponse::Pong)
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.shutting_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            self.shut_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reaper_stop_finishes_sweep_in_progress() {
+        use std::sync::atomic::Ordering;
+
+        let clock = Arc::new(MockClock::new());
+        let manager = Arc::new(SessionManager::with_clock(
+            SessionConfig {
+                reaper_interval: Duration::from_millis(20),
+                ..SessionConfig::default()
+            },
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        ));
+        let slow = Arc::new(SlowShutdownTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&slow)))
+            .await;
+        clock.advance(Duration::from_secs(301));
+
+        let reaper = manager.start_reaper();
+        while !slow.shutting_down.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        reaper.stop().await;
+
+        assert!(slow.shut_down.load(Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+    }
+
+    /// Transport whose agent has hung: requests never complete.
+    #[derive(Default)]
+    struct HungTransport {
+        shut_down: std::sync::atomic::AtomicBool,
+        capabilities: Option<crate::transport::Capabilities>,
+    }
+
+    #[async_trait]
+    impl Transport for Arc<HungTransport> {
+        async fn request(&self, _req: &AgentRequest) -> Result<AgentResponse> {
+            std::future::pending().await
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.shut_down
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+
+        fn capabilities(&self) -> Option<&crate::transport::Capabilities> {
+            self.capabilities.as_ref()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unanswered_interpreter_list_desyncs_session() {
+        let hung = Arc::new(HungTransport {
+            capabilities: Some(crate::transport::Capabilities {
+                interpreters: vec!["python".into()],
+                list_interpreters: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&hung)))
+            .await;
+        let session = Arc::clone(&manager.sessions.read().await["s1"]);
+        assert!(session.is_usable());
+
+        session.query_interpreters().await;
+
+        // Its late reply would answer the next request, so the agent is replaced
+        assert!(!session.is_usable());
+        assert_eq!(session.interpreters(), ["python"]);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_reaps_unresponsive_session() {
+        let manager = Arc::new(SessionManager::new(SessionConfig {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            ping_timeout: Some(Duration::from_millis(50)),
+            ..SessionConfig::default()
+        }));
+        let hung = Arc::new(HungTransport::default());
+        manager
+            .insert_session(
+                "healthy",
+                "python",
+                Box::new(Arc::new(MockTransport::default())),
+            )
+            .await;
+        manager
+            .insert_session("hung", "python", Box::new(Arc::clone(&hung)))
+            .await;
+
+        // Far sooner than the 60s reaper sweep or the 300s idle timeout
+        let reaper = manager.start_reaper();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        reaper.stop().await;
+
+        let infos = manager.list().await;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].id, "healthy");
+        assert!(hung.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+
+        // Keepalive pings don't count as activity
+        assert!(infos[0].idle >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_skips_busy_session() {
+        let manager = SessionManager::new(SessionConfig {
+            ping_timeout: Some(Duration::from_millis(50)),
+            ..SessionConfig::default()
+        });
+        let hung = Arc::new(HungTransport::default());
+        manager
+            .insert_session("busy", "python", Box::new(Arc::clone(&hung)))
+            .await;
+
+        let lock = manager.get_execute_lock("busy").await;
+        let guard = lock.lock().await;
+        manager.keepalive().await;
+        drop(guard);
+
+        assert!(!hung.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(manager.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_healthy_session_is_reused() {
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+        let before = Arc::clone(&manager.sessions.read().await["s1"]);
+
+        let meta = meta_with_interpreter_type(None);
+        let session = manager
+            .get_or_create("s1", "python", &meta, &Mounts::default())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&before, &session));
+    }
+
+    #[tokio::test]
+    async fn test_dead_session_is_recreated() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(SessionConfig::default());
+        let dead = Arc::new(DeadTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&dead)))
+            .await;
+        let before = Arc::clone(&manager.sessions.read().await["s1"]);
+
+        let meta = EnvironmentMeta {
+            session_exec: Some(fake_session_exec(dir.path())),
+            ..meta_with_interpreter_type(None)
+        };
+        let session = manager
+            .get_or_create("s1", "python", &meta, &Mounts::default())
+            .await
+            .unwrap();
+
+        assert!(!Arc::ptr_eq(&before, &session));
+        assert!(dead.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(Arc::ptr_eq(&manager.sessions.read().await["s1"], &session));
+        session.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lru_session_evicted_at_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(SessionConfig {
+            max_sessions: 2,
+            ..SessionConfig::default()
+        });
+
+        let oldest = Arc::new(MockTransport::default());
+        let newer = Arc::new(MockTransport::default());
+        manager
+            .insert_session("oldest", "python", Box::new(Arc::clone(&oldest)))
+            .await;
+        manager
+            .insert_session("newer", "python", Box::new(Arc::clone(&newer)))
+            .await;
+        let stale_since = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        *manager.sessions.read().await["oldest"]
+            .last_used
+            .lock()
+            .await = stale_since;
+
+        let meta = EnvironmentMeta {
+            session_exec: Some(fake_session_exec(dir.path())),
+            ..meta_with_interpreter_type(None)
+        };
+        let created = manager
+            .get_or_create("third", "python", &meta, &Mounts::default())
+            .await
+            .unwrap();
+
+        let ids: Vec<_> = manager.list().await.into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["newer", "third"]);
+        assert_eq!(manager.session_count().await, 2);
+        assert!(oldest.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(!newer.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        let metrics = manager.metrics();
+        assert_eq!(metrics.created, 1);
+        assert_eq!(metrics.evicted, 1);
+        created.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_limit_with_all_sessions_busy() {
+        let manager = SessionManager::new(SessionConfig {
+            max_sessions: 1,
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session(
+                "busy",
+                "python",
+                Box::new(Arc::new(MockTransport::default())),
+            )
+            .await;
+        let lock = manager.get_execute_lock("busy").await;
+        let _guard = lock.lock().await;
+
+        let err = manager.evict_lru_if_full(0).await.unwrap_err();
+        assert!(err.to_string().contains("all sessions are busy"));
+        assert_eq!(manager.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_memory_limit_evicts_lru() {
+        let manager = SessionManager::new(SessionConfig {
+            max_session_memory_mb: Some(1024),
+            ..SessionConfig::default()
+        });
+        let transports: Vec<_> = (0..3).map(|_| Arc::new(MockTransport::default())).collect();
+        for (i, (id, transport)) in ["a", "b", "c"].iter().zip(&transports).enumerate() {
+            let session = Session::new(
+                (*id).to_string(),
+                "python".to_string(),
+                256,
+                Box::new(Arc::clone(transport)),
+                Arc::new(RealClock),
+            );
+            let last_used = Instant::now()
+                .checked_sub(Duration::from_secs(60 - 10 * i as u64))
+                .unwrap();
+            *session.last_used.lock().await = last_used;
+            manager
+                .sessions
+                .write()
+                .await
+                .insert((*id).to_string(), Arc::new(session));
+        }
+
+        // 768 MB in use: a 512 MB session needs the oldest one gone
+        manager.evict_lru_if_full(512).await.unwrap();
+        let ids: Vec<_> = manager.list().await.into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["b", "c"]);
+        assert!(transports[0]
+            .shut_down
+            .load(std::sync::atomic::Ordering::SeqCst));
+
+        // Evicting everything can't fit a session over the ceiling
+        let err = manager.evict_lru_if_full(2048).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("more than the session memory limit"));
+        assert_eq!(manager.list().await.len(), 2);
+
+        // All busy: nothing can be evicted
+        let lock_b = manager.get_execute_lock("b").await;
+        let lock_c = manager.get_execute_lock("c").await;
+        let (_b, _c) = (lock_b.lock().await, lock_c.lock().await);
+        let err = manager.evict_lru_if_full(768).await.unwrap_err();
+        assert!(err.to_string().contains("Session memory limit reached"));
+    }
+
+    #[tokio::test]
+    async fn test_session_timeout_reports_timed_out() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let transport = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        let meta = EnvironmentMeta {
+            timeout_seconds: 1,
+            ..meta_with_interpreter_type(None)
+        };
+
+        // MockTransport never answers an Execute without a cancel
+        let result = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "while True: pass",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, crate::backend::TIMEOUT_EXIT_CODE);
+        assert!(
+            result.stderr.contains("session 's1' was restarted"),
+            "{}",
+            result.stderr
+        );
+
+        // The agent was told to stop, and its late reply can't reach the
+        // next call: the session is gone
+        assert!(matches!(
+            transport.controls.lock().unwrap().as_slice(),
+            [AgentRequest::Cancel { id }] if id == "r1"
+        ));
+        assert!(transport
+            .shut_down
+            .load(std::sync::atomic::Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+    }
+
+    /// Transport whose agent answers every `Execute` with another id.
+    struct StaleReplyTransport;
+
+    #[async_trait]
+    impl Transport for StaleReplyTransport {
+        async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+            if matches!(req, AgentRequest::Ping) {
+                return Ok(AgentResponse::Pong);
+            }
+            Ok(AgentResponse::Result {
+                id: "earlier".to_string(),
+                stdout: "stale\n".to_string(),
+                stderr: String::new(),
+                exit_code: 0,
+            })
+        }
+
+        async fn send_control(&self, _req: &AgentRequest) -> Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_for_another_request_is_rejected() {
+        let manager = SessionManager::new(SessionConfig::default());
+        manager
+            .insert_session("s1", "python", Box::new(StaleReplyTransport))
+            .await;
+
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta_with_interpreter_type(None),
+                "print(1)",
+                Duration::from_secs(5),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("answered request 'earlier' while 'r1' was pending"),
+            "{message}"
+        );
+        assert!(
+            message.contains("lost track of its agent's replies"),
+            "{message}"
+        );
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_session() {
+        let manager = SessionManager::new(SessionConfig::default());
+        let transport = Arc::new(MockTransport::default());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::clone(&transport)))
+            .await;
+        manager.get_execute_lock("s1").await;
+
+        assert!(manager.close("s1").await.unwrap());
+        assert!(transport
+            .shut_down
+            .load(std::sync::atomic::Ordering::SeqCst));
+        assert!(manager.list().await.is_empty());
+        assert!(!manager.execute_locks.read().await.contains_key("s1"));
+
+        // Closing again is a no-op
+        assert!(!manager.close("s1").await.unwrap());
+        assert_eq!(manager.metrics().closed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_close_unknown_session() {
+        let manager = SessionManager::new(SessionConfig::default());
+        assert!(!manager.close("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions() {
+        let manager = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_secs(300),
+            max_lifetime: Duration::from_secs(3600),
+            ..SessionConfig::default()
+        });
+        assert!(manager.list().await.is_empty());
+
+        for (id, env) in [("beta", "shell"), ("alpha", "python")] {
+            manager
+                .insert_session(id, env, Box::new(Arc::new(MockTransport::default())))
+                .await;
+        }
+
+        let infos = manager.list().await;
+        let ids: Vec<_> = infos.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, vec!["alpha", "beta"]);
+        assert_eq!(infos[0].env_name, "python");
+        assert_eq!(infos[1].env_name, "shell");
+
+        for info in &infos {
+            assert!(info.age < Duration::from_secs(5));
+            assert!(info.idle <= info.age);
+            // Fresh sessions are bounded by the idle timeout, not the lifetime
+            assert!(info.expires_in <= Duration::from_secs(300));
+            assert!(info.expires_in > Duration::from_secs(295));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_lifetime_bound() {
+        let manager = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_secs(300),
+            max_lifetime: Duration::from_secs(60),
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+
+        let infos = manager.list().await;
+        assert!(infos[0].expires_in <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_reaper_counts_idle_expiry() {
+        let manager = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_secs(30),
+            ..SessionConfig::default()
+        });
+        let idle = Arc::new(MockTransport::default());
+        manager
+            .insert_session("idle", "python", Box::new(Arc::clone(&idle)))
+            .await;
+        manager
+            .insert_session(
+                "fresh",
+                "python",
+                Box::new(Arc::new(MockTransport::default())),
+            )
+            .await;
+        let stale_since = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        *manager.sessions.read().await["idle"].last_used.lock().await = stale_since;
+
+        manager.cleanup_expired().await;
+
+        let ids: Vec<_> = manager.list().await.into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["fresh"]);
+        assert!(idle.shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        let metrics = manager.metrics();
+        assert_eq!(metrics.reaped_idle, 1);
+        assert_eq!(metrics.reaped_lifetime, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reaping_notifies() {
+        let manager = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_secs(30),
+            ..SessionConfig::default()
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        manager.attach_notifier(tx);
+        for id in ["idle", "fresh"] {
+            manager
+                .insert_session(id, "python", Box::new(Arc::new(MockTransport::default())))
+                .await;
+        }
+        let stale_since = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        *manager.sessions.read().await["idle"].last_used.lock().await = stale_since;
+
+        manager.cleanup_expired().await;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            SessionEvent {
+                session: "idle".to_string(),
+                env: "python".to_string(),
+                event: SessionEventKind::Closed,
+                reason: "idle timeout".to_string(),
+            }
+        );
+        assert!(rx.try_recv().is_err());
+
+        manager.destroy_all().await;
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            (event.session.as_str(), event.reason.as_str()),
+            ("fresh", "shutdown")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_touch_extends_idle_deadline() {
+        let manager = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_secs(30),
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+        let stale_since = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        *manager.sessions.read().await["s1"].last_used.lock().await = stale_since;
+        let before = manager.last_used("s1").await.unwrap();
+        assert_eq!(manager.list().await[0].expires_in, Duration::ZERO);
+
+        assert!(manager.touch("s1").await);
+
+        assert!(manager.last_used("s1").await.unwrap() > before);
+        assert!(manager.list().await[0].expires_in > Duration::from_secs(25));
+        manager.cleanup_expired().await;
+        assert_eq!(manager.session_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_touch_unknown_session() {
+        let manager = SessionManager::new(SessionConfig::default());
+        assert!(!manager.touch("missing").await);
+        assert!(manager.last_used("missing").await.is_none());
+        // No execute lock is left behind for an ID that never existed
+        assert!(manager.execute_locks.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reaper_counts_lifetime_expiry() {
+        let manager = SessionManager::new(SessionConfig {
+            max_lifetime: Duration::ZERO,
+            ..SessionConfig::default()
+        });
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+        // Past its lifetime and idle at once: counted as lifetime only
+        let stale_since = Instant::now()
+            .checked_sub(Duration::from_secs(600))
+            .unwrap();
+        *manager.sessions.read().await["s1"].last_used.lock().await = stale_since;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        manager.cleanup_expired().await;
+        manager.cleanup_expired().await;
+
+        assert_eq!(manager.session_count().await, 0);
+        let metrics = manager.metrics();
+        assert_eq!(metrics.reaped_lifetime, 1);
+        assert_eq!(metrics.reaped_idle, 0);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_drives_idle_expiry() {
+        let clock = Arc::new(MockClock::new());
+        let manager = SessionManager::with_clock(
+            SessionConfig {
+                idle_timeout: Duration::from_secs(30),
+                max_lifetime: Duration::from_secs(300),
+                ..SessionConfig::default()
+            },
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+        for id in ["idle", "busy"] {
+            manager
+                .insert_session(id, "python", Box::new(Arc::new(MockTransport::default())))
+                .await;
+        }
+
+        clock.advance(Duration::from_secs(20));
+        assert!(manager.touch("busy").await);
+        manager.cleanup_expired().await;
+        assert_eq!(manager.session_count().await, 2);
+
+        // 35s idle against 15s: only the untouched session is past 30s
+        clock.advance(Duration::from_secs(15));
+        manager.cleanup_expired().await;
+        let ids: Vec<_> = manager.list().await.into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["busy"]);
+        assert_eq!(manager.metrics().reaped_idle, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_drives_lifetime_expiry() {
+        let clock = Arc::new(MockClock::new());
+        let manager = SessionManager::with_clock(
+            SessionConfig {
+                idle_timeout: Duration::from_secs(30),
+                max_lifetime: Duration::from_secs(60),
+                ..SessionConfig::default()
+            },
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+
+        // Used every 25s, so never idle-expired, until it outlives 60s
+        for elapsed in [25, 50, 75] {
+            clock.advance(Duration::from_secs(25));
+            manager.touch("s1").await;
+            manager.cleanup_expired().await;
+            let live = manager.session_count().await;
+            assert_eq!(live, usize::from(elapsed <= 60), "after {elapsed}s");
+        }
+        let metrics = manager.metrics();
+        assert_eq!(metrics.reaped_lifetime, 1);
+        assert_eq!(metrics.reaped_idle, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_persist_across_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SessionConfig {
+            state_dir: Some(dir.path().to_path_buf()),
+            ..SessionConfig::default()
+        };
+
+        // First daemon run: one live session, persisted on creation
+        let manager = SessionManager::new(config.clone());
+        manager
+            .insert_session("s1", "python", Box::new(Arc::new(MockTransport::default())))
+            .await;
+        manager.save_state().await;
+
+        let records = persist::load(&dir.path().join(persist::STATE_FILE)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "s1");
+        assert_eq!(records[0].env_name, "python");
+
+        // Second daemon run: the session is known but its agent is gone
+        let manager = SessionManager::new(config);
+        let meta = meta_with_interpreter_type(None);
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "x",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("expired across a daemon restart"));
+
+        // Reported once: the next call tries to create a fresh session
+        let err = manager
+            .execute(
+                "s1",
+                "r1",
+                "python",
+                &meta,
+                "x",
+                meta.effective_timeout(None),
+                &Mounts::default(),
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not support sessions"));
+        assert!(persist::load(&dir.path().join(persist::STATE_FILE))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_records_dropped_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = persist::unix_now() - 10_000;
+        persist::save(
+            &dir.path().join(persist::STATE_FILE),
+            &[SessionRecord {
+                id: "ancient".to_string(),
+                env_name: "python".to_string(),
+                created_at: old,
+                last_used: old,
+            }],
+        )
+        .unwrap();
+
+        let manager = SessionManager::new(SessionConfig {
+            state_dir: Some(dir.path().to_path_buf()),
+            ..SessionConfig::default()
+        });
+        assert!(manager.stale.lock().await.is_empty());
     }
 }
@@ -0,0 +1,88 @@
+//! Time source for session timing.
+//!
+//! Idle and lifetime expiry, touches, and rate limiting read the time
+//! through a [`Clock`], so tests can step a [`MockClock`] past a deadline
+//! instead of sleeping until it.
+
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the current monotonic time, and the wall-clock time for
+/// timestamps that are reported.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn wall_now(&self) -> SystemTime;
+}
+
+/// The system's monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that stands still until advanced.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<(Instant, SystemTime)>,
+}
+
+impl MockClock {
+    /// A clock stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+
+    /// Move the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap_or_else(PoisonError::into_inner).0
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        self.now.lock().unwrap_or_else(PoisonError::into_inner).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_moves_only_when_advanced() {
+        let clock = MockClock::new();
+        let (start, wall_start) = (clock.now(), clock.wall_now());
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.wall_now(), wall_start);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(
+            clock.wall_now().duration_since(wall_start).unwrap(),
+            Duration::from_secs(90)
+        );
+    }
+}
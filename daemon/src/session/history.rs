@@ -0,0 +1,107 @@
+//! Bounded record of a session's recent executions, for debugging.
+//!
+//! Each `run` on a session appends one entry, dropping the oldest once the
+//! configured size is reached. Code is kept as a SHA-256 digest; the code
+//! itself only with `history_store_code`, for trusted deployments.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::audit::{code_hash, unix_now_ms};
+use crate::config::HashAlgorithm;
+
+/// One execution in a session's history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    /// ID of the run call, as in the daemon's logs.
+    pub request_id: String,
+    /// `sha256:<hex digest>` of the code.
+    pub code_hash: String,
+    /// The code itself, only with `history_store_code`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// `None` when the execution timed out.
+    pub exit_code: Option<i32>,
+    /// Unix time the execution finished, in milliseconds.
+    pub timestamp_ms: u64,
+}
+
+impl HistoryEntry {
+    /// Entry for `code` finishing now, keeping the code only if `store_code`.
+    pub fn new(request_id: &str, code: &str, exit_code: Option<i32>, store_code: bool) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            code_hash: code_hash(HashAlgorithm::Sha256, code.as_bytes()),
+            code: store_code.then(|| code.to_string()),
+            exit_code,
+            timestamp_ms: unix_now_ms(),
+        }
+    }
+}
+
+/// A session's executions, oldest first.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    /// Append `entry`, keeping at most `limit` entries (0 keeps none).
+    pub fn push(&mut self, entry: HistoryEntry, limit: usize) {
+        if limit == 0 {
+            return;
+        }
+        while self.entries.len() >= limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_is_bounded_and_ordered() {
+        let mut history = History::default();
+        for i in 0..5 {
+            history.push(HistoryEntry::new(&format!("r{i}"), "x", Some(i), false), 3);
+        }
+        let ids: Vec<_> = history
+            .entries()
+            .into_iter()
+            .map(|e| e.request_id)
+            .collect();
+        assert_eq!(ids, ["r2", "r3", "r4"]);
+    }
+
+    #[test]
+    fn history_hashes_code_unless_stored() {
+        let entry = HistoryEntry::new("r1", "abc", Some(0), false);
+        assert_eq!(
+            entry.code_hash,
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(entry.code, None);
+        assert_eq!(
+            HistoryEntry::new("r1", "abc", Some(0), true)
+                .code
+                .as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn zero_limit_keeps_nothing() {
+        let mut history = History::default();
+        history.push(HistoryEntry::new("r1", "x", Some(0), false), 0);
+        assert!(history.entries().is_empty());
+    }
+}
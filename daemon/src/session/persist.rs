@@ -0,0 +1,139 @@
+//! On-disk session metadata.
+//!
+//! Only metadata (id, env, timestamps) is persisted — the agent process and
+//! its interpreter state die with the daemon. After a restart the records
+//! let the manager tell clients their session expired, instead of silently
+//! handing them a fresh interpreter under the same ID.
+
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// File name of the session state file inside `state_dir`.
+pub const STATE_FILE: &str = "sessions.json";
+
+/// Persisted metadata for one session. Timestamps are Unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub env_name: String,
+    pub created_at: u64,
+    pub last_used: u64,
+}
+
+impl SessionRecord {
+    /// Check whether this record would have been reaped by now.
+    pub fn is_expired(&self, idle_timeout: Duration, max_lifetime: Duration) -> bool {
+        let now = unix_now();
+        now.saturating_sub(self.last_used) > idle_timeout.as_secs()
+            || now.saturating_sub(self.created_at) > max_lifetime.as_secs()
+    }
+}
+
+/// Current wall-clock time in Unix seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Convert a monotonic `Instant` to wall-clock Unix seconds, given the
+/// monotonic time `now`.
+pub fn instant_to_unix(instant: Instant, now: Instant) -> u64 {
+    unix_now().saturating_sub(now.saturating_duration_since(instant).as_secs())
+}
+
+/// Load session records from `path`. A missing file means no records.
+pub fn load(path: &Path) -> Result<Vec<SessionRecord>> {
+    match std::fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .with_context(|| format!("Invalid session state file: {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => {
+            Err(e).with_context(|| format!("Cannot read session state file: {}", path.display()))
+        }
+    }
+}
+
+/// Atomically write session records to `path` (write temp file, then rename).
+///
+/// The temp file's name is unique to this write, so writers sharing the
+/// directory never clobber each other's half-written file.
+pub fn save(path: &Path, records: &[SessionRecord]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Cannot create state dir: {}", dir.display()))?;
+    }
+    let json = serde_json::to_vec_pretty(records).context("Failed to serialize sessions")?;
+    let tmp = path.with_extension(format!("json.{}.tmp", uuid::Uuid::new_v4()));
+    std::fs::write(&tmp, json).with_context(|| format!("Cannot write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Cannot replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, created_at: u64, last_used: u64) -> SessionRecord {
+        SessionRecord {
+            id: id.to_string(),
+            env_name: "python".to_string(),
+            created_at,
+            last_used,
+        }
+    }
+
+    #[test]
+    fn record_serialization() {
+        let json = serde_json::to_string(&record("s1", 100, 200)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":"s1","env_name":"python","created_at":100,"last_used":200}"#
+        );
+    }
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(STATE_FILE);
+        let records = vec![record("a", 1, 2), record("b", 3, 4)];
+
+        save(&path, &records).unwrap();
+        assert_eq!(load(&path).unwrap(), records);
+    }
+
+    #[test]
+    fn concurrent_saves_dont_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE);
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || save(path, &[record(&format!("s{i}"), 1, 2)]).unwrap());
+            }
+        });
+
+        assert_eq!(load(&path).unwrap().len(), 1);
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 1, "temp files left behind");
+    }
+
+    #[test]
+    fn load_missing_file_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(&dir.path().join(STATE_FILE)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn record_expiry() {
+        let now = unix_now();
+        let idle = Duration::from_secs(300);
+        let lifetime = Duration::from_secs(3600);
+
+        assert!(!record("fresh", now, now).is_expired(idle, lifetime));
+        assert!(record("idle", now, now - 301).is_expired(idle, lifetime));
+        assert!(record("old", now - 3601, now).is_expired(idle, lifetime));
+    }
+}
@@ -0,0 +1,93 @@
+//! Pausing idle session agents.
+//!
+//! An agent's jail wrapper runs the interpreter as a descendant, so stopping
+//! the wrapper alone would leave the interpreter running. The whole process
+//! tree is signalled instead, found by walking `/proc`.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+/// Send `signal` to `root` and every process descended from it, parents
+/// before their children.
+pub fn signal_tree(root: u32, signal: Signal) -> Result<()> {
+    for pid in process_tree(root)? {
+        let pid = i32::try_from(pid).context("Process ID out of range")?;
+        match signal::kill(Pid::from_raw(pid), signal) {
+            // A descendant may exit between listing and signalling
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to send {signal} to {pid}")),
+        }
+    }
+    Ok(())
+}
+
+/// `root` followed by its descendants, breadth first.
+fn process_tree(root: u32) -> Result<Vec<u32>> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in std::fs::read_dir("/proc").context("Cannot list /proc")? {
+        let Some(pid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        // Processes that exit while we look are simply skipped
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            continue;
+        };
+        if let Some(ppid) = parent_pid(&stat) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut tree = vec![root];
+    let mut next = 0;
+    while let Some(&pid) = tree.get(next) {
+        tree.extend(children.remove(&pid).unwrap_or_default());
+        next += 1;
+    }
+    Ok(tree)
+}
+
+/// Parent PID from the contents of `/proc/<pid>/stat`. The command name
+/// in parentheses may itself hold spaces and parentheses, so fields are
+/// counted from the last `)`.
+fn parent_pid(stat: &str) -> Option<u32> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_pid_skips_tricky_command_names() {
+        assert_eq!(parent_pid("812 (python3) S 790 812 790 0"), Some(790));
+        assert_eq!(parent_pid("813 (a) b (c)) R 1 813 813 0"), Some(1));
+        assert_eq!(parent_pid("garbage"), None);
+    }
+
+    #[test]
+    fn process_tree_finds_grandchildren() {
+        let mut child = std::process::Command::new("/bin/sh")
+            .args(["-c", "sleep 30 & wait"])
+            .spawn()
+            .unwrap();
+        let root = child.id();
+        // Give the shell a moment to fork its sleep
+        let mut tree = Vec::new();
+        for _ in 0..50 {
+            tree = process_tree(root).unwrap();
+            if tree.len() > 1 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        signal_tree(root, Signal::SIGKILL).unwrap();
+        child.wait().unwrap();
+        assert_eq!(tree[0], root);
+        assert_eq!(tree.len(), 2, "{tree:?}");
+    }
+}
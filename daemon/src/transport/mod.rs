@@ -1,21 +1,55 @@
 //! Transport layer for daemon ↔ agent communication.
 //!
 //! Provides the `Transport` trait and length-prefixed JSON framing functions.
-//! Phase 2b uses `StdioPipeTransport` (stdin/stdout pipes to jailed agent).
-//! Phase 3 will add `VsockTransport` for microVM agents.
+//! The high bit of the length header marks a gzip-compressed payload; peers
+//! only send one after the other side announced `gzip` support.
+//! `StdioPipeTransport` talks to jailed agents over stdin/stdout pipes;
+//! `VsockTransport` connects to agents inside microVMs, and `TcpTransport`
+//! to agents on other hosts.
 
 pub mod protocol;
 pub mod stdio_pipe;
+pub mod tcp;
+pub mod vsock;
 
-pub use protocol::{AgentRequest, AgentResponse};
+pub use protocol::{
+    negotiate_protocol, AgentRequest, AgentResponse, Capabilities, FragmentResult, BATCH_PROTOCOL,
+    SUPPORTED_PROTOCOL,
+};
 pub use stdio_pipe::StdioPipeTransport;
+pub use tcp::TcpTransport;
+pub use vsock::VsockTransport;
 
-use anyhow::Result;
+use std::io::{Read, Write};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tracing::debug;
 
 /// Maximum message size (64 MB). Safety valve against malformed messages.
+///
+/// Applies to the payload on the wire and, for compressed messages, to the
+/// decompressed payload too.
 const MAX_MESSAGE_SIZE: u32 = 64 * 1024 * 1024;
 
+/// Largest single read of a message body. The buffer grows as bytes arrive
+/// rather than being sized up front from the (peer-supplied) length header.
+const RECV_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Length-header bit marking a gzip-compressed payload.
+const GZIP_FLAG: u32 = 1 << 31;
+
+/// How long an agent may go without sending a byte once a response has
+/// started, before the transport gives up on it (see [`recv_message_timeout`]).
+pub const RESPONSE_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Payloads at least this large are compressed when the peer accepts gzip.
+pub const COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
 /// Abstraction over daemon ↔ agent communication channels.
 ///
 /// Implementations handle connection-specific details (pipes, vsock, etc.)
@@ -27,11 +61,40 @@ pub trait Transport: Send + Sync {
     /// Access is mutex-guarded internally — concurrent callers serialize.
     async fn request(&self, req: &AgentRequest) -> Result<AgentResponse>;
 
+    /// Send a request that expects no response (e.g. `Cancel`).
+    ///
+    /// Must not wait behind an in-flight `request()` — this is how control
+    /// messages reach the agent while it's busy executing.
+    async fn send_control(&self, req: &AgentRequest) -> Result<()>;
+
     /// Gracefully shut down the transport and the underlying agent process.
     async fn shutdown(&self) -> Result<()>;
 
     /// Check whether the underlying agent process is still alive.
     fn is_alive(&self) -> bool;
+
+    /// Capabilities the agent announced in its `Ready` message, if any.
+    fn capabilities(&self) -> Option<&Capabilities> {
+        None
+    }
+
+    /// Protocol version agreed with the agent in the handshake. By default
+    /// worked out again from `capabilities`.
+    fn protocol_version(&self) -> u32 {
+        negotiate_protocol(self.capabilities()).unwrap_or_default()
+    }
+
+    /// OS process ID of the agent, when the transport spawned it locally.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    /// How the agent process ended, once `shutdown` has reaped it: its own
+    /// exit code after a clean shutdown, or the signal that killed it.
+    /// `None` while it runs, or when the transport didn't spawn it.
+    fn last_exit(&self) -> Option<ExitStatus> {
+        None
+    }
 }
 
 /// Write a length-prefixed message to a writer.
@@ -40,6 +103,18 @@ pub trait Transport: Send + Sync {
 pub async fn send_message<W: tokio::io::AsyncWriteExt + Unpin>(
     writer: &mut W,
     payload: &[u8],
+) -> Result<()> {
+    send_frame(writer, payload, false).await
+}
+
+/// Write a message, gzip-compressing it if `gzip` is set and the payload is
+/// at least `COMPRESSION_THRESHOLD` bytes.
+///
+/// Only pass `gzip = true` for a peer that announced gzip support.
+pub async fn send_frame<W: tokio::io::AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+    gzip: bool,
 ) -> Result<()> {
     let len = u32::try_from(payload.len())
         .map_err(|_| anyhow::anyhow!("Message too large: {} bytes", payload.len()))?;
@@ -48,35 +123,164 @@ pub async fn send_message<W: tokio::io::AsyncWriteExt + Unpin>(
         "Message exceeds max size: {len} > {MAX_MESSAGE_SIZE}"
     );
 
-    writer.write_all(&len.to_be_bytes()).await?;
-    writer.write_all(payload).await?;
+    if gzip && payload.len() >= COMPRESSION_THRESHOLD {
+        let compressed = compress(payload)?;
+        // Compressed output is bounded by the input size plus a small overhead
+        let len = u32::try_from(compressed.len()).context("Compressed message too large")?;
+        writer.write_all(&(len | GZIP_FLAG).to_be_bytes()).await?;
+        writer.write_all(&compressed).await?;
+    } else {
+        writer.write_all(&len.to_be_bytes()).await?;
+        writer.write_all(payload).await?;
+    }
     writer.flush().await?;
     Ok(())
 }
 
+/// Gzip-compress a payload.
+fn compress(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder
+        .write_all(payload)
+        .context("Failed to compress message")?;
+    encoder.finish().context("Failed to compress message")
+}
+
+/// Decompress a gzip payload, refusing output beyond `MAX_MESSAGE_SIZE`.
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    GzDecoder::new(compressed)
+        .take(u64::from(MAX_MESSAGE_SIZE) + 1)
+        .read_to_end(&mut out)
+        .context("Failed to decompress message")?;
+    anyhow::ensure!(
+        out.len() <= MAX_MESSAGE_SIZE as usize,
+        "Decompressed message exceeds max size ({MAX_MESSAGE_SIZE} bytes)"
+    );
+    Ok(out)
+}
+
+/// Read the agent's first `Ready` message and check its protocol version.
+///
+/// Returns the capabilities the agent announced (`None` for older agents)
+/// and the version to speak with it. Callers bound this with their own
+/// timeout.
+pub async fn wait_ready<R: tokio::io::AsyncReadExt + Unpin>(
+    reader: &mut R,
+) -> Result<(Option<Capabilities>, u32)> {
+    let ready_bytes = recv_message(reader)
+        .await
+        .context("Failed to read agent Ready message")?;
+
+    let ready_msg: AgentResponse =
+        serde_json::from_slice(&ready_bytes).context("Failed to parse agent Ready message")?;
+
+    let AgentResponse::Ready { capabilities } = ready_msg else {
+        anyhow::bail!("Expected Ready message, got: {ready_msg:?}");
+    };
+    let version = negotiate_protocol(capabilities.as_ref())?;
+    debug!(version, "Negotiated agent protocol");
+    Ok((capabilities, version))
+}
+
+/// Tell the agent to speak `version` if it announced a newer one; an agent
+/// already speaking it hears nothing, so older agents never see the request.
+pub async fn use_protocol<W: tokio::io::AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    capabilities: Option<&Capabilities>,
+    version: u32,
+) -> Result<()> {
+    if capabilities.map_or(0, |c| c.protocol_version) == version {
+        return Ok(());
+    }
+    let req = serde_json::to_vec(&AgentRequest::UseProtocol { version })
+        .context("Failed to serialize request")?;
+    send_message(writer, &req)
+        .await
+        .context("Failed to select agent protocol")
+}
+
+/// Turn on compression if the agent announced gzip support.
+///
+/// Tells the agent it may compress its responses and returns whether the
+/// caller should compress its requests.
+pub async fn enable_compression<W: tokio::io::AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    capabilities: Option<&Capabilities>,
+) -> Result<bool> {
+    if !capabilities.is_some_and(|c| c.gzip) {
+        return Ok(false);
+    }
+    let req = serde_json::to_vec(&AgentRequest::EnableCompression)
+        .context("Failed to serialize request")?;
+    send_message(writer, &req)
+        .await
+        .context("Failed to enable compression")?;
+    Ok(true)
+}
+
 /// Read a length-prefixed message from a reader.
 ///
-/// Returns the raw payload bytes. Enforces `MAX_MESSAGE_SIZE`.
-pub async fn recv_message<R: tokio::io::AsyncReadExt + Unpin>(
+/// Returns the raw (decompressed) payload bytes. Enforces `MAX_MESSAGE_SIZE`
+/// on both the wire size and the decompressed size.
+pub async fn recv_message<R: tokio::io::AsyncReadExt + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    recv_message_timeout(reader, None).await
+}
+
+/// Like [`recv_message`], failing if the sender stalls mid-message.
+///
+/// Waiting for a message to start is unbounded (the agent may be running
+/// code); once the header has arrived, each read of the body must make
+/// progress within `stall_timeout`. The body is read in `RECV_CHUNK_BYTES`
+/// pieces, so a large advertised length only costs memory as it arrives.
+pub async fn recv_message_timeout<R: tokio::io::AsyncReadExt + Unpin>(
     reader: &mut R,
+    stall_timeout: Option<Duration>,
 ) -> Result<Vec<u8>> {
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf);
+    let header = u32::from_be_bytes(len_buf);
+    let gzip = header & GZIP_FLAG != 0;
+    let len = header & !GZIP_FLAG;
 
     anyhow::ensure!(
         len <= MAX_MESSAGE_SIZE,
         "Message exceeds max size: {len} > {MAX_MESSAGE_SIZE}"
     );
 
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    Ok(buf)
+    let len = len as usize;
+    let mut buf = Vec::with_capacity(len.min(RECV_CHUNK_BYTES));
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(start + (len - start).min(RECV_CHUNK_BYTES), 0);
+        let read = reader.read(&mut buf[start..]);
+        let n = match stall_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| {
+                anyhow::anyhow!(
+                    "Sender stalled mid-message: {start} of {len} bytes received, \
+                     none for {timeout:?}"
+                )
+            })??,
+            None => read.await?,
+        };
+        if n == 0 {
+            // Same error `read_exact` gives, so callers can detect it
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.truncate(start + n);
+    }
+
+    if gzip {
+        decompress(&buf)
+    } else {
+        Ok(buf)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::io::AsyncWriteExt;
 
     #[tokio::test]
     async fn roundtrip_framing() {
@@ -90,6 +294,117 @@ mod tests {
         assert_eq!(received, payload);
     }
 
+    #[tokio::test]
+    async fn roundtrip_compressed() {
+        let payload = "x".repeat(COMPRESSION_THRESHOLD * 4).into_bytes();
+        let mut buf = Vec::new();
+        send_frame(&mut buf, &payload, true).await.unwrap();
+
+        let header = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        assert_ne!(header & GZIP_FLAG, 0, "large payload should be compressed");
+        assert!(buf.len() < payload.len() / 10);
+
+        let mut cursor = std::io::Cursor::new(buf);
+        assert_eq!(recv_message(&mut cursor).await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn small_or_unnegotiated_payloads_stay_uncompressed() {
+        let large = vec![b'y'; COMPRESSION_THRESHOLD];
+        for (payload, gzip) in [(&b"small"[..], true), (&large[..], false)] {
+            let mut buf = Vec::new();
+            send_frame(&mut buf, payload, gzip).await.unwrap();
+
+            let header = u32::from_be_bytes(buf[..4].try_into().unwrap());
+            assert_eq!(header as usize, payload.len());
+
+            let mut cursor = std::io::Cursor::new(buf);
+            assert_eq!(recv_message(&mut cursor).await.unwrap(), payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn decompressed_size_is_limited() {
+        // A small frame that inflates past MAX_MESSAGE_SIZE is rejected
+        let bomb = compress(&vec![0u8; MAX_MESSAGE_SIZE as usize + 1]).unwrap();
+        let mut buf = (u32::try_from(bomb.len()).unwrap() | GZIP_FLAG)
+            .to_be_bytes()
+            .to_vec();
+        buf.extend_from_slice(&bomb);
+
+        let mut cursor = std::io::Cursor::new(buf);
+        let err = recv_message(&mut cursor).await.unwrap_err();
+        assert!(
+            err.to_string().contains("Decompressed message exceeds"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_sender_times_out() {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        // Advertise 1 MiB, send a few bytes, then stall with the stream open
+        tx.write_all(&(1024 * 1024u32).to_be_bytes()).await.unwrap();
+        tx.write_all(b"partial").await.unwrap();
+
+        let err = recv_message_timeout(&mut rx, Some(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Sender stalled mid-message: 7 of 1048576 bytes received"),
+            "{err}"
+        );
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn slow_sender_within_timeout_is_read_in_full() {
+        let payload: Vec<u8> = (0..=255).cycle().take(3 * RECV_CHUNK_BYTES + 5).collect();
+        let (mut tx, mut rx) = tokio::io::duplex(4096);
+        let expected = payload.clone();
+        let sender = tokio::spawn(async move {
+            tx.write_all(&u32::try_from(payload.len()).unwrap().to_be_bytes())
+                .await
+                .unwrap();
+            for chunk in payload.chunks(50_000) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                tx.write_all(chunk).await.unwrap();
+            }
+        });
+
+        let received = recv_message_timeout(&mut rx, Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(received, expected);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_length_rejected_before_reading_body() {
+        let (mut tx, mut rx) = tokio::io::duplex(64);
+        // Nothing follows the header; only the length check can end this
+        tx.write_all(&(MAX_MESSAGE_SIZE + 1).to_be_bytes())
+            .await
+            .unwrap();
+
+        let err = recv_message_timeout(&mut rx, Some(Duration::from_secs(5)))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("Message exceeds max size"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_unexpected_eof() {
+        let mut reader: &[u8] = b"\x00\x00\x00\x10{}";
+        let err = recv_message(&mut reader).await.unwrap_err();
+        let io = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     #[tokio::test]
     async fn empty_payload() {
         let mut buf = Vec::new();
@@ -112,6 +427,88 @@ mod tests {
         assert!(json.contains("\"interpreter\":\"python\""));
     }
 
+    #[tokio::test]
+    async fn protocol_serialize_cancel() {
+        let req = AgentRequest::Cancel {
+            id: "abc".to_string(),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"type":"cancel","id":"abc"}"#);
+
+        let parsed: AgentRequest = serde_json::from_str(&json).unwrap();
+        assert!(matches!(parsed, AgentRequest::Cancel { id } if id == "abc"));
+    }
+
+    #[tokio::test]
+    async fn protocol_serialize_reset() {
+        let req = AgentRequest::Reset {
+            id: "r1".to_string(),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"type":"reset","id":"r1"}"#);
+
+        let resp: AgentResponse =
+            serde_json::from_str(r#"{"type":"reset_done","id":"r1"}"#).unwrap();
+        assert!(matches!(resp, AgentResponse::ResetDone { id } if id == "r1"));
+
+        // Agents announce support; older ones simply don't say
+        let caps: Capabilities = serde_json::from_str(r#"{"reset":true}"#).unwrap();
+        assert!(caps.reset);
+        let caps: Capabilities = serde_json::from_str(r#"{"protocol_version":2}"#).unwrap();
+        assert!(!caps.reset);
+    }
+
+    #[tokio::test]
+    async fn protocol_serialize_session_env() {
+        let req = AgentRequest::SetEnv {
+            id: "r1".to_string(),
+            key: "FEATURE_X".to_string(),
+            value: "on".to_string(),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"set_env","id":"r1","key":"FEATURE_X","value":"on"}"#
+        );
+
+        let req = AgentRequest::GetEnv {
+            id: "r2".to_string(),
+            key: "FEATURE_X".to_string(),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"type":"get_env","id":"r2","key":"FEATURE_X"}"#);
+
+        let json = r#"{"type":"env_value","id":"r2","key":"FEATURE_X","value":null}"#;
+        let resp: AgentResponse = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            resp,
+            AgentResponse::EnvValue { key, value: None, .. } if key == "FEATURE_X"
+        ));
+    }
+
+    #[tokio::test]
+    async fn protocol_serialize_list_interpreters() {
+        let json = serde_json::to_string(&AgentRequest::ListInterpreters).unwrap();
+        assert_eq!(json, r#"{"type":"list_interpreters"}"#);
+
+        let json = r#"{"type":"interpreters","names":["python","bash"]}"#;
+        let resp: AgentResponse = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            resp,
+            AgentResponse::Interpreters { names } if names == ["python", "bash"]
+        ));
+
+        let json =
+            r#"{"type":"ready","capabilities":{"protocol_version":2,"list_interpreters":true}}"#;
+        let AgentResponse::Ready {
+            capabilities: Some(caps),
+        } = serde_json::from_str(json).unwrap()
+        else {
+            panic!("expected Ready with capabilities");
+        };
+        assert!(caps.list_interpreters);
+    }
+
     #[tokio::test]
     async fn protocol_serialize_response() {
         let resp = AgentResponse::Result {
@@ -125,10 +522,139 @@ mod tests {
         assert!(json.contains("\"exit_code\":0"));
     }
 
+    #[tokio::test]
+    async fn protocol_serialize_execute_batch() {
+        let req = AgentRequest::ExecuteBatch {
+            id: "1".to_string(),
+            interpreter: "python".to_string(),
+            fragments: vec!["x = 1".to_string(), "print(x)".to_string()],
+            stop_on_error: true,
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"execute_batch","id":"1","interpreter":"python","fragments":["x = 1","print(x)"],"stop_on_error":true}"#
+        );
+
+        let json = r#"{"type":"batch_result","id":"1","results":[
+            {"stdout":"","stderr":"","exit_code":0},
+            {"stdout":"1\n","stderr":"","exit_code":0}]}"#;
+        let resp: AgentResponse = serde_json::from_str(json).unwrap();
+        let AgentResponse::BatchResult { id, results } = resp else {
+            panic!("expected BatchResult, got {resp:?}");
+        };
+        assert_eq!(id, "1");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].stdout, "1\n");
+    }
+
     #[tokio::test]
     async fn protocol_deserialize_ready() {
         let json = r#"{"type":"ready"}"#;
         let resp: AgentResponse = serde_json::from_str(json).unwrap();
-        assert!(matches!(resp, AgentResponse::Ready));
+        assert!(matches!(resp, AgentResponse::Ready { capabilities: None }));
+    }
+
+    #[tokio::test]
+    async fn protocol_deserialize_ready_with_capabilities() {
+        let json = r#"{"type":"ready","capabilities":{"protocol_version":1,"interpreters":["python","bash"]}}"#;
+        let resp: AgentResponse = serde_json::from_str(json).unwrap();
+        let AgentResponse::Ready {
+            capabilities: Some(caps),
+        } = resp
+        else {
+            panic!("expected Ready with capabilities, got {resp:?}");
+        };
+        assert_eq!(caps.protocol_version, 1);
+        assert_eq!(caps.interpreters, ["python", "bash"]);
+
+        // Unknown fields and missing ones are tolerated
+        let json = r#"{"type":"ready","capabilities":{"streaming":true}}"#;
+        let resp: AgentResponse = serde_json::from_str(json).unwrap();
+        let AgentResponse::Ready {
+            capabilities: Some(caps),
+        } = resp
+        else {
+            panic!("expected Ready with capabilities, got {resp:?}");
+        };
+        assert_eq!(caps, Capabilities::default());
+    }
+
+    /// Run `wait_ready` on a framed `Ready` message.
+    async fn ready_from(json: &str) -> Result<(Option<Capabilities>, u32)> {
+        let mut buf = Vec::new();
+        send_message(&mut buf, json.as_bytes()).await.unwrap();
+        wait_ready(&mut buf.as_slice()).await
+    }
+
+    #[tokio::test]
+    async fn ready_matching_protocol() {
+        let (caps, version) =
+            ready_from(r#"{"type":"ready","capabilities":{"protocol_version":2}}"#)
+                .await
+                .unwrap();
+        assert_eq!(caps.unwrap().protocol_version, SUPPORTED_PROTOCOL);
+        assert_eq!(version, SUPPORTED_PROTOCOL);
+    }
+
+    #[tokio::test]
+    async fn ready_older_compatible_protocol() {
+        // A bare Ready is protocol v0: no capabilities, still usable
+        assert_eq!(ready_from(r#"{"type":"ready"}"#).await.unwrap(), (None, 0));
+        assert_eq!(negotiate_protocol(None).unwrap(), 0);
+
+        // v1 agents predate batches but are otherwise fine
+        let caps = Capabilities {
+            protocol_version: 1,
+            ..Capabilities::default()
+        };
+        assert_eq!(negotiate_protocol(Some(&caps)).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn ready_incompatible_protocol() {
+        let err = ready_from(r#"{"type":"ready","capabilities":{"protocol_version":3}}"#)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Agent protocol v3, daemon expects v2."),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn ready_newer_protocol_negotiates_down() {
+        let caps = Capabilities {
+            protocol_version: 4,
+            min_protocol_version: Some(1),
+            ..Capabilities::default()
+        };
+        assert_eq!(negotiate_protocol(Some(&caps)).unwrap(), 2);
+
+        // The agent is told to speak down; one already at v2 isn't told
+        let mut buf = Vec::new();
+        use_protocol(&mut buf, Some(&caps), 2).await.unwrap();
+        let msg = recv_message(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(msg, br#"{"type":"use_protocol","version":2}"#);
+        let current = Capabilities {
+            protocol_version: 2,
+            ..Capabilities::default()
+        };
+        let mut buf = Vec::new();
+        use_protocol(&mut buf, Some(&current), 2).await.unwrap();
+        assert!(buf.is_empty());
+
+        let caps = Capabilities {
+            min_protocol_version: Some(3),
+            ..caps
+        };
+        assert!(negotiate_protocol(Some(&caps)).is_err());
+    }
+
+    #[tokio::test]
+    async fn protocol_serialize_bare_ready() {
+        let json = serde_json::to_string(&AgentResponse::Ready { capabilities: None }).unwrap();
+        assert_eq!(json, r#"{"type":"ready"}"#);
     }
 }
@@ -1,10 +1,25 @@
 //! Agent protocol message types.
 //!
 //! Length-prefixed JSON protocol for daemon ↔ agent communication.
-//! Messages are framed as: [4-byte BE length][JSON payload]
+//! Messages are framed as: [4-byte BE length][JSON payload], with the
+//! length's high bit set when the payload is gzip-compressed.
+//!
+//! Agents announce their protocol version in `Ready`; see
+//! [`negotiate_protocol`] for which versions the daemon accepts.
 
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+/// Newest agent protocol version this daemon speaks.
+pub const SUPPORTED_PROTOCOL: u32 = 2;
+
+/// First protocol version with `ExecuteBatch`.
+pub const BATCH_PROTOCOL: u32 = 2;
+
+/// Oldest agent protocol version this daemon still speaks. Version 0 is an
+/// agent that sends a bare `Ready` without capabilities.
+pub const MIN_SUPPORTED_PROTOCOL: u32 = 0;
+
 /// Request sent from daemon to agent.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -15,10 +30,136 @@ pub enum AgentRequest {
         interpreter: String,
         code: String,
     },
+    /// Execute code fragments in order, in one round-trip (protocol v2+).
+    ///
+    /// With `stop_on_error`, fragments after the first nonzero exit are
+    /// skipped. Answered by a single `BatchResult`.
+    ExecuteBatch {
+        id: String,
+        interpreter: String,
+        fragments: Vec<String>,
+        stop_on_error: bool,
+    },
+    /// Interrupt the in-flight execution with the given id.
+    ///
+    /// Sent out-of-band while an `Execute` is awaiting its result. The agent
+    /// sends no response of its own — the interrupted `Execute` replies.
+    Cancel { id: String },
+    /// Clear interpreter state (globals, imports) without restarting the
+    /// agent; files in /workspace are kept.
+    ///
+    /// Sent only to agents announcing `reset`. Answered by `ResetDone`, or
+    /// `Error` if the agent couldn't reset.
+    Reset { id: String },
+    /// Set an environment variable for all later executions in the session.
+    ///
+    /// Sent only to agents announcing `session_env`. Answered by `EnvValue`
+    /// with the new value, or `Error`.
+    SetEnv {
+        id: String,
+        key: String,
+        value: String,
+    },
+    /// Read an environment variable as the agent sees it (`session_env`).
+    /// Answered by `EnvValue`.
+    GetEnv { id: String, key: String },
+    /// Ask which interpreters the agent can run.
+    ///
+    /// Sent once after `Ready`, only to agents announcing
+    /// `list_interpreters`. Answered by `Interpreters`.
+    ListInterpreters,
     /// Graceful shutdown.
     Shutdown,
     /// Health check.
     Ping,
+    /// Allow the agent to gzip large responses.
+    ///
+    /// Sent once after `Ready`, only to agents announcing `gzip`. No response.
+    EnableCompression,
+    /// Tell an agent announcing a newer protocol than the daemon's which
+    /// version the two settled on (see [`negotiate_protocol`]).
+    ///
+    /// Sent once after `Ready`, only when that differs from the agent's
+    /// `protocol_version`. No response.
+    UseProtocol { version: u32 },
+}
+
+impl AgentRequest {
+    /// The id the agent's reply must carry, for requests that have one.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Self::Execute { id, .. }
+            | Self::ExecuteBatch { id, .. }
+            | Self::Cancel { id }
+            | Self::Reset { id }
+            | Self::SetEnv { id, .. }
+            | Self::GetEnv { id, .. } => Some(id),
+            Self::ListInterpreters
+            | Self::Shutdown
+            | Self::Ping
+            | Self::EnableCompression
+            | Self::UseProtocol { .. } => None,
+        }
+    }
+}
+
+/// What an agent supports, announced in its `Ready` message.
+// Each flag is an independent feature, a plain JSON boolean on the wire
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Agent protocol version.
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// Oldest version the agent can still speak, if it can speak down to
+    /// an older daemon. Absent means only `protocol_version`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_protocol_version: Option<u32>,
+    /// Interpreter names the agent can run.
+    #[serde(default)]
+    pub interpreters: Vec<String>,
+    /// Whether the agent accepts (and, once enabled, sends) gzip frames.
+    #[serde(default)]
+    pub gzip: bool,
+    /// Whether the agent accepts `Reset`.
+    #[serde(default)]
+    pub reset: bool,
+    /// Whether the agent accepts `SetEnv` and `GetEnv`.
+    #[serde(default)]
+    pub session_env: bool,
+    /// Whether the agent answers `ListInterpreters`.
+    #[serde(default)]
+    pub list_interpreters: bool,
+}
+
+/// Pick the protocol version to speak with an agent, from its `Ready`.
+///
+/// Older agents within `MIN_SUPPORTED_PROTOCOL` are spoken to at their own
+/// version. A newer agent is accepted only if its `min_protocol_version`
+/// reaches down to `SUPPORTED_PROTOCOL`, which is then used.
+pub fn negotiate_protocol(capabilities: Option<&Capabilities>) -> Result<u32> {
+    let agent = capabilities.map_or(0, |c| c.protocol_version);
+    let agent_min = capabilities
+        .and_then(|c| c.min_protocol_version)
+        .unwrap_or(agent);
+
+    // Always true while v0 is supported; kept for when it no longer is
+    #[allow(clippy::absurd_extreme_comparisons)]
+    let too_old = agent < MIN_SUPPORTED_PROTOCOL;
+    anyhow::ensure!(
+        !too_old,
+        "Agent protocol v{agent} is too old, daemon expects v{MIN_SUPPORTED_PROTOCOL} \
+         to v{SUPPORTED_PROTOCOL}. Rebuild the sandbox to update its agent."
+    );
+    if agent <= SUPPORTED_PROTOCOL {
+        return Ok(agent);
+    }
+    anyhow::ensure!(
+        agent_min <= SUPPORTED_PROTOCOL,
+        "Agent protocol v{agent}, daemon expects v{SUPPORTED_PROTOCOL}. \
+         Update the daemon or rebuild the sandbox with a matching agent."
+    );
+    Ok(SUPPORTED_PROTOCOL)
 }
 
 /// Response sent from agent to daemon.
@@ -26,7 +167,12 @@ pub enum AgentRequest {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum AgentResponse {
     /// Agent is ready to accept requests (sent on startup).
-    Ready,
+    ///
+    /// Older agents send a bare `{"type":"ready"}` with no capabilities.
+    Ready {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Capabilities>,
+    },
     /// Execution result.
     Result {
         id: String,
@@ -34,8 +180,46 @@ pub enum AgentResponse {
         stderr: String,
         exit_code: i32,
     },
+    /// Results of an `ExecuteBatch`, one per fragment that ran.
+    BatchResult {
+        id: String,
+        results: Vec<FragmentResult>,
+    },
+    /// The `Reset` with this id cleared the interpreter state.
+    ResetDone { id: String },
+    /// Value of `key` after a `SetEnv` or for a `GetEnv`; `None` if unset.
+    EnvValue {
+        id: String,
+        key: String,
+        value: Option<String>,
+    },
+    /// Interpreter names the agent can run, for `ListInterpreters`.
+    Interpreters { names: Vec<String> },
     /// Pong response to health check.
     Pong,
     /// Error response.
     Error { message: String },
 }
+
+impl AgentResponse {
+    /// The id of the request this answers, for replies that carry one.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Self::Result { id, .. }
+            | Self::BatchResult { id, .. }
+            | Self::ResetDone { id }
+            | Self::EnvValue { id, .. } => Some(id),
+            Self::Ready { .. } | Self::Interpreters { .. } | Self::Pong | Self::Error { .. } => {
+                None
+            }
+        }
+    }
+}
+
+/// Outcome of one fragment of an `ExecuteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FragmentResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
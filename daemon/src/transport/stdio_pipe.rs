@@ -3,30 +3,75 @@
 //! Owns a child process, communicates via length-prefixed JSON on
 //! the child's stdin (requests) and stdout (responses).
 //! Mutex-guarded for safe concurrent access from multiple MCP calls.
+//!
+//! A request holds `request_lock` (and stdout) for its whole round-trip but
+//! only holds stdin while writing. That leaves stdin free for control
+//! messages like `Cancel` to be written while the agent is still executing.
+//!
+//! A round-trip can be bounded by a protocol-level deadline (separate from
+//! the execution timeout): an agent that doesn't answer in time is marked
+//! dead, so the session layer replaces it instead of every caller hanging.
+//!
+//! If the agent's stdout closes partway through a response (it crashed after
+//! reading the request), the transport is marked dead and the request fails
+//! with a distinct "terminated mid-response" error.
+//!
+//! If the agent fails before it's ready, whatever it wrote to stderr (such as
+//! a Python traceback) is appended to the spawn error.
 
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
 use tokio::process::{Child, ChildStdin, ChildStdout};
 use tokio::sync::Mutex;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
+
+use super::protocol::{AgentRequest, AgentResponse, Capabilities};
+use super::{
+    enable_compression, recv_message_timeout, send_frame, use_protocol, wait_ready, Transport,
+    RESPONSE_STALL_TIMEOUT,
+};
+use crate::backend::ExecError;
+use crate::config::SandboxDepth;
+
+/// Most agent stderr kept in a spawn error; the end is kept, where
+/// tracebacks put the actual error.
+const MAX_SPAWN_STDERR: usize = 4096;
+
+/// How long to wait for a failed agent's stderr to reach EOF.
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
-use super::protocol::{AgentRequest, AgentResponse};
-use super::{recv_message, send_message, Transport};
+/// How long an agent that took `Shutdown` gets to exit before it's killed.
+const SHUTDOWN_EXIT_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Transport that communicates with a jailed agent via stdin/stdout pipes.
 ///
 /// The agent process is spawned once and kept alive for the session lifetime.
-/// Each `request()` call acquires both stdin and stdout mutexes to ensure
-/// atomic send/receive (no interleaving from concurrent callers).
+/// Each `request()` call acquires `request_lock` to ensure atomic
+/// send/receive (no interleaving from concurrent callers).
 pub struct StdioPipeTransport {
     child: Mutex<Child>,
-    stdin: Mutex<ChildStdin>,
+    request_lock: Mutex<()>,
+    /// `None` once shutdown has closed it.
+    stdin: Mutex<Option<ChildStdin>>,
     stdout: Mutex<ChildStdout>,
     alive: AtomicBool,
+    capabilities: Option<Capabilities>,
+    /// Protocol version agreed in the handshake.
+    protocol: u32,
+    /// Whether large requests are gzip-compressed (negotiated in `Ready`).
+    gzip: bool,
+    /// Deadline for one request's send/receive round-trip (`None` = no limit).
+    request_timeout: Option<Duration>,
+    /// Process ID of the agent (the jail wrapper), taken at spawn.
+    pid: Option<u32>,
+    /// How the agent process ended, once `shutdown` has reaped it.
+    last_exit: OnceLock<ExitStatus>,
 }
 
 impl StdioPipeTransport {
@@ -35,122 +80,489 @@ impl StdioPipeTransport {
     /// `exec_path` is the path to the session jail wrapper (which runs the agent).
     /// `ready_timeout` is how long to wait for the agent's Ready message.
     /// `env_vars` is an optional list of extra environment variables to set.
+    /// `depth` is the daemon's own sandbox nesting; the agent runs one deeper.
     pub async fn spawn(
         exec_path: &str,
         ready_timeout: Duration,
         env_vars: &[(String, String)],
+        depth: SandboxDepth,
     ) -> Result<Self> {
         debug!(exec = %exec_path, "Spawning agent process");
+        depth.check()?;
 
         let mut cmd = tokio::process::Command::new(exec_path);
+        // Killed with the transport, so a session dropped while its agent
+        // is busy doesn't leave the agent running
         cmd.stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
 
         for (key, value) in env_vars {
             cmd.env(key, value);
         }
+        let (depth_var, depth_value) = depth.child_var();
+        cmd.env(depth_var, depth_value);
 
         let mut child = cmd
             .spawn()
             .with_context(|| format!("Failed to spawn agent: {exec_path}"))?;
 
-        let stdin = child.stdin.take().context("Failed to take agent stdin")?;
+        let pid = child.id();
+        let mut stdin = child.stdin.take().context("Failed to take agent stdin")?;
         let mut stdout = child.stdout.take().context("Failed to take agent stdout")?;
 
         // Wait for the agent's Ready message
-        let ready_result =
-            tokio::time::timeout(ready_timeout, recv_message(&mut stdout)).await;
-
-        let ready_bytes = ready_result
-            .map_err(|_| anyhow::anyhow!("Agent did not send Ready within {ready_timeout:?}"))?
-            .context("Failed to read agent Ready message")?;
-
-        let ready_msg: AgentResponse = serde_json::from_slice(&ready_bytes)
-            .context("Failed to parse agent Ready message")?;
+        let ready = async {
+            let (capabilities, protocol) =
+                tokio::time::timeout(ready_timeout, wait_ready(&mut stdout))
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!("Agent did not send Ready within {ready_timeout:?}")
+                    })??;
+            debug!(?capabilities, "Agent is ready");
+            use_protocol(&mut stdin, capabilities.as_ref(), protocol).await?;
+            let gzip = enable_compression(&mut stdin, capabilities.as_ref()).await?;
+            Ok::<_, anyhow::Error>((capabilities, protocol, gzip))
+        }
+        .await;
 
-        match ready_msg {
-            AgentResponse::Ready => {
-                debug!("Agent is ready");
-            }
-            other => {
-                anyhow::bail!("Expected Ready message, got: {other:?}");
+        let (capabilities, protocol, gzip) = match ready {
+            Ok(ready) => ready,
+            Err(e) => {
+                let _ = child.start_kill();
+                let stderr = drain_stderr(&mut child).await;
+                if stderr.is_empty() {
+                    return Err(e);
+                }
+                anyhow::bail!("{e:#}\n--- agent stderr ---\n{stderr}");
             }
-        }
+        };
 
         Ok(Self {
             child: Mutex::new(child),
-            stdin: Mutex::new(stdin),
+            request_lock: Mutex::new(()),
+            stdin: Mutex::new(Some(stdin)),
             stdout: Mutex::new(stdout),
             alive: AtomicBool::new(true),
+            capabilities,
+            protocol,
+            gzip,
+            request_timeout: None,
+            pid,
+            last_exit: OnceLock::new(),
         })
     }
+
+    /// Bound each request round-trip by `timeout`.
+    ///
+    /// Must be longer than any execution the agent is asked to run, or
+    /// legitimate long executions are cut off as a hung agent.
+    #[must_use]
+    pub const fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Write one frame to the agent's stdin, failing once shutdown closed it.
+    async fn write_frame(&self, bytes: &[u8]) -> Result<()> {
+        let mut guard = self.stdin.lock().await;
+        let stdin = guard.as_mut().context("Agent stdin is closed")?;
+        let sent = send_frame(stdin, bytes, self.gzip).await;
+        drop(guard);
+        sent
+    }
+}
+
+/// Read what a failed agent wrote to stderr, keeping the last
+/// `MAX_SPAWN_STDERR` bytes. Best-effort: gives up after a short wait.
+async fn drain_stderr(child: &mut Child) -> String {
+    let Some(mut stderr) = child.stderr.take() else {
+        return String::new();
+    };
+    let mut buf = Vec::new();
+    let _ = tokio::time::timeout(STDERR_DRAIN_TIMEOUT, stderr.read_to_end(&mut buf)).await;
+
+    let text = String::from_utf8_lossy(&buf);
+    let text = text.trim_end();
+    let mut start = text.len().saturating_sub(MAX_SPAWN_STDERR);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    text[start..].to_string()
+}
+
+/// Read one response frame from the agent's stdout.
+///
+/// EOF before a full frame means the agent is gone, and an agent stalled
+/// mid-frame (see `RESPONSE_STALL_TIMEOUT`) can't be trusted either: `alive`
+/// is cleared so the session layer replaces it rather than reusing a
+/// half-read pipe.
+async fn recv_response<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    alive: &AtomicBool,
+) -> Result<Vec<u8>, ExecError> {
+    recv_message_timeout(reader, Some(RESPONSE_STALL_TIMEOUT))
+        .await
+        .map_err(|e| {
+            alive.store(false, Ordering::Relaxed);
+            let eof = e
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof);
+            if eof {
+                ExecError::IoError(anyhow::anyhow!("Agent terminated mid-response"))
+            } else {
+                ExecError::IoError(e.context("Failed to read response from agent"))
+            }
+        })
 }
 
 #[async_trait]
 impl Transport for StdioPipeTransport {
     async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+        let not_alive = || ExecError::IoError(anyhow::anyhow!("Agent process is not alive"));
         if !self.alive.load(Ordering::Relaxed) {
-            anyhow::bail!("Agent process is not alive");
+            return Err(not_alive().into());
         }
 
-        // Acquire both locks for atomic send/receive
-        let mut stdin = self.stdin.lock().await;
+        // Serialize whole round-trips; stdin is only held while writing so
+        // `send_control` can reach the agent mid-request
+        let _request_guard = self.request_lock.lock().await;
+        // A caller ahead of us may have timed out on a hung agent
+        if !self.alive.load(Ordering::Relaxed) {
+            return Err(not_alive().into());
+        }
         let mut stdout = self.stdout.lock().await;
 
         let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
 
-        let io_result: Result<AgentResponse> = async {
-            send_message(&mut *stdin, &req_bytes)
+        let round_trip = async {
+            self.write_frame(&req_bytes)
                 .await
-                .context("Failed to send request to agent")?;
+                .context("Failed to send request to agent")
+                .map_err(ExecError::IoError)?;
 
-            let resp_bytes = recv_message(&mut *stdout)
-                .await
-                .context("Failed to read response from agent")?;
-
-            serde_json::from_slice(&resp_bytes).context("Failed to parse agent response")
-        }
-        .await;
+            recv_response(&mut *stdout, &self.alive).await
+        };
+        let io_result: Result<Vec<u8>, ExecError> = match self.request_timeout {
+            Some(timeout) => {
+                let Ok(result) = tokio::time::timeout(timeout, round_trip).await else {
+                    // The pipes may hold half a frame now; the agent can't be reused
+                    self.alive.store(false, Ordering::Relaxed);
+                    warn!(?timeout, "Agent did not respond in time, killing it");
+                    let _ = self.child.lock().await.start_kill();
+                    return Err(ExecError::Timeout(anyhow::anyhow!(
+                        "Agent did not respond within {timeout:?}"
+                    ))
+                    .into());
+                };
+                result
+            }
+            None => round_trip.await,
+        };
 
-        if io_result.is_err() {
-            // Check if the agent process died
+        // An EOF mid-response already marked the agent dead; for other
+        // failures, check whether the agent process died
+        if io_result.is_err() && self.alive.load(Ordering::Relaxed) {
             let mut child = self.child.lock().await;
             if let Ok(Some(status)) = child.try_wait() {
                 self.alive.store(false, Ordering::Relaxed);
-                anyhow::bail!("Agent process exited unexpectedly (status: {status})");
+                return Err(ExecError::IoError(anyhow::anyhow!(
+                    "Agent process exited unexpectedly (status: {status})"
+                ))
+                .into());
             }
         }
 
-        io_result
+        let resp_bytes = io_result?;
+        serde_json::from_slice(&resp_bytes)
+            .context("Failed to parse agent response")
+            .map_err(|e| ExecError::ProtocolError(e).into())
     }
 
-    async fn shutdown(&self) -> Result<()> {
+    async fn send_control(&self, req: &AgentRequest) -> Result<()> {
         if !self.alive.load(Ordering::Relaxed) {
-            return Ok(());
+            anyhow::bail!("Agent process is not alive");
         }
 
-        // Try graceful shutdown first
-        let shutdown_result = self
-            .request(&AgentRequest::Shutdown)
-            .await;
+        let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
+        self.write_frame(&req_bytes)
+            .await
+            .context("Failed to send control message to agent")
+    }
 
-        if let Err(e) = shutdown_result {
-            warn!(error = %e, "Graceful shutdown failed, killing agent");
+    async fn shutdown(&self) -> Result<()> {
+        if self.last_exit.get().is_some() {
+            return Ok(());
         }
 
+        // Try graceful shutdown first; an agent already dead is just reaped.
+        // The agent exits on Shutdown without replying, so don't wait for one
+        let graceful = self.alive.load(Ordering::Relaxed)
+            && match self.send_control(&AgentRequest::Shutdown).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(error = %e, "Failed to send shutdown, killing agent");
+                    false
+                }
+            };
+        // EOF too, for an agent that stops reading there rather than at Shutdown
+        drop(self.stdin.lock().await.take());
         self.alive.store(false, Ordering::Relaxed);
 
-        // Kill the process to ensure cleanup
-        let mut child = self.child.lock().await;
-        let _ = child.kill().await;
-        let _ = child.wait().await;
-
-        debug!("Agent process shut down");
+        // Let a cooperating agent exit by itself, so its status is its own;
+        // kill anything left to ensure cleanup
+        let reaped = {
+            let mut child = self.child.lock().await;
+            let exited = graceful
+                && tokio::time::timeout(SHUTDOWN_EXIT_TIMEOUT, child.wait())
+                    .await
+                    .is_ok();
+            if !exited {
+                let _ = child.kill().await;
+            }
+            child.wait().await
+        };
+        match reaped {
+            Ok(status) if status.success() => {
+                debug!(%status, "Agent process shut down");
+                let _ = self.last_exit.set(status);
+            }
+            Ok(status) => {
+                info!(%status, "Agent process ended abnormally");
+                let _ = self.last_exit.set(status);
+            }
+            Err(e) => warn!(error = %e, "Failed to reap agent process"),
+        }
         Ok(())
     }
 
     fn is_alive(&self) -> bool {
         self.alive.load(Ordering::Relaxed)
     }
+
+    fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    fn protocol_version(&self) -> u32 {
+        self.protocol
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    fn last_exit(&self) -> Option<ExitStatus> {
+        self.last_exit.get().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write an executable shell script standing in for the agent wrapper.
+    fn fake_agent(dir: &std::path::Path, script: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("agent");
+        std::fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn spawn_error_includes_agent_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = fake_agent(
+            dir.path(),
+            "echo 'Traceback (most recent call last):' >&2\n\
+             echo 'ModuleNotFoundError: No module named sandbox' >&2\n\
+             exit 1\n",
+        );
+
+        let err =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .err()
+                .expect("spawn should fail");
+        let msg = format!("{err:#}");
+        assert!(msg.contains("--- agent stderr ---"), "{msg}");
+        assert!(
+            msg.contains("ModuleNotFoundError: No module named sandbox"),
+            "{msg}"
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_timeout_includes_agent_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = fake_agent(dir.path(), "echo 'still importing' >&2\nexec sleep 30\n");
+
+        let err = StdioPipeTransport::spawn(
+            &exec,
+            Duration::from_millis(200),
+            &[],
+            SandboxDepth::default(),
+        )
+        .await
+        .err()
+        .expect("spawn should time out");
+        let msg = format!("{err:#}");
+        assert!(msg.starts_with("Agent did not send Ready within"), "{msg}");
+        assert!(msg.contains("still importing"), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn request_times_out_on_silent_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        // Sends Ready, then never answers
+        let exec = fake_agent(
+            dir.path(),
+            "printf '\\000\\000\\000\\020{\"type\":\"ready\"}'\nexec sleep 30\n",
+        );
+        let transport =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .unwrap()
+                .with_request_timeout(Some(Duration::from_millis(200)));
+        assert!(transport.is_alive());
+
+        let err = transport.request(&AgentRequest::Ping).await.unwrap_err();
+        assert!(
+            err.to_string().starts_with("Agent did not respond within"),
+            "{err:#}"
+        );
+        assert!(matches!(ExecError::find(&err), Some(ExecError::Timeout(_))));
+        assert!(!transport.is_alive());
+
+        // Later callers fail fast instead of queueing behind the dead agent
+        let err = transport.request(&AgentRequest::Ping).await.unwrap_err();
+        assert_eq!(err.to_string(), "Agent process is not alive");
+        assert!(matches!(ExecError::find(&err), Some(ExecError::IoError(_))));
+        transport.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_captures_agent_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        // Sends Ready, then exits with 3 on the first request it reads
+        let exec = fake_agent(
+            dir.path(),
+            "printf '\\000\\000\\000\\020{\"type\":\"ready\"}'\nhead -c 4 >/dev/null\nexit 3\n",
+        );
+        let transport =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .unwrap();
+        assert_eq!(transport.last_exit(), None);
+
+        transport.shutdown().await.unwrap();
+        assert_eq!(transport.last_exit().unwrap().code(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_agent_exit_cleanly() {
+        let dir = tempfile::tempdir().unwrap();
+        // Sends Ready, then exits 0 once it reads Shutdown, without replying
+        let exec = fake_agent(
+            dir.path(),
+            "printf '\\000\\000\\000\\020{\"type\":\"ready\"}'\n\
+             head -c 23 | grep -q '\"shutdown\"' && exit 0\nexit 5\n",
+        );
+        let transport =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .unwrap();
+
+        let started = std::time::Instant::now();
+        transport.shutdown().await.unwrap();
+        assert_eq!(transport.last_exit().unwrap().code(), Some(0));
+        assert!(started.elapsed() < SHUTDOWN_EXIT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn shutdown_captures_kill_signal() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Sends Ready, then never answers
+        let exec = fake_agent(
+            dir.path(),
+            "printf '\\000\\000\\000\\020{\"type\":\"ready\"}'\nexec sleep 30\n",
+        );
+        let transport =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .unwrap()
+                .with_request_timeout(Some(Duration::from_millis(200)));
+
+        transport.shutdown().await.unwrap();
+        let status = transport.last_exit().unwrap();
+        assert_eq!(status.signal(), Some(9), "{status}");
+    }
+
+    #[tokio::test]
+    async fn eof_mid_response_marks_agent_dead() {
+        // A length prefix promising 16 bytes, then only 5 before EOF
+        let mut reader: &[u8] = b"\x00\x00\x00\x10{\"typ";
+        let alive = AtomicBool::new(true);
+
+        let err = recv_response(&mut reader, &alive).await.unwrap_err();
+        assert!(matches!(err, ExecError::IoError(_)), "{err}");
+        assert_eq!(err.to_string(), "Agent terminated mid-response");
+        assert!(!alive.load(Ordering::Relaxed));
+
+        // A complete frame leaves the agent alive
+        let alive = AtomicBool::new(true);
+        let mut reader: &[u8] = b"\x00\x00\x00\x02{}";
+        assert_eq!(recv_response(&mut reader, &alive).await.unwrap(), b"{}");
+        assert!(alive.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stall_mid_response_marks_agent_dead() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut tx, mut rx) = tokio::io::duplex(64);
+        tx.write_all(b"\x00\x00\x00\x10{\"typ").await.unwrap();
+        let alive = AtomicBool::new(true);
+
+        let err = recv_response(&mut rx, &alive).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("stalled mid-message"),
+            "{err:#}"
+        );
+        assert!(!alive.load(Ordering::Relaxed));
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn spawn_refused_at_max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("spawned");
+        let exec = fake_agent(dir.path(), &format!("touch {}\n", marker.display()));
+
+        let depth = SandboxDepth::new(Some("3"), 3);
+        let err = StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], depth)
+            .await
+            .err()
+            .expect("spawn should be refused");
+        assert!(err.to_string().contains("max_sandbox_depth is 3"), "{err}");
+        assert!(!marker.exists());
+    }
+
+    #[tokio::test]
+    async fn spawn_error_without_stderr_is_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = fake_agent(dir.path(), "exit 1\n");
+
+        let err =
+            StdioPipeTransport::spawn(&exec, Duration::from_secs(5), &[], SandboxDepth::default())
+                .await
+                .err()
+                .expect("spawn should fail");
+        assert!(!format!("{err:#}").contains("agent stderr"));
+    }
 }
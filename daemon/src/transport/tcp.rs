@@ -0,0 +1,410 @@
+//! TCP transport for agents running on another host.
+//!
+//! Connects to an agent listening on `host:port`, optionally over TLS, and
+//! speaks the same length-prefixed JSON protocol as the other transports.
+//! TLS verifies the agent's certificate against the configured CA file, or
+//! the built-in web roots when none is given. With a `token_file`, the
+//! daemon authenticates first, sending the agent its token before `Ready`.
+//!
+//! Locking mirrors `VsockTransport`: a request holds `request_lock` (and the
+//! read half) for its round-trip, the write half only while writing, so
+//! `send_control` can reach a busy agent.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+use super::protocol::{AgentRequest, AgentResponse, Capabilities};
+use super::{
+    enable_compression, recv_message_timeout, send_frame, send_message, use_protocol, wait_ready,
+    Transport, RESPONSE_STALL_TIMEOUT,
+};
+use crate::backend::ExecError;
+use crate::config::TcpAddr;
+
+/// A connected byte stream to the agent: plain TCP or TLS.
+trait AgentStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AgentStream for S {}
+
+type Stream = Box<dyn AgentStream>;
+
+/// Transport that communicates with a remote agent over TCP.
+///
+/// The agent is managed outside the daemon; dropping or shutting down the
+/// transport only closes the connection.
+pub struct TcpTransport {
+    request_lock: Mutex<()>,
+    writer: Mutex<WriteHalf<Stream>>,
+    reader: Mutex<ReadHalf<Stream>>,
+    alive: AtomicBool,
+    capabilities: Option<Capabilities>,
+    /// Protocol version agreed in the handshake.
+    protocol: u32,
+    /// Whether large requests are gzip-compressed (negotiated in `Ready`).
+    gzip: bool,
+}
+
+impl TcpTransport {
+    /// Connect to the agent at `addr` and wait for its `Ready` message.
+    ///
+    /// `ready_timeout` bounds the connect, TLS handshake, and `Ready` together.
+    pub async fn connect(addr: &TcpAddr, ready_timeout: Duration) -> Result<Self> {
+        debug!(host = %addr.host, port = addr.port, tls = addr.tls, "Connecting to TCP agent");
+
+        let token = match &addr.token_file {
+            Some(path) => Some(read_token(path).await?),
+            None => None,
+        };
+        tokio::time::timeout(ready_timeout, async {
+            let stream = connect_stream(addr).await?;
+            Self::handshake(stream, token.as_deref()).await
+        })
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Agent at {}:{} not ready within {ready_timeout:?}",
+                addr.host,
+                addr.port
+            )
+        })?
+    }
+
+    /// Authenticate with `token`, if any, then wait for `Ready` on an
+    /// already-connected stream.
+    async fn handshake(stream: Stream, token: Option<&str>) -> Result<Self> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        if let Some(token) = token {
+            // Built by hand: `AgentRequest` derives Debug, and this mustn't be logged
+            let auth = serde_json::json!({ "type": "auth", "token": token });
+            send_message(&mut writer, auth.to_string().as_bytes())
+                .await
+                .context("Failed to authenticate with agent")?;
+        }
+        let (capabilities, protocol) = wait_ready(&mut reader).await?;
+        debug!(?capabilities, "Agent is ready");
+        use_protocol(&mut writer, capabilities.as_ref(), protocol).await?;
+        let gzip = enable_compression(&mut writer, capabilities.as_ref()).await?;
+
+        Ok(Self {
+            request_lock: Mutex::new(()),
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            alive: AtomicBool::new(true),
+            capabilities,
+            protocol,
+            gzip,
+        })
+    }
+
+    /// Record a connection error: the agent is unreachable from now on.
+    fn mark_dead<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.alive.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Read the agent token from `path`, ignoring surrounding whitespace.
+async fn read_token(path: &Path) -> Result<String> {
+    let token = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Cannot read agent token file: {}", path.display()))?;
+    let token = token.trim();
+    anyhow::ensure!(
+        !token.is_empty(),
+        "Agent token file is empty: {}",
+        path.display()
+    );
+    Ok(token.to_string())
+}
+
+/// Open a TCP connection to `addr`, wrapped in TLS if it asks for it.
+async fn connect_stream(addr: &TcpAddr) -> Result<Stream> {
+    let tcp = TcpStream::connect((addr.host.as_str(), addr.port))
+        .await
+        .with_context(|| format!("Failed to connect to {}:{}", addr.host, addr.port))?;
+    // Requests are single frames; don't hold them back for coalescing
+    tcp.set_nodelay(true)?;
+    if !addr.tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let connector = tls_connector(addr.ca_cert.as_deref())?;
+    let name = addr.server_name.as_deref().unwrap_or(&addr.host);
+    let server_name = ServerName::try_from(name.to_string())
+        .with_context(|| format!("Invalid TLS server name '{name}'"))?;
+    let tls = connector
+        .connect(server_name, tcp)
+        .await
+        .with_context(|| format!("TLS handshake with {}:{} failed", addr.host, addr.port))?;
+    Ok(Box::new(tls))
+}
+
+/// TLS client trusting the certificates in `ca_cert`, or the web roots.
+fn tls_connector(ca_cert: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    if let Some(path) = ca_cert {
+        let certs = CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Cannot read CA certificate: {}", path.display()))?;
+        for cert in certs {
+            let cert =
+                cert.with_context(|| format!("Invalid CA certificate: {}", path.display()))?;
+            roots.add(cert)?;
+        }
+        anyhow::ensure!(
+            !roots.is_empty(),
+            "No certificates in CA file: {}",
+            path.display()
+        );
+    } else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+        if !self.alive.load(Ordering::Relaxed) {
+            return Err(ExecError::IoError(anyhow::anyhow!("Agent connection is closed")).into());
+        }
+
+        let _request_guard = self.request_lock.lock().await;
+        let mut reader = self.reader.lock().await;
+
+        let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
+
+        let io_result: Result<Vec<u8>> = async {
+            send_frame(&mut *self.writer.lock().await, &req_bytes, self.gzip)
+                .await
+                .context("Failed to send request to agent")?;
+
+            recv_message_timeout(&mut *reader, Some(RESPONSE_STALL_TIMEOUT))
+                .await
+                .context("Failed to read response from agent")
+        }
+        .await;
+        drop(reader);
+
+        let resp_bytes = self.mark_dead(io_result).map_err(ExecError::IoError)?;
+        serde_json::from_slice(&resp_bytes)
+            .context("Failed to parse agent response")
+            .map_err(|e| ExecError::ProtocolError(e).into())
+    }
+
+    async fn send_control(&self, req: &AgentRequest) -> Result<()> {
+        if !self.alive.load(Ordering::Relaxed) {
+            anyhow::bail!("Agent connection is closed");
+        }
+
+        let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
+        let result = send_frame(&mut *self.writer.lock().await, &req_bytes, self.gzip)
+            .await
+            .context("Failed to send control message to agent");
+        self.mark_dead(result)
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        if !self.alive.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // The agent exits on Shutdown without replying, so don't wait for one
+        if let Err(e) = self.send_control(&AgentRequest::Shutdown).await {
+            warn!(error = %e, "Graceful shutdown failed, closing connection");
+        }
+
+        self.alive.store(false, Ordering::Relaxed);
+        let _ = self.writer.lock().await.shutdown().await;
+
+        debug!("TCP agent connection closed");
+        Ok(())
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    fn protocol_version(&self) -> u32 {
+        self.protocol
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::recv_message;
+    use tokio::net::TcpListener;
+
+    /// Accept one connection and play the agent: send `Ready`, then echo
+    /// `Execute` code as stdout and answer pings until `Shutdown` or EOF.
+    async fn fake_agent(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+        send_message(&mut writer, &ready).await.unwrap();
+
+        while let Ok(bytes) = recv_message(&mut reader).await {
+            let resp = match serde_json::from_slice(&bytes).unwrap() {
+                AgentRequest::Execute { id, code, .. } => AgentResponse::Result {
+                    id,
+                    stdout: code,
+                    stderr: String::new(),
+                    exit_code: 0,
+                },
+                AgentRequest::Ping => AgentResponse::Pong,
+                AgentRequest::Shutdown => break,
+                _ => continue,
+            };
+            send_message(&mut writer, &serde_json::to_vec(&resp).unwrap())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn local_agent() -> (TcpAddr, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddr {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            ..TcpAddr::default()
+        };
+        (addr, tokio::spawn(fake_agent(listener)))
+    }
+
+    #[tokio::test]
+    async fn request_roundtrip() {
+        let (addr, agent) = local_agent().await;
+        let transport = TcpTransport::connect(&addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(transport.is_alive());
+
+        let resp = transport
+            .request(&AgentRequest::Execute {
+                id: "1".to_string(),
+                interpreter: "python".to_string(),
+                code: "print(1)".to_string(),
+            })
+            .await
+            .unwrap();
+        let AgentResponse::Result { id, stdout, .. } = resp else {
+            panic!("expected Result, got {resp:?}");
+        };
+        assert_eq!((id.as_str(), stdout.as_str()), ("1", "print(1)"));
+
+        transport.shutdown().await.unwrap();
+        assert!(!transport.is_alive());
+        agent.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn token_is_sent_before_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_file = dir.path().join("token");
+        std::fs::write(&token_file, "s3cret\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddr {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            token_file: Some(token_file),
+            ..TcpAddr::default()
+        };
+        let agent = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let auth: serde_json::Value =
+                serde_json::from_slice(&recv_message(&mut stream).await.unwrap()).unwrap();
+            let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+            send_message(&mut stream, &ready).await.unwrap();
+            auth
+        });
+
+        TcpTransport::connect(&addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            agent.await.unwrap(),
+            serde_json::json!({ "type": "auth", "token": "s3cret" })
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_loss_marks_dead() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddr {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            ..TcpAddr::default()
+        };
+        let agent = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+            send_message(&mut stream, &ready).await.unwrap();
+            // Drop the connection: the daemon sees EOF on its next request
+        });
+
+        let transport = TcpTransport::connect(&addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        agent.await.unwrap();
+
+        assert!(transport.request(&AgentRequest::Ping).await.is_err());
+        assert!(!transport.is_alive());
+    }
+
+    #[tokio::test]
+    async fn connect_refused() {
+        // Bind then drop, so nothing listens on the port
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = TcpAddr {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..TcpAddr::default()
+        };
+        let err = TcpTransport::connect(&addr, Duration::from_secs(5))
+            .await
+            .err()
+            .expect("connect should fail");
+        assert!(err.to_string().contains("Failed to connect to 127.0.0.1"));
+    }
+
+    #[test]
+    fn tls_connector_rejects_empty_ca_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, "").unwrap();
+        let err = tls_connector(Some(&path)).err().unwrap();
+        assert!(err.to_string().contains("No certificates"), "{err}");
+        assert!(tls_connector(None).is_ok());
+    }
+}
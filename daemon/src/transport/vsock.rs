@@ -0,0 +1,349 @@
+//! Vsock transport for agents running inside microVMs.
+//!
+//! Connects to the agent's `AF_VSOCK` listener at a given CID/port and speaks
+//! the same length-prefixed JSON protocol as `StdioPipeTransport`. The socket
+//! is created with `socket2` and driven through tokio's `UnixStream`, which
+//! only needs a connected stream socket for reads and writes.
+//!
+//! Locking mirrors `StdioPipeTransport`: a request holds `request_lock` (and
+//! the read half) for its round-trip, the write half only while writing, so
+//! `send_control` can reach a busy agent.
+
+use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::protocol::{AgentRequest, AgentResponse, Capabilities};
+use super::{
+    enable_compression, recv_message_timeout, send_frame, use_protocol, wait_ready, Transport,
+    RESPONSE_STALL_TIMEOUT,
+};
+use crate::backend::ExecError;
+
+/// Transport that communicates with a microVM agent over vsock.
+///
+/// The VM (and its agent) is managed outside the daemon; dropping or
+/// shutting down the transport only closes the connection.
+pub struct VsockTransport {
+    request_lock: Mutex<()>,
+    writer: Mutex<OwnedWriteHalf>,
+    reader: Mutex<OwnedReadHalf>,
+    alive: AtomicBool,
+    capabilities: Option<Capabilities>,
+    /// Protocol version agreed in the handshake.
+    protocol: u32,
+    /// Whether large requests are gzip-compressed (negotiated in `Ready`).
+    gzip: bool,
+}
+
+impl VsockTransport {
+    /// Connect to an agent at `cid:port` and wait for its `Ready` message.
+    ///
+    /// `ready_timeout` bounds the connect and the handshake together.
+    pub async fn connect(cid: u32, port: u32, ready_timeout: Duration) -> Result<Self> {
+        debug!(cid, port, "Connecting to vsock agent");
+
+        tokio::time::timeout(ready_timeout, async {
+            let stream = connect_stream(cid, port).await?;
+            Self::handshake(stream).await
+        })
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!("Agent at vsock {cid}:{port} not ready within {ready_timeout:?}")
+        })?
+    }
+
+    /// Wait for `Ready` on an already-connected stream.
+    async fn handshake(stream: UnixStream) -> Result<Self> {
+        let (mut reader, mut writer) = stream.into_split();
+        let (capabilities, protocol) = wait_ready(&mut reader).await?;
+        debug!(?capabilities, "Agent is ready");
+        use_protocol(&mut writer, capabilities.as_ref(), protocol).await?;
+        let gzip = enable_compression(&mut writer, capabilities.as_ref()).await?;
+
+        Ok(Self {
+            request_lock: Mutex::new(()),
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            alive: AtomicBool::new(true),
+            capabilities,
+            protocol,
+            gzip,
+        })
+    }
+
+    /// Record a connection error: the agent is unreachable from now on.
+    fn mark_dead<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.alive.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Open a blocking vsock connection off the runtime, then hand it to tokio.
+async fn connect_stream(cid: u32, port: u32) -> Result<UnixStream> {
+    let socket = tokio::task::spawn_blocking(move || -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.connect(&SockAddr::vsock(cid, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    })
+    .await
+    .context("vsock connect task panicked")?
+    .with_context(|| format!("Failed to connect to vsock {cid}:{port}"))?;
+
+    let std_stream = std::os::unix::net::UnixStream::from(OwnedFd::from(socket));
+    UnixStream::from_std(std_stream).context("Failed to register vsock stream")
+}
+
+#[async_trait]
+impl Transport for VsockTransport {
+    async fn request(&self, req: &AgentRequest) -> Result<AgentResponse> {
+        if !self.alive.load(Ordering::Relaxed) {
+            return Err(ExecError::IoError(anyhow::anyhow!("Agent connection is closed")).into());
+        }
+
+        let _request_guard = self.request_lock.lock().await;
+        let mut reader = self.reader.lock().await;
+
+        let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
+
+        let io_result: Result<Vec<u8>> = async {
+            send_frame(&mut *self.writer.lock().await, &req_bytes, self.gzip)
+                .await
+                .context("Failed to send request to agent")?;
+
+            recv_message_timeout(&mut *reader, Some(RESPONSE_STALL_TIMEOUT))
+                .await
+                .context("Failed to read response from agent")
+        }
+        .await;
+        drop(reader);
+
+        let resp_bytes = self.mark_dead(io_result).map_err(ExecError::IoError)?;
+        serde_json::from_slice(&resp_bytes)
+            .context("Failed to parse agent response")
+            .map_err(|e| ExecError::ProtocolError(e).into())
+    }
+
+    async fn send_control(&self, req: &AgentRequest) -> Result<()> {
+        if !self.alive.load(Ordering::Relaxed) {
+            anyhow::bail!("Agent connection is closed");
+        }
+
+        let req_bytes = serde_json::to_vec(req).context("Failed to serialize request")?;
+        let result = send_frame(&mut *self.writer.lock().await, &req_bytes, self.gzip)
+            .await
+            .context("Failed to send control message to agent");
+        self.mark_dead(result)
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        if !self.alive.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // The agent exits on Shutdown without replying, so don't wait for one
+        if let Err(e) = self.send_control(&AgentRequest::Shutdown).await {
+            warn!(error = %e, "Graceful shutdown failed, closing connection");
+        }
+
+        self.alive.store(false, Ordering::Relaxed);
+        let _ = self.writer.lock().await.shutdown().await;
+
+        debug!("Vsock agent connection closed");
+        Ok(())
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    fn protocol_version(&self) -> u32 {
+        self.protocol
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{recv_message, send_message};
+
+    /// Play the agent side of a stream: send `Ready`, then answer pings
+    /// until `Shutdown` or EOF.
+    async fn fake_agent(stream: UnixStream) {
+        let (mut reader, mut writer) = stream.into_split();
+        let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+        send_message(&mut writer, &ready).await.unwrap();
+
+        while let Ok(bytes) = recv_message(&mut reader).await {
+            match serde_json::from_slice(&bytes).unwrap() {
+                AgentRequest::Ping => {
+                    let pong = serde_json::to_vec(&AgentResponse::Pong).unwrap();
+                    send_message(&mut writer, &pong).await.unwrap();
+                }
+                AgentRequest::Shutdown => break,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_and_ping() {
+        let (daemon_side, agent_side) = UnixStream::pair().unwrap();
+        tokio::spawn(fake_agent(agent_side));
+
+        let transport = VsockTransport::handshake(daemon_side).await.unwrap();
+        assert!(transport.is_alive());
+
+        let resp = transport.request(&AgentRequest::Ping).await.unwrap();
+        assert!(matches!(resp, AgentResponse::Pong));
+
+        transport.shutdown().await.unwrap();
+        assert!(!transport.is_alive());
+        assert!(transport.request(&AgentRequest::Ping).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn negotiates_gzip_compression() {
+        let (daemon_side, agent_side) = UnixStream::pair().unwrap();
+        let agent = tokio::spawn(async move {
+            let (mut reader, mut writer) = agent_side.into_split();
+            let ready = AgentResponse::Ready {
+                capabilities: Some(Capabilities {
+                    gzip: true,
+                    ..Default::default()
+                }),
+            };
+            send_message(&mut writer, &serde_json::to_vec(&ready).unwrap())
+                .await
+                .unwrap();
+
+            let first = recv_message(&mut reader).await.unwrap();
+            assert!(matches!(
+                serde_json::from_slice(&first).unwrap(),
+                AgentRequest::EnableCompression
+            ));
+
+            // Echo the (transparently decompressed) code back, compressed
+            let bytes = recv_message(&mut reader).await.unwrap();
+            let AgentRequest::Execute { id, code, .. } = serde_json::from_slice(&bytes).unwrap()
+            else {
+                panic!("expected Execute");
+            };
+            let resp = AgentResponse::Result {
+                id,
+                stdout: code,
+                stderr: String::new(),
+                exit_code: 0,
+            };
+            send_frame(&mut writer, &serde_json::to_vec(&resp).unwrap(), true)
+                .await
+                .unwrap();
+        });
+
+        let transport = VsockTransport::handshake(daemon_side).await.unwrap();
+        assert!(transport.gzip);
+
+        let code = "print('x')\n".repeat(10_000);
+        let resp = transport
+            .request(&AgentRequest::Execute {
+                id: "1".to_string(),
+                interpreter: "python".to_string(),
+                code: code.clone(),
+            })
+            .await
+            .unwrap();
+        let AgentResponse::Result { stdout, .. } = resp else {
+            panic!("expected Result, got {resp:?}");
+        };
+        assert_eq!(stdout, code);
+        agent.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_non_ready() {
+        let (daemon_side, agent_side) = UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            let (_reader, mut writer) = agent_side.into_split();
+            let pong = serde_json::to_vec(&AgentResponse::Pong).unwrap();
+            send_message(&mut writer, &pong).await.unwrap();
+        });
+
+        let err = VsockTransport::handshake(daemon_side)
+            .await
+            .err()
+            .expect("handshake should fail");
+        assert!(err.to_string().contains("Expected Ready"));
+    }
+
+    #[tokio::test]
+    async fn connection_error_marks_dead() {
+        let (daemon_side, agent_side) = UnixStream::pair().unwrap();
+        let agent = tokio::spawn(async move {
+            let (_reader, mut writer) = agent_side.into_split();
+            let ready = serde_json::to_vec(&AgentResponse::Ready { capabilities: None }).unwrap();
+            send_message(&mut writer, &ready).await.unwrap();
+            // Drop both halves: the daemon sees EOF on its next request
+        });
+
+        let transport = VsockTransport::handshake(daemon_side).await.unwrap();
+        agent.await.unwrap();
+
+        assert!(transport.request(&AgentRequest::Ping).await.is_err());
+        assert!(!transport.is_alive());
+    }
+
+    /// Real `AF_VSOCK` round-trip over the local loopback CID.
+    /// Requires the `vsock_loopback` kernel module.
+    #[tokio::test]
+    async fn vsock_loopback() {
+        const VMADDR_CID_LOCAL: u32 = 1;
+        const PORT: u32 = 52_000;
+
+        if std::env::var("NIX_SANDBOX_VSOCK_TEST").is_err() {
+            eprintln!("Skipping vsock test (set NIX_SANDBOX_VSOCK_TEST=1 to run)");
+            return;
+        }
+
+        let listener = Socket::new(Domain::VSOCK, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SockAddr::vsock(VMADDR_CID_LOCAL, PORT))
+            .unwrap();
+        listener.listen(1).unwrap();
+
+        let agent = tokio::task::spawn_blocking(move || {
+            let (socket, _) = listener.accept().unwrap();
+            socket.set_nonblocking(true).unwrap();
+            std::os::unix::net::UnixStream::from(OwnedFd::from(socket))
+        });
+
+        let connect = tokio::spawn(VsockTransport::connect(
+            VMADDR_CID_LOCAL,
+            PORT,
+            Duration::from_secs(5),
+        ));
+        let agent_stream = UnixStream::from_std(agent.await.unwrap()).unwrap();
+        let serve = tokio::spawn(fake_agent(agent_stream));
+
+        let transport = connect.await.unwrap().unwrap();
+        let resp = transport.request(&AgentRequest::Ping).await.unwrap();
+        assert!(matches!(resp, AgentResponse::Pong));
+        transport.shutdown().await.unwrap();
+        serve.await.unwrap();
+    }
+}
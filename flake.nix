@@ -60,6 +60,8 @@
             version = "0.1.0";
             src = ./daemon;
             cargoLock.lockFile = ./daemon/Cargo.lock;
+            # Reported by the `version` tool; the source copy has no .git
+            NIX_SANDBOX_GIT_REV = inputs.self.shortRev or inputs.self.dirtyShortRev or "unknown";
           };
 
           mkServer =
@@ -163,6 +165,13 @@
             integration = import ./nix/tests {
               inherit pkgs;
               mcpServer = mkServer ./config.example.toml;
+              # Non-bash "pipe" wrapper, to check code plus input data on stdin
+              pythonPipeEnv = (import ./nix/backends { inherit pkgs jail; }).jail.mkJailedEnv {
+                name = "python-pipe";
+                env = presets.python;
+                interpreter = "python3 -";
+                stdinMode = "pipe";
+              };
             };
           };
 
@@ -2,6 +2,28 @@
 { pkgs, jail, agentPkg ? null }:
 
 rec {
+  # "python3 -c" -> [ "python3" "-c" ]
+  commandWords = command:
+    builtins.filter (s: builtins.isString s && s != "") (builtins.split " " command);
+
+  # "python3 -c" -> python3 "${interp_args[@]}" -c: the runner's extra
+  # interpreter flags go right after the program, before its own flags
+  withInterpreterArgs = command:
+    let words = commandWords command;
+    in builtins.concatStringsSep " "
+      ([ (builtins.head words) "\"\${interp_args[@]}\"" ] ++ builtins.tail words);
+
+  # "bash -s" -> "bash", "python3 -" -> "python3": the flag that makes an
+  # interpreter read its program from stdin, which a code file replaces
+  stripStdinFlag = interpreter:
+    let
+      words = commandWords interpreter;
+      last = if words == [] then "" else builtins.elemAt words (builtins.length words - 1);
+    in
+      if last == "-s" || last == "-"
+      then builtins.concatStringsSep " " (pkgs.lib.init words)
+      else interpreter;
+
   # Create a jailed wrapper for an environment
   # Returns a derivation with /bin/run that:
   #   1. Reads code from stdin
@@ -13,6 +35,8 @@ rec {
   #   env: The environment package (from nix/environments/)
   #   interpreter: Command to run code (e.g., "python3 -c")
   #   stdinMode: How to pass code - "arg" (python -c "$(cat)") or "pipe" (bash -s)
+  #   fileInterpreter: Command to run a code file in "pipe" mode when stdin also
+  #     carries input data (default: interpreter without a trailing "-s" or "-")
   #   projectPath: Optional path to mount as project directory (null = no project)
   #   projectMount: Mount point for project inside sandbox (default: /project)
   # Note: Project is always mounted read-only for security and reproducibility
@@ -21,6 +45,7 @@ rec {
     env,
     interpreter,
     stdinMode ? "arg",  # "arg" = pass as argument, "pipe" = pipe to stdin
+    fileInterpreter ? stripStdinFlag interpreter,
     projectPath ? null,
     projectMount ? "/project",
     inheritVars ? [],  # Environment variable names to inherit from host
@@ -30,18 +55,54 @@ rec {
       # The runner script that executes inside the jail
       # Note: interpreter commands (python3, bash, node) are available via add-pkg-deps
       # Use writeShellScriptBin to create a package with bin/ structure as expected by jail.nix
+      # When SANDBOX_CODE_BYTES is set, stdin carries the code followed by
+      # input data for the program: only the first N bytes are read as code
+      # and the remainder is left on stdin for the interpreter.
+      # SANDBOX_WORKDIR (validated by the daemon) overrides the /workspace CWD.
+      # Per-call secrets arrive as a read-only file of NUL-terminated
+      # NAME=value entries (see secretCombs), exported here so the values
+      # never appear on a command line.
+      # SANDBOX_INTERPRETER_ARGS holds the environment's interpreter_args,
+      # one per line; they go before the interpreter's own flags.
+      loadInterpreterArgs = ''
+        interp_args=()
+        if [ -n "''${SANDBOX_INTERPRETER_ARGS:-}" ]; then
+          mapfile -t interp_args <<< "$SANDBOX_INTERPRETER_ARGS"
+        fi
+      '';
+      loadSecrets = ''
+        if [ -f /run/sandbox-secrets ]; then
+          while IFS= read -r -d "" entry; do
+            export "$entry"
+          done < /run/sandbox-secrets
+        fi
+      '';
       runnerScript = if stdinMode == "arg" then
         pkgs.writeShellScriptBin "runner-${name}" ''
           set -euo pipefail
-          cd /workspace
-          code="$(cat)"
-          exec ${interpreter} "$code"
+          ${loadSecrets}
+          ${loadInterpreterArgs}
+          cd "''${SANDBOX_WORKDIR:-/workspace}"
+          if [ -n "''${SANDBOX_CODE_BYTES:-}" ]; then
+            code="$(head -c "$SANDBOX_CODE_BYTES")"
+          else
+            code="$(cat)"
+          fi
+          exec ${withInterpreterArgs interpreter} "$code"
         ''
       else
         pkgs.writeShellScriptBin "runner-${name}" ''
           set -euo pipefail
-          cd /workspace
-          exec ${interpreter}
+          ${loadSecrets}
+          ${loadInterpreterArgs}
+          cd "''${SANDBOX_WORKDIR:-/workspace}"
+          if [ -n "''${SANDBOX_CODE_BYTES:-}" ]; then
+            # Script can't share stdin with its input: run it from a file
+            code_file="$(mktemp)"
+            head -c "$SANDBOX_CODE_BYTES" > "$code_file"
+            exec ${withInterpreterArgs fileInterpreter} "$code_file"
+          fi
+          exec ${withInterpreterArgs interpreter}
         '';
 
       # Capture inherited environment variables at build time
@@ -60,11 +121,27 @@ rec {
       jailed = jail "sandbox-${name}" "${runnerScript}/bin/runner-${name}" (c:
         let
           # Project mounting combinator
-          # runtimeProjectMount: check PROJECT_DIR env var at runtime (for mkSandbox artifacts)
+          # runtimeProjectMount: bind PROJECT_DIR_<i>/PROJECT_MOUNT_<i> at runtime
+          # (read-write if PROJECT_READ_ONLY_<i> is 0), falling back to the
+          # single PROJECT_DIR/PROJECT_MOUNT pair (for mkSandbox artifacts)
           # projectPath: bind at build time (for bundled presets built via fromToml.nix)
           projectCombs = if runtimeProjectMount then [
             (c.add-runtime ''
-              if [ -n "''${PROJECT_DIR:-}" ] && [ -d "$PROJECT_DIR" ]; then
+              if [ -n "''${PROJECT_DIR_0:-}" ]; then
+                i=0
+                while dir_var="PROJECT_DIR_$i" && [ -n "''${!dir_var:-}" ]; do
+                  mount_var="PROJECT_MOUNT_$i"
+                  ro_var="PROJECT_READ_ONLY_$i"
+                  if [ -d "''${!dir_var}" ]; then
+                    if [ "''${!ro_var:-1}" = "0" ]; then
+                      RUNTIME_ARGS+=(--bind "''${!dir_var}" "''${!mount_var}")
+                    else
+                      RUNTIME_ARGS+=(--ro-bind "''${!dir_var}" "''${!mount_var}")
+                    fi
+                  fi
+                  i=$((i + 1))
+                done
+              elif [ -n "''${PROJECT_DIR:-}" ] && [ -d "$PROJECT_DIR" ]; then
                 RUNTIME_ARGS+=(--ro-bind "$PROJECT_DIR" "''${PROJECT_MOUNT:-/project}")
               fi
             '')
@@ -72,8 +149,50 @@ rec {
             (c.ro-bind projectPath projectMount)
           ] else [];
 
+          # Scratch mounting combinator (always runtime): read-write bind of
+          # SCRATCH_DIR, layered over the per-run /workspace tmpfs by default
+          scratchCombs = [
+            (c.add-runtime ''
+              if [ -n "''${SCRATCH_DIR:-}" ] && [ -d "$SCRATCH_DIR" ]; then
+                RUNTIME_ARGS+=(--bind "$SCRATCH_DIR" "''${SCRATCH_MOUNT:-/workspace}")
+              fi
+            '')
+          ];
+
           # Environment variable combinators
-          envVarCombs = map (e: c.set-env e.name e.value) inheritedEnvCombs;
+          # Build-time values from inheritVars, plus vars the daemon forwards
+          # at runtime (names listed in SANDBOX_INHERIT_ENV)
+          envVarCombs = map (e: c.set-env e.name e.value) inheritedEnvCombs ++ [
+            (c.add-runtime ''
+              for var in ''${SANDBOX_INHERIT_ENV:-}; do
+                RUNTIME_ARGS+=(--setenv "$var" "''${!var}")
+              done
+            '')
+          ];
+
+          # Network policy from the daemon: SANDBOX_NETWORK=1 (`network = true`)
+          # keeps the host's network namespace, with its resolver config and
+          # CA certificates; anything else leaves the sandbox with loopback only
+          networkCombs = [
+            (c.add-runtime ''
+              if [ "''${SANDBOX_NETWORK:-0}" = "1" ]; then
+                RUNTIME_ARGS+=(--share-net)
+                for f in /etc/resolv.conf /etc/hosts /etc/nsswitch.conf /etc/ssl/certs; do
+                  RUNTIME_ARGS+=(--ro-bind-try "$f" "$f")
+                done
+              fi
+            '')
+          ];
+
+          # Per-call secrets: the daemon's private file, bound read-only for
+          # the runner to export (values stay off the bwrap command line)
+          secretCombs = [
+            (c.add-runtime ''
+              if [ -n "''${SANDBOX_SECRET_FILE:-}" ] && [ -f "$SANDBOX_SECRET_FILE" ]; then
+                RUNTIME_ARGS+=(--ro-bind "$SANDBOX_SECRET_FILE" /run/sandbox-secrets)
+              fi
+            '')
+          ];
         in [
           # Minimal base: fake /proc, /dev, coreutils, bash
           c.base
@@ -87,12 +206,11 @@ rec {
           (c.set-env "HOME" "/workspace")
           (c.set-env "TMPDIR" "/workspace")
 
-          # No network access by default (security)
-          # Network would require: c.network
+          # No network access by default (security); see networkCombs
 
           # Minimal environment variables
           (c.set-env "TERM" "dumb")
-        ] ++ projectCombs ++ envVarCombs);
+        ] ++ projectCombs ++ scratchCombs ++ envVarCombs ++ networkCombs ++ secretCombs);
     in
       # Return derivation with /bin/run pointing to the jailed script
       # ${jailed} is a derivation with bin/sandbox-${name} executable
@@ -135,6 +135,7 @@ let
 
       # Extract config values with defaults
       timeout = envConfig.timeout_seconds or config.defaults.timeout_seconds or 30;
+      maxTimeout = envConfig.max_timeout_seconds or config.defaults.max_timeout_seconds or null;
       memory = envConfig.memory_mb or config.defaults.memory_mb or 512;
     in {
       drv = jailedEnv;
@@ -146,6 +147,39 @@ let
         memory_mb = memory;
       } // (if sessionJailedEnv != null then {
         session_exec = "${sessionJailedEnv}/bin/run";
+      } else {})
+        // (if maxTimeout != null then {
+        max_timeout_seconds = maxTimeout;
+      } else {})
+        // (if envConfig ? max_output_bytes then {
+        inherit (envConfig) max_output_bytes;
+      } else {})
+        // (if envConfig ? inherit_env then {
+        inherit (envConfig) inherit_env;
+      } else {})
+        // (if envConfig ? aliases then {
+        inherit (envConfig) aliases;
+      } else {})
+        // (if envConfig ? preamble then {
+        inherit (envConfig) preamble;
+      } else {})
+        // (if envConfig ? setup_exec then {
+        inherit (envConfig) setup_exec;
+      } else {})
+        // (if envConfig ? setup_timeout_seconds then {
+        inherit (envConfig) setup_timeout_seconds;
+      } else {})
+        // (if envConfig ? output_encoding then {
+        inherit (envConfig) output_encoding;
+      } else {})
+        // (if envConfig ? network then {
+        inherit (envConfig) network;
+      } else {})
+        // (if envConfig ? input_mode then {
+        inherit (envConfig) input_mode;
+      } else {})
+        // (if envConfig ? interpreter_args then {
+        inherit (envConfig) interpreter_args;
       } else {});
     };
 
@@ -243,13 +277,42 @@ let
   sessionConfig = if config ? session then {
     idle_timeout_seconds = config.session.idle_timeout_seconds or 300;
     max_lifetime_seconds = config.session.max_lifetime_seconds or 3600;
-  } else null;
+    max_sessions = config.session.max_sessions or 16;
+  } // (if config.session ? keepalive_interval_seconds then {
+    inherit (config.session) keepalive_interval_seconds;
+  } else {}) // (if config.session ? request_timeout_seconds then {
+    inherit (config.session) request_timeout_seconds;
+  } else {}) // (if config.session ? allowed_interpreters then {
+    inherit (config.session) allowed_interpreters;
+  } else {}) // (if config.session ? session_allow then {
+    inherit (config.session) session_allow;
+  } else {}) // (if config.session ? session_deny then {
+    inherit (config.session) session_deny;
+  } else {}) // (if config.session ? agent_ready_timeout_seconds then {
+    inherit (config.session) agent_ready_timeout_seconds;
+  } else {}) // (if config.session ? reaper_interval_seconds then {
+    inherit (config.session) reaper_interval_seconds;
+  } else {}) // (if config.session ? rate_limit_executions then {
+    inherit (config.session) rate_limit_executions;
+  } else {}) // (if config.session ? rate_limit_window_seconds then {
+    inherit (config.session) rate_limit_window_seconds;
+  } else {}) else null;
 
   # Full metadata structure expected by daemon
-  # Shape: { environments: {...}, session?: {...} }
+  # Shape: { environments: {...}, session?: {...}, scratch?: {...}, mounts?: [...], pool?: {...}, limits?: {...}, interpreter_map?: {...}, audit?: {...}, output?: {...}, instructions_template?: string, default_env?: string, sandbox_merge?: string }
   fullMetadata = {
     environments = envMetadata;
-  } // (if sessionConfig != null then { session = sessionConfig; } else {});
+  } // (if sessionConfig != null then { session = sessionConfig; } else {})
+    // (if config ? scratch then { scratch = config.scratch; } else {})
+    // (if config ? mounts then { inherit (config) mounts; } else {})
+    // (if config ? pool then { inherit (config) pool; } else {})
+    // (if config ? limits then { inherit (config) limits; } else {})
+    // (if config ? interpreter_map then { inherit (config) interpreter_map; } else {})
+    // (if config ? audit then { inherit (config) audit; } else {})
+    // (if config ? output then { inherit (config) output; } else {})
+    // (if config ? instructions_template then { inherit (config) instructions_template; } else {})
+    // (if config ? default_env then { inherit (config) default_env; } else {})
+    // (if config ? sandbox_merge then { inherit (config) sandbox_merge; } else {});
 
   metadataJson = builtins.toJSON fullMetadata;
 
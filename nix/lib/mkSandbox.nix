@@ -1,7 +1,7 @@
 # mkSandbox — build a standalone sandbox artifact for nix-sandbox-mcp.
 #
 # Produces a derivation with standard layout:
-#   $out/metadata.json       # {name, interpreter_type, timeout_seconds, memory_mb}
+#   $out/metadata.json       # {name, interpreter_type, timeout_seconds, memory_mb, max_output_bytes, aliases, preamble}
 #   $out/bin/run             # Ephemeral execution wrapper (jailed)
 #   $out/bin/session-run     # Session execution wrapper (jailed)
 #
@@ -23,6 +23,9 @@
   packages,                   # List of Nix packages to include
   timeout_seconds ? 30,
   memory_mb ? 512,
+  max_output_bytes ? 1048576, # Output returned to the client is truncated past this
+  aliases ? [],               # Other names clients may use for this sandbox
+  preamble ? null,            # Code run once at the start of each session
 }:
 
 let
@@ -60,7 +63,7 @@ let
 
   # metadata.json for the daemon's scanner
   metadataJson = builtins.toJSON {
-    inherit name interpreter_type timeout_seconds memory_mb;
+    inherit name interpreter_type timeout_seconds memory_mb max_output_bytes aliases preamble;
   };
 
 in pkgs.runCommand "sandbox-${name}" { } ''
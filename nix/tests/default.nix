@@ -1,5 +1,5 @@
 # NixOS VM-based integration tests
-{ pkgs, mcpServer }:
+{ pkgs, mcpServer, pythonPipeEnv }:
 
 pkgs.testers.nixosTest {
   name = "nix-sandbox-mcp";
@@ -7,6 +7,7 @@ pkgs.testers.nixosTest {
   nodes.machine = { pkgs, ... }: {
     environment.systemPackages = [
       mcpServer
+      pythonPipeEnv
       pkgs.python3
     ];
 
@@ -79,6 +80,32 @@ with subtest("Empty command returns success"):
     result = mcp_call('{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"run","arguments":{"env":"python","code":""}}}')
     assert '"isError":true' not in result, f"Empty command should not error: {result}"
 
+# Test 9b: a "pipe" wrapper for a non-bash interpreter runs the code with its
+# own interpreter when input data follows the code on stdin
+with subtest("Pipe wrapper with input data uses its interpreter"):
+    code = "import sys; print(sys.version_info[0], sys.stdin.read().upper())"
+    result = machine.succeed(
+        f"printf '%s%s' '{code}' 'hello' | SANDBOX_CODE_BYTES={len(code)} ${pythonPipeEnv}/bin/run"
+    )
+    assert result.strip() == "3 HELLO", f"Expected python to run the code and read input: {result}"
+
+# Test 9c: SANDBOX_INTERPRETER_ARGS (one per line) go before the
+# interpreter's own flags
+with subtest("Wrapper applies interpreter args"):
+    result = machine.succeed(
+        "printf 'print(__debug__)' | SANDBOX_INTERPRETER_ARGS=-O ${pythonPipeEnv}/bin/run"
+    )
+    assert result.strip() == "False", f"Expected python -O: {result}"
+
+# Test 9d: SANDBOX_NETWORK=1 keeps the host's network namespace; without it
+# the sandbox only has loopback
+with subtest("SANDBOX_NETWORK controls the network namespace"):
+    code = "import socket; print(sorted(n for _, n in socket.if_nameindex()))"
+    isolated = machine.succeed(f"printf '%s' '{code}' | ${pythonPipeEnv}/bin/run")
+    assert isolated.strip() == "['lo']", f"Expected only loopback: {isolated}"
+    shared = machine.succeed(f"printf '%s' '{code}' | SANDBOX_NETWORK=1 ${pythonPipeEnv}/bin/run")
+    assert "eth" in shared, f"Expected the host's interfaces: {shared}"
+
 
 # ─────────────────────────────────────────────────────────────────
 # Session persistence tests